@@ -0,0 +1,244 @@
+use anyhow::{bail, Context};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// `[serve.auth]` section of the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthConfig {
+    /// plain tokens, named by their position: token-0, token-1...
+    #[serde(default)]
+    pub tokens: Vec<String>,
+    /// file with `name:sha256-hex-of-token` lines
+    pub tokens_file: Option<PathBuf>,
+    /// requests per minute allowed for every token, 0 for no limit
+    #[serde(default)]
+    pub requests_per_minute: u32,
+}
+
+/// Reason to reject the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    RateLimited { retry_after: Duration },
+}
+
+impl AuthError {
+    /// HTTP status of the response
+    pub fn status(&self) -> u16 {
+        match self {
+            AuthError::Missing | AuthError::Invalid => 401,
+            AuthError::RateLimited { .. } => 429,
+        }
+    }
+
+    /// value of Retry-After header, in whole seconds
+    pub fn retry_after_secs(&self) -> Option<u64> {
+        match self {
+            AuthError::RateLimited { retry_after } => {
+                Some(retry_after.as_secs() + (retry_after.subsec_nanos() > 0) as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+// paths that are served without a token
+const PUBLIC_PATHS: &[&str] = &["/healthz"];
+
+fn hash_token(token: &str) -> [u8; 32] {
+    Sha256::digest(token.as_bytes()).into()
+}
+
+// comparison time doesn't depend on the position of the first difference
+fn constant_time_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+// fixed one-minute window of requests
+#[derive(Debug)]
+struct Window {
+    started: Instant,
+    count: u32,
+}
+
+/// Bearer token authentication with per-token rate limits.
+/// Only names and hashes of the tokens are kept
+#[derive(Debug)]
+pub struct Authenticator {
+    tokens: Vec<(String, [u8; 32])>,
+    requests_per_minute: u32,
+    windows: Mutex<HashMap<String, Window>>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> anyhow::Result<Self> {
+        let mut tokens: Vec<(String, [u8; 32])> = config
+            .tokens
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (format!("token-{}", i), hash_token(t)))
+            .collect();
+        if let Some(path) = &config.tokens_file {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("read {}", path.display()))?;
+            tokens.extend(parse_tokens_file(&content)?);
+        }
+        if tokens.is_empty() {
+            bail!("no tokens configured");
+        }
+        Ok(Self {
+            tokens,
+            requests_per_minute: config.requests_per_minute,
+            windows: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// checks Authorization header of the request to the path,
+    /// returns name of the token (None for public paths)
+    pub fn check(
+        &self,
+        path: &str,
+        authorization: Option<&str>,
+        now: Instant,
+    ) -> Result<Option<String>, AuthError> {
+        if PUBLIC_PATHS.contains(&path) {
+            return Ok(None);
+        }
+        let token = authorization
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .ok_or(AuthError::Missing)?;
+        let hash = hash_token(token);
+        // every token is compared, so timing doesn't reveal which one matched
+        let mut found = None;
+        for (name, expected) in &self.tokens {
+            if constant_time_eq(expected, &hash) {
+                found = Some(name);
+            }
+        }
+        let name = found.ok_or(AuthError::Invalid)?;
+        self.take(name, now)?;
+        Ok(Some(name.clone()))
+    }
+
+    fn take(&self, name: &str, now: Instant) -> Result<(), AuthError> {
+        if self.requests_per_minute == 0 {
+            return Ok(());
+        }
+        let minute = Duration::from_secs(60);
+        let mut windows = self.windows.lock().unwrap();
+        let w = windows.entry(name.to_string()).or_insert(Window {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(w.started) >= minute {
+            w.started = now;
+            w.count = 0;
+        }
+        if w.count >= self.requests_per_minute {
+            let retry_after = minute - now.duration_since(w.started);
+            return Err(AuthError::RateLimited { retry_after });
+        }
+        w.count += 1;
+        Ok(())
+    }
+}
+
+fn parse_tokens_file(content: &str) -> anyhow::Result<Vec<(String, [u8; 32])>> {
+    let mut out = Vec::new();
+    for (n, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, digest) = line
+            .split_once(':')
+            .with_context(|| format!("tokens file line {}: expected name:sha256", n + 1))?;
+        let mut hash = [0u8; 32];
+        hex::decode_to_slice(digest.trim(), &mut hash)
+            .with_context(|| format!("tokens file line {}: invalid sha256", n + 1))?;
+        out.push((name.trim().to_string(), hash));
+    }
+    Ok(out)
+}
+
+/// structured access log line, token itself is never logged
+pub fn log_access(token: Option<&str>, path: &str, status: u16, latency: Duration) {
+    info!(
+        token = token.unwrap_or("-"),
+        path,
+        status,
+        latency_ms = latency.as_millis() as u64,
+        "access"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(requests_per_minute: u32) -> Authenticator {
+        Authenticator::new(&AuthConfig {
+            tokens: vec!["secret".to_string()],
+            tokens_file: None,
+            requests_per_minute,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn it_checks_tokens() {
+        let a = auth(0);
+        let now = Instant::now();
+        assert_eq!(a.check("/blocks/1", None, now), Err(AuthError::Missing));
+        assert_eq!(
+            a.check("/blocks/1", Some("Basic c2VjcmV0"), now),
+            Err(AuthError::Missing)
+        );
+        assert_eq!(
+            a.check("/blocks/1", Some("Bearer wrong"), now),
+            Err(AuthError::Invalid)
+        );
+        assert_eq!(
+            a.check("/blocks/1", Some("Bearer secret"), now),
+            Ok(Some("token-0".to_string()))
+        );
+        assert_eq!(a.check("/healthz", None, now), Ok(None));
+    }
+
+    #[test]
+    fn it_limits_rate_per_token() {
+        let a = auth(2);
+        let t0 = Instant::now();
+        let h = Some("Bearer secret");
+        assert!(a.check("/blocks/1", h, t0).is_ok());
+        assert!(a.check("/blocks/1", h, t0).is_ok());
+        let err = a
+            .check("/blocks/1", h, t0 + Duration::from_millis(20_500))
+            .unwrap_err();
+        assert_eq!(err.status(), 429);
+        assert_eq!(err.retry_after_secs(), Some(40));
+        // next window
+        assert!(a
+            .check("/blocks/1", h, t0 + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn it_reads_hashed_tokens() {
+        let digest = hex::encode(hash_token("other"));
+        let tokens = parse_tokens_file(&format!("# comment\nreader: {}\n", digest)).unwrap();
+        assert_eq!(tokens, vec![("reader".to_string(), hash_token("other"))]);
+        assert!(parse_tokens_file("reader:xyz").is_err());
+    }
+}
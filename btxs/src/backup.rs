@@ -0,0 +1,318 @@
+use crate::bench::parse_range;
+use crate::import::{apply_chunk, notify};
+use crate::manifest::{Manifest, ManifestBucket, OperationId, StoredFormat};
+use crate::notify::EventLog;
+use anyhow::{bail, Context};
+use clap::Args;
+use kv::KV;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use tracing::*;
+
+/// kind of the manifest of restores
+const OPERATION_KIND: &str = "restore";
+
+/// kind of the notification of restored blocks
+const APPLIED_EVENT: &str = "restore.applied";
+
+/// Copy of stored blocks as they are encoded
+#[derive(Debug, Clone, Args)]
+pub struct BackupArgs {
+    /// blocks FROM..TO to be copied
+    #[arg(long)]
+    pub range: String,
+    /// backup file to be written
+    #[arg(long)]
+    pub out: PathBuf,
+}
+
+/// Blocks of a backup file written back into the storage
+#[derive(Debug, Clone, Args)]
+pub struct RestoreArgs {
+    /// backup file written by `btxs backup`
+    #[arg(long)]
+    pub file: PathBuf,
+    /// apply blocks again even if the manifest says they are applied
+    #[arg(long)]
+    pub force_reapply: bool,
+    /// blocks applied and recorded in the manifest together
+    #[arg(long, default_value_t = 1000)]
+    pub chunk_size: usize,
+}
+
+// first line of the backup file, blocks follow one per line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Header {
+    format: StoredFormat,
+}
+
+// stored value of one block
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    number: u32,
+    /// hex of the stored blob
+    blob: String,
+}
+
+/// writes the stored blocks in the range with the format they are encoded in,
+/// returns their number
+pub async fn backup<K: KV + Sync, W: Write>(
+    storage: &K,
+    range: &str,
+    out: &mut W,
+) -> anyhow::Result<u64> {
+    let (from, to) = parse_range(range)?;
+    let header = Header {
+        format: StoredFormat::current()?,
+    };
+    serde_json::to_writer(&mut *out, &header)?;
+    out.write_all(b"\n")?;
+    let numbers = storage
+        .keys(from, to - 1)
+        .await?
+        .context("storage can't list its blocks")?;
+    let mut written = 0;
+    for number in numbers {
+        let blob = match storage.get(number).await? {
+            Some(blob) if !blob.is_empty() => blob,
+            _ => continue,
+        };
+        let entry = Entry {
+            number,
+            blob: hex::encode(blob),
+        };
+        serde_json::to_writer(&mut *out, &entry)?;
+        out.write_all(b"\n")?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+// blocks of the backup in the order they were written
+fn read_backup<R: BufRead>(input: R) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+    let mut lines = input.lines();
+    let header: Header = match lines.next() {
+        Some(line) => serde_json::from_str(&line?).context("backup header")?,
+        None => bail!("backup is empty"),
+    };
+    StoredFormat::current()?
+        .ensure_compatible(&header.format)
+        .context("can't restore the backup")?;
+    let mut out = vec![];
+    for (n, line) in lines.enumerate() {
+        let entry: Entry =
+            serde_json::from_str(&line?).with_context(|| format!("backup line {}", n + 2))?;
+        let blob = hex::decode(&entry.blob).with_context(|| format!("block {}", entry.number))?;
+        out.push((entry.number, blob));
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    pub restored: u64,
+    pub first: Option<u32>,
+    pub last: Option<u32>,
+    /// hash of the restored blocks
+    pub operation: String,
+    /// blocks left out as applied by an earlier run
+    pub already_applied: u64,
+}
+
+impl fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                writeln!(f, "restored {} blocks {}..={}", self.restored, first, last)?
+            }
+            _ => writeln!(f, "restored {} blocks", self.restored)?,
+        }
+        if self.already_applied > 0 {
+            writeln!(
+                f,
+                "left out {} blocks already applied by operation {}, use --force-reapply to apply them again",
+                self.already_applied, self.operation
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Restores the backup in chunks recorded in the manifest of the operation,
+/// as the import does: a second run of the same backup leaves out what is
+/// applied and an interrupted one resumes after the last recorded chunk
+pub async fn restore<K: KV + Sync, R: BufRead>(
+    args: &RestoreArgs,
+    input: R,
+    storage: &K,
+    table: &str,
+    manifests: &ManifestBucket<K>,
+    events: Option<&EventLog<K>>,
+) -> anyhow::Result<RestoreReport> {
+    if args.chunk_size == 0 {
+        bail!("chunk size should be positive");
+    }
+    let mut blocks = read_backup(input)?;
+    blocks.sort_by_key(|(number, _)| *number);
+    let mut id = OperationId::default();
+    for (number, blob) in &blocks {
+        id.update(*number, blob);
+    }
+    let operation = id.finish();
+    let mut manifest = match manifests.get(&operation).await? {
+        Some(m) if !args.force_reapply => m,
+        _ => Manifest::new(OPERATION_KIND, &operation),
+    };
+    manifest.resume_format()?;
+    let mut report = RestoreReport {
+        operation: operation.clone(),
+        ..Default::default()
+    };
+    for range in manifest.pending_notifications() {
+        info!(?range, "notifying blocks applied before the crash");
+        notify(events, APPLIED_EVENT, manifests, &mut manifest, range).await?;
+    }
+    let total = blocks.len();
+    blocks.retain(|(number, _)| !manifest.is_applied(*number));
+    report.already_applied = (total - blocks.len()) as u64;
+    if report.already_applied > 0 {
+        info!(
+            operation,
+            applied = report.already_applied,
+            "leaving out applied blocks"
+        );
+    }
+
+    let total = blocks.len();
+    let mut done = 0;
+    for chunk in blocks.chunks(args.chunk_size) {
+        let range = (chunk[0].0, chunk[chunk.len() - 1].0);
+        manifest.apply(range.0, range.1);
+        apply_chunk(storage, table, manifests, &mut manifest, chunk.to_vec()).await?;
+        notify(events, APPLIED_EVENT, manifests, &mut manifest, range).await?;
+        report.restored += chunk.len() as u64;
+        report.first = Some(report.first.unwrap_or(range.0));
+        report.last = Some(range.1);
+        done += chunk.len();
+        info!(done, total, number = range.1, "restore progress");
+    }
+    manifest.completed = true;
+    manifests.save(&mut manifest).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::MemoryKV;
+
+    fn args(force_reapply: bool) -> RestoreArgs {
+        RestoreArgs {
+            file: PathBuf::from("backup.jsonl"),
+            force_reapply,
+            chunk_size: 1,
+        }
+    }
+
+    // notified block numbers, every one as many times as it was notified
+    async fn notified(events: &EventLog<MemoryKV>) -> Vec<u32> {
+        let mut out = vec![];
+        for e in events.since(0, 1000).await.unwrap() {
+            assert_eq!(e.kind, APPLIED_EVENT);
+            let first = e.payload["first"].as_u64().unwrap() as u32;
+            let last = e.payload["last"].as_u64().unwrap() as u32;
+            out.extend(first..=last);
+        }
+        out.sort();
+        out
+    }
+
+    #[tokio::test]
+    async fn it_restores_a_backup_exactly_once() {
+        let (source, blocks) = crate::export::stored_fixtures().await;
+        let mut file = vec![];
+        let written = backup(&source, "0..20000000", &mut file).await.unwrap();
+        assert_eq!(written, 2);
+
+        let memory = MemoryKV::new();
+        let storage = memory.bucket("blocks");
+        let manifests = ManifestBucket::new(memory.bucket("operations"), "operations");
+        let events = EventLog::new(memory.bucket("events"));
+        let report = restore(
+            &args(false),
+            file.as_slice(),
+            &storage,
+            "blocks",
+            &manifests,
+            Some(&events),
+        )
+        .await
+        .unwrap();
+        assert_eq!((report.restored, report.already_applied), (2, 0));
+        let mut numbers = vec![];
+        for block in &blocks {
+            let number = block.block.number.unwrap().as_u32();
+            let restored = storage.get(number).await.unwrap();
+            assert_eq!(restored, source.get(number).await.unwrap());
+            numbers.push(number);
+        }
+        assert_eq!(notified(&events).await, numbers);
+
+        // running it again applies and notifies nothing
+        let again = restore(
+            &args(false),
+            file.as_slice(),
+            &storage,
+            "blocks",
+            &manifests,
+            Some(&events),
+        )
+        .await
+        .unwrap();
+        assert_eq!((again.restored, again.already_applied), (0, 2));
+        assert_eq!(again.operation, report.operation);
+        assert!(again.to_string().contains("--force-reapply"));
+        assert_eq!(notified(&events).await, numbers);
+        let manifest = manifests.get(&report.operation).await.unwrap().unwrap();
+        assert!(manifest.completed);
+
+        let forced = restore(
+            &args(true),
+            file.as_slice(),
+            &storage,
+            "blocks",
+            &manifests,
+            Some(&events),
+        )
+        .await
+        .unwrap();
+        assert_eq!((forced.restored, forced.already_applied), (2, 0));
+        assert_eq!(notified(&events).await.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn it_refuses_backups_of_another_format() {
+        let header = Header {
+            format: StoredFormat {
+                version: crate::manifest::FORMAT_VERSION + 1,
+                ..StoredFormat::current().unwrap()
+            },
+        };
+        let file = format!("{}\n", serde_json::to_string(&header).unwrap());
+        let memory = MemoryKV::new();
+        let manifests = ManifestBucket::new(memory.bucket("operations"), "operations");
+        let err = restore(
+            &args(false),
+            file.as_bytes(),
+            &memory.bucket("blocks"),
+            "blocks",
+            &manifests,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("format version"), "{:#}", err);
+    }
+}
@@ -0,0 +1,493 @@
+#[cfg(feature = "test-util")]
+use crate::process::{follow_step, Sink};
+#[cfg(feature = "test-util")]
+use crate::writer::{Writer, CHECKPOINT_KEY};
+use anyhow::{bail, Context};
+use clap::{Args, ValueEnum};
+#[cfg(feature = "test-util")]
+use eth_logs::fixtures::ChainGenerator;
+#[cfg(feature = "test-util")]
+use eth_logs::{EthBatchClient, EthLogsStream, QuarantineBucket};
+use jsondp::dictionary::{MapDictionary, NoDictionary};
+use kv::{MemoryKV, PostgresKV, KV};
+use serde::Serialize;
+use serde_json::{json, Value};
+#[cfg(feature = "test-util")]
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+
+// blocks per eth_getLogs request of the pipeline
+#[cfg(feature = "test-util")]
+const PIPELINE_BATCH: u64 = 100;
+
+// splitmix64 generator of the synthetic blocks, same seed gives the same blocks
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// JSON documents into jsondp blobs
+    Encode,
+    /// jsondp blobs into JSON documents
+    Decode,
+    /// storing blobs
+    KvWrite,
+    /// loading blobs
+    KvRead,
+    /// follow the mock chain of the seed through the stream and the writer,
+    /// then load and decode every stored block
+    #[cfg(feature = "test-util")]
+    Pipeline,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Storage {
+    Postgres,
+    Memory,
+}
+
+/// Repeatable performance measurement
+#[derive(Debug, Clone, Args)]
+pub struct BenchArgs {
+    #[arg(value_enum)]
+    pub mode: Mode,
+    /// number of synthetic blocks
+    #[arg(long, default_value_t = 1000)]
+    pub count: u32,
+    /// transactions per synthetic block
+    #[arg(long, default_value_t = 100)]
+    pub txs: usize,
+    /// seed of synthetic blocks, same seed gives the same blocks
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+    /// use stored blocks FROM..TO instead of synthetic ones
+    #[arg(long)]
+    pub range: Option<String>,
+    /// storage for kv-write, kv-read and pipeline modes
+    #[arg(long, value_enum, default_value_t = Storage::Postgres)]
+    pub storage: Storage,
+    /// table to be written by the benchmark, the pipeline keeps its checkpoint
+    /// in the table with the `_checkpoint` suffix
+    #[arg(long, default_value = "btxs_bench")]
+    pub table: String,
+    /// print report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub mode: String,
+    pub items: usize,
+    /// size of encoded blobs that were processed
+    pub bytes: u64,
+    pub seconds: f64,
+    pub items_per_sec: f64,
+    pub mb_per_sec: f64,
+    pub p50_us: u64,
+    pub p90_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+    /// peak resident set size of the process, where the platform reports it
+    pub peak_rss_kb: Option<u64>,
+}
+
+impl Report {
+    fn new(mode: Mode, bytes: u64, elapsed: Duration, mut latencies: Vec<Duration>) -> Self {
+        latencies.sort();
+        let seconds = elapsed.as_secs_f64();
+        let per_sec = |x: f64| if seconds > 0.0 { x / seconds } else { 0.0 };
+        Self {
+            mode: mode
+                .to_possible_value()
+                .map(|v| v.get_name().to_string())
+                .unwrap_or_default(),
+            items: latencies.len(),
+            bytes,
+            seconds,
+            items_per_sec: per_sec(latencies.len() as f64),
+            mb_per_sec: per_sec(bytes as f64 / 1_000_000.0),
+            p50_us: percentile(&latencies, 0.5),
+            p90_us: percentile(&latencies, 0.9),
+            p99_us: percentile(&latencies, 0.99),
+            max_us: percentile(&latencies, 1.0),
+            peak_rss_kb: peak_rss_kb(),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{:<12} {}", "mode", self.mode)?;
+        writeln!(f, "{:<12} {}", "items", self.items)?;
+        writeln!(f, "{:<12} {}", "bytes", self.bytes)?;
+        writeln!(f, "{:<12} {:.3}", "seconds", self.seconds)?;
+        writeln!(f, "{:<12} {:.1}", "items/sec", self.items_per_sec)?;
+        writeln!(f, "{:<12} {:.3}", "MB/sec", self.mb_per_sec)?;
+        writeln!(f, "{:<12} {}us", "p50", self.p50_us)?;
+        writeln!(f, "{:<12} {}us", "p90", self.p90_us)?;
+        writeln!(f, "{:<12} {}us", "p99", self.p99_us)?;
+        writeln!(f, "{:<12} {}us", "max", self.max_us)?;
+        match self.peak_rss_kb {
+            Some(kb) => writeln!(f, "{:<12} {}kB", "peak rss", kb),
+            None => writeln!(f, "{:<12} n/a", "peak rss"),
+        }
+    }
+}
+
+/// latency at the given quantile of sorted measurements, in microseconds
+fn percentile(sorted: &[Duration], q: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * q).round() as usize;
+    sorted[idx].as_micros() as u64
+}
+
+#[cfg(target_os = "linux")]
+fn peak_rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn peak_rss_kb() -> Option<u64> {
+    None
+}
+
+fn random_hex(rng: &mut Rng, len: usize) -> String {
+    let mut out = String::with_capacity(2 + len * 2);
+    out.push_str("0x");
+    for _ in 0..len {
+        out.push_str(&format!("{:02x}", rng.next_u64() as u8));
+    }
+    out
+}
+
+fn random_quantity(rng: &mut Rng, max: u64) -> String {
+    format!("0x{:x}", rng.next_u64() % max)
+}
+
+/// block document with transactions and receipts, deterministic from the seed
+fn synthetic_block(rng: &mut Rng, number: u32, txs: usize) -> Value {
+    let block_hash = random_hex(rng, 32);
+    let mut transactions = Vec::with_capacity(txs);
+    let mut receipts = Vec::with_capacity(txs);
+    for i in 0..txs {
+        let hash = random_hex(rng, 32);
+        let from = random_hex(rng, 20);
+        let to = random_hex(rng, 20);
+        let input_len = 4 + (rng.next_u64() % 128) as usize;
+        transactions.push(json!({
+            "blockHash": block_hash,
+            "blockNumber": format!("0x{:x}", number),
+            "from": from,
+            "gas": random_quantity(rng, 1_000_000),
+            "gasPrice": random_quantity(rng, 100_000_000_000),
+            "hash": hash,
+            "input": random_hex(rng, input_len),
+            "nonce": random_quantity(rng, 10_000),
+            "to": to,
+            "transactionIndex": format!("0x{:x}", i),
+            "value": random_quantity(rng, u64::MAX),
+        }));
+        let logs: Vec<Value> = (0..rng.next_u64() % 4)
+            .map(|log_index| {
+                json!({
+                    "address": to,
+                    "data": random_hex(rng, 32),
+                    "logIndex": format!("0x{:x}", log_index),
+                    "topics": [random_hex(rng, 32), random_hex(rng, 32)],
+                    "transactionHash": hash,
+                })
+            })
+            .collect();
+        receipts.push(json!({
+            "blockHash": block_hash,
+            "cumulativeGasUsed": random_quantity(rng, 30_000_000),
+            "from": from,
+            "gasUsed": random_quantity(rng, 1_000_000),
+            "logs": logs,
+            "status": "0x1",
+            "to": to,
+            "transactionHash": hash,
+        }));
+    }
+    json!({
+        "block": {
+            "hash": block_hash,
+            "number": format!("0x{:x}", number),
+            "parentHash": random_hex(rng, 32),
+            "miner": random_hex(rng, 20),
+            "gasUsed": random_quantity(rng, 30_000_000),
+            "timestamp": random_quantity(rng, 2_000_000_000),
+        },
+        "transactions": transactions,
+        "receipts": receipts,
+    })
+}
+
+pub fn parse_range(range: &str) -> anyhow::Result<(u32, u32)> {
+    let (from, to) = match range.split_once("..") {
+        Some(x) => x,
+        None => bail!("range must be FROM..TO"),
+    };
+    let from: u32 = from.parse().context("range start")?;
+    let to: u32 = to.parse().context("range end")?;
+    if from >= to {
+        bail!("empty range {}", range);
+    }
+    Ok((from, to))
+}
+
+fn encode(input: &Value, fd: &MapDictionary) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    jsondp::encode(input, &mut buf, fd, &NoDictionary {})?;
+    Ok(buf)
+}
+
+fn decode(input: &[u8], fd: &MapDictionary) -> anyhow::Result<Value> {
+    jsondp::decode(&mut BufReader::new(input), fd, &NoDictionary {})
+}
+
+/// blocks to be measured, as JSON documents
+async fn load_blocks(args: &BenchArgs, database_url: &str) -> anyhow::Result<Vec<Value>> {
+    let range = match &args.range {
+        Some(range) => parse_range(range)?,
+        None => {
+            let mut rng = Rng(args.seed);
+            return Ok((0..args.count)
+                .map(|n| synthetic_block(&mut rng, n, args.txs))
+                .collect());
+        }
+    };
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let storage = PostgresKV::try_new(database_url, "btxs_blocks").await?;
+    let mut out = Vec::new();
+    for n in range.0..range.1 {
+        if let Some(blob) = storage.get(n).await? {
+            out.push(decode(&blob, &fd).with_context(|| format!("block {}", n))?);
+        }
+    }
+    if out.is_empty() {
+        bail!("no stored blocks in range {:?}", range);
+    }
+    Ok(out)
+}
+
+async fn measure<K: KV>(mode: Mode, blocks: &[Value], storage: &K) -> anyhow::Result<Report> {
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let blobs = blocks
+        .iter()
+        .map(|b| encode(b, &fd))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let bytes: u64 = blobs.iter().map(|b| b.len() as u64).sum();
+    if mode == Mode::KvRead {
+        for (n, blob) in blobs.iter().enumerate() {
+            storage.set(n as u32, blob.clone()).await?;
+        }
+    }
+
+    let mut latencies = Vec::with_capacity(blocks.len());
+    let started = Instant::now();
+    for (n, (block, blob)) in blocks.iter().zip(blobs.iter()).enumerate() {
+        let t = Instant::now();
+        match mode {
+            Mode::Encode => {
+                encode(block, &fd)?;
+            }
+            Mode::Decode => {
+                decode(blob, &fd)?;
+            }
+            Mode::KvWrite => storage.set(n as u32, blob.clone()).await?,
+            Mode::KvRead => {
+                storage.get(n as u32).await?.context("missing blob")?;
+            }
+            #[cfg(feature = "test-util")]
+            Mode::Pipeline => bail!("pipeline is measured over the mock chain"),
+        }
+        latencies.push(t.elapsed());
+    }
+    Ok(Report::new(mode, bytes, started.elapsed(), latencies))
+}
+
+/// Follows the chain generated from the seed through the mock transport,
+/// so the whole path from JSON-RPC responses to the stored blobs is measured
+/// offline. Latency of a block is its share of the batch it was stored in,
+/// plus its load and decode
+#[cfg(feature = "test-util")]
+async fn pipeline<K: KV + Send + Sync>(
+    args: &BenchArgs,
+    blocks: K,
+    checkpoint: K,
+) -> anyhow::Result<Report> {
+    let mut g = ChainGenerator::seeded(args.seed).density(args.txs as u64, 3);
+    g.generate(args.count as u64);
+    let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+    let checkpoint_table = format!("{}_checkpoint", args.table);
+    let buckets = BTreeMap::from([
+        (args.table.clone(), blocks),
+        (checkpoint_table.clone(), checkpoint),
+    ]);
+    let sink = Sink {
+        address: None,
+        bucket: args.table.clone(),
+        writer: Writer::new(buckets, &checkpoint_table)?,
+    };
+    let sinks = [sink];
+    let quarantine = QuarantineBucket::new(MemoryKV::new());
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+
+    let started = Instant::now();
+    let stream = EthLogsStream::new(client, 1, PIPELINE_BATCH, vec![], None, None, None, None)?;
+    let mut latencies = vec![];
+    loop {
+        let t = Instant::now();
+        let step = match follow_step(&stream, &sinks, &quarantine).await? {
+            Some(step) => step,
+            None => break,
+        };
+        let share = t.elapsed() / step.blocks.max(1) as u32;
+        latencies.extend(std::iter::repeat_n(share, step.blocks));
+    }
+    // blocks without logs are not fetched, stale blocks of earlier runs are left alone
+    let numbers: Vec<u32> = g
+        .canonical()
+        .iter()
+        .filter(|b| !b.log_ids().is_empty())
+        .filter_map(|b| b.block.number.map(|n| n.as_u32()))
+        .collect();
+    if numbers.len() != latencies.len() {
+        bail!(
+            "{} blocks with logs were generated, {} were stored",
+            numbers.len(),
+            latencies.len()
+        );
+    }
+    let storage = sinks[0].writer.bucket(&args.table)?;
+    let mut bytes = 0;
+    for (n, latency) in numbers.into_iter().zip(latencies.iter_mut()) {
+        let t = Instant::now();
+        let blob = storage
+            .get(n)
+            .await?
+            .with_context(|| format!("block {} is not stored", n))?;
+        decode(&blob, &fd).with_context(|| format!("block {}", n))?;
+        bytes += blob.len() as u64;
+        *latency += t.elapsed();
+    }
+    Ok(Report::new(
+        Mode::Pipeline,
+        bytes,
+        started.elapsed(),
+        latencies,
+    ))
+}
+
+// the pipeline follows the mock chain from its first block on every run
+#[cfg(feature = "test-util")]
+async fn forget_checkpoint<K: KV + Sync>(checkpoint: &K, table: &str) -> anyhow::Result<()> {
+    let mut txn = checkpoint
+        .transaction()
+        .await?
+        .context("storage has no transactions")?;
+    txn.delete(table, CHECKPOINT_KEY).await?;
+    txn.commit().await
+}
+
+pub async fn run(args: &BenchArgs, database_url: &str) -> anyhow::Result<Report> {
+    #[cfg(feature = "test-util")]
+    if args.mode == Mode::Pipeline {
+        if args.range.is_some() {
+            bail!("pipeline follows the mock chain of the seed, --range is not supported");
+        }
+        return match args.storage {
+            Storage::Postgres => {
+                let checkpoint_table = format!("{}_checkpoint", args.table);
+                let blocks = PostgresKV::try_new(database_url, &args.table).await?;
+                let checkpoint = PostgresKV::try_new(database_url, &checkpoint_table).await?;
+                forget_checkpoint(&checkpoint, &checkpoint_table).await?;
+                pipeline(args, blocks, checkpoint).await
+            }
+            Storage::Memory => {
+                let memory = MemoryKV::new();
+                let checkpoint = memory.bucket(&format!("{}_checkpoint", args.table));
+                pipeline(args, memory.bucket(&args.table), checkpoint).await
+            }
+        };
+    }
+    let blocks = load_blocks(args, database_url).await?;
+    let uses_storage = matches!(args.mode, Mode::KvWrite | Mode::KvRead);
+    if uses_storage && args.storage == Storage::Postgres {
+        let storage = PostgresKV::try_new(database_url, &args.table).await?;
+        measure(args.mode, &blocks, &storage).await
+    } else {
+        measure(args.mode, &blocks, &MemoryKV::new()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiny(mode: Mode) -> BenchArgs {
+        BenchArgs {
+            mode,
+            count: 3,
+            txs: 2,
+            seed: 7,
+            range: None,
+            storage: Storage::Memory,
+            table: "btxs_bench".to_string(),
+            json: false,
+        }
+    }
+
+    #[test]
+    fn it_generates_same_blocks_from_seed() {
+        let a = synthetic_block(&mut Rng(1), 10, 3);
+        let b = synthetic_block(&mut Rng(1), 10, 3);
+        let c = synthetic_block(&mut Rng(2), 10, 3);
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[tokio::test]
+    async fn it_runs_every_mode() {
+        // the pipeline stores blocks of the mock chain which have logs
+        #[cfg(feature = "test-util")]
+        let with_logs = {
+            let mut g = ChainGenerator::seeded(7).density(2, 3);
+            let with_logs = g
+                .generate(3)
+                .iter()
+                .filter(|b| !b.log_ids().is_empty())
+                .count();
+            assert!(with_logs > 0);
+            with_logs
+        };
+        for mode in Mode::value_variants() {
+            let report = run(&tiny(*mode), "").await.unwrap();
+            let items = match mode {
+                #[cfg(feature = "test-util")]
+                Mode::Pipeline => with_logs,
+                _ => 3,
+            };
+            assert_eq!(report.items, items, "{:?}", mode);
+            assert!(report.bytes > 0);
+            assert!(report.p50_us <= report.max_us);
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use crate::process::{contract_tables, CHECKPOINT_TABLE};
+use crate::writer::Writer;
+use clap::Args;
+use eth_logs::EthBatchClient;
+use ethers::types::{Address, H256};
+use jsondp::dictionary::MapDictionary;
+use kv::KV;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Preflight validation of the configuration, endpoints and storage
+#[derive(Debug, Clone, Args)]
+pub struct CheckArgs {
+    /// JSON-RPC endpoints to verify
+    #[arg(long, env = "RPC_ETH_ADDR", value_delimiter = ',')]
+    pub rpc_addr: Vec<String>,
+    /// expected chain id of every endpoint
+    #[arg(long)]
+    pub chain_id: Option<u64>,
+    /// contract addresses to be indexed
+    #[arg(long, value_delimiter = ',')]
+    pub address: Vec<String>,
+    /// event topics to filter by
+    #[arg(long, value_delimiter = ',')]
+    pub topic: Vec<String>,
+    /// value dictionary file
+    #[arg(long)]
+    pub dictionary: Option<PathBuf>,
+    /// print report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+/// result of a single check
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckItem {
+    pub name: String,
+    pub status: Status,
+    pub message: String,
+}
+
+impl CheckItem {
+    fn new(name: &str, status: Status, message: impl Into<String>) -> Self {
+        Self {
+            name: name.to_string(),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub items: Vec<CheckItem>,
+}
+
+impl Report {
+    pub fn failed(&self) -> bool {
+        self.items.iter().any(|i| i.status == Status::Fail)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for item in &self.items {
+            let status = match item.status {
+                Status::Pass => "PASS",
+                Status::Warn => "WARN",
+                Status::Fail => "FAIL",
+            };
+            writeln!(f, "{:<4} {:<24} {}", status, item.name, item.message)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn check_addresses(addresses: &[String]) -> Vec<CheckItem> {
+    addresses
+        .iter()
+        .map(|a| match Address::from_str(a) {
+            Ok(_) => CheckItem::new("address", Status::Pass, a.as_str()),
+            Err(e) => CheckItem::new("address", Status::Fail, format!("{}: {}", a, e)),
+        })
+        .collect()
+}
+
+pub fn check_topics(topics: &[String]) -> Vec<CheckItem> {
+    topics
+        .iter()
+        .map(|t| match H256::from_str(t) {
+            Ok(_) => CheckItem::new("topic", Status::Pass, t.as_str()),
+            Err(e) => CheckItem::new("topic", Status::Fail, format!("{}: {}", t, e)),
+        })
+        .collect()
+}
+
+pub fn check_rpc(rpc_addr: &str, expected_chain_id: Option<u64>) -> CheckItem {
+    let client = EthBatchClient::new(rpc_addr);
+    match client.connect() {
+        Ok((chain_id, latest)) => match expected_chain_id {
+            Some(expected) if expected != chain_id => CheckItem::new(
+                "rpc",
+                Status::Fail,
+                format!("{}: chain id {}, expected {}", rpc_addr, chain_id, expected),
+            ),
+            Some(_) => CheckItem::new(
+                "rpc",
+                Status::Pass,
+                format!(
+                    "{}: chain id {}, latest block {}",
+                    rpc_addr, chain_id, latest
+                ),
+            ),
+            None => CheckItem::new(
+                "rpc",
+                Status::Warn,
+                format!(
+                    "{}: chain id {} is not verified, latest block {}",
+                    rpc_addr, chain_id, latest
+                ),
+            ),
+        },
+        Err(e) => CheckItem::new("rpc", Status::Fail, format!("{}: {:#}", rpc_addr, e)),
+    }
+}
+
+pub async fn check_database(database_url: &str, table_name: &str) -> CheckItem {
+    match kv::PostgresKV::try_new(database_url, table_name).await {
+        Ok(_) => CheckItem::new("database", Status::Pass, table_name),
+        Err(e) => CheckItem::new("database", Status::Fail, format!("{:#}", e)),
+    }
+}
+
+pub fn check_dictionary(path: &PathBuf) -> CheckItem {
+    let loaded = std::fs::File::open(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|mut f| MapDictionary::from(&mut f));
+    match loaded {
+        Ok(d) => CheckItem::new(
+            "dictionary",
+            Status::Pass,
+            format!("{}: {} entries", path.display(), d.ids().count()),
+        ),
+        Err(e) => CheckItem::new(
+            "dictionary",
+            Status::Fail,
+            format!("{}: {:#}", path.display(), e),
+        ),
+    }
+}
+
+/// verifies that the follow command can resume from the stored checkpoint
+pub async fn check_checkpoint<K: KV + Send + Sync>(name: &str, writer: &Writer<K>) -> CheckItem {
+    let resumable = writer.ensure_resumable().await;
+    match (writer.checkpoint().await, resumable) {
+        (Ok(None), Ok(())) => CheckItem::new("checkpoint", Status::Pass, format!("{}: none", name)),
+        (Ok(Some(n)), Ok(())) => {
+            CheckItem::new("checkpoint", Status::Pass, format!("{}: block {}", name, n))
+        }
+        (Err(e), _) | (_, Err(e)) => CheckItem::new("checkpoint", Status::Fail, format!("{:#}", e)),
+    }
+}
+
+async fn checkpoint_writer(
+    database_url: &str,
+    table_name: &str,
+    checkpoint_table: &str,
+) -> anyhow::Result<Writer<kv::PostgresKV>> {
+    let mut buckets = BTreeMap::new();
+    for name in [table_name, checkpoint_table] {
+        buckets.insert(
+            name.to_string(),
+            kv::PostgresKV::try_new(database_url, name).await?,
+        );
+    }
+    Writer::new(buckets, checkpoint_table)
+}
+
+/// runs all checks
+pub async fn run(args: &CheckArgs, database_url: &str, table_name: &str) -> Report {
+    let mut report = Report::default();
+    report.items.extend(check_addresses(&args.address));
+    report.items.extend(check_topics(&args.topic));
+    if args.rpc_addr.is_empty() {
+        report.items.push(CheckItem::new(
+            "rpc",
+            Status::Warn,
+            "no endpoints configured",
+        ));
+    }
+    for rpc_addr in &args.rpc_addr {
+        let rpc_addr = rpc_addr.clone();
+        let chain_id = args.chain_id;
+        // client is blocking
+        let item = tokio::task::spawn_blocking(move || check_rpc(&rpc_addr, chain_id))
+            .await
+            .unwrap_or_else(|e| CheckItem::new("rpc", Status::Fail, e.to_string()));
+        report.items.push(item);
+    }
+    report
+        .items
+        .push(check_database(database_url, table_name).await);
+    if let Some(path) = &args.dictionary {
+        report.items.push(check_dictionary(path));
+    }
+    let mut tables = vec![(table_name.to_string(), CHECKPOINT_TABLE.to_string())];
+    for address in args
+        .address
+        .iter()
+        .filter_map(|a| Address::from_str(a).ok())
+    {
+        tables.push(contract_tables(table_name, &address));
+    }
+    for (table, checkpoint_table) in tables {
+        let item = match checkpoint_writer(database_url, &table, &checkpoint_table).await {
+            Ok(writer) => check_checkpoint(&checkpoint_table, &writer).await,
+            Err(e) => CheckItem::new("checkpoint", Status::Fail, format!("{:#}", e)),
+        };
+        report.items.push(item);
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // serves a single JSON-RPC batch response
+    fn mock_rpc(chain_id: u64) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                if let Some(v) = line.to_lowercase().strip_prefix("content-length:") {
+                    content_length = v.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let response = format!(
+                "[{{\"jsonrpc\":\"2.0\",\"id\":\"net\",\"result\":\"{}\"}},{{\"jsonrpc\":\"2.0\",\"id\":\"latest\",\"result\":\"0x10d4f\"}}]",
+                chain_id
+            );
+            let mut stream = reader.into_inner();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                response.len(),
+                response
+            )
+            .unwrap();
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn it_fails_on_bad_address() {
+        let items = check_addresses(&[
+            "0x0b38210ea11411557c13457d4da7dc6ea731b88a".to_string(),
+            "0x0b38210ea114".to_string(),
+        ]);
+        assert_eq!(items[0].status, Status::Pass);
+        assert_eq!(items[1].status, Status::Fail);
+        assert!(items[1].message.starts_with("0x0b38210ea114:"));
+    }
+
+    #[test]
+    fn it_fails_on_unreachable_rpc() {
+        let item = check_rpc("http://127.0.0.1:1", Some(1));
+        assert_eq!(item.status, Status::Fail);
+        assert_eq!(item.name, "rpc");
+    }
+
+    #[test]
+    fn it_fails_on_chain_id_mismatch() {
+        let item = check_rpc(&mock_rpc(5), Some(1));
+        assert_eq!(item.status, Status::Fail);
+        assert!(item.message.ends_with("chain id 5, expected 1"));
+
+        let item = check_rpc(&mock_rpc(1), Some(1));
+        assert_eq!(item.status, Status::Pass);
+    }
+
+    #[tokio::test]
+    async fn it_fails_on_incompatible_checkpoint() {
+        let memory = kv::MemoryKV::new();
+        let buckets = ["blocks", "checkpoint"]
+            .into_iter()
+            .map(|name| (name.to_string(), memory.bucket(name)))
+            .collect();
+        let writer = Writer::new(buckets, "checkpoint").unwrap();
+        let item = check_checkpoint("checkpoint", &writer).await;
+        assert_eq!(
+            (item.status, item.message.as_str()),
+            (Status::Pass, "checkpoint: none")
+        );
+
+        let mut value = 9u32.to_le_bytes().to_vec();
+        value.extend_from_slice(br#"{"version":0,"dictionary":"00"}"#);
+        memory.bucket("checkpoint").set(0, value).await.unwrap();
+        let item = check_checkpoint("checkpoint", &writer).await;
+        assert_eq!(item.status, Status::Fail);
+        assert!(
+            item.message.contains("format version 0"),
+            "{}",
+            item.message
+        );
+    }
+
+    #[test]
+    fn it_reports_failure() {
+        let report = Report {
+            items: check_addresses(&["zzz".to_string()]),
+        };
+        assert!(report.failed());
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["items"][0]["status"], "fail");
+    }
+}
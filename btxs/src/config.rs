@@ -0,0 +1,62 @@
+use crate::auth::AuthConfig;
+use crate::maintenance::MaintenanceConfig;
+use anyhow::Context;
+use serde::Deserialize;
+use std::path::Path;
+
+/// `[serve]` section of the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ServeConfig {
+    /// requests without a valid token are rejected when present
+    pub auth: Option<AuthConfig>,
+}
+
+/// TOML configuration of the long-running commands
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub serve: ServeConfig,
+    /// background prune, dictionary scan and stats refresh of `process follow`
+    pub maintenance: Option<MaintenanceConfig>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("parse {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_reads_sections() {
+        let config: Config = toml::from_str(
+            r#"
+            [serve.auth]
+            tokens = ["secret"]
+            requests_per_minute = 60
+
+            [maintenance]
+            prune_keep_blocks = 1000
+            interval = "6h"
+            dict_gc_interval = "7d"
+            "#,
+        )
+        .unwrap();
+        let auth = config.serve.auth.unwrap();
+        assert_eq!(auth.tokens, vec!["secret".to_string()]);
+        assert_eq!(auth.requests_per_minute, 60);
+        let maintenance = config.maintenance.unwrap();
+        assert_eq!(maintenance.prune_keep_blocks, Some(1000));
+        assert_eq!(maintenance.interval.0.as_secs(), 6 * 3600);
+        assert_eq!(maintenance.max_lag, 100);
+
+        let empty: Config = toml::from_str("").unwrap();
+        assert!(empty.serve.auth.is_none());
+        assert!(empty.maintenance.is_none());
+    }
+}
@@ -0,0 +1,328 @@
+use crate::bench::parse_range;
+use anyhow::{bail, Context};
+use clap::{Args, Subcommand};
+use jsondp::dictionary::MapDictionary;
+use jsondp::gc::{DictionaryUsage, GcReport};
+use kv::KV;
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use tracing::*;
+
+/// bucket with the generations of the value dictionary
+pub const DICTIONARY_TABLE: &str = "btxs_dictionary";
+
+/// key of the number of the current generation, generations are kept from 1
+const CURRENT_KEY: u32 = 0;
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum DictCommand {
+    /// find value dictionary entries that stored blocks never refer to
+    Gc(GcArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GcArgs {
+    /// blocks FROM..TO to be scanned
+    #[arg(long)]
+    pub range: String,
+    /// store a new generation without the unreferenced entries
+    #[arg(long)]
+    pub apply: bool,
+    /// remove entries that blocks outside the range refer to, and reencode those blocks
+    #[arg(long)]
+    pub force: bool,
+    /// entries referenced fewer times are reported as rare
+    #[arg(long, default_value_t = 2)]
+    pub rare_below: u64,
+    /// print report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Generations of the value dictionary, the last one is used to encode blocks.
+/// Entries keep their ids between generations, so blocks encoded with an older one
+/// decode with the newer one as long as they don't refer to removed entries
+pub struct Generations<K> {
+    kv: K,
+}
+
+impl<K: KV + Sync> Generations<K> {
+    pub fn new(kv: K) -> Self {
+        Self { kv }
+    }
+
+    async fn current_number(&self) -> anyhow::Result<u32> {
+        match self.kv.get(CURRENT_KEY).await? {
+            Some(b) => Ok(u32::from_le_bytes(
+                b.as_slice().try_into().context("current generation")?,
+            )),
+            None => Ok(0),
+        }
+    }
+
+    /// number and entries of the current generation, 0 and empty before the first one
+    pub async fn current(&self) -> anyhow::Result<(u32, MapDictionary)> {
+        let n = self.current_number().await?;
+        if n == 0 {
+            return Ok((0, MapDictionary::new()));
+        }
+        let blob = self
+            .kv
+            .get(n)
+            .await?
+            .with_context(|| format!("dictionary generation {} is missing", n))?;
+        let dict = MapDictionary::from_binary(&mut blob.as_slice())
+            .with_context(|| format!("dictionary generation {}", n))?;
+        Ok((n, dict))
+    }
+
+    /// stores the dictionary as the next generation, then makes it the current one
+    pub async fn store(&self, dict: &MapDictionary) -> anyhow::Result<u32> {
+        let next = self.current_number().await? + 1;
+        let mut blob = vec![];
+        dict.write_binary(&mut blob)?;
+        self.kv.set(next, blob).await?;
+        self.kv
+            .set(CURRENT_KEY, next.to_le_bytes().to_vec())
+            .await?;
+        Ok(next)
+    }
+}
+
+/// Result of the scan, and of the apply when it was asked for
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// generation that was scanned
+    pub generation: u32,
+    pub entries: usize,
+    pub blocks: u64,
+    pub unreferenced: Vec<u32>,
+    pub rare: Vec<(u32, u64)>,
+    /// blocks outside the range that refer to unreferenced entries
+    pub referenced_outside: Vec<u32>,
+    /// generation stored without the unreferenced entries
+    pub applied: Option<u32>,
+    pub reencoded: Vec<u32>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "generation {}: {} entries, {} blocks scanned",
+            self.generation, self.entries, self.blocks
+        )?;
+        writeln!(
+            f,
+            "unreferenced {}: {:?}",
+            self.unreferenced.len(),
+            self.unreferenced
+        )?;
+        writeln!(f, "rare {}: {:?}", self.rare.len(), self.rare)?;
+        if let Some(applied) = self.applied {
+            writeln!(
+                f,
+                "stored generation {}, reencoded blocks {:?}",
+                applied, self.reencoded
+            )?;
+        }
+        Ok(())
+    }
+}
+
+async fn usage_of<S: KV + Sync>(storage: &S, n: u32) -> anyhow::Result<DictionaryUsage> {
+    let mut usage = DictionaryUsage::new();
+    if let Some(blob) = storage.get(n).await? {
+        usage
+            .scan(&mut blob.as_slice())
+            .with_context(|| format!("block {}", n))?;
+    }
+    Ok(usage)
+}
+
+async fn keys<S: KV + Sync>(storage: &S, from: u32, to: u32) -> anyhow::Result<Vec<u32>> {
+    if from > to {
+        return Ok(vec![]);
+    }
+    storage
+        .keys(from, to)
+        .await?
+        .context("storage can't list its blocks")
+}
+
+/// counts references of the blocks in the range to the current value dictionary.
+/// With `apply`, stores a generation without the unreferenced entries; entries that
+/// blocks outside the range refer to are only removed with `force`, and those
+/// blocks are reencoded without them before the generation is stored
+pub async fn gc<S: KV + Sync, D: KV + Sync>(
+    args: &GcArgs,
+    storage: &S,
+    generations: &Generations<D>,
+) -> anyhow::Result<Report> {
+    let (from, to) = parse_range(&args.range)?;
+    let (generation, vd) = generations.current().await?;
+    let mut usage = DictionaryUsage::new();
+    let mut report = Report {
+        generation,
+        entries: vd.ids().count(),
+        ..Default::default()
+    };
+    for n in keys(storage, from, to - 1).await? {
+        usage.merge(&usage_of(storage, n).await?);
+        report.blocks += 1;
+        // gives way to other tasks on the same runtime
+        tokio::task::yield_now().await;
+    }
+    let found = GcReport::new(&vd, &usage.values, args.rare_below);
+    report.unreferenced = found.unreferenced.clone();
+    report.rare = found.rare.clone();
+    if !args.apply || found.unreferenced.is_empty() {
+        return Ok(report);
+    }
+
+    let removed: BTreeSet<u32> = found.unreferenced.iter().copied().collect();
+    let mut outside = match from {
+        0 => vec![],
+        _ => keys(storage, 0, from - 1).await?,
+    };
+    outside.extend(keys(storage, to, u32::MAX).await?);
+    for n in outside {
+        let part = usage_of(storage, n).await?;
+        if part.values.keys().any(|id| removed.contains(id)) {
+            report.referenced_outside.push(n);
+        }
+    }
+    if !report.referenced_outside.is_empty() && !args.force {
+        bail!(
+            "{} blocks outside {} refer to entries to be removed, e.g. block {}; --force removes them and reencodes the blocks",
+            report.referenced_outside.len(),
+            args.range,
+            report.referenced_outside[0]
+        );
+    }
+
+    let next = found.apply(&vd);
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    for n in &report.referenced_outside {
+        let blob = storage.get(*n).await?.context("block is gone")?;
+        let value =
+            jsondp::decode_slice(&blob, &fd, &vd).with_context(|| format!("block {}", n))?;
+        let mut out = vec![];
+        jsondp::encode(&value, &mut out, &fd, &next)?;
+        storage.set(*n, out).await?;
+    }
+    report.reencoded = report.referenced_outside.clone();
+    let applied = generations.store(&next).await?;
+    info!(
+        generation = applied,
+        removed = removed.len(),
+        reencoded = report.reencoded.len(),
+        "stored dictionary generation"
+    );
+    report.applied = Some(applied);
+    Ok(report)
+}
+
+pub async fn run(cmd: &DictCommand, database_url: &str, table_name: &str) -> anyhow::Result<()> {
+    match cmd {
+        DictCommand::Gc(args) => {
+            let storage = kv::PostgresKV::try_new(database_url, table_name).await?;
+            let generations =
+                Generations::new(kv::PostgresKV::try_new(database_url, DICTIONARY_TABLE).await?);
+            let report = gc(args, &storage, &generations).await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::MemoryKV;
+    use serde_json::{json, Value};
+
+    fn args(range: &str, apply: bool, force: bool) -> GcArgs {
+        GcArgs {
+            range: range.to_string(),
+            apply,
+            force,
+            rare_below: 2,
+            json: false,
+        }
+    }
+
+    async fn put(storage: &MemoryKV, n: u32, value: &Value, vd: &MapDictionary) {
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        let mut blob = vec![];
+        jsondp::encode(value, &mut blob, &fd, vd).unwrap();
+        storage.set(n, blob).await.unwrap();
+    }
+
+    fn transfer(from: &str, to: &str) -> Value {
+        json!({"block": {"number": "0x01"}, "memo": [from, to]})
+    }
+
+    #[tokio::test]
+    async fn it_removes_exactly_the_junk() {
+        let store = MemoryKV::new();
+        let storage = store.bucket("blocks");
+        let generations = Generations::new(store.bucket("dictionary"));
+        let mut vd = MapDictionary::from_strings(vec!["alice", "bob", "carol"]);
+        assert_eq!(generations.store(&vd).await.unwrap(), 1);
+        let rows = [transfer("alice", "bob"), transfer("bob", "carol")];
+        for (n, row) in rows.iter().enumerate() {
+            put(&storage, 1 + n as u32, row, &vd).await;
+        }
+
+        // junk learned during an airdrop, one old block outside the range refers to it
+        vd.insert("airdrop1");
+        vd.insert("airdrop2");
+        assert_eq!(generations.store(&vd).await.unwrap(), 2);
+        let old = transfer("airdrop2", "alice");
+        put(&storage, 100, &old, &vd).await;
+
+        let report = gc(&args("1..3", false, false), &storage, &generations)
+            .await
+            .unwrap();
+        assert_eq!((report.generation, report.blocks), (2, 2));
+        assert_eq!(report.unreferenced, vec![4, 5]);
+        assert_eq!(report.rare, vec![(1, 1), (3, 1)]);
+        assert_eq!(report.applied, None);
+
+        let err = gc(&args("1..3", true, false), &storage, &generations)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("e.g. block 100"), "{}", err);
+        assert_eq!(generations.current().await.unwrap().0, 2);
+
+        let report = gc(&args("1..3", true, true), &storage, &generations)
+            .await
+            .unwrap();
+        assert_eq!(report.applied, Some(3));
+        assert_eq!(report.reencoded, vec![100]);
+        let (generation, next) = generations.current().await.unwrap();
+        assert_eq!(generation, 3);
+        assert_eq!(next.ids().collect::<Vec<u32>>(), vec![1, 2, 3]);
+
+        // every block decodes with the new generation
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        for (n, expected) in [(1, &rows[0]), (2, &rows[1]), (100, &old)] {
+            let blob = storage.get(n).await.unwrap().unwrap();
+            assert_eq!(&jsondp::decode_slice(&blob, &fd, &next).unwrap(), expected);
+        }
+        // and nothing is left to remove
+        let report = gc(&args("0..1000", true, false), &storage, &generations)
+            .await
+            .unwrap();
+        assert!(report.unreferenced.is_empty());
+        assert_eq!(report.applied, None);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::bench::parse_range;
+use crate::notify::log_payload;
+use anyhow::Context;
+use clap::{Args, Subcommand};
+use eth_logs::{BlockTransactions, LogId};
+use jsondp::dictionary::{MapDictionary, NoDictionary};
+use kv::KV;
+use serde_json::Value;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::*;
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ExportCommand {
+    /// stored block documents as JSON lines, with uncles and withdrawals
+    Blocks(ExportArgs),
+    /// logs of the stored blocks as JSON lines, with the ids that log events carry
+    Logs(ExportArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ExportArgs {
+    /// blocks FROM..TO to be exported
+    #[arg(long)]
+    pub range: String,
+    /// output file, standard output when missing
+    #[arg(long)]
+    pub out: Option<PathBuf>,
+}
+
+// numbers of the stored blocks in the range
+async fn stored_numbers<K: KV + Sync>(storage: &K, range: &str) -> anyhow::Result<Vec<u32>> {
+    let (from, to) = parse_range(range)?;
+    storage
+        .keys(from, to - 1)
+        .await?
+        .context("storage can't list its blocks")
+}
+
+// document of the stored block, None for blocks that were not written
+async fn document<K: KV + Sync>(
+    storage: &K,
+    fd: &MapDictionary,
+    number: u32,
+) -> anyhow::Result<Option<Value>> {
+    let blob = match storage.get(number).await? {
+        Some(blob) if !blob.is_empty() => blob,
+        _ => return Ok(None),
+    };
+    let value = jsondp::decode_slice(&blob, fd, &NoDictionary {})
+        .with_context(|| format!("block {}", number))?;
+    Ok(Some(value))
+}
+
+/// writes documents of the stored blocks in the range, one per line, returns their number
+pub async fn blocks<K: KV + Sync, W: Write>(
+    storage: &K,
+    range: &str,
+    out: &mut W,
+) -> anyhow::Result<u64> {
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let mut exported = 0;
+    for number in stored_numbers(storage, range).await? {
+        if let Some(value) = document(storage, &fd, number).await? {
+            serde_json::to_writer(&mut *out, &value)?;
+            out.write_all(b"\n")?;
+            exported += 1;
+        }
+    }
+    Ok(exported)
+}
+
+/// writes logs of the stored blocks in the range in the payload of log events,
+/// one per line, returns their number
+pub async fn logs<K: KV + Sync, W: Write>(
+    storage: &K,
+    range: &str,
+    out: &mut W,
+) -> anyhow::Result<u64> {
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let mut exported = 0;
+    for number in stored_numbers(storage, range).await? {
+        let value = match document(storage, &fd, number).await? {
+            Some(value) => value,
+            None => continue,
+        };
+        let block =
+            BlockTransactions::from_value(value).with_context(|| format!("block {}", number))?;
+        for log in block.receipts.values().flat_map(|r| r.logs.iter()) {
+            if let Some(id) = LogId::of(log) {
+                serde_json::to_writer(&mut *out, &log_payload(id, log))?;
+                out.write_all(b"\n")?;
+                exported += 1;
+            }
+        }
+    }
+    Ok(exported)
+}
+
+pub async fn run(cmd: &ExportCommand, database_url: &str, table_name: &str) -> anyhow::Result<()> {
+    let storage = kv::PostgresKV::try_new(database_url, table_name).await?;
+    let args = match cmd {
+        ExportCommand::Blocks(args) | ExportCommand::Logs(args) => args,
+    };
+    let mut out: Box<dyn Write> = match &args.out {
+        Some(path) => {
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("create {}", path.display()))?;
+            Box::new(std::io::BufWriter::new(file))
+        }
+        None => Box::new(std::io::stdout().lock()),
+    };
+    match cmd {
+        ExportCommand::Blocks(args) => {
+            let exported = blocks(&storage, &args.range, &mut out).await?;
+            info!(exported, "exported blocks");
+        }
+        ExportCommand::Logs(args) => {
+            let exported = logs(&storage, &args.range, &mut out).await?;
+            info!(exported, "exported logs");
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// fixtures of a pre-merge block with uncles and a post-Shanghai block with withdrawals
+#[cfg(test)]
+pub(crate) async fn stored_fixtures() -> (kv::MemoryKV, Vec<eth_logs::BlockTransactions>) {
+    let storage = kv::MemoryKV::new();
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+    let mut blocks = vec![];
+    for src in [
+        include_str!("../../eth-logs/tests/fixtures/block_uncles.json"),
+        include_str!("../../eth-logs/tests/fixtures/block_withdrawals.json"),
+    ] {
+        let block =
+            eth_logs::BlockTransactions::from_block_value(serde_json::from_str(src).unwrap())
+                .unwrap();
+        let mut blob = vec![];
+        jsondp::encode(&block.to_value().unwrap(), &mut blob, &fd, &NoDictionary {}).unwrap();
+        let number = block.block.number.unwrap().as_u32();
+        storage.set(number, blob).await.unwrap();
+        blocks.push(block);
+    }
+    (storage, blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn it_exports_uncles_and_withdrawals() {
+        let (storage, blocks) = stored_fixtures().await;
+        let mut out = vec![];
+        let exported = super::blocks(&storage, "0..20000000", &mut out)
+            .await
+            .unwrap();
+        assert_eq!(exported, 2);
+        let rows: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        let restored: Vec<BlockTransactions> = rows
+            .into_iter()
+            .map(|v| BlockTransactions::from_value(v).unwrap())
+            .collect();
+        assert_eq!(restored[0].block.uncles, blocks[0].block.uncles);
+        assert_eq!(restored[0].block.uncles.len(), 2);
+        assert_eq!(restored[1].block.withdrawals, blocks[1].block.withdrawals);
+        assert_eq!(
+            restored[1].block.withdrawals_root,
+            blocks[1].block.withdrawals_root
+        );
+    }
+
+    #[tokio::test]
+    async fn it_exports_logs_with_their_ids() {
+        let storage = kv::MemoryKV::new();
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        let chain = eth_logs::fixtures::ChainGenerator::seeded(7)
+            .density(3, 2)
+            .generate(5);
+        let mut expected = vec![];
+        for block in &chain {
+            let mut blob = vec![];
+            jsondp::encode(&block.to_value().unwrap(), &mut blob, &fd, &NoDictionary {}).unwrap();
+            storage
+                .set(block.block.number.unwrap().as_u32(), blob)
+                .await
+                .unwrap();
+            expected.extend(block.log_ids());
+        }
+        assert!(!expected.is_empty());
+        let mut out = vec![];
+        let exported = super::logs(&storage, "0..1000", &mut out).await.unwrap();
+        assert_eq!(exported as usize, expected.len());
+        let ids: Vec<LogId> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|l| {
+                let row: Value = serde_json::from_str(l).unwrap();
+                serde_json::from_value(row["id"].clone()).unwrap()
+            })
+            .collect();
+        assert_eq!(ids, expected);
+    }
+}
@@ -0,0 +1,361 @@
+use anyhow::{bail, Context};
+use jsondp::dictionary::DictionaryRead;
+use jsondp::Decoder;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+/// Gas fields of one stored block
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BlockGas {
+    pub number: u64,
+    pub timestamp: u64,
+    pub gas_used: u64,
+    pub gas_limit: u64,
+    /// None before London
+    pub base_fee: Option<u64>,
+    pub tx_count: u64,
+}
+
+impl BlockGas {
+    /// share of the gas limit used by the block, in percent
+    pub fn utilization(&self) -> f64 {
+        if self.gas_limit == 0 {
+            return 0.0;
+        }
+        self.gas_used as f64 * 100.0 / self.gas_limit as f64
+    }
+}
+
+fn value_u64(value: &Value) -> anyhow::Result<u64> {
+    match value {
+        Value::Number(n) => n.as_u64().context("not an unsigned number"),
+        Value::String(s) => match s.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).context("hex quantity"),
+            None => s.parse().context("decimal quantity"),
+        },
+        _ => bail!("not a number"),
+    }
+}
+
+/// gas fields of the stored block document, read by their paths
+/// without decoding the rest of it
+pub fn extract<D1: DictionaryRead, D2: DictionaryRead>(
+    blob: &[u8],
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<BlockGas> {
+    let decoder = Decoder::new(fd, vd);
+    // missing and null fields are None
+    let field = |name: &str| -> anyhow::Result<Option<u64>> {
+        match decoder.decode_path(blob, &format!("block.{}", name))? {
+            None | Some(Value::Null) => Ok(None),
+            Some(v) => value_u64(&v).map(Some).context(name.to_string()),
+        }
+    };
+    let Some(tx_count) = decoder.len_at(blob, "block.transactions")? else {
+        bail!("no block in the document");
+    };
+    Ok(BlockGas {
+        number: field("number")?.unwrap_or_default(),
+        timestamp: field("timestamp")?.unwrap_or_default(),
+        gas_used: field("gasUsed")?.unwrap_or_default(),
+        gas_limit: field("gasLimit")?.unwrap_or_default(),
+        base_fee: field("baseFeePerGas")?,
+        tx_count: tx_count as u64,
+    })
+}
+
+/// How blocks are grouped in the report
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bucket {
+    Hour,
+    Day,
+    /// fixed number of blocks
+    Blocks(u64),
+}
+
+impl FromStr for Bucket {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "hour" => Ok(Self::Hour),
+            "day" => Ok(Self::Day),
+            _ => match s.strip_suffix("k").unwrap_or(s).parse::<u64>() {
+                Ok(0) | Err(_) => {
+                    bail!("bucket should be hour, day or a number of blocks like 10k")
+                }
+                Ok(n) if s.ends_with('k') => Ok(Self::Blocks(n * 1000)),
+                Ok(n) => Ok(Self::Blocks(n)),
+            },
+        }
+    }
+}
+
+impl Bucket {
+    // start of the bucket, in seconds or block numbers
+    fn key(&self, block: &BlockGas) -> u64 {
+        match self {
+            Self::Hour => block.timestamp - block.timestamp % 3600,
+            Self::Day => block.timestamp - block.timestamp % 86400,
+            Self::Blocks(n) => block.number - block.number % n,
+        }
+    }
+}
+
+/// Aggregates of one bucket
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct GasBucket {
+    /// unix time or block number where the bucket starts
+    pub start: u64,
+    pub first_block: u64,
+    pub last_block: u64,
+    pub blocks: u64,
+    pub transactions: u64,
+    pub gas_used: u64,
+    /// percentiles of the block utilization, in percent
+    pub utilization_p50: f64,
+    pub utilization_p90: f64,
+    pub utilization_p99: f64,
+    /// base fee of the first and the last block with one, None before London
+    pub base_fee_first: Option<u64>,
+    pub base_fee_last: Option<u64>,
+    pub base_fee_min: Option<u64>,
+    pub base_fee_max: Option<u64>,
+    /// mean change of the base fee between consecutive blocks, in percent
+    pub base_fee_mean_delta: Option<f64>,
+}
+
+// nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn aggregate(start: u64, blocks: &[BlockGas]) -> GasBucket {
+    let mut utilization: Vec<f64> = blocks.iter().map(|b| b.utilization()).collect();
+    utilization.sort_by(f64::total_cmp);
+    let fees: Vec<u64> = blocks.iter().filter_map(|b| b.base_fee).collect();
+    // deltas only between consecutive blocks that both have a base fee
+    let deltas: Vec<f64> = blocks
+        .windows(2)
+        .filter(|w| w[1].number == w[0].number + 1)
+        .filter_map(|w| match (w[0].base_fee, w[1].base_fee) {
+            (Some(a), Some(b)) if a > 0 => Some((b as f64 - a as f64) * 100.0 / a as f64),
+            _ => None,
+        })
+        .collect();
+    GasBucket {
+        start,
+        first_block: blocks[0].number,
+        last_block: blocks[blocks.len() - 1].number,
+        blocks: blocks.len() as u64,
+        transactions: blocks.iter().map(|b| b.tx_count).sum(),
+        gas_used: blocks.iter().map(|b| b.gas_used).sum(),
+        utilization_p50: percentile(&utilization, 50.0),
+        utilization_p90: percentile(&utilization, 90.0),
+        utilization_p99: percentile(&utilization, 99.0),
+        base_fee_first: fees.first().copied(),
+        base_fee_last: fees.last().copied(),
+        base_fee_min: fees.iter().min().copied(),
+        base_fee_max: fees.iter().max().copied(),
+        base_fee_mean_delta: if deltas.is_empty() {
+            None
+        } else {
+            Some(deltas.iter().sum::<f64>() / deltas.len() as f64)
+        },
+    }
+}
+
+/// groups blocks ordered by number into buckets
+pub fn report(blocks: &[BlockGas], bucket: Bucket) -> Vec<GasBucket> {
+    let mut groups: BTreeMap<u64, Vec<BlockGas>> = BTreeMap::new();
+    for b in blocks {
+        groups.entry(bucket.key(b)).or_default().push(b.clone());
+    }
+    groups
+        .into_iter()
+        .map(|(start, mut blocks)| {
+            blocks.sort_by_key(|b| b.number);
+            aggregate(start, &blocks)
+        })
+        .collect()
+}
+
+fn opt<T: ToString>(v: &Option<T>) -> String {
+    v.as_ref().map_or(String::new(), |v| v.to_string())
+}
+
+pub fn to_csv(buckets: &[GasBucket]) -> String {
+    let mut out = String::from(
+        "start,first_block,last_block,blocks,transactions,gas_used,\
+        utilization_p50,utilization_p90,utilization_p99,\
+        base_fee_first,base_fee_last,base_fee_min,base_fee_max,base_fee_mean_delta\n",
+    );
+    for b in buckets {
+        let _ = writeln!(
+            out,
+            "{},{},{},{},{},{},{:.2},{:.2},{:.2},{},{},{},{},{}",
+            b.start,
+            b.first_block,
+            b.last_block,
+            b.blocks,
+            b.transactions,
+            b.gas_used,
+            b.utilization_p50,
+            b.utilization_p90,
+            b.utilization_p99,
+            opt(&b.base_fee_first),
+            opt(&b.base_fee_last),
+            opt(&b.base_fee_min),
+            opt(&b.base_fee_max),
+            opt(&b.base_fee_mean_delta.map(|d| format!("{:.4}", d))),
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eth_logs::fixtures::ChainGenerator;
+    use jsondp::dictionary::NoDictionary;
+
+    fn stored(blocks: &[eth_logs::BlockTransactions]) -> Vec<Vec<u8>> {
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        blocks
+            .iter()
+            .map(|b| {
+                let mut blob = vec![];
+                jsondp::encode(&b.to_value().unwrap(), &mut blob, &fd, &NoDictionary {}).unwrap();
+                blob
+            })
+            .collect()
+    }
+
+    #[test]
+    fn it_extracts_gas_fields_of_stored_blocks() {
+        let mut g = ChainGenerator::seeded(11).london_block(3);
+        let blocks = g.generate(5);
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        for (block, blob) in blocks.iter().zip(stored(&blocks)) {
+            let gas = extract(&blob, &fd, &NoDictionary {}).unwrap();
+            assert_eq!(
+                gas,
+                BlockGas {
+                    number: block.block.number.unwrap().as_u64(),
+                    timestamp: block.block.timestamp.as_u64(),
+                    gas_used: block.block.gas_used.as_u64(),
+                    gas_limit: block.block.gas_limit.as_u64(),
+                    base_fee: block.block.base_fee_per_gas.map(|f| f.as_u64()),
+                    tx_count: block.transactions.len() as u64,
+                }
+            );
+        }
+        assert_eq!(
+            extract(&stored(&blocks)[0], &fd, &NoDictionary {})
+                .unwrap()
+                .base_fee,
+            None
+        );
+    }
+
+    fn block(number: u64, gas_used: u64, base_fee: Option<u64>) -> BlockGas {
+        BlockGas {
+            number,
+            timestamp: 1_700_000_000 + number * 12,
+            gas_used,
+            gas_limit: 1000,
+            base_fee,
+            tx_count: 2,
+        }
+    }
+
+    #[test]
+    fn it_aggregates_buckets() {
+        // blocks 8 and 9 are before London
+        let mut blocks = vec![block(8, 100, None), block(9, 300, None)];
+        for (i, used) in [500, 1000, 250, 750, 0].iter().enumerate() {
+            blocks.push(block(10 + i as u64, *used, Some(100 + 10 * i as u64)));
+        }
+        let buckets = report(&blocks, "10".parse().unwrap());
+        assert_eq!(buckets.len(), 2);
+        let pre = &buckets[0];
+        assert_eq!((pre.start, pre.blocks, pre.transactions), (0, 2, 4));
+        assert_eq!((pre.base_fee_first, pre.base_fee_mean_delta), (None, None));
+        assert_eq!(pre.utilization_p50, 10.0);
+
+        let post = &buckets[1];
+        assert_eq!(
+            (post.first_block, post.last_block, post.gas_used),
+            (10, 14, 2500)
+        );
+        // utilization sorted: 0, 25, 50, 75, 100
+        assert_eq!(
+            (
+                post.utilization_p50,
+                post.utilization_p90,
+                post.utilization_p99
+            ),
+            (50.0, 100.0, 100.0)
+        );
+        assert_eq!(
+            (post.base_fee_first, post.base_fee_last),
+            (Some(100), Some(140))
+        );
+        assert_eq!(
+            (post.base_fee_min, post.base_fee_max),
+            (Some(100), Some(140))
+        );
+        // +10% +9.09% +8.33% +7.69%
+        let delta = post.base_fee_mean_delta.unwrap();
+        assert!((delta - (10.0 + 100.0 / 11.0 + 100.0 / 12.0 + 100.0 / 13.0) / 4.0).abs() < 1e-9);
+
+        let csv = to_csv(&buckets);
+        assert_eq!(csv.lines().count(), 3);
+        assert!(csv.lines().nth(1).unwrap().ends_with(",,,,,"));
+    }
+
+    #[test]
+    fn it_groups_generated_blocks_by_time() {
+        let mut g = ChainGenerator::seeded(5).london_block(0);
+        g.generate(600);
+        let gas: Vec<BlockGas> = g
+            .canonical()
+            .iter()
+            .map(|b| BlockGas {
+                number: b.block.number.unwrap().as_u64(),
+                timestamp: b.block.timestamp.as_u64(),
+                gas_used: b.block.gas_used.as_u64(),
+                gas_limit: b.block.gas_limit.as_u64(),
+                base_fee: b.block.base_fee_per_gas.map(|f| f.as_u64()),
+                tx_count: b.transactions.len() as u64,
+            })
+            .collect();
+        let buckets = report(&gas, Bucket::Hour);
+        // 12 seconds per block, 300 blocks an hour
+        assert!(buckets
+            .iter()
+            .all(|b| b.blocks <= 300 && b.start % 3600 == 0));
+        assert_eq!(buckets.iter().map(|b| b.blocks).sum::<u64>(), 600);
+        assert_eq!(
+            buckets.iter().map(|b| b.gas_used).sum::<u64>(),
+            gas.iter().map(|b| b.gas_used).sum::<u64>()
+        );
+        // blocks are far below the target, so the base fee only goes down
+        assert!(buckets
+            .iter()
+            .all(|b| b.base_fee_mean_delta.unwrap() <= 0.0));
+        assert!(buckets[0].base_fee_last < buckets[0].base_fee_first);
+        assert_eq!("10k".parse::<Bucket>().unwrap(), Bucket::Blocks(10_000));
+        assert!("0".parse::<Bucket>().is_err() && "week".parse::<Bucket>().is_err());
+    }
+}
@@ -0,0 +1,399 @@
+use anyhow::bail;
+use serde::de::{self, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// grammar of durations, repeated in every error
+pub const DURATION_GRAMMAR: &str =
+    "a number with a unit, parts may be combined: 500ms, 90s, 2h30m, 1.5h; units ns, us, ms, s, m, h, d";
+
+/// grammar of sizes, repeated in every error
+pub const SIZE_GRAMMAR: &str =
+    "a number with a unit: 512B, 64KiB, 512MiB, 1.5GB; units B, kB, MB, GB, TB (powers of 1000), KiB, MiB, GiB, TiB (powers of 1024)";
+
+const DURATION_UNITS: &[(&str, u128)] = &[
+    ("d", 86_400_000_000_000),
+    ("h", 3_600_000_000_000),
+    ("m", 60_000_000_000),
+    ("s", 1_000_000_000),
+    ("ms", 1_000_000),
+    ("us", 1_000),
+    ("ns", 1),
+];
+
+const SIZE_UNITS: &[(&str, u128)] = &[
+    ("TiB", 1 << 40),
+    ("TB", 1_000_000_000_000),
+    ("GiB", 1 << 30),
+    ("GB", 1_000_000_000),
+    ("MiB", 1 << 20),
+    ("MB", 1_000_000),
+    ("KiB", 1 << 10),
+    ("kB", 1_000),
+    ("B", 1),
+];
+
+// one `<number><unit>` part, only ASCII digits and a dot, so parsing
+// does not depend on the locale
+struct Part<'a> {
+    int: &'a str,
+    frac: &'a str,
+    unit: &'a str,
+}
+
+fn next_part(s: &str) -> anyhow::Result<(Part<'_>, &str)> {
+    let digits = |s: &str| s.bytes().take_while(|b| b.is_ascii_digit()).count();
+    let n = digits(s);
+    let int = &s[..n];
+    let mut rest = &s[n..];
+    let mut frac = "";
+    if let Some(after) = rest.strip_prefix('.') {
+        let n = digits(after);
+        frac = &after[..n];
+        rest = &after[n..];
+        if frac.is_empty() {
+            bail!("no digits after the decimal point");
+        }
+    }
+    if int.is_empty() {
+        bail!("expected a number");
+    }
+    let n = rest.bytes().take_while(|b| b.is_ascii_alphabetic()).count();
+    let unit = &rest[..n];
+    if unit.is_empty() {
+        match rest.chars().next() {
+            Some(c) => bail!("unexpected {:?}", c),
+            None => bail!("missing unit"),
+        }
+    }
+    Ok((Part { int, frac, unit }, rest[n..].trim_start()))
+}
+
+// sums parts in base units, fractions must come out whole
+fn parse_parts(s: &str, units: &[(&str, u128)], combine: bool) -> anyhow::Result<u128> {
+    let mut rest = s.trim();
+    if rest.starts_with('-') {
+        bail!("negative values are not allowed");
+    }
+    if rest.is_empty() {
+        bail!("empty value");
+    }
+    let mut total: u128 = 0;
+    let mut last_scale = u128::MAX;
+    while !rest.is_empty() {
+        if last_scale != u128::MAX && !combine {
+            bail!("unexpected {:?}", rest);
+        }
+        let (part, tail) = next_part(rest)?;
+        let scale = match units.iter().find(|(u, _)| *u == part.unit) {
+            Some((_, scale)) => *scale,
+            None => bail!("unknown unit {:?}", part.unit),
+        };
+        if scale >= last_scale {
+            bail!("parts should go from larger to smaller units");
+        }
+        last_scale = scale;
+        if part.int.len() + part.frac.len() > 30 {
+            bail!("too large");
+        }
+        let int: u128 = part.int.parse()?;
+        let mut value = int.checked_mul(scale).ok_or(anyhow::anyhow!("too large"))?;
+        if !part.frac.is_empty() {
+            let denom = 10u128.pow(part.frac.len() as u32);
+            let frac = part.frac.parse::<u128>()? * scale;
+            if frac % denom != 0 {
+                bail!(
+                    "{}.{}{} is not a whole number of the smallest unit",
+                    part.int,
+                    part.frac,
+                    part.unit
+                );
+            }
+            value += frac / denom;
+        }
+        total = total
+            .checked_add(value)
+            .ok_or(anyhow::anyhow!("too large"))?;
+        rest = tail;
+    }
+    Ok(total)
+}
+
+/// parses "500ms", "2h30m", "1.5h"; a bare number is rejected as it has no unit
+pub fn parse_duration(s: &str) -> anyhow::Result<Duration> {
+    let nanos = parse_parts(s, DURATION_UNITS, true).map_err(|e| {
+        anyhow::anyhow!(
+            "invalid duration {:?} ({}), expected {}",
+            s,
+            e,
+            DURATION_GRAMMAR
+        )
+    })?;
+    let secs = u64::try_from(nanos / 1_000_000_000)
+        .map_err(|_| anyhow::anyhow!("invalid duration {:?} (too large)", s))?;
+    Ok(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+/// parses "512MiB", "1.5GB"; a bare number is rejected as it has no unit
+pub fn parse_size(s: &str) -> anyhow::Result<u64> {
+    let bytes = parse_parts(s, SIZE_UNITS, false)
+        .map_err(|e| anyhow::anyhow!("invalid size {:?} ({}), expected {}", s, e, SIZE_GRAMMAR))?;
+    u64::try_from(bytes).map_err(|_| anyhow::anyhow!("invalid size {:?} (too large)", s))
+}
+
+/// shortest exact form, largest units first: 9000s is "2h30m"
+pub fn format_duration(d: Duration) -> String {
+    let mut nanos = d.as_nanos();
+    if nanos == 0 {
+        return "0s".to_string();
+    }
+    let mut out = String::new();
+    for (unit, scale) in DURATION_UNITS {
+        if nanos >= *scale {
+            out.push_str(&format!("{}{}", nanos / scale, unit));
+            nanos %= scale;
+        }
+    }
+    out
+}
+
+/// largest unit that keeps the size whole: 536870912 is "512MiB"
+pub fn format_size(bytes: u64) -> String {
+    let bytes = bytes as u128;
+    for (unit, scale) in SIZE_UNITS {
+        if bytes > 0 && bytes.is_multiple_of(*scale) {
+            return format!("{}{}", bytes / scale, unit);
+        }
+    }
+    "0B".to_string()
+}
+
+/// Duration written as "500ms" or "2h30m" in flags and configuration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct HumaneDuration(pub Duration);
+
+impl FromStr for HumaneDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        parse_duration(s).map(Self)
+    }
+}
+
+impl fmt::Display for HumaneDuration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format_duration(self.0))
+    }
+}
+
+impl From<Duration> for HumaneDuration {
+    fn from(d: Duration) -> Self {
+        Self(d)
+    }
+}
+
+impl Serialize for HumaneDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for HumaneDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(|e| de::Error::custom(format!("{:#}", e)))
+    }
+}
+
+/// Number of bytes written as "512MiB" or "1.5GB" in flags and configuration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn as_usize(&self) -> usize {
+        usize::try_from(self.0).unwrap_or(usize::MAX)
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        parse_size(s).map(Self)
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&format_size(self.0))
+    }
+}
+
+impl Serialize for ByteSize {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+struct ByteSizeVisitor;
+
+impl Visitor<'_> for ByteSizeVisitor {
+    type Value = ByteSize;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}, or an integer number of bytes", SIZE_GRAMMAR)
+    }
+
+    // plain integers are kept for configs written before units
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<ByteSize, E> {
+        Ok(ByteSize(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<ByteSize, E> {
+        u64::try_from(v).map(ByteSize).map_err(|_| {
+            E::custom(format!(
+                "invalid size {} (negative values are not allowed)",
+                v
+            ))
+        })
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<ByteSize, E> {
+        s.parse().map_err(|e| E::custom(format!("{:#}", e)))
+    }
+}
+
+impl<'de> Deserialize<'de> for ByteSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ByteSizeVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    #[test]
+    fn it_parses_durations() {
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2h30m").unwrap(), secs(9000));
+        assert_eq!(parse_duration(" 2h 30m ").unwrap(), secs(9000));
+        assert_eq!(parse_duration("1.5h").unwrap(), secs(5400));
+        assert_eq!(parse_duration("7d").unwrap(), secs(7 * 86400));
+        assert_eq!(parse_duration("0.25s").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("1us").unwrap(), Duration::from_micros(1));
+        assert_eq!(parse_duration("0s").unwrap(), Duration::ZERO);
+    }
+
+    #[test]
+    fn it_rejects_ambiguous_durations() {
+        let err = parse_duration("1024").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "invalid duration \"1024\" (missing unit), expected {}",
+                DURATION_GRAMMAR
+            )
+        );
+        for s in [
+            "",
+            "h",
+            "1.h",
+            ".5h",
+            "30m2h",
+            "1h1h",
+            "1.5ns",
+            "5 minutes",
+            "5M",
+        ] {
+            assert!(parse_duration(s).is_err(), "{}", s);
+        }
+        assert!(parse_duration("-5s")
+            .unwrap_err()
+            .to_string()
+            .contains("negative values are not allowed"));
+        assert!(parse_duration("99999999999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn it_parses_sizes() {
+        assert_eq!(parse_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_size("1.5GB").unwrap(), 1_500_000_000);
+        assert_eq!(parse_size("1.5KiB").unwrap(), 1536);
+        assert_eq!(parse_size("64kB").unwrap(), 64_000);
+        assert_eq!(parse_size("0B").unwrap(), 0);
+        assert!(parse_size("1024")
+            .unwrap_err()
+            .to_string()
+            .contains("(missing unit)"));
+        for s in ["-1MiB", "1.1B", "1MiB512KiB", "1mb", "1 GiB extra", "1Kib"] {
+            assert!(parse_size(s).is_err(), "{}", s);
+        }
+        assert!(parse_size("20000000TiB").is_err());
+    }
+
+    #[test]
+    fn it_parses_independently_of_locale() {
+        // decimal comma, digit grouping and non-ASCII digits are never accepted
+        for s in ["1,5h", "1 000ms", "1_000ms", "١٢s", "1'000ms"] {
+            assert!(parse_duration(s).is_err(), "{}", s);
+        }
+        for s in ["1,5GB", "１GB", "1 000B", "1.5 GB"] {
+            assert!(parse_size(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn it_round_trips_humane_forms() {
+        for s in ["500ms", "2h30m", "7d", "1m30s", "1s1ns", "0s"] {
+            assert_eq!(format_duration(parse_duration(s).unwrap()), s);
+        }
+        assert_eq!(format_duration(parse_duration("1.5h").unwrap()), "1h30m");
+        for s in ["512MiB", "1GB", "1536B", "0B", "3TiB"] {
+            assert_eq!(format_size(parse_size(s).unwrap()), s);
+        }
+        assert_eq!(format_size(parse_size("1.5GB").unwrap()), "1500MB");
+        assert_eq!(format_size(parse_size("1.5KiB").unwrap()), "1536B");
+
+        let d: HumaneDuration = serde_json::from_str("\"2h30m\"").unwrap();
+        assert_eq!(serde_json::to_string(&d).unwrap(), "\"2h30m\"");
+        let b: ByteSize = serde_json::from_str("\"512MiB\"").unwrap();
+        assert_eq!(serde_json::to_string(&b).unwrap(), "\"512MiB\"");
+        assert_eq!(
+            serde_json::from_str::<ByteSize>("4096").unwrap(),
+            ByteSize(4096)
+        );
+        let err = serde_json::from_str::<ByteSize>("-1").unwrap_err();
+        assert!(err.to_string().contains("negative values are not allowed"));
+        let err = serde_json::from_str::<HumaneDuration>("\"6\"").unwrap_err();
+        assert!(err.to_string().contains("missing unit"));
+    }
+
+    #[test]
+    fn it_names_the_flag_in_clap_errors() {
+        use clap::Parser;
+
+        #[derive(Debug, Parser)]
+        struct Cli {
+            #[arg(long)]
+            poll_interval: HumaneDuration,
+            #[arg(long, default_value = "64MiB")]
+            cache_size: ByteSize,
+        }
+
+        let cli = Cli::try_parse_from(["btxs", "--poll-interval", "1.5s"]).unwrap();
+        assert_eq!(cli.poll_interval.0, Duration::from_millis(1500));
+        assert_eq!(cli.cache_size, ByteSize(64 << 20));
+        let err = Cli::try_parse_from(["btxs", "--poll-interval", "1000"])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("--poll-interval"), "{}", err);
+        assert!(err.contains("missing unit"), "{}", err);
+    }
+}
@@ -0,0 +1,604 @@
+use crate::manifest::{Manifest, ManifestBucket, OperationId};
+use crate::notify::EventLog;
+use anyhow::{bail, Context};
+use clap::Args;
+use eth_logs::BlockTransactions;
+use jsondp::dictionary::{MapDictionary, NoDictionary};
+use kv::KV;
+use serde_json::{json, Value};
+use std::fmt;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tracing::*;
+
+/// Import of per-block JSON files from an archive directory
+#[derive(Debug, Clone, Args)]
+pub struct ImportArgs {
+    /// directory with the archive, walked recursively
+    #[arg(long)]
+    pub dir: PathBuf,
+    /// file name pattern, `{number}` is the decimal block number.
+    /// Files with additional `.gz` extension are decompressed
+    #[arg(long, default_value = "{number}.json")]
+    pub pattern: String,
+    /// file keeping the last imported block, to resume interrupted imports
+    #[arg(long)]
+    pub position_file: Option<PathBuf>,
+    /// abort on the first malformed file instead of skipping it
+    #[arg(long)]
+    pub strict: bool,
+    /// apply files again even if the manifest says they are applied
+    #[arg(long)]
+    pub force_reapply: bool,
+    /// files applied and recorded in the manifest together
+    #[arg(long, default_value_t = 1000)]
+    pub chunk_size: usize,
+}
+
+/// kind of the manifest of imports
+const OPERATION_KIND: &str = "import-json";
+
+/// kind of the notification of imported blocks
+const APPLIED_EVENT: &str = "import.applied";
+
+#[derive(Debug, Clone, Default)]
+pub struct ImportReport {
+    pub imported: u64,
+    pub first: Option<u32>,
+    pub last: Option<u32>,
+    /// malformed files with the reason
+    pub skipped: Vec<(PathBuf, String)>,
+    /// hash of the imported files
+    pub operation: String,
+    /// files left out as applied by an earlier run
+    pub already_applied: u64,
+}
+
+impl fmt::Display for ImportReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.first, self.last) {
+            (Some(first), Some(last)) => {
+                writeln!(f, "imported {} blocks {}..={}", self.imported, first, last)?
+            }
+            _ => writeln!(f, "imported {} blocks", self.imported)?,
+        }
+        if self.already_applied > 0 {
+            writeln!(
+                f,
+                "left out {} blocks already applied by operation {}, use --force-reapply to apply them again",
+                self.already_applied, self.operation
+            )?;
+        }
+        for (path, reason) in &self.skipped {
+            writeln!(f, "skipped {}: {}", path.display(), reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// block number from the file name, if it matches the pattern
+fn match_pattern(pattern: &str, file_name: &str) -> Option<u32> {
+    let (prefix, suffix) = pattern.split_once("{number}")?;
+    let file_name = file_name.strip_suffix(".gz").unwrap_or(file_name);
+    let digits = file_name.strip_prefix(prefix)?.strip_suffix(suffix)?;
+    if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+fn walk(dir: &Path, pattern: &str, out: &mut Vec<(u32, PathBuf)>) -> anyhow::Result<()> {
+    for entry in std::fs::read_dir(dir).with_context(|| format!("read {}", dir.display()))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            walk(&path, pattern, out)?;
+        } else if let Some(number) = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .and_then(|n| match_pattern(pattern, n))
+        {
+            out.push((number, path));
+        }
+    }
+    Ok(())
+}
+
+fn read_file(path: &Path) -> anyhow::Result<Value> {
+    let file = std::fs::File::open(path)?;
+    let mut text = String::new();
+    if path.extension().is_some_and(|e| e == "gz") {
+        flate2::read::GzDecoder::new(file)
+            .read_to_string(&mut text)
+            .context("gzip")?;
+    } else {
+        std::io::BufReader::new(file).read_to_string(&mut text)?;
+    }
+    Ok(serde_json::from_str(&text)?)
+}
+
+/// parses the file into the stored document shape and checks its block number
+fn normalize(value: Value, expected: u32) -> anyhow::Result<Value> {
+    let block = if value.get("block").is_some() {
+        BlockTransactions::from_value(value)?
+    } else {
+        BlockTransactions::from_block_value(value)?
+    };
+    let number = block.block.number.context("no block number")?.as_u64();
+    if number != expected as u64 {
+        bail!("block number {} doesn't match the file name", number);
+    }
+    block.to_value()
+}
+
+fn read_position(path: &Path) -> anyhow::Result<Option<u32>> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => Ok(Some(s.trim().parse().context("position file")?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// hash of the file contents, the same files give the same operation
+fn operation_id(files: &[(u32, PathBuf)]) -> anyhow::Result<String> {
+    let mut id = OperationId::default();
+    for (number, path) in files {
+        let content = std::fs::read(path).with_context(|| format!("{}", path.display()))?;
+        id.update(*number, &content);
+    }
+    Ok(id.finish())
+}
+
+/// stores blocks of the chunk and the manifest which records them,
+/// in one transaction when the storage has them
+pub(crate) async fn apply_chunk<K: KV + Sync>(
+    storage: &K,
+    table: &str,
+    manifests: &ManifestBucket<K>,
+    manifest: &mut Manifest,
+    blobs: Vec<(u32, Vec<u8>)>,
+) -> anyhow::Result<()> {
+    let mut txn = match storage.transaction().await? {
+        Some(txn) => txn,
+        None => {
+            for (number, blob) in blobs {
+                storage.set(number, blob).await?;
+            }
+            return manifests.save(manifest).await;
+        }
+    };
+    let mut result = Ok(());
+    for (number, blob) in blobs {
+        result = txn.set(table, number, blob).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = manifests.save_in(&mut txn, manifest).await;
+    }
+    match result {
+        Ok(()) => txn.commit().await,
+        Err(e) => {
+            if let Err(rollback) = txn.rollback().await {
+                warn!("rollback failed: {:#}", rollback);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// issues the notification of applied blocks and records it in the manifest
+pub(crate) async fn notify<K: KV>(
+    events: Option<&EventLog<K>>,
+    kind: &str,
+    manifests: &ManifestBucket<K>,
+    manifest: &mut Manifest,
+    (first, last): (u32, u32),
+) -> anyhow::Result<()> {
+    if let Some(events) = events {
+        let payload = json!({"operation": manifest.operation, "first": first, "last": last});
+        events.stamp(kind, payload).await?;
+    }
+    manifest.notify(first, last);
+    manifests.save(manifest).await
+}
+
+/// Imports files in chunks. Every chunk is recorded in the manifest of the
+/// operation, so a second run of the same files leaves out what is applied
+/// and an interrupted one resumes after the last recorded chunk
+pub async fn run<K: KV + Sync>(
+    args: &ImportArgs,
+    storage: &K,
+    table: &str,
+    manifests: &ManifestBucket<K>,
+    events: Option<&EventLog<K>>,
+) -> anyhow::Result<ImportReport> {
+    if args.chunk_size == 0 {
+        bail!("chunk size should be positive");
+    }
+    let mut files = Vec::new();
+    walk(&args.dir, &args.pattern, &mut files)?;
+    files.sort();
+    let operation = operation_id(&files)?;
+    let mut manifest = match manifests.get(&operation).await? {
+        Some(m) if !args.force_reapply => m,
+        _ => Manifest::new(OPERATION_KIND, &operation),
+    };
+    manifest.resume_format()?;
+    let mut report = ImportReport {
+        operation: operation.clone(),
+        ..Default::default()
+    };
+    for range in manifest.pending_notifications() {
+        info!(?range, "notifying blocks applied before the crash");
+        notify(events, APPLIED_EVENT, manifests, &mut manifest, range).await?;
+    }
+    let total = files.len();
+    files.retain(|(number, _)| !manifest.is_applied(*number));
+    report.already_applied = (total - files.len()) as u64;
+    if report.already_applied > 0 {
+        info!(
+            operation,
+            applied = report.already_applied,
+            "leaving out applied files"
+        );
+    }
+
+    let position = match &args.position_file {
+        Some(path) => read_position(path)?,
+        None => None,
+    };
+    if let Some(position) = position {
+        info!(position, "resuming import");
+        files.retain(|(number, _)| *number > position);
+    }
+
+    let fd: MapDictionary =
+        jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let total = files.len();
+    let mut done = 0;
+    for chunk in files.chunks(args.chunk_size) {
+        let mut blobs = Vec::with_capacity(chunk.len());
+        for (number, path) in chunk {
+            let doc = read_file(path).and_then(|v| normalize(v, *number));
+            let doc = match doc {
+                Ok(doc) => doc,
+                Err(e) if !args.strict => {
+                    warn!(path = %path.display(), "skipped: {:#}", e);
+                    report.skipped.push((path.clone(), format!("{:#}", e)));
+                    continue;
+                }
+                Err(e) => return Err(e.context(format!("{}", path.display()))),
+            };
+            let mut blob = Vec::new();
+            jsondp::encode(&doc, &mut blob, &fd, &NoDictionary {})?;
+            blobs.push((*number, blob));
+        }
+        let range = (chunk[0].0, chunk[chunk.len() - 1].0);
+        let imported = blobs.len() as u64;
+        let stored = blobs.first().zip(blobs.last()).map(|(f, l)| (f.0, l.0));
+        manifest.apply(range.0, range.1);
+        apply_chunk(storage, table, manifests, &mut manifest, blobs).await?;
+        notify(events, APPLIED_EVENT, manifests, &mut manifest, range).await?;
+
+        report.imported += imported;
+        if let Some((first, last)) = stored {
+            report.first = Some(report.first.unwrap_or(first));
+            report.last = Some(last);
+            if let Some(path) = &args.position_file {
+                std::fs::write(path, last.to_string()).context("position file")?;
+            }
+        }
+        done += chunk.len();
+        info!(done, total, number = range.1, "import progress");
+    }
+    manifest.completed = true;
+    manifests.save(&mut manifest).await?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use kv::{KvTxn, MemoryKV};
+    use std::collections::BTreeMap;
+    use std::io::Write;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    // blocks committed per key, across runs
+    type Applied = Arc<Mutex<BTreeMap<u32, usize>>>;
+
+    // storage that fails after the given number of writes and counts
+    // committed writes of the blocks bucket
+    struct FlakyKV {
+        inner: MemoryKV,
+        left: AtomicUsize,
+        applied: Applied,
+    }
+
+    impl FlakyKV {
+        fn new(inner: MemoryKV, fail_after: usize, applied: &Applied) -> Self {
+            Self {
+                inner,
+                left: AtomicUsize::new(fail_after),
+                applied: applied.clone(),
+            }
+        }
+
+        fn tick(&self) -> anyhow::Result<()> {
+            self.left
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .map(|_| ())
+                .map_err(|_| anyhow::anyhow!("injected failure"))
+        }
+    }
+
+    #[async_trait]
+    impl KV for FlakyKV {
+        async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.get(n).await
+        }
+
+        async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+            self.tick()?;
+            self.inner.set(n, v).await
+        }
+
+        async fn transaction(&self) -> anyhow::Result<Option<Box<dyn KvTxn + '_>>> {
+            let inner = self.inner.transaction().await?.unwrap();
+            Ok(Some(Box::new(FlakyTxn {
+                kv: self,
+                inner,
+                blocks: vec![],
+            })))
+        }
+    }
+
+    struct FlakyTxn<'a> {
+        kv: &'a FlakyKV,
+        inner: Box<dyn KvTxn + 'a>,
+        blocks: Vec<u32>,
+    }
+
+    #[async_trait]
+    impl KvTxn for FlakyTxn<'_> {
+        async fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+            self.kv.tick()?;
+            if bucket == "blocks" {
+                self.blocks.push(n);
+            }
+            self.inner.set(bucket, n, v).await
+        }
+
+        async fn delete(&mut self, bucket: &str, n: u32) -> anyhow::Result<()> {
+            self.inner.delete(bucket, n).await
+        }
+
+        async fn commit(self: Box<Self>) -> anyhow::Result<()> {
+            {
+                let mut applied = self.kv.applied.lock().unwrap();
+                for n in &self.blocks {
+                    *applied.entry(*n).or_default() += 1;
+                }
+            }
+            self.inner.commit().await
+        }
+
+        async fn rollback(self: Box<Self>) -> anyhow::Result<()> {
+            self.inner.rollback().await
+        }
+    }
+
+    fn block(number: u64) -> Value {
+        serde_json::json!({
+            "hash": format!("0x{:064x}", number + 0xabc),
+            "parentHash": format!("0x{:064x}", number + 0xabb),
+            "number": format!("0x{:x}", number),
+            "gasUsed": "0x0",
+            "gasLimit": "0x1c9c380",
+            "timestamp": "0x64a7a5d3",
+            "transactions": [],
+            "uncles": [],
+        })
+    }
+
+    fn fixture_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("btxs-import-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        for n in [100u64, 101] {
+            std::fs::write(dir.join(format!("{}.json", n)), block(n).to_string()).unwrap();
+        }
+        // gzipped file in a nested directory
+        let file = std::fs::File::create(dir.join("nested/102.json.gz")).unwrap();
+        let mut gz = flate2::write::GzEncoder::new(file, flate2::Compression::default());
+        gz.write_all(block(102).to_string().as_bytes()).unwrap();
+        gz.finish().unwrap();
+        // corrupt and mismatching files
+        std::fs::write(dir.join("103.json"), "{\"number\": ").unwrap();
+        std::fs::write(dir.join("104.json"), block(5).to_string()).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a block").unwrap();
+        dir
+    }
+
+    fn args(dir: &Path) -> ImportArgs {
+        ImportArgs {
+            dir: dir.to_path_buf(),
+            pattern: "{number}.json".to_string(),
+            position_file: None,
+            strict: false,
+            force_reapply: false,
+            chunk_size: 1000,
+        }
+    }
+
+    fn manifests(storage: &MemoryKV) -> ManifestBucket<MemoryKV> {
+        ManifestBucket::new(storage.bucket("operations"), "operations")
+    }
+
+    #[tokio::test]
+    async fn it_imports_and_reports_skipped() {
+        let dir = fixture_dir("skip");
+        let storage = MemoryKV::new();
+        let report = run(&args(&dir), &storage, "", &manifests(&storage), None)
+            .await
+            .unwrap();
+        assert_eq!(report.imported, 3);
+        assert_eq!((report.first, report.last), (Some(100), Some(102)));
+        let skipped: Vec<_> = report
+            .skipped
+            .iter()
+            .map(|(p, _)| p.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(skipped, vec!["103.json", "104.json"]);
+        assert!(report.skipped[1].1.contains("doesn't match"));
+
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        let blob = storage.get(102).await.unwrap().unwrap();
+        let doc = jsondp::decode(&mut blob.as_slice(), &fd, &NoDictionary {}).unwrap();
+        assert_eq!(doc["block"]["number"], "0x66");
+        assert_eq!(storage.get(103).await.unwrap(), None);
+
+        let mut strict = args(&dir);
+        strict.strict = true;
+        let storage = MemoryKV::new();
+        let m = manifests(&storage);
+        assert!(run(&strict, &storage, "", &m, None).await.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_resumes_from_position() {
+        let dir = fixture_dir("resume");
+        let mut a = args(&dir);
+        a.position_file = Some(dir.join("position"));
+        std::fs::write(dir.join("position"), "100").unwrap();
+        let storage = MemoryKV::new();
+        let report = run(&a, &storage, "", &manifests(&storage), None)
+            .await
+            .unwrap();
+        assert_eq!((report.first, report.last), (Some(101), Some(102)));
+        assert_eq!(storage.get(100).await.unwrap(), None);
+        assert_eq!(read_position(&dir.join("position")).unwrap(), Some(102));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn it_matches_pattern() {
+        assert_eq!(match_pattern("{number}.json", "15.json"), Some(15));
+        assert_eq!(match_pattern("{number}.json", "15.json.gz"), Some(15));
+        assert_eq!(
+            match_pattern("block-{number}.json", "block-7.json"),
+            Some(7)
+        );
+        assert_eq!(match_pattern("{number}.json", "x15.json"), None);
+        assert_eq!(match_pattern("{number}.json", ".json"), None);
+    }
+
+    fn chain_dir(name: &str, count: u64) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("btxs-import-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        for n in 100..100 + count {
+            std::fs::write(dir.join(format!("{}.json", n)), block(n).to_string()).unwrap();
+        }
+        dir
+    }
+
+    // notified block numbers, every one as many times as it was notified
+    async fn notified(events: &EventLog<FlakyKV>) -> Vec<u32> {
+        let mut out = vec![];
+        for e in events.since(0, 1000).await.unwrap() {
+            assert_eq!(e.kind, "import.applied");
+            let first = e.payload["first"].as_u64().unwrap() as u32;
+            let last = e.payload["last"].as_u64().unwrap() as u32;
+            out.extend(first..=last);
+        }
+        out.sort();
+        out
+    }
+
+    #[tokio::test]
+    async fn it_applies_interrupted_import_exactly_once() {
+        let dir = chain_dir("interrupt", 10);
+        let memory = MemoryKV::new();
+        let applied = Applied::default();
+        let manifests = ManifestBucket::new(
+            FlakyKV::new(memory.bucket("operations"), usize::MAX, &applied),
+            "operations",
+        );
+        let events = EventLog::new(FlakyKV::new(memory.bucket("events"), usize::MAX, &applied));
+        let mut a = args(&dir);
+        a.chunk_size = 3;
+
+        // a chunk is 3 blocks and the manifest, fail in the third chunk
+        let storage = FlakyKV::new(memory.bucket("blocks"), 9, &applied);
+        let err = run(&a, &storage, "blocks", &manifests, Some(&events))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("injected failure"));
+        assert_eq!(applied.lock().unwrap().len(), 6);
+        assert_eq!(memory.bucket("blocks").get(106).await.unwrap(), None);
+        assert_eq!(notified(&events).await, (100..106).collect::<Vec<_>>());
+
+        let storage = FlakyKV::new(memory.bucket("blocks"), usize::MAX, &applied);
+        let report = run(&a, &storage, "blocks", &manifests, Some(&events))
+            .await
+            .unwrap();
+        assert_eq!((report.imported, report.already_applied), (4, 6));
+        assert_eq!((report.first, report.last), (Some(106), Some(109)));
+        let counts: Vec<(u32, usize)> = applied.lock().unwrap().clone().into_iter().collect();
+        assert_eq!(counts, (100..110).map(|n| (n, 1)).collect::<Vec<_>>());
+        assert_eq!(notified(&events).await, (100..110).collect::<Vec<_>>());
+        let manifest = manifests.get(&report.operation).await.unwrap().unwrap();
+        assert!(manifest.completed);
+        assert_eq!(manifest.applied, vec![(100, 109)]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_skips_applied_imports_unless_forced() {
+        let dir = chain_dir("replay", 4);
+        let memory = MemoryKV::new();
+        let applied = Applied::default();
+        let manifests = ManifestBucket::new(
+            FlakyKV::new(memory.bucket("operations"), usize::MAX, &applied),
+            "operations",
+        );
+        let events = EventLog::new(FlakyKV::new(memory.bucket("events"), usize::MAX, &applied));
+        let storage = FlakyKV::new(memory.bucket("blocks"), usize::MAX, &applied);
+        let mut a = args(&dir);
+        let first = run(&a, &storage, "blocks", &manifests, Some(&events))
+            .await
+            .unwrap();
+        assert_eq!(first.imported, 4);
+
+        let second = run(&a, &storage, "blocks", &manifests, Some(&events))
+            .await
+            .unwrap();
+        assert_eq!((second.imported, second.already_applied), (0, 4));
+        assert_eq!(second.operation, first.operation);
+        assert!(second.to_string().contains("--force-reapply"));
+        assert_eq!(notified(&events).await.len(), 4);
+
+        // changed files are another operation
+        std::fs::write(dir.join("104.json"), block(104).to_string()).unwrap();
+        let third = run(&a, &storage, "blocks", &manifests, Some(&events))
+            .await
+            .unwrap();
+        assert_ne!(third.operation, first.operation);
+        assert_eq!(third.imported, 5);
+
+        a.force_reapply = true;
+        std::fs::remove_file(dir.join("104.json")).unwrap();
+        let forced = run(&a, &storage, "blocks", &manifests, Some(&events))
+            .await
+            .unwrap();
+        assert_eq!((forced.imported, forced.already_applied), (4, 0));
+        assert_eq!(applied.lock().unwrap()[&100], 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,13 +1,76 @@
-use clap::Parser;
+use anyhow::Context;
+use clap::{Parser, Subcommand};
 use kv::KV;
+use std::io::Write;
 use tracing::*;
 
+mod auth;
+mod backup;
+mod bench;
+mod check;
+mod config;
+mod dict;
+mod export;
+mod gas_report;
+mod humane;
+mod import;
+mod maintenance;
+mod manifest;
+mod metrics;
+mod notify;
+mod process;
+mod quarantine;
+mod serve;
+#[cfg(feature = "chaos")]
+mod soak;
+mod status;
+mod storage;
+mod writer;
+
 #[derive(Debug, Clone, Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
     /// database url
     #[arg(long, env)]
     database_url: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+    /// validate configuration, endpoints and database without starting
+    Check(check::CheckArgs),
+    /// measure encode, decode and storage throughput
+    Bench(bench::BenchArgs),
+    /// follow the mock chain under injected failures and check nothing was lost
+    #[cfg(feature = "chaos")]
+    Soak(soak::SoakArgs),
+    /// storage maintenance
+    #[command(subcommand)]
+    Kv(storage::KvCommand),
+    /// import per-block JSON files of an existing archive
+    ImportJson(import::ImportArgs),
+    /// copy stored blocks into a file
+    Backup(backup::BackupArgs),
+    /// write blocks of a backup file back, each of them once
+    Restore(backup::RestoreArgs),
+    /// show health scores of the JSON-RPC endpoints
+    Status(status::StatusArgs),
+    /// logs of blocks that the provider would not serve
+    #[command(subcommand)]
+    Quarantine(quarantine::QuarantineCommand),
+    /// built-in processors over stored blocks
+    #[command(subcommand)]
+    Process(process::ProcessCommand),
+    /// value dictionary maintenance
+    #[command(subcommand)]
+    Dict(dict::DictCommand),
+    /// HTTP API over the stored blocks
+    Serve(serve::ServeArgs),
+    /// stored data as JSON lines
+    #[command(subcommand)]
+    Export(export::ExportCommand),
 }
 
 mod logging {
@@ -36,6 +99,121 @@ async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
     debug!("args {:?}", args);
 
+    match &args.command {
+        Some(Command::Check(check_args)) => {
+            let report = check::run(check_args, &args.database_url, "btxs_blocks").await;
+            if check_args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            if report.failed() {
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+        Some(Command::Bench(bench_args)) => {
+            let report = bench::run(bench_args, &args.database_url).await?;
+            if bench_args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            return Ok(());
+        }
+        #[cfg(feature = "chaos")]
+        Some(Command::Soak(soak_args)) => {
+            let report = soak::run(soak_args).await?;
+            if soak_args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            if !report.violations.is_empty() {
+                anyhow::bail!(
+                    "{} violations, replay with --seed {}",
+                    report.violations.len(),
+                    report.seed
+                );
+            }
+            return Ok(());
+        }
+        Some(Command::Kv(cmd)) => {
+            return storage::run(cmd, &args.database_url, "btxs_blocks").await;
+        }
+        Some(Command::ImportJson(import_args)) => {
+            let storage = kv::PostgresKV::try_new(&args.database_url, "btxs_blocks").await?;
+            let manifests = manifest::ManifestBucket::new(
+                kv::PostgresKV::try_new(&args.database_url, manifest::MANIFEST_TABLE).await?,
+                manifest::MANIFEST_TABLE,
+            );
+            let report =
+                import::run(import_args, &storage, "btxs_blocks", &manifests, None).await?;
+            print!("{}", report);
+            return Ok(());
+        }
+        Some(Command::Backup(backup_args)) => {
+            let storage = kv::PostgresKV::try_new(&args.database_url, "btxs_blocks").await?;
+            let path = &backup_args.out;
+            let file = std::fs::File::create(path)
+                .with_context(|| format!("create {}", path.display()))?;
+            let mut out = std::io::BufWriter::new(file);
+            let written = backup::backup(&storage, &backup_args.range, &mut out).await?;
+            out.flush()?;
+            info!(written, "backup written");
+            return Ok(());
+        }
+        Some(Command::Restore(restore_args)) => {
+            let storage = kv::PostgresKV::try_new(&args.database_url, "btxs_blocks").await?;
+            let manifests = manifest::ManifestBucket::new(
+                kv::PostgresKV::try_new(&args.database_url, manifest::MANIFEST_TABLE).await?,
+                manifest::MANIFEST_TABLE,
+            );
+            let events = notify::EventLog::new(
+                kv::PostgresKV::try_new(&args.database_url, notify::EVENTS_TABLE).await?,
+            );
+            let path = &restore_args.file;
+            let file =
+                std::fs::File::open(path).with_context(|| format!("open {}", path.display()))?;
+            let report = backup::restore(
+                restore_args,
+                std::io::BufReader::new(file),
+                &storage,
+                "btxs_blocks",
+                &manifests,
+                Some(&events),
+            )
+            .await?;
+            print!("{}", report);
+            return Ok(());
+        }
+        Some(Command::Status(status_args)) => {
+            let report = status::run(status_args, &args.database_url).await?;
+            if status_args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            return Ok(());
+        }
+        Some(Command::Quarantine(cmd)) => {
+            return quarantine::run(cmd, &args.database_url, "btxs_blocks").await;
+        }
+        Some(Command::Process(cmd)) => {
+            return process::run(cmd, &args.database_url, "btxs_blocks").await;
+        }
+        Some(Command::Dict(cmd)) => {
+            return dict::run(cmd, &args.database_url, "btxs_blocks").await;
+        }
+        Some(Command::Export(cmd)) => {
+            return export::run(cmd, &args.database_url, "btxs_blocks").await;
+        }
+        Some(Command::Serve(serve_args)) => {
+            return serve::run(serve_args, &args.database_url, "btxs_blocks").await;
+        }
+        None => {}
+    }
+
     let storage = kv::PostgresKV::new(&args.database_url, "btxs_blocks").await;
     storage.set(1000, vec![]).await.unwrap();
     let result = storage.get(1000).await.unwrap().unwrap();
@@ -0,0 +1,437 @@
+use crate::humane::HumaneDuration;
+use anyhow::{bail, Context};
+use async_trait::async_trait;
+use jsondp::gc::{DictionaryUsage, GcReport};
+use kv::{PostgresKV, KV};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::*;
+
+/// `[maintenance]` section of the configuration
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MaintenanceConfig {
+    /// number of latest blocks kept by the periodic prune, no pruning when missing
+    pub prune_keep_blocks: Option<u32>,
+    /// interval of prune and stats refresh, like "30m", "6h"
+    #[serde(default = "default_interval")]
+    pub interval: HumaneDuration,
+    /// interval of dictionary usage scan, no scan when missing
+    pub dict_gc_interval: Option<HumaneDuration>,
+    /// maintenance is paused while the indexer is behind the chain by more blocks
+    #[serde(default = "default_max_lag")]
+    pub max_lag: u32,
+}
+
+fn default_interval() -> HumaneDuration {
+    Duration::from_secs(6 * 3600).into()
+}
+
+fn default_max_lag() -> u32 {
+    100
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> Self {
+        Self {
+            prune_keep_blocks: None,
+            interval: default_interval(),
+            dict_gc_interval: None,
+            max_lag: default_max_lag(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Task {
+    Prune,
+    DictionaryGc,
+    Stats,
+}
+
+/// Source of time for the scheduler, replaced in tests
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Progress of the indexer shared with the maintenance task.
+/// Reorg repair holds `repair` lock, so maintenance never runs at the same time
+#[derive(Debug, Default)]
+pub struct IndexerState {
+    /// latest block stored by the indexer
+    pub indexed: AtomicU32,
+    /// latest block seen on the chain
+    pub chain_head: AtomicU32,
+    pub repair: tokio::sync::Mutex<()>,
+}
+
+impl IndexerState {
+    pub fn lag(&self) -> u32 {
+        let head = self.chain_head.load(Ordering::Relaxed);
+        head.saturating_sub(self.indexed.load(Ordering::Relaxed))
+    }
+}
+
+/// Storage routines called by the maintenance task
+#[async_trait]
+pub trait Maintain: Send + Sync {
+    /// removes blocks below `n`
+    async fn prune_below(&self, n: u32) -> anyhow::Result<()>;
+    /// counts dictionary references in blocks of the range
+    async fn dictionary_gc(&self, from: u32, to: u32) -> anyhow::Result<GcReport>;
+    /// refreshes statistics of the storage
+    async fn refresh_stats(&self) -> anyhow::Result<()>;
+}
+
+#[async_trait]
+impl Maintain for PostgresKV {
+    async fn prune_below(&self, n: u32) -> anyhow::Result<()> {
+        PostgresKV::prune_below(self, n).await
+    }
+
+    async fn dictionary_gc(&self, from: u32, to: u32) -> anyhow::Result<GcReport> {
+        let mut usage = DictionaryUsage::new();
+        for n in from..=to {
+            if let Some(blob) = self.get(n).await? {
+                usage
+                    .scan(&mut blob.as_slice())
+                    .with_context(|| format!("block {}", n))?;
+            }
+            // gives way to the indexer on the same runtime
+            tokio::task::yield_now().await;
+        }
+        let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+        Ok(GcReport::new(&fd, &usage.fields, 1))
+    }
+
+    async fn refresh_stats(&self) -> anyhow::Result<()> {
+        self.analyze().await
+    }
+}
+
+/// Counters of the maintenance task
+#[derive(Debug, Default)]
+pub struct MaintenanceMetrics {
+    pub runs: AtomicU64,
+    pub failures: AtomicU64,
+    /// ticks skipped because the indexer was behind
+    pub paused: AtomicU64,
+    /// ticks skipped because reorg repair was running
+    pub deferred: AtomicU64,
+}
+
+/// Result of one task, logged as a structured line
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskReport {
+    pub task: Task,
+    pub ok: bool,
+    pub elapsed_ms: u64,
+    pub detail: String,
+}
+
+/// Outcome of one scheduler tick
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tick {
+    Idle,
+    /// indexer lag in blocks
+    Paused(u32),
+    RepairRunning,
+    Ran(Vec<Task>),
+}
+
+pub struct Maintenance<M: Maintain, C: Clock> {
+    config: MaintenanceConfig,
+    intervals: BTreeMap<Task, Duration>,
+    next_run: Mutex<BTreeMap<Task, Instant>>,
+    storage: M,
+    state: Arc<IndexerState>,
+    clock: C,
+    pub metrics: MaintenanceMetrics,
+}
+
+impl<M: Maintain, C: Clock> Maintenance<M, C> {
+    pub fn new(
+        config: MaintenanceConfig,
+        storage: M,
+        state: Arc<IndexerState>,
+        clock: C,
+    ) -> anyhow::Result<Self> {
+        let interval = config.interval.0;
+        if interval.is_zero() {
+            bail!("maintenance.interval should be positive");
+        }
+        let mut intervals = BTreeMap::new();
+        if config.prune_keep_blocks.is_some() {
+            intervals.insert(Task::Prune, interval);
+        }
+        intervals.insert(Task::Stats, interval);
+        if let Some(gc) = config.dict_gc_interval {
+            if gc.0.is_zero() {
+                bail!("maintenance.dict_gc_interval should be positive");
+            }
+            intervals.insert(Task::DictionaryGc, gc.0);
+        }
+        // first runs happen one interval after the start, not during startup catch up
+        let now = clock.now();
+        let next_run = intervals.iter().map(|(t, d)| (*t, now + *d)).collect();
+        Ok(Self {
+            config,
+            intervals,
+            next_run: Mutex::new(next_run),
+            storage,
+            state,
+            clock,
+            metrics: MaintenanceMetrics::default(),
+        })
+    }
+
+    fn due(&self, now: Instant) -> Vec<Task> {
+        let next_run = self.next_run.lock().unwrap();
+        next_run
+            .iter()
+            .filter(|(_, at)| **at <= now)
+            .map(|(t, _)| *t)
+            .collect()
+    }
+
+    /// runs tasks that are due, unless the indexer is behind or repairing a reorg
+    pub async fn tick(&self) -> Tick {
+        let now = self.clock.now();
+        let due = self.due(now);
+        if due.is_empty() {
+            return Tick::Idle;
+        }
+        let lag = self.state.lag();
+        if lag > self.config.max_lag {
+            self.metrics.paused.fetch_add(1, Ordering::Relaxed);
+            debug!(lag, "maintenance paused, indexer is catching up");
+            return Tick::Paused(lag);
+        }
+        // repair waits for the guard, so tasks are never interleaved with it
+        let _guard = match self.state.repair.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                self.metrics.deferred.fetch_add(1, Ordering::Relaxed);
+                debug!("maintenance deferred, reorg repair is running");
+                return Tick::RepairRunning;
+            }
+        };
+        for task in &due {
+            let report = self.run_task(*task).await;
+            info!(
+                task = ?report.task,
+                ok = report.ok,
+                elapsed_ms = report.elapsed_ms,
+                detail = report.detail.as_str(),
+                "maintenance"
+            );
+            self.metrics.runs.fetch_add(1, Ordering::Relaxed);
+            if !report.ok {
+                self.metrics.failures.fetch_add(1, Ordering::Relaxed);
+            }
+            let mut next_run = self.next_run.lock().unwrap();
+            next_run.insert(*task, now + self.intervals[task]);
+        }
+        Tick::Ran(due)
+    }
+
+    async fn run_task(&self, task: Task) -> TaskReport {
+        let started = Instant::now();
+        let indexed = self.state.indexed.load(Ordering::Relaxed);
+        let keep = self.config.prune_keep_blocks.unwrap_or(u32::MAX);
+        let result = match task {
+            Task::Prune => {
+                let below = indexed.saturating_sub(keep);
+                self.storage
+                    .prune_below(below)
+                    .await
+                    .map(|_| format!("below {}", below))
+            }
+            Task::DictionaryGc => self
+                .storage
+                .dictionary_gc(indexed.saturating_sub(keep), indexed)
+                .await
+                .map(|r| {
+                    format!(
+                        "{} unreferenced, {} rare",
+                        r.unreferenced.len(),
+                        r.rare.len()
+                    )
+                }),
+            Task::Stats => self.storage.refresh_stats().await.map(|_| String::new()),
+        };
+        TaskReport {
+            task,
+            ok: result.is_ok(),
+            elapsed_ms: started.elapsed().as_millis() as u64,
+            detail: result.unwrap_or_else(|e| format!("{:#}", e)),
+        }
+    }
+
+    /// checks the schedule every `poll` until the process exits
+    pub async fn run(&self, poll: Duration) {
+        loop {
+            self.tick().await;
+            tokio::time::sleep(poll).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct FakeClock(Arc<Mutex<Instant>>);
+
+    impl FakeClock {
+        fn advance(&self, d: Duration) {
+            *self.0.lock().unwrap() += d;
+        }
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeStorage {
+        calls: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Maintain for Arc<FakeStorage> {
+        async fn prune_below(&self, n: u32) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push(format!("prune {}", n));
+            Ok(())
+        }
+        async fn dictionary_gc(&self, from: u32, to: u32) -> anyhow::Result<GcReport> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("gc {}..={}", from, to));
+            Ok(GcReport::default())
+        }
+        async fn refresh_stats(&self) -> anyhow::Result<()> {
+            self.calls.lock().unwrap().push("stats".to_string());
+            Ok(())
+        }
+    }
+
+    fn setup() -> (
+        Maintenance<Arc<FakeStorage>, FakeClock>,
+        Arc<FakeStorage>,
+        Arc<IndexerState>,
+        FakeClock,
+    ) {
+        let config = MaintenanceConfig {
+            prune_keep_blocks: Some(1000),
+            interval: "6h".parse().unwrap(),
+            dict_gc_interval: Some("7d".parse().unwrap()),
+            max_lag: 10,
+        };
+        let storage = Arc::new(FakeStorage::default());
+        let state = Arc::new(IndexerState::default());
+        state.indexed.store(5000, Ordering::Relaxed);
+        state.chain_head.store(5002, Ordering::Relaxed);
+        let clock = FakeClock(Arc::new(Mutex::new(Instant::now())));
+        let m = Maintenance::new(config, storage.clone(), state.clone(), clock.clone()).unwrap();
+        (m, storage, state, clock)
+    }
+
+    #[test]
+    fn it_reads_humane_intervals() {
+        let config: MaintenanceConfig = serde_json::from_str(
+            r#"{"prune_keep_blocks": 1000, "interval": "2h30m", "dict_gc_interval": "7d"}"#,
+        )
+        .unwrap();
+        assert_eq!(config.interval.0, Duration::from_secs(9000));
+        assert_eq!(config.max_lag, 100);
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            (&json["interval"], &json["dict_gc_interval"]),
+            (&"2h30m".into(), &"7d".into())
+        );
+        assert!(serde_json::from_str::<MaintenanceConfig>(r#"{"interval": "6"}"#).is_err());
+
+        let zero = MaintenanceConfig {
+            interval: "0m".parse().unwrap(),
+            ..Default::default()
+        };
+        let clock = FakeClock(Arc::new(Mutex::new(Instant::now())));
+        let err = Maintenance::new(
+            zero,
+            Arc::new(FakeStorage::default()),
+            Default::default(),
+            clock,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(err.to_string(), "maintenance.interval should be positive");
+    }
+
+    #[tokio::test]
+    async fn it_runs_tasks_on_schedule() {
+        let (m, storage, _, clock) = setup();
+        assert_eq!(m.tick().await, Tick::Idle);
+        clock.advance(Duration::from_secs(6 * 3600));
+        assert_eq!(m.tick().await, Tick::Ran(vec![Task::Prune, Task::Stats]));
+        assert_eq!(m.tick().await, Tick::Idle);
+        clock.advance(Duration::from_secs(7 * 86400));
+        assert_eq!(
+            m.tick().await,
+            Tick::Ran(vec![Task::Prune, Task::DictionaryGc, Task::Stats])
+        );
+        assert_eq!(
+            *storage.calls.lock().unwrap(),
+            vec![
+                "prune 4000",
+                "stats",
+                "prune 4000",
+                "gc 4000..=5000",
+                "stats"
+            ]
+        );
+        assert_eq!(m.metrics.runs.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn it_pauses_while_indexer_lags() {
+        let (m, storage, state, clock) = setup();
+        clock.advance(Duration::from_secs(6 * 3600));
+        state.chain_head.store(5100, Ordering::Relaxed);
+        assert_eq!(m.tick().await, Tick::Paused(100));
+        assert!(storage.calls.lock().unwrap().is_empty());
+        // tasks stay due and run once the indexer caught up
+        state.indexed.store(5095, Ordering::Relaxed);
+        assert_eq!(m.tick().await, Tick::Ran(vec![Task::Prune, Task::Stats]));
+        assert_eq!(m.metrics.paused.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn it_never_overlaps_with_repair() {
+        let (m, storage, state, clock) = setup();
+        clock.advance(Duration::from_secs(6 * 3600));
+        {
+            let _repair = state.repair.lock().await;
+            assert_eq!(m.tick().await, Tick::RepairRunning);
+            assert!(storage.calls.lock().unwrap().is_empty());
+        }
+        assert_eq!(m.tick().await, Tick::Ran(vec![Task::Prune, Task::Stats]));
+        assert_eq!(m.metrics.deferred.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,304 @@
+use anyhow::{bail, Context};
+use kv::{KvTxn, KV};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// bucket of operation manifests
+pub const MANIFEST_TABLE: &str = "btxs_operations";
+
+/// version of the stored block encoding, raised on changes old blocks don't decode with
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Encoding the stored blocks were written with, kept with manifests and checkpoints
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredFormat {
+    pub version: u32,
+    /// hex fingerprint of the field dictionary
+    pub dictionary: String,
+}
+
+impl StoredFormat {
+    /// format this build writes
+    pub fn current() -> anyhow::Result<Self> {
+        let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+        Ok(Self {
+            version: FORMAT_VERSION,
+            dictionary: hex::encode(fd.fingerprint()),
+        })
+    }
+
+    /// fails when blocks stored in the format can't be continued by this build
+    pub fn ensure_compatible(&self, stored: &StoredFormat) -> anyhow::Result<()> {
+        if stored.version != self.version {
+            bail!(
+                "stored with format version {}, this build writes version {}",
+                stored.version,
+                self.version
+            );
+        }
+        if stored.dictionary != self.dictionary {
+            bail!(
+                "stored with field dictionary {}, this build uses {}",
+                stored.dictionary,
+                self.dictionary
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Record of one import or restore, so that running it again
+/// does not apply the same source twice
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// hex hash of the source
+    pub operation: String,
+    /// command that applied it, e.g. import-json
+    pub kind: String,
+    /// applied ranges of keys, inclusive, sorted and merged
+    pub applied: Vec<(u32, u32)>,
+    /// ranges which notifications were issued for
+    pub notified: Vec<(u32, u32)>,
+    /// set once the whole source is applied
+    pub completed: bool,
+    /// unix time of the last change
+    pub updated_at: u64,
+    /// encoding of the applied blocks, missing in manifests of older builds
+    #[serde(default)]
+    pub format: Option<StoredFormat>,
+}
+
+fn merge(ranges: &mut Vec<(u32, u32)>, first: u32, last: u32) {
+    ranges.push((first, last));
+    ranges.sort();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    *ranges = merged;
+}
+
+impl Manifest {
+    pub fn new(kind: &str, operation: &str) -> Self {
+        Self {
+            operation: operation.to_string(),
+            kind: kind.to_string(),
+            applied: vec![],
+            notified: vec![],
+            completed: false,
+            updated_at: 0,
+            format: None,
+        }
+    }
+
+    /// records the current format, or checks that the applied part was written
+    /// in it before the operation is resumed
+    pub fn resume_format(&mut self) -> anyhow::Result<()> {
+        let current = StoredFormat::current()?;
+        match &self.format {
+            Some(stored) => current
+                .ensure_compatible(stored)
+                .with_context(|| format!("can't resume operation {}", self.operation)),
+            None => {
+                self.format = Some(current);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_applied(&self, n: u32) -> bool {
+        self.applied
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&n))
+    }
+
+    pub fn apply(&mut self, first: u32, last: u32) {
+        merge(&mut self.applied, first, last);
+    }
+
+    pub fn notify(&mut self, first: u32, last: u32) {
+        merge(&mut self.notified, first, last);
+    }
+
+    /// applied ranges without notifications, left by a crash in between
+    pub fn pending_notifications(&self) -> Vec<(u32, u32)> {
+        let mut out = vec![];
+        for (first, last) in &self.applied {
+            let mut next = Some(*first);
+            for (nf, nl) in &self.notified {
+                let n = match next {
+                    Some(n) => n,
+                    None => break,
+                };
+                if *nl < n || nf > last {
+                    continue;
+                }
+                if *nf > n {
+                    out.push((n, nf - 1));
+                }
+                next = nl.checked_add(1);
+            }
+            if let Some(n) = next.filter(|n| n <= last) {
+                out.push((n, *last));
+            }
+        }
+        out
+    }
+
+    // stamps the change time
+    fn touch(&mut self) {
+        self.updated_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+    }
+}
+
+/// Hashes the source piece by piece into an operation id
+#[derive(Default)]
+pub struct OperationId(Sha256);
+
+impl OperationId {
+    pub fn update(&mut self, key: u32, content: &[u8]) {
+        self.0.update(key.to_le_bytes());
+        self.0.update(Sha256::digest(content));
+    }
+
+    pub fn finish(self) -> String {
+        hex::encode(self.0.finalize())
+    }
+}
+
+/// Bucket of manifests, keyed by the first bytes of the operation id.
+/// Every key keeps all manifests that share it
+pub struct ManifestBucket<K: KV> {
+    bucket: K,
+    name: String,
+}
+
+impl<K: KV> ManifestBucket<K> {
+    /// `name` addresses the bucket in transactions of its storage
+    pub fn new(bucket: K, name: &str) -> Self {
+        Self {
+            bucket,
+            name: name.to_string(),
+        }
+    }
+
+    fn key(operation: &str) -> anyhow::Result<u32> {
+        let bytes = hex::decode(operation.get(..8).context("operation id")?)?;
+        Ok(u32::from_be_bytes(bytes.as_slice().try_into()?))
+    }
+
+    async fn entries(&self, key: u32) -> anyhow::Result<Vec<Manifest>> {
+        match self.bucket.get(key).await? {
+            Some(bytes) if !bytes.is_empty() => serde_json::from_slice(&bytes).context("manifest"),
+            _ => Ok(vec![]),
+        }
+    }
+
+    pub async fn get(&self, operation: &str) -> anyhow::Result<Option<Manifest>> {
+        let entries = self.entries(Self::key(operation)?).await?;
+        Ok(entries.into_iter().find(|m| m.operation == operation))
+    }
+
+    async fn replaced(&self, manifest: &mut Manifest) -> anyhow::Result<(u32, Vec<u8>)> {
+        manifest.touch();
+        let key = Self::key(&manifest.operation)?;
+        let mut entries = self.entries(key).await?;
+        entries.retain(|m| m.operation != manifest.operation);
+        entries.push(manifest.clone());
+        Ok((key, serde_json::to_vec(&entries)?))
+    }
+
+    pub async fn save(&self, manifest: &mut Manifest) -> anyhow::Result<()> {
+        let (key, value) = self.replaced(manifest).await?;
+        self.bucket.set(key, value).await
+    }
+
+    /// saves the manifest as part of the transaction, with the data it describes
+    pub async fn save_in(
+        &self,
+        txn: &mut Box<dyn KvTxn + '_>,
+        manifest: &mut Manifest,
+    ) -> anyhow::Result<()> {
+        let (key, value) = self.replaced(manifest).await?;
+        txn.set(&self.name, key, value).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::MemoryKV;
+
+    #[test]
+    fn it_merges_ranges() {
+        let mut m = Manifest::new("import-json", "00");
+        m.apply(10, 12);
+        m.apply(1, 3);
+        m.apply(4, 6);
+        m.apply(20, 20);
+        m.apply(11, 15);
+        assert_eq!(m.applied, vec![(1, 6), (10, 15), (20, 20)]);
+        assert!(m.is_applied(5) && m.is_applied(20) && !m.is_applied(8));
+        m.notify(1, 2);
+        m.notify(10, 15);
+        assert_eq!(m.pending_notifications(), vec![(3, 6), (20, 20)]);
+        m.apply(u32::MAX - 1, u32::MAX);
+        m.apply(u32::MAX, u32::MAX);
+        assert_eq!(m.applied.last(), Some(&(u32::MAX - 1, u32::MAX)));
+    }
+
+    #[tokio::test]
+    async fn it_keeps_manifests_sharing_a_key() {
+        let bucket = ManifestBucket::new(MemoryKV::new(), "operations");
+        let mut a = Manifest::new("import-json", "abcdef0011");
+        let mut b = Manifest::new("import-json", "abcdef0022");
+        a.apply(1, 2);
+        bucket.save(&mut a).await.unwrap();
+        bucket.save(&mut b).await.unwrap();
+        a.completed = true;
+        bucket.save(&mut a).await.unwrap();
+        assert_eq!(bucket.get("abcdef0011").await.unwrap(), Some(a));
+        assert_eq!(bucket.get("abcdef0022").await.unwrap(), Some(b));
+        assert_eq!(bucket.get("abcdef0033").await.unwrap(), None);
+
+        let mut id = OperationId::default();
+        id.update(1, b"block");
+        assert_eq!(id.finish().len(), 64);
+    }
+
+    #[test]
+    fn it_refuses_to_resume_other_formats() {
+        let mut m = Manifest::new("import-json", "abcdef0011");
+        m.resume_format().unwrap();
+        let current = StoredFormat::current().unwrap();
+        assert_eq!(m.format.as_ref(), Some(&current));
+        m.resume_format().unwrap();
+
+        let mut old = m.clone();
+        old.format.as_mut().unwrap().version = FORMAT_VERSION + 1;
+        let err = old.resume_format().unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("format version 2"),
+            "{:#}",
+            err
+        );
+
+        let mut other = m.clone();
+        other.format.as_mut().unwrap().dictionary = "00".repeat(16);
+        let err = other.resume_format().unwrap_err();
+        assert!(format!("{:#}", err).contains("can't resume operation abcdef0011"));
+        assert!(format!("{:#}", err).contains("field dictionary 0000"));
+
+        // manifests of older builds are taken as written in the current format
+        let legacy: Manifest = serde_json::from_str(
+            r#"{"operation":"ab","kind":"import-json","applied":[],"notified":[],"completed":false,"updated_at":0}"#,
+        )
+        .unwrap();
+        assert_eq!(legacy.format, None);
+    }
+}
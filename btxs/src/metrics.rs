@@ -0,0 +1,74 @@
+use std::fmt::Write;
+
+/// content type of the rendered metrics
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Metrics in the Prometheus text exposition format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    out: String,
+}
+
+// backslash, quote and newline are escaped in label values
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// starts the family, `kind` is gauge or counter
+    pub fn family(&mut self, name: &str, kind: &str, help: &str) -> &mut Self {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, kind);
+        self
+    }
+
+    /// adds the sample to the family started last
+    pub fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) -> &mut Self {
+        self.out.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {}", value);
+        self
+    }
+
+    pub fn render(self) -> String {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_renders_families() {
+        let mut m = Metrics::new();
+        m.family("btxs_endpoint_score", "gauge", "health score")
+            .sample("btxs_endpoint_score", &[("endpoint", "http://a")], 0.5)
+            .sample("btxs_endpoint_score", &[("endpoint", "say \"hi\"")], 1.0);
+        m.family("btxs_events_last_seq", "counter", "last event")
+            .sample("btxs_events_last_seq", &[], 7.0);
+        assert_eq!(
+            m.render(),
+            "# HELP btxs_endpoint_score health score\n\
+             # TYPE btxs_endpoint_score gauge\n\
+             btxs_endpoint_score{endpoint=\"http://a\"} 0.5\n\
+             btxs_endpoint_score{endpoint=\"say \\\"hi\\\"\"} 1\n\
+             # HELP btxs_events_last_seq last event\n\
+             # TYPE btxs_events_last_seq counter\n\
+             btxs_events_last_seq 7\n"
+        );
+    }
+}
@@ -0,0 +1,355 @@
+use anyhow::Context;
+use async_trait::async_trait;
+use eth_logs::{LogId, Transport};
+use ethers::types::Log;
+use kv::KV;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tracing::*;
+
+/// bucket of issued events
+pub const EVENTS_TABLE: &str = "btxs_events";
+
+/// kind of the events of stored logs
+pub const LOG_EVENT: &str = "log";
+
+// key of the last issued sequence number, events are stored under their numbers
+const LAST_SEQ_KEY: u32 = 0;
+
+/// Notification with its position in the stream, consumers detect gaps
+/// when `prev_seq` is not the `seq` they have seen last
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Event {
+    pub seq: u64,
+    pub prev_seq: Option<u64>,
+    pub kind: String,
+    pub payload: Value,
+}
+
+/// Issues sequence numbers and keeps events for replay, both in the KV bucket
+pub struct EventLog<K: KV> {
+    bucket: K,
+    // last issued number, serializes stamping
+    last: Mutex<Option<u64>>,
+}
+
+impl<K: KV> EventLog<K> {
+    pub fn new(bucket: K) -> Self {
+        Self {
+            bucket,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// sequence number of the last issued event, 0 before the first one
+    pub async fn load_last(&self) -> anyhow::Result<u64> {
+        match self.bucket.get(LAST_SEQ_KEY).await? {
+            Some(bytes) => {
+                let b: [u8; 8] = bytes.as_slice().try_into().context("last seq")?;
+                Ok(u64::from_le_bytes(b))
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// assigns the next number to the event and persists it before it is delivered
+    pub async fn stamp(&self, kind: &str, payload: Value) -> anyhow::Result<Event> {
+        let mut last = self.last.lock().await;
+        let prev = match *last {
+            Some(seq) => seq,
+            None => self.load_last().await?,
+        };
+        let seq = prev + 1;
+        let event = Event {
+            seq,
+            prev_seq: if prev == 0 { None } else { Some(prev) },
+            kind: kind.to_string(),
+            payload,
+        };
+        let key = u32::try_from(seq).context("sequence overflow")?;
+        self.bucket.set(key, serde_json::to_vec(&event)?).await?;
+        self.bucket
+            .set(LAST_SEQ_KEY, seq.to_le_bytes().to_vec())
+            .await?;
+        *last = Some(seq);
+        Ok(event)
+    }
+
+    /// events after `since_seq` for gap recovery, at most `limit` of them
+    pub async fn since(&self, since_seq: u64, limit: usize) -> anyhow::Result<Vec<Event>> {
+        let last = self.load_last().await?;
+        let mut out = vec![];
+        let mut seq = since_seq + 1;
+        while seq <= last && out.len() < limit {
+            if let Some(bytes) = self.bucket.get(seq as u32).await? {
+                out.push(serde_json::from_slice(&bytes).context("event")?);
+            }
+            seq += 1;
+        }
+        Ok(out)
+    }
+}
+
+/// Sends one event to one destination, e.g. webhook POST or SSE write
+#[async_trait]
+pub trait Delivery: Send + Sync {
+    async fn deliver(&self, destination: &str, event: &Event) -> anyhow::Result<()>;
+}
+
+/// Delivery to webhooks: the event is POSTed as JSON to the destination URL
+pub struct Webhook {
+    transport: Arc<dyn Transport>,
+}
+
+impl Webhook {
+    pub fn new(transport: impl Transport + 'static) -> Self {
+        Self {
+            transport: Arc::new(transport),
+        }
+    }
+}
+
+#[async_trait]
+impl Delivery for Webhook {
+    async fn deliver(&self, destination: &str, event: &Event) -> anyhow::Result<()> {
+        let body = serde_json::to_string(event)?;
+        let transport = self.transport.clone();
+        let url = destination.to_string();
+        // transport is blocking
+        tokio::task::spawn_blocking(move || {
+            let headers = [("Content-Type", "application/json".to_string())];
+            transport.post(&url, &headers, &body)
+        })
+        .await??;
+        Ok(())
+    }
+}
+
+/// payload of the log event and the log export row,
+/// `id` lets consumers drop logs they have seen already
+pub fn log_payload(id: LogId, log: &Log) -> Value {
+    json!({
+        "id": id,
+        "block_number": log.block_number,
+        "transaction_hash": log.transaction_hash,
+        "address": log.address,
+        "topics": log.topics,
+        "data": log.data,
+    })
+}
+
+/// stamps an event for every log and publishes it, returns the number of events
+pub async fn publish_logs<K: KV, D: Delivery>(
+    events: &EventLog<K>,
+    notifier: Option<&Notifier<D>>,
+    logs: &BTreeMap<LogId, Log>,
+) -> anyhow::Result<usize> {
+    for (id, log) in logs {
+        let event = events.stamp(LOG_EVENT, log_payload(*id, log)).await?;
+        if let Some(notifier) = notifier {
+            notifier.publish(event).await;
+        }
+    }
+    Ok(logs.len())
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    pending: VecDeque<Event>,
+    dead_letter: Vec<Event>,
+}
+
+/// Delivers events to every destination at least once and in sequence order.
+/// Events after a failed one wait in the buffer; when the buffer is over
+/// `max_buffered`, the whole gap goes to the dead letter list and can be
+/// recovered from the event log
+pub struct Notifier<D: Delivery> {
+    delivery: D,
+    max_buffered: usize,
+    queues: Mutex<BTreeMap<String, Queue>>,
+}
+
+impl<D: Delivery> Notifier<D> {
+    pub fn new(delivery: D, destinations: &[String], max_buffered: usize) -> Self {
+        let queues = destinations
+            .iter()
+            .map(|d| (d.clone(), Queue::default()))
+            .collect();
+        Self {
+            delivery,
+            max_buffered: max_buffered.max(1),
+            queues: Mutex::new(queues),
+        }
+    }
+
+    /// queues the event for every destination and delivers what can be delivered
+    pub async fn publish(&self, event: Event) {
+        {
+            let mut queues = self.queues.lock().await;
+            for queue in queues.values_mut() {
+                queue.pending.push_back(event.clone());
+            }
+        }
+        self.flush().await;
+    }
+
+    /// delivers pending events in order, stopping at the first failure of a destination
+    pub async fn flush(&self) {
+        let mut queues = self.queues.lock().await;
+        for (destination, queue) in queues.iter_mut() {
+            while let Some(event) = queue.pending.front() {
+                match self.delivery.deliver(destination, event).await {
+                    Ok(()) => {
+                        queue.pending.pop_front();
+                    }
+                    Err(e) => {
+                        warn!(
+                            destination = destination.as_str(),
+                            seq = event.seq,
+                            "delivery failed: {:#}",
+                            e
+                        );
+                        break;
+                    }
+                }
+            }
+            if queue.pending.len() > self.max_buffered {
+                let gap: Vec<Event> = queue.pending.drain(..).collect();
+                error!(
+                    destination = destination.as_str(),
+                    from = gap[0].seq,
+                    to = gap[gap.len() - 1].seq,
+                    "dead letter"
+                );
+                queue.dead_letter.extend(gap);
+            }
+        }
+    }
+
+    /// number of events waiting for delivery to any destination
+    pub async fn pending(&self) -> usize {
+        let queues = self.queues.lock().await;
+        queues.values().map(|q| q.pending.len()).sum()
+    }
+
+    /// sequence numbers of the dead letter events of the destination
+    pub async fn dead_letter(&self, destination: &str) -> Vec<u64> {
+        let queues = self.queues.lock().await;
+        queues
+            .get(destination)
+            .map(|q| q.dead_letter.iter().map(|e| e.seq).collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::MemoryKV;
+    use serde_json::json;
+    use std::sync::Mutex as SyncMutex;
+
+    // fails listed deliveries once per entry, and some sequence numbers always
+    #[derive(Default)]
+    struct FlakyDelivery {
+        fail_once: SyncMutex<Vec<(String, u64)>>,
+        fail_always: Vec<u64>,
+        delivered: SyncMutex<Vec<(String, u64)>>,
+    }
+
+    #[async_trait]
+    impl Delivery for FlakyDelivery {
+        async fn deliver(&self, destination: &str, event: &Event) -> anyhow::Result<()> {
+            let mut fail_once = self.fail_once.lock().unwrap();
+            if let Some(pos) = fail_once
+                .iter()
+                .position(|(d, s)| d == destination && *s == event.seq)
+            {
+                fail_once.remove(pos);
+                anyhow::bail!("timeout");
+            }
+            if self.fail_always.contains(&event.seq) {
+                anyhow::bail!("rejected");
+            }
+            self.delivered
+                .lock()
+                .unwrap()
+                .push((destination.to_string(), event.seq));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_stamps_and_replays_events() {
+        let log = EventLog::new(MemoryKV::new());
+        let a = log.stamp("block", json!({"number": 1})).await.unwrap();
+        let b = log.stamp("block", json!({"number": 2})).await.unwrap();
+        assert_eq!((a.seq, a.prev_seq), (1, None));
+        assert_eq!((b.seq, b.prev_seq), (2, Some(1)));
+        let replay = log.since(1, 10).await.unwrap();
+        assert_eq!(replay, vec![b]);
+    }
+
+    #[tokio::test]
+    async fn it_keeps_order_while_retrying() {
+        let delivery = FlakyDelivery {
+            fail_once: SyncMutex::new(vec![("hook-a".to_string(), 2), ("hook-a".to_string(), 2)]),
+            ..Default::default()
+        };
+        let destinations = vec!["hook-a".to_string(), "hook-b".to_string()];
+        let notifier = Notifier::new(delivery, &destinations, 10);
+        let log = EventLog::new(MemoryKV::new());
+        for n in 0..3 {
+            notifier
+                .publish(log.stamp("block", json!(n)).await.unwrap())
+                .await;
+        }
+        // 2 failed twice for hook-a, so 3 waits behind it
+        let delivered = notifier.delivery.delivered.lock().unwrap().clone();
+        let to_a: Vec<u64> = delivered
+            .iter()
+            .filter(|(d, _)| d == "hook-a")
+            .map(|(_, s)| *s)
+            .collect();
+        assert_eq!(to_a, vec![1]);
+
+        notifier.flush().await;
+        let delivered = notifier.delivery.delivered.lock().unwrap().clone();
+        for d in &destinations {
+            let seqs: Vec<u64> = delivered
+                .iter()
+                .filter(|(dd, _)| dd == d)
+                .map(|(_, s)| *s)
+                .collect();
+            assert_eq!(seqs, vec![1, 2, 3]);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_dead_letters_the_gap() {
+        let delivery = FlakyDelivery {
+            fail_always: vec![1],
+            ..Default::default()
+        };
+        let notifier = Notifier::new(delivery, &["hook".to_string()], 2);
+        let log = EventLog::new(MemoryKV::new());
+        for n in 0..3 {
+            notifier
+                .publish(log.stamp("block", json!(n)).await.unwrap())
+                .await;
+        }
+        assert_eq!(notifier.dead_letter("hook").await, vec![1, 2, 3]);
+        assert!(notifier.delivery.delivered.lock().unwrap().is_empty());
+        // later events are delivered again
+        notifier
+            .publish(log.stamp("block", json!(4)).await.unwrap())
+            .await;
+        assert_eq!(
+            *notifier.delivery.delivered.lock().unwrap(),
+            vec![("hook".to_string(), 4)]
+        );
+    }
+}
@@ -0,0 +1,789 @@
+use crate::config::Config;
+use crate::gas_report::{self, Bucket};
+use crate::humane::{ByteSize, HumaneDuration};
+use crate::maintenance::{IndexerState, Maintenance, MaintenanceMetrics, SystemClock};
+use crate::metrics::{self, Metrics};
+use crate::notify::{self, EventLog, Notifier, Webhook};
+use crate::writer::{Batch, OversizePolicy, Oversized, Writer, WriterConfig};
+use anyhow::Context;
+use axum::http::header;
+use axum::routing::get;
+use axum::Router;
+use clap::{Args, Subcommand, ValueEnum};
+use eth_logs::{EthBatchClient, EthLogsStream, LogId, QuarantineBucket, UreqTransport};
+use ethers::types::{Address, Log};
+use jsondp::dictionary::NoDictionary;
+use kv::KV;
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tracing::*;
+
+/// table of the follow checkpoint
+pub const CHECKPOINT_TABLE: &str = "btxs_checkpoint";
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum ProcessCommand {
+    /// gas utilization and base fee trend of stored blocks
+    GasReport(GasReportArgs),
+    /// store blocks with logs of the contracts as they appear on chain
+    Follow(FollowArgs),
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GasReportArgs {
+    /// first block
+    #[arg(long)]
+    pub from: u32,
+    /// last block, inclusive
+    #[arg(long)]
+    pub to: u32,
+    /// hour, day or a number of blocks, e.g. 10k
+    #[arg(long, default_value = "hour")]
+    pub bucket: Bucket,
+    #[arg(long, value_enum, default_value = "json")]
+    pub format: Format,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct FollowArgs {
+    /// JSON-RPC endpoint
+    #[arg(long, env = "RPC_ETH_ADDR")]
+    pub rpc_addr: String,
+    /// contract which logs are followed, repeated for several contracts
+    #[arg(long = "address", required = true)]
+    pub addresses: Vec<Address>,
+    /// first block when there is no checkpoint yet
+    #[arg(long, default_value_t = 1)]
+    pub from: u64,
+    /// blocks per eth_getLogs request
+    #[arg(long, default_value_t = 1000)]
+    pub batch_size: u64,
+    /// pause between polls for new blocks, like "12s"
+    #[arg(long, default_value = "12s")]
+    pub poll_interval: HumaneDuration,
+    /// largest stored block, like "16MiB", no limit when missing
+    #[arg(long)]
+    pub max_value_bytes: Option<ByteSize>,
+    /// what to do with blocks over the limit
+    #[arg(long, value_enum, default_value = "error")]
+    pub oversize: OversizePolicy,
+    /// stop once the latest block is reached
+    #[arg(long)]
+    pub once: bool,
+    /// store every contract in its own bucket with its own checkpoint,
+    /// blocks are still fetched once for all of them
+    #[arg(long)]
+    pub per_contract: bool,
+    /// URL every stored log is POSTed to, repeated for several webhooks
+    #[arg(long = "webhook")]
+    pub webhooks: Vec<String>,
+    /// TOML configuration, maintenance runs by its `[maintenance]` section
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// address to serve `/metrics` of the indexer on, not served when missing
+    #[arg(long)]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+/// Bucket of followed blocks with its own checkpoint
+pub struct Sink<K: KV> {
+    /// contract which view of the blocks is stored, whole blocks when None
+    pub address: Option<Address>,
+    /// name of the blocks bucket in the writer
+    pub bucket: String,
+    pub writer: Writer<K>,
+}
+
+/// names of the blocks and checkpoint tables of the contract
+pub fn contract_tables(table_name: &str, address: &Address) -> (String, String) {
+    let suffix = hex::encode(address.as_bytes());
+    (
+        format!("{}_{}", table_name, suffix),
+        format!("{}_{}", CHECKPOINT_TABLE, suffix),
+    )
+}
+
+/// Blocks stored by one step of the follow loop
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Step {
+    /// fetched blocks, each of them once for all sinks
+    pub blocks: usize,
+    pub checkpoint: u32,
+    /// blocks left out by the oversize policy, their logs are quarantined
+    pub skipped: Vec<Oversized>,
+    /// entries added to the quarantine
+    pub quarantined: usize,
+    /// logs of the followed contracts in the stored blocks, each of them once
+    pub logs: BTreeMap<LogId, Log>,
+}
+
+/// fetches the range after the checkpoint and stores the blocks of every sink
+/// together with its checkpoint; sinks with a checkpoint ahead of the range are
+/// left alone. Logs of blocks the provider would not serve and of blocks the
+/// writer left out go to the quarantine. None once the stream reached the latest block
+pub async fn follow_step<K: KV + Send + Sync, Q: KV>(
+    stream: &EthLogsStream,
+    sinks: &[Sink<K>],
+    quarantine: &QuarantineBucket<Q>,
+) -> anyhow::Result<Option<Step>> {
+    let blocks = match stream.next()? {
+        Some(blocks) => blocks,
+        None => return Ok(None),
+    };
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let checkpoint = u32::try_from(stream.checkpoint()).context("checkpoint")?;
+    let addresses: Vec<Address> = sinks.iter().filter_map(|s| s.address).collect();
+    let mut views = eth_logs::demultiplex(&blocks, &addresses);
+    let mut entries = stream.take_quarantined();
+    let mut skipped = vec![];
+    let mut logs = BTreeMap::new();
+    for sink in sinks {
+        let stored = sink.writer.checkpoint().await?;
+        if stored.is_some_and(|n| n >= checkpoint) {
+            continue;
+        }
+        let views = match sink.address {
+            Some(address) => views.remove(&address).unwrap_or_default(),
+            None => blocks.clone(),
+        };
+        let mut batch = Batch::new(checkpoint);
+        for block in &views {
+            let number = block.block.number.context("no block number")?.as_u32();
+            if stored.is_some_and(|n| number <= n) {
+                continue;
+            }
+            let mut blob = vec![];
+            jsondp::encode(&block.to_value()?, &mut blob, &fd, &NoDictionary {})?;
+            batch.set(&sink.bucket, number, blob);
+            let followed = |log: &&Log| match sink.address {
+                Some(address) => log.address == address,
+                None => stream.addresses().is_empty() || stream.addresses().contains(&log.address),
+            };
+            for log in block
+                .receipts
+                .values()
+                .flat_map(|r| r.logs.iter())
+                .filter(followed)
+            {
+                if let Some(id) = LogId::of(log) {
+                    logs.insert(id, log.clone());
+                }
+            }
+        }
+        for oversized in sink.writer.write(batch).await? {
+            let block = views
+                .iter()
+                .find(|b| b.block.number == Some(oversized.key.into()))
+                .context("skipped block is not in the batch")?;
+            entries.push(crate::quarantine::oversized(block, oversized.reason()));
+            skipped.push(oversized);
+        }
+    }
+    let quarantined = entries.len();
+    for entry in entries {
+        quarantine.add(entry).await?;
+    }
+    Ok(Some(Step {
+        blocks: blocks.len(),
+        checkpoint,
+        skipped,
+        quarantined,
+        logs,
+    }))
+}
+
+// name, help and value of the counters of every maintenance task
+type MaintenanceCounter = (&'static str, &'static str, fn(&MaintenanceMetrics) -> u64);
+
+const MAINTENANCE_COUNTERS: [MaintenanceCounter; 4] = [
+    (
+        "btxs_maintenance_runs_total",
+        "maintenance tasks run",
+        |c| c.runs.load(Ordering::Relaxed),
+    ),
+    (
+        "btxs_maintenance_failures_total",
+        "maintenance tasks failed",
+        |c| c.failures.load(Ordering::Relaxed),
+    ),
+    (
+        "btxs_maintenance_paused_total",
+        "maintenance ticks skipped while the indexer was behind",
+        |c| c.paused.load(Ordering::Relaxed),
+    ),
+    (
+        "btxs_maintenance_deferred_total",
+        "maintenance ticks skipped during reorg repair",
+        |c| c.deferred.load(Ordering::Relaxed),
+    ),
+];
+
+/// progress, oversized values and maintenance runs of the follow loop
+/// in the Prometheus text format
+pub fn follow_metrics<K: KV>(
+    state: &IndexerState,
+    sinks: &[Sink<K>],
+    maintenance: &[(&str, &MaintenanceMetrics)],
+) -> String {
+    let mut m = Metrics::new();
+    m.family(
+        "btxs_indexed_block",
+        "gauge",
+        "last block stored by the indexer",
+    )
+    .sample(
+        "btxs_indexed_block",
+        &[],
+        state.indexed.load(Ordering::Relaxed) as f64,
+    );
+    m.family(
+        "btxs_chain_head_block",
+        "gauge",
+        "latest block of the chain",
+    )
+    .sample(
+        "btxs_chain_head_block",
+        &[],
+        state.chain_head.load(Ordering::Relaxed) as f64,
+    );
+    let name = "btxs_oversized_total";
+    m.family(name, "counter", "values over the size limit by the policy");
+    for sink in sinks {
+        let oversized = &sink.writer.metrics;
+        for (policy, count) in [("error", &oversized.errors), ("skip", &oversized.skipped)] {
+            let labels = [("bucket", sink.bucket.as_str()), ("policy", policy)];
+            m.sample(name, &labels, count.load(Ordering::Relaxed) as f64);
+        }
+    }
+    for (name, help, value) in MAINTENANCE_COUNTERS {
+        m.family(name, "counter", help);
+        for (bucket, counts) in maintenance {
+            m.sample(name, &[("bucket", bucket)], value(counts) as f64);
+        }
+    }
+    m.render()
+}
+
+/// stream over the client which starts after the earliest checkpoint of the sinks
+pub async fn resume<K: KV + Send + Sync>(
+    args: &FollowArgs,
+    client: EthBatchClient,
+    sinks: &[Sink<K>],
+) -> anyhow::Result<EthLogsStream> {
+    let mut from = u64::MAX;
+    for sink in sinks {
+        sink.writer.ensure_resumable().await?;
+        let next = match sink.writer.checkpoint().await? {
+            Some(checkpoint) => checkpoint as u64 + 1,
+            None => args.from,
+        };
+        from = from.min(next);
+    }
+    info!(from, "following");
+    EthLogsStream::new(
+        client,
+        from,
+        args.batch_size,
+        args.addresses.clone(),
+        None,
+        None,
+        None,
+        None,
+    )
+}
+
+/// gas report of the stored range, missing blocks are skipped
+pub async fn gas_report<K: KV>(
+    args: &GasReportArgs,
+    storage: &K,
+) -> anyhow::Result<Vec<gas_report::GasBucket>> {
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let mut blocks = vec![];
+    for number in args.from..=args.to {
+        match storage.get(number).await? {
+            Some(blob) if !blob.is_empty() => {
+                blocks.push(gas_report::extract(&blob, &fd, &NoDictionary {})?)
+            }
+            _ => debug!(number, "block not stored"),
+        }
+    }
+    Ok(gas_report::report(&blocks, args.bucket))
+}
+
+async fn sink(
+    args: &FollowArgs,
+    database_url: &str,
+    address: Option<Address>,
+    (table, checkpoint_table): (String, String),
+) -> anyhow::Result<Sink<kv::PostgresKV>> {
+    let mut buckets = BTreeMap::new();
+    for name in [&table, &checkpoint_table] {
+        let kv = kv::PostgresKV::try_new(database_url, name).await?;
+        buckets.insert(name.to_string(), kv);
+    }
+    let writer = Writer::new(buckets, &checkpoint_table)?.with_config(WriterConfig {
+        max_value_bytes: args.max_value_bytes,
+        oversize: args.oversize,
+    })?;
+    Ok(Sink {
+        address,
+        bucket: table,
+        writer,
+    })
+}
+
+async fn follow(args: &FollowArgs, database_url: &str, table_name: &str) -> anyhow::Result<()> {
+    let mut sinks = vec![];
+    if args.per_contract {
+        for address in &args.addresses {
+            let tables = contract_tables(table_name, address);
+            sinks.push(sink(args, database_url, Some(*address), tables).await?);
+        }
+    } else {
+        let tables = (table_name.to_string(), CHECKPOINT_TABLE.to_string());
+        sinks.push(sink(args, database_url, None, tables).await?);
+    }
+    let quarantine = QuarantineBucket::new(
+        kv::PostgresKV::try_new(database_url, crate::quarantine::QUARANTINE_TABLE).await?,
+    );
+    let events = EventLog::new(kv::PostgresKV::try_new(database_url, notify::EVENTS_TABLE).await?);
+    let notifier = match args.webhooks.is_empty() {
+        true => None,
+        false => Some(Notifier::new(
+            Webhook::new(UreqTransport::default()),
+            &args.webhooks,
+            1000,
+        )),
+    };
+    let stream = resume(args, EthBatchClient::new(&args.rpc_addr), &sinks).await?;
+    let state = Arc::new(IndexerState::default());
+    state
+        .chain_head
+        .store(stream.latest_block() as u32, Ordering::Relaxed);
+    state
+        .indexed
+        .store(stream.checkpoint() as u32, Ordering::Relaxed);
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut scheduled = vec![];
+    if let Some(maintenance) = &config.maintenance {
+        for sink in &sinks {
+            let storage = kv::PostgresKV::try_new(database_url, &sink.bucket).await?;
+            let m = Maintenance::new(maintenance.clone(), storage, state.clone(), SystemClock)?;
+            let m = Arc::new(m);
+            info!(bucket = sink.bucket.as_str(), "maintenance scheduled");
+            let poll = args.poll_interval.0;
+            let task = m.clone();
+            tokio::spawn(async move { task.run(poll).await });
+            scheduled.push((sink.bucket.clone(), m));
+        }
+    }
+    let sinks = Arc::new(sinks);
+    if let Some(listen) = args.metrics_listen {
+        let (state, sinks) = (state.clone(), sinks.clone());
+        let app = Router::new().route(
+            "/metrics",
+            get(move || {
+                let maintenance: Vec<(&str, &MaintenanceMetrics)> = scheduled
+                    .iter()
+                    .map(|(bucket, m)| (bucket.as_str(), &m.metrics))
+                    .collect();
+                let body = follow_metrics(&state, &sinks, &maintenance);
+                async move { ([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], body) }
+            }),
+        );
+        let server = axum::Server::try_bind(&listen)
+            .with_context(|| format!("listen on {}", listen))?
+            .serve(app.into_make_service());
+        info!(listen = %listen, "serving metrics");
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("metrics server: {:#}", e);
+            }
+        });
+    }
+    loop {
+        let step = follow_step(&stream, &sinks, &quarantine).await?;
+        state
+            .chain_head
+            .store(stream.latest_block() as u32, Ordering::Relaxed);
+        match step {
+            Some(step) => {
+                state.indexed.store(step.checkpoint, Ordering::Relaxed);
+                let published =
+                    notify::publish_logs(&events, notifier.as_ref(), &step.logs).await?;
+                let (mut pending, mut dead_letter) = (0, 0);
+                if let Some(notifier) = &notifier {
+                    pending = notifier.pending().await;
+                    for webhook in &args.webhooks {
+                        dead_letter += notifier.dead_letter(webhook).await.len();
+                    }
+                }
+                info!(
+                    blocks = step.blocks,
+                    checkpoint = step.checkpoint,
+                    quarantined = step.quarantined,
+                    published,
+                    pending,
+                    dead_letter,
+                    "stored"
+                );
+            }
+            None if args.once => return Ok(()),
+            None => {
+                // retries deliveries that failed while the chain was being followed
+                if let Some(notifier) = &notifier {
+                    notifier.flush().await;
+                }
+                tokio::time::sleep(args.poll_interval.0).await;
+                stream.poll()?;
+            }
+        }
+    }
+}
+
+pub async fn run(cmd: &ProcessCommand, database_url: &str, table_name: &str) -> anyhow::Result<()> {
+    match cmd {
+        ProcessCommand::GasReport(args) => {
+            let storage = kv::PostgresKV::try_new(database_url, table_name).await?;
+            let buckets = gas_report(args, &storage).await?;
+            match args.format {
+                Format::Json => println!("{}", serde_json::to_string_pretty(&buckets)?),
+                Format::Csv => print!("{}", gas_report::to_csv(&buckets)),
+            }
+        }
+        ProcessCommand::Follow(args) => follow(args, database_url, table_name).await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eth_logs::fixtures::{ChainGenerator, MockChain};
+    use eth_logs::{BlockTransactions, Transport};
+    use kv::MemoryKV;
+    use std::sync::{Arc, Mutex};
+
+    fn args(addresses: Vec<Address>) -> FollowArgs {
+        FollowArgs {
+            rpc_addr: "http://mock".to_string(),
+            addresses,
+            from: 1,
+            batch_size: 4,
+            poll_interval: "1s".parse().unwrap(),
+            max_value_bytes: None,
+            oversize: OversizePolicy::Error,
+            once: true,
+            per_contract: false,
+            webhooks: vec![],
+            config: None,
+            metrics_listen: None,
+        }
+    }
+
+    fn sink(memory: &MemoryKV, address: Option<Address>, name: &str) -> Sink<MemoryKV> {
+        let checkpoint = format!("{}_checkpoint", name);
+        let buckets = [name, &checkpoint]
+            .into_iter()
+            .map(|name| (name.to_string(), memory.bucket(name)))
+            .collect();
+        Sink {
+            address,
+            bucket: name.to_string(),
+            writer: Writer::new(buckets, &checkpoint).unwrap(),
+        }
+    }
+
+    async fn follow_all(stream: &EthLogsStream, sinks: &[Sink<MemoryKV>], memory: &MemoryKV) {
+        let quarantine = QuarantineBucket::new(memory.bucket("quarantine"));
+        while follow_step(stream, sinks, &quarantine)
+            .await
+            .unwrap()
+            .is_some()
+        {}
+        assert!(quarantine.list().await.unwrap().is_empty());
+    }
+
+    // stored views of the bucket by block number
+    async fn stored(memory: &MemoryKV, name: &str) -> BTreeMap<u32, BlockTransactions> {
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        let bucket = memory.bucket(name);
+        let mut out = BTreeMap::new();
+        for n in bucket.keys(0, u32::MAX).await.unwrap().unwrap() {
+            let blob = bucket.get(n).await.unwrap().unwrap();
+            let value = jsondp::decode_slice(&blob, &fd, &NoDictionary {}).unwrap();
+            out.insert(n, BlockTransactions::from_value(value).unwrap());
+        }
+        out
+    }
+
+    #[tokio::test]
+    async fn it_follows_the_chain_through_the_writer() {
+        let contract = Address::from_low_u64_be(0xc0);
+        let mut g = ChainGenerator::seeded(11)
+            .density(3, 2)
+            .contracts(vec![contract]);
+        g.generate(10);
+        let memory = MemoryKV::new();
+        let sinks = [sink(&memory, None, "blocks")];
+        let args = args(vec![contract]);
+
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let stream = resume(&args, client, &sinks).await.unwrap();
+        let quarantine = QuarantineBucket::new(memory.bucket("quarantine"));
+        let mut steps = vec![];
+        while let Some(step) = follow_step(&stream, &sinks, &quarantine).await.unwrap() {
+            steps.push(step);
+        }
+        assert_eq!(steps.len(), 3);
+        assert_eq!(sinks[0].writer.checkpoint().await.unwrap(), Some(10));
+
+        // the chain grows, the next run starts after the checkpoint
+        g.generate(5);
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let stream = resume(&args, client, &sinks).await.unwrap();
+        assert_eq!(stream.checkpoint(), 10);
+        follow_all(&stream, &sinks, &memory).await;
+        assert_eq!(sinks[0].writer.checkpoint().await.unwrap(), Some(15));
+
+        let stored = stored(&memory, "blocks").await;
+        let with_logs: Vec<&BlockTransactions> = g
+            .canonical()
+            .iter()
+            .filter(|b| !b.log_ids().is_empty())
+            .collect();
+        assert_eq!(stored.len(), with_logs.len());
+        for expected in with_logs {
+            let block = &stored[&expected.block.number.unwrap().as_u32()];
+            assert_eq!(block.block.hash, expected.block.hash);
+            assert_eq!(block.receipts.len(), expected.receipts.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn it_quarantines_oversized_blocks() {
+        let contract = Address::from_low_u64_be(0xc0);
+        let mut g = ChainGenerator::seeded(3)
+            .density(200, 4)
+            .contracts(vec![contract]);
+        g.generate(1);
+        let memory = MemoryKV::new();
+        let mut blocks = sink(&memory, None, "blocks");
+        blocks.writer = blocks
+            .writer
+            .with_config(WriterConfig {
+                max_value_bytes: Some("16KiB".parse().unwrap()),
+                oversize: OversizePolicy::Skip,
+            })
+            .unwrap();
+        let sinks = [blocks];
+        let quarantine = QuarantineBucket::new(memory.bucket("quarantine"));
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let stream = resume(&args(vec![contract]), client, &sinks).await.unwrap();
+        let step = follow_step(&stream, &sinks, &quarantine)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((step.blocks, step.quarantined), (1, 1));
+        assert_eq!(step.skipped[0].key, 1);
+
+        // the checkpoint moved on, the logs wait in the quarantine
+        assert_eq!(sinks[0].writer.checkpoint().await.unwrap(), Some(1));
+        assert_eq!(memory.bucket("blocks").get(1).await.unwrap(), None);
+        let listed = quarantine.list().await.unwrap();
+        assert_eq!(listed[0].block_number, 1);
+        assert_eq!(listed[0].logs.len(), g.canonical()[0].log_ids().len());
+        assert!(listed[0].error.contains("over the limit of 16384 bytes"));
+
+        let state = IndexerState::default();
+        state.indexed.store(step.checkpoint, Ordering::Relaxed);
+        let maintenance = MaintenanceMetrics::default();
+        maintenance.runs.fetch_add(2, Ordering::Relaxed);
+        let text = follow_metrics(&state, &sinks, &[("blocks", &maintenance)]);
+        assert!(text.contains("btxs_indexed_block 1\n"), "{}", text);
+        assert!(text.contains("btxs_oversized_total{bucket=\"blocks\",policy=\"skip\"} 1\n"));
+        assert!(text.contains("btxs_oversized_total{bucket=\"blocks\",policy=\"error\"} 0\n"));
+        assert!(text.contains("btxs_maintenance_runs_total{bucket=\"blocks\"} 2\n"));
+    }
+
+    // provider that records the method and the first param of every request
+    #[derive(Clone)]
+    struct Recorded {
+        chain: MockChain,
+        requests: Arc<Mutex<Vec<(String, String)>>>,
+    }
+
+    impl Transport for Recorded {
+        fn post(
+            &self,
+            url: &str,
+            headers: &[(&str, String)],
+            body: &str,
+        ) -> anyhow::Result<String> {
+            let batch: Vec<serde_json::Value> = serde_json::from_str(body)?;
+            for r in &batch {
+                let method = r["method"].as_str().unwrap_or_default().to_string();
+                self.requests
+                    .lock()
+                    .unwrap()
+                    .push((method, r["params"][0].to_string()));
+            }
+            self.chain.post(url, headers, body)
+        }
+    }
+
+    impl Recorded {
+        fn count(&self, method: &str) -> (usize, usize) {
+            let requests = self.requests.lock().unwrap();
+            let params: Vec<&String> = requests
+                .iter()
+                .filter(|(m, _)| m == method)
+                .map(|(_, p)| p)
+                .collect();
+            let unique: std::collections::BTreeSet<&&String> = params.iter().collect();
+            (params.len(), unique.len())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_feeds_contracts_from_one_fetch() {
+        let a = Address::from_low_u64_be(0xa);
+        let b = Address::from_low_u64_be(0xb);
+        let c = Address::from_low_u64_be(0xc);
+        let mut g = ChainGenerator::seeded(21)
+            .density(4, 3)
+            .contracts(vec![a, b, c]);
+        g.generate(12);
+        let memory = MemoryKV::new();
+        let sinks = [sink(&memory, Some(a), "a"), sink(&memory, Some(b), "b")];
+        let rpc = Recorded {
+            chain: g.mock(),
+            requests: Default::default(),
+        };
+        let client = EthBatchClient::new("http://mock").with_transport(rpc.clone());
+        let stream = resume(&args(vec![a, b]), client, &sinks).await.unwrap();
+        follow_all(&stream, &sinks, &memory).await;
+
+        // one log query per range carries both contracts, every object is fetched once
+        assert_eq!(rpc.count("eth_getLogs").0, 3);
+        for (method, filter) in rpc.requests.lock().unwrap().iter() {
+            if method == "eth_getLogs" {
+                assert!(
+                    filter.contains(&format!("{:?}", a)) && filter.contains(&format!("{:?}", b))
+                );
+            }
+        }
+        let (blocks, unique) = rpc.count("eth_getBlockByHash");
+        assert_eq!(blocks, unique);
+        let (receipts, unique) = rpc.count("eth_getTransactionReceipt");
+        assert_eq!(receipts, unique);
+        let shared = g
+            .canonical()
+            .iter()
+            .filter(|blk| blk.for_address(&a).is_some() && blk.for_address(&b).is_some())
+            .count();
+        assert!(shared > 0, "no block with both contracts");
+
+        for (address, name) in [(a, "a"), (b, "b")] {
+            let stored = stored(&memory, name).await;
+            let expected = eth_logs::demultiplex(g.canonical(), &[address]);
+            assert_eq!(stored.len(), expected[&address].len());
+            for view in &expected[&address] {
+                let got = &stored[&view.block.number.unwrap().as_u32()];
+                let hashes = |b: &BlockTransactions| -> Vec<_> {
+                    b.transactions.iter().map(|tx| tx.hash).collect()
+                };
+                assert_eq!(hashes(got), hashes(view));
+                assert!(got
+                    .receipts
+                    .values()
+                    .all(|r| r.logs.iter().any(|l| l.address == address)));
+            }
+            let sink = sinks.iter().find(|s| s.address == Some(address)).unwrap();
+            assert_eq!(sink.writer.checkpoint().await.unwrap(), Some(12));
+        }
+
+        // a contract added later is backfilled alone, the others keep their checkpoints
+        let late = [sink(&memory, Some(c), "c")];
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let stream = resume(&args(vec![c]), client, &late).await.unwrap();
+        assert_eq!(stream.checkpoint(), 0);
+        follow_all(&stream, &late, &memory).await;
+        let expected = eth_logs::demultiplex(g.canonical(), &[c]);
+        assert_eq!(stored(&memory, "c").await.len(), expected[&c].len());
+        assert_eq!(late[0].writer.checkpoint().await.unwrap(), Some(12));
+        let expected = eth_logs::demultiplex(g.canonical(), &[a]);
+        assert_eq!(stored(&memory, "a").await.len(), expected[&a].len());
+    }
+
+    // records delivered events
+    #[derive(Clone, Default)]
+    struct Delivered(Arc<Mutex<Vec<notify::Event>>>);
+
+    #[async_trait::async_trait]
+    impl notify::Delivery for Delivered {
+        async fn deliver(&self, _: &str, event: &notify::Event) -> anyhow::Result<()> {
+            self.0.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn it_notifies_every_log_once_after_a_replay() {
+        let contract = Address::from_low_u64_be(0xc0);
+        let mut g = ChainGenerator::seeded(17)
+            .density(3, 2)
+            .contracts(vec![contract]);
+        g.generate(10);
+        let memory = MemoryKV::new();
+        let sinks = [sink(&memory, None, "blocks")];
+        let quarantine = QuarantineBucket::new(memory.bucket("quarantine"));
+        let events = EventLog::new(memory.bucket("events"));
+        let delivered = Delivered::default();
+        let notifier = Notifier::new(delivered.clone(), &["hook".to_string()], 100);
+
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let stream = resume(&args(vec![contract]), client, &sinks).await.unwrap();
+        while let Some(step) = follow_step(&stream, &sinks, &quarantine).await.unwrap() {
+            notify::publish_logs(&events, Some(&notifier), &step.logs)
+                .await
+                .unwrap();
+        }
+        // the range from block 5 is fetched again, as after a rollback of the stream
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let replay =
+            EthLogsStream::new(client, 5, 4, vec![contract], None, None, None, None).unwrap();
+        while let Some(step) = follow_step(&replay, &sinks, &quarantine).await.unwrap() {
+            assert!(step.logs.is_empty());
+            notify::publish_logs(&events, Some(&notifier), &step.logs)
+                .await
+                .unwrap();
+        }
+
+        let expected: Vec<LogId> = g.canonical().iter().flat_map(|b| b.log_ids()).collect();
+        let stamped = events.since(0, 1000).await.unwrap();
+        let mut ids: Vec<LogId> = stamped
+            .iter()
+            .map(|e| serde_json::from_value(e.payload["id"].clone()).unwrap())
+            .collect();
+        ids.sort();
+        let mut unique = ids.clone();
+        unique.dedup();
+        assert_eq!(ids, unique);
+        assert_eq!(ids.len(), expected.len());
+        assert_eq!(delivered.0.lock().unwrap().len(), expected.len());
+    }
+}
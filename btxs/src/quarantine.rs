@@ -0,0 +1,191 @@
+use anyhow::{bail, Context};
+use clap::{Args, Subcommand};
+use eth_logs::{
+    get_block, get_receipt, BlockTransactions, EthBatchClient, QuarantineBucket, QuarantinedLogs,
+};
+use ethers::types::TransactionReceipt;
+use jsondp::dictionary::NoDictionary;
+use kv::KV;
+use tracing::*;
+
+/// bucket of logs which blocks could not be fetched
+pub const QUARANTINE_TABLE: &str = "btxs_quarantine";
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum QuarantineCommand {
+    /// show quarantined logs
+    List(ListArgs),
+    /// fetch quarantined blocks again and store the ones that are served now
+    Retry(RetryArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ListArgs {
+    /// print entries as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct RetryArgs {
+    /// JSON-RPC endpoint
+    #[arg(long, env = "RPC_ETH_ADDR")]
+    pub rpc_addr: String,
+}
+
+/// block with its receipts, as it is stored
+fn fetch(client: &EthBatchClient, entry: &QuarantinedLogs) -> anyhow::Result<BlockTransactions> {
+    let response = client.get(vec![get_block(entry.block_hash, true)])?;
+    let value = response.value(&format!("b{:?}", entry.block_hash))?;
+    if value.is_null() {
+        bail!("block not found");
+    }
+    let mut block = BlockTransactions::from_block_value(value)?;
+    for tx in &block.transactions {
+        let response = client.get(vec![get_receipt(tx.hash)])?;
+        let receipt: TransactionReceipt =
+            serde_json::from_value(response.value(&format!("r{:?}", tx.hash))?)
+                .context("receipt")?;
+        block.receipts.insert(tx.hash, receipt);
+    }
+    Ok(block)
+}
+
+/// entry for a block the writer left out, with the reason it was left out
+pub fn oversized(block: &BlockTransactions, reason: String) -> QuarantinedLogs {
+    let mut logs: Vec<_> = block
+        .transactions
+        .iter()
+        .filter_map(|tx| block.receipts.get(&tx.hash))
+        .flat_map(|r| r.logs.iter().cloned())
+        .collect();
+    logs.sort_by_key(|l| l.log_index);
+    QuarantinedLogs {
+        block_hash: block.block.hash.unwrap_or_default(),
+        block_number: block.block.number.map_or(0, |n| n.as_u64()),
+        logs,
+        error: reason,
+        quarantined_at: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+    }
+}
+
+/// reprocesses quarantined entries, returns numbers of stored and remaining entries
+pub async fn retry<Q: KV, S: KV>(
+    quarantine: &QuarantineBucket<Q>,
+    client: &EthBatchClient,
+    storage: &S,
+) -> anyhow::Result<(usize, usize)> {
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let (mut stored, mut left) = (0, 0);
+    for mut entry in quarantine.list().await? {
+        let number = entry.block_number as u32;
+        match fetch(client, &entry) {
+            Ok(block) => {
+                let mut blob = Vec::new();
+                jsondp::encode(&block.to_value()?, &mut blob, &fd, &NoDictionary {})?;
+                storage.set(number, blob).await?;
+                quarantine.remove(number, &entry.block_hash).await?;
+                info!(number, "quarantined block stored");
+                stored += 1;
+            }
+            Err(e) => {
+                warn!(
+                    number,
+                    "block {:?} still unavailable: {:#}", entry.block_hash, e
+                );
+                entry.error = format!("{:#}", e);
+                quarantine.add(entry).await?;
+                left += 1;
+            }
+        }
+    }
+    Ok((stored, left))
+}
+
+pub async fn run(
+    cmd: &QuarantineCommand,
+    database_url: &str,
+    table_name: &str,
+) -> anyhow::Result<()> {
+    let bucket = kv::PostgresKV::try_new(database_url, QUARANTINE_TABLE).await?;
+    let quarantine = QuarantineBucket::new(bucket);
+    match cmd {
+        QuarantineCommand::List(args) => {
+            let entries = quarantine.list().await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                for e in entries {
+                    println!(
+                        "{}\t{:?}\t{} logs\t{}",
+                        e.block_number,
+                        e.block_hash,
+                        e.logs.len(),
+                        e.error
+                    );
+                }
+            }
+        }
+        QuarantineCommand::Retry(args) => {
+            let client = EthBatchClient::new(&args.rpc_addr);
+            let storage = kv::PostgresKV::try_new(database_url, table_name).await?;
+            let (stored, left) = retry(&quarantine, &client, &storage).await?;
+            println!("stored {} blocks, {} still quarantined", stored, left);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eth_logs::Transport;
+    use ethers::types::{Log, H256};
+    use kv::MemoryKV;
+    use serde_json::{json, Value};
+
+    // provider which serves only the block 0x...14
+    struct Rpc;
+
+    impl Transport for Rpc {
+        fn post(&self, _url: &str, _: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+            let requests: Vec<Value> = serde_json::from_str(body)?;
+            let r = &requests[0];
+            let hash = r["params"][0].as_str().unwrap();
+            let result = if H256::from_low_u64_be(20) == hash.parse()? {
+                json!({"hash": hash, "number": "0x14", "transactions": []})
+            } else {
+                Value::Null
+            };
+            Ok(json!([{"jsonrpc": "2.0", "id": r["id"], "result": result}]).to_string())
+        }
+    }
+
+    fn entry(number: u64) -> QuarantinedLogs {
+        QuarantinedLogs {
+            block_hash: H256::from_low_u64_be(number),
+            block_number: number,
+            logs: vec![Log::default()],
+            error: "block not found".to_string(),
+            quarantined_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_retries_quarantined_blocks() {
+        let quarantine = QuarantineBucket::new(MemoryKV::new());
+        quarantine.add(entry(20)).await.unwrap();
+        quarantine.add(entry(25)).await.unwrap();
+        let client = EthBatchClient::new("http://mock").with_transport(Rpc);
+        let storage = MemoryKV::new();
+
+        assert_eq!(retry(&quarantine, &client, &storage).await.unwrap(), (1, 1));
+        let left = quarantine.list().await.unwrap();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].block_number, 25);
+        assert!(storage.get(20).await.unwrap().is_some());
+        assert_eq!(storage.get(25).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,425 @@
+use crate::auth::{log_access, Authenticator};
+use crate::config::Config;
+use crate::metrics::{self, Metrics};
+use crate::notify::{self, EventLog};
+use crate::status::{self, EndpointStatus};
+use anyhow::Context;
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use clap::Args;
+use eth_logs::EndpointHealth;
+use futures::stream::{self, Stream, StreamExt};
+use jsondp::dictionary::NoDictionary;
+use kv::KV;
+use serde::Deserialize;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+// events read from the event log at once
+const EVENTS_BATCH: usize = 100;
+
+// pause of the event stream when it has sent every event
+const EVENTS_POLL: Duration = Duration::from_secs(1);
+
+/// HTTP API over the stored blocks
+#[derive(Debug, Clone, Args)]
+pub struct ServeArgs {
+    /// address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    pub listen: SocketAddr,
+    /// TOML configuration, tokens are taken from its `[serve.auth]` section
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+}
+
+/// storage the API serves from
+pub struct Api<K: KV> {
+    pub blocks: K,
+    pub events: EventLog<K>,
+    /// health of the JSON-RPC endpoints persisted by the indexer
+    pub endpoints: K,
+}
+
+// failure of a handler, logged and answered with its status
+struct ApiError(StatusCode, String);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        Self(StatusCode::INTERNAL_SERVER_ERROR, format!("{:#}", e))
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if self.0.is_server_error() {
+            error!("{}", self.1);
+        }
+        (self.0, Json(serde_json::json!({ "error": self.1 }))).into_response()
+    }
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// stored document of the block
+async fn block<K: KV + Send + Sync + 'static>(
+    State(api): State<Arc<Api<K>>>,
+    Path(number): Path<u32>,
+) -> Result<Json<serde_json::Value>, ApiError> {
+    let blob = match api.blocks.get(number).await? {
+        Some(blob) if !blob.is_empty() => blob,
+        _ => {
+            let reason = format!("block {} is not stored", number);
+            return Err(ApiError(StatusCode::NOT_FOUND, reason));
+        }
+    };
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let value = jsondp::decode_slice(&blob, &fd, &NoDictionary {})
+        .with_context(|| format!("block {}", number))?;
+    Ok(Json(value))
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    since: Option<u64>,
+}
+
+/// server-sent events after `Last-Event-ID` or `?since`, from the start without them;
+/// ids of the events are their sequence numbers
+async fn events<K: KV + Send + Sync + 'static>(
+    State(api): State<Arc<Api<K>>>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<sse::Event, serde_json::Error>>> {
+    let since = headers
+        .get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or(query.since)
+        .unwrap_or(0);
+    let batches = stream::unfold((api, since), |(api, since)| async move {
+        loop {
+            match api.events.since(since, EVENTS_BATCH).await {
+                Ok(batch) if !batch.is_empty() => {
+                    let last = batch[batch.len() - 1].seq;
+                    return Some((batch, (api, last)));
+                }
+                Ok(_) => {}
+                Err(e) => error!("read events after {}: {:#}", since, e),
+            }
+            tokio::time::sleep(EVENTS_POLL).await;
+        }
+    });
+    let events = batches.flat_map(|batch| {
+        stream::iter(batch.into_iter().map(|event| {
+            sse::Event::default()
+                .id(event.seq.to_string())
+                .event(&event.kind)
+                .json_data(&event)
+        }))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+// name, help and value of the gauges of every endpoint
+type EndpointGauge = (
+    &'static str,
+    &'static str,
+    fn(&EndpointStatus) -> Option<f64>,
+);
+
+const ENDPOINT_GAUGES: [EndpointGauge; 4] = [
+    (
+        "btxs_endpoint_score",
+        "health score of the JSON-RPC endpoint, higher is better",
+        |e| Some(e.score),
+    ),
+    (
+        "btxs_endpoint_successes",
+        "successful requests to the endpoint, decayed with the half-life",
+        |e| Some(e.successes),
+    ),
+    (
+        "btxs_endpoint_failures",
+        "failed requests to the endpoint, decayed with the half-life",
+        |e| Some(e.failures),
+    ),
+    (
+        "btxs_endpoint_median_latency_seconds",
+        "median latency of the latest requests to the endpoint",
+        |e| e.median_latency_ms.map(|ms| ms as f64 / 1000.0),
+    ),
+];
+
+/// health scores of the endpoints and counters of the event log
+/// in the Prometheus text format
+async fn api_metrics<K: KV + Send + Sync + 'static>(
+    State(api): State<Arc<Api<K>>>,
+) -> Result<impl IntoResponse, ApiError> {
+    let health = EndpointHealth::load(&api.endpoints)
+        .await?
+        .unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let known: Vec<String> = health.endpoints.keys().cloned().collect();
+    let endpoints = status::statuses(&health, health.scores(&known, now));
+    let mut m = Metrics::new();
+    for (name, help, value) in ENDPOINT_GAUGES {
+        m.family(name, "gauge", help);
+        for e in &endpoints {
+            if let Some(v) = value(e) {
+                m.sample(name, &[("endpoint", &e.endpoint)], v);
+            }
+        }
+    }
+    m.family(
+        "btxs_events_last_seq",
+        "counter",
+        "sequence number of the last issued event",
+    )
+    .sample(
+        "btxs_events_last_seq",
+        &[],
+        api.events.load_last().await? as f64,
+    );
+    Ok(([(header::CONTENT_TYPE, metrics::CONTENT_TYPE)], m.render()))
+}
+
+// rejects requests without a valid token when authentication is configured,
+// and writes the access log of every request
+async fn authenticate<B>(
+    State(auth): State<Option<Arc<Authenticator>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let started = Instant::now();
+    let path = req.uri().path().to_string();
+    let checked = match &auth {
+        Some(auth) => {
+            let authorization = req
+                .headers()
+                .get(header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok());
+            auth.check(&path, authorization, started)
+        }
+        None => Ok(None),
+    };
+    let (token, response) = match checked {
+        Ok(token) => (token, next.run(req).await),
+        Err(e) => {
+            let status = StatusCode::from_u16(e.status()).unwrap_or(StatusCode::UNAUTHORIZED);
+            let mut response = status.into_response();
+            if let Some(secs) = e.retry_after_secs() {
+                response
+                    .headers_mut()
+                    .insert(header::RETRY_AFTER, HeaderValue::from(secs));
+            }
+            (None, response)
+        }
+    };
+    log_access(
+        token.as_deref(),
+        &path,
+        response.status().as_u16(),
+        started.elapsed(),
+    );
+    response
+}
+
+/// routes of the API, behind the authentication when it is given
+pub fn router<K: KV + Send + Sync + 'static>(
+    api: Arc<Api<K>>,
+    auth: Option<Arc<Authenticator>>,
+) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/blocks/:number", get(block::<K>))
+        .route("/events", get(events::<K>))
+        .route("/metrics", get(api_metrics::<K>))
+        .with_state(api)
+        .layer(middleware::from_fn_with_state(auth, authenticate))
+}
+
+pub async fn run(args: &ServeArgs, database_url: &str, table_name: &str) -> anyhow::Result<()> {
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let auth = match &config.serve.auth {
+        Some(auth) => Some(Arc::new(Authenticator::new(auth)?)),
+        None if !args.listen.ip().is_loopback() => {
+            warn!(listen = %args.listen, "serving without authentication");
+            None
+        }
+        None => None,
+    };
+    let api = Arc::new(Api {
+        blocks: kv::PostgresKV::try_new(database_url, table_name).await?,
+        events: EventLog::new(kv::PostgresKV::try_new(database_url, notify::EVENTS_TABLE).await?),
+        endpoints: kv::PostgresKV::try_new(database_url, status::ENDPOINTS_TABLE).await?,
+    });
+    info!(listen = %args.listen, "serving");
+    axum::Server::bind(&args.listen)
+        .serve(router(api, auth).into_make_service())
+        .await
+        .context("serve")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AuthConfig;
+    use axum::body::{Body, HttpBody};
+    use kv::MemoryKV;
+    use tower::ServiceExt;
+
+    fn app(requests_per_minute: u32) -> Router {
+        let auth = Authenticator::new(&AuthConfig {
+            tokens: vec!["secret".to_string()],
+            tokens_file: None,
+            requests_per_minute,
+        })
+        .unwrap();
+        router(api(MemoryKV::new()), Some(Arc::new(auth)))
+    }
+
+    fn api(store: MemoryKV) -> Arc<Api<MemoryKV>> {
+        Arc::new(Api {
+            blocks: store.bucket("blocks"),
+            events: EventLog::new(store.bucket("events")),
+            endpoints: store.bucket("endpoints"),
+        })
+    }
+
+    async fn request(app: &Router, path: &str, token: Option<&str>) -> Response {
+        let mut req = Request::get(path);
+        if let Some(token) = token {
+            req = req.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        app.clone()
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn it_rejects_unauthenticated_requests() {
+        let app = app(0);
+        let missing = request(&app, "/blocks/1", None).await;
+        assert_eq!(missing.status(), StatusCode::UNAUTHORIZED);
+        let wrong = request(&app, "/blocks/1", Some("wrong")).await;
+        assert_eq!(wrong.status(), StatusCode::UNAUTHORIZED);
+        // valid token reaches the handler, the block is not stored
+        let valid = request(&app, "/blocks/1", Some("secret")).await;
+        assert_eq!(valid.status(), StatusCode::NOT_FOUND);
+        let health = request(&app, "/healthz", None).await;
+        assert_eq!(health.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn it_serves_uncles_and_withdrawals() {
+        let (storage, blocks) = crate::export::stored_fixtures().await;
+        let app = router(
+            Arc::new(Api {
+                blocks: storage,
+                events: EventLog::new(MemoryKV::new()),
+                endpoints: MemoryKV::new(),
+            }),
+            None,
+        );
+        for expected in blocks {
+            let number = expected.block.number.unwrap();
+            let response = request(&app, &format!("/blocks/{}", number), None).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            let block = eth_logs::BlockTransactions::from_value(value).unwrap();
+            assert_eq!(block.block.uncles, expected.block.uncles);
+            assert_eq!(block.block.withdrawals, expected.block.withdrawals);
+        }
+    }
+
+    #[tokio::test]
+    async fn it_limits_requests_per_token() {
+        let app = app(2);
+        for _ in 0..2 {
+            let r = request(&app, "/blocks/1", Some("secret")).await;
+            assert_eq!(r.status(), StatusCode::NOT_FOUND);
+        }
+        let limited = request(&app, "/blocks/1", Some("secret")).await;
+        assert_eq!(limited.status(), StatusCode::TOO_MANY_REQUESTS);
+        let retry_after: u64 = limited.headers()[header::RETRY_AFTER]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+    }
+
+    #[tokio::test]
+    async fn it_streams_events_after_the_last_seen() {
+        let api = api(MemoryKV::new());
+        for n in 0..3 {
+            api.events
+                .stamp(notify::LOG_EVENT, serde_json::json!(n))
+                .await
+                .unwrap();
+        }
+        let app = router(api, None);
+        let req = Request::get("/events")
+            .header("Last-Event-ID", "1")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(req).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let mut body = response.into_body();
+        let mut text = String::new();
+        while text.matches("\n\n").count() < 2 {
+            let chunk = body.data().await.unwrap().unwrap();
+            text.push_str(std::str::from_utf8(&chunk).unwrap());
+        }
+        let ids: Vec<&str> = text.lines().filter_map(|l| l.strip_prefix("id:")).collect();
+        assert_eq!(ids, vec!["2", "3"]);
+        assert!(text.contains("event:log"));
+    }
+
+    #[tokio::test]
+    async fn it_exposes_endpoint_scores() {
+        let api = api(MemoryKV::new());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut health = EndpointHealth::default();
+        health.record_failure("http://a", now);
+        health.record_success("http://b", Duration::from_millis(50), now);
+        health.save(&api.endpoints).await.unwrap();
+        api.events
+            .stamp(notify::LOG_EVENT, serde_json::json!(1))
+            .await
+            .unwrap();
+        let response = request(&router(api, None), "/metrics", None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(
+            text.contains("# TYPE btxs_endpoint_score gauge"),
+            "{}",
+            text
+        );
+        assert!(text.contains("btxs_endpoint_score{endpoint=\"http://a\"} 0.5"));
+        assert!(text.contains("btxs_endpoint_failures{endpoint=\"http://a\"} 1"));
+        assert!(text.contains("btxs_endpoint_median_latency_seconds{endpoint=\"http://b\"} 0.05"));
+        assert!(text.contains("btxs_events_last_seq 1"));
+    }
+}
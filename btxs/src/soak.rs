@@ -0,0 +1,765 @@
+use crate::humane::HumaneDuration;
+use crate::notify::{self, Event, EventLog, Notifier, Webhook};
+use crate::process::{follow_step, resume, FollowArgs, Sink};
+use crate::writer::{OversizePolicy, Writer};
+use anyhow::{bail, Context};
+use clap::Args;
+use eth_logs::chaos::{ChaosCounters, ChaosTransport, TransportChaosProfile};
+use eth_logs::fixtures::{ChainGenerator, MockChain};
+use eth_logs::{
+    BlockTransactions, EthBatchClient, EthLogsStream, LogId, QuarantineBucket, Transport,
+};
+use ethers::types::H256;
+use jsondp::dictionary::NoDictionary;
+use kv::chaos::{ChaosKV, ChaosProfile};
+use kv::{MemoryKV, KV};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+// buckets of the followed blocks
+const BLOCKS: &str = "soak_blocks";
+const CHECKPOINT: &str = "soak_checkpoint";
+
+// destination of the notifications, served by the recorder
+const WEBHOOK: &str = "http://soak/webhook";
+
+// blocks per eth_getLogs request, small enough for several ranges a round
+const BATCH_SIZE: u64 = 8;
+
+// attempts to fetch a block before its logs are quarantined
+const BLOCK_RETRIES: u32 = 5;
+
+// failed steps or deliveries in a row before the soak gives up
+const MAX_FAILURES_IN_A_ROW: u32 = 1000;
+
+/// Follows the growing mock chain through failing transports and storage,
+/// then checks that nothing was lost or stored twice
+#[derive(Debug, Clone, Args)]
+pub struct SoakArgs {
+    /// TOML failure rates of the `[rpc]`, `[webhook]` and `[kv]` sections
+    #[arg(long)]
+    pub chaos_profile: PathBuf,
+    /// how long the chain keeps growing, like "10m"
+    #[arg(long, default_value = "1m")]
+    pub duration: HumaneDuration,
+    /// seed of the chain and of the failures, random when missing
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// blocks added to the chain every round
+    #[arg(long, default_value_t = 20)]
+    pub blocks_per_round: u64,
+    /// print report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// `[rpc]` and `[webhook]` sections of the chaos profile,
+/// rates are probabilities in [0, 1]
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportChaos {
+    pub timeout_rate: f64,
+    pub server_error_rate: f64,
+    pub malformed_rate: f64,
+    pub slow_rate: f64,
+    /// delay of slow requests, like "50ms"
+    pub slow_delay: HumaneDuration,
+    pub duplicate_rate: f64,
+}
+
+impl TransportChaos {
+    fn rates(&self) -> [(&'static str, f64); 5] {
+        [
+            ("timeout_rate", self.timeout_rate),
+            ("server_error_rate", self.server_error_rate),
+            ("malformed_rate", self.malformed_rate),
+            ("slow_rate", self.slow_rate),
+            ("duplicate_rate", self.duplicate_rate),
+        ]
+    }
+
+    fn profile(&self, seed: u64) -> TransportChaosProfile {
+        TransportChaosProfile {
+            seed,
+            timeout_rate: self.timeout_rate,
+            server_error_rate: self.server_error_rate,
+            malformed_rate: self.malformed_rate,
+            slow_rate: self.slow_rate,
+            slow_delay: self.slow_delay.0,
+            duplicate_rate: self.duplicate_rate,
+        }
+    }
+}
+
+/// `[kv]` section of the chaos profile, failures of the block and checkpoint storage
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageChaos {
+    pub read_error_rate: f64,
+    pub write_error_rate: f64,
+    /// failed commits roll the whole batch back
+    pub commit_error_rate: f64,
+    pub slow_rate: f64,
+    /// delay of slow calls, like "10ms"
+    pub slow_delay: HumaneDuration,
+}
+
+impl StorageChaos {
+    fn rates(&self) -> [(&'static str, f64); 4] {
+        [
+            ("read_error_rate", self.read_error_rate),
+            ("write_error_rate", self.write_error_rate),
+            ("commit_error_rate", self.commit_error_rate),
+            ("slow_rate", self.slow_rate),
+        ]
+    }
+
+    fn profile(&self, seed: u64) -> ChaosProfile {
+        ChaosProfile {
+            seed,
+            read_error_rate: self.read_error_rate,
+            write_error_rate: self.write_error_rate,
+            commit_error_rate: self.commit_error_rate,
+            slow_rate: self.slow_rate,
+            slow_delay: self.slow_delay.0,
+        }
+    }
+}
+
+/// Failures injected by the soak, missing sections inject nothing
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChaosFile {
+    pub rpc: TransportChaos,
+    pub webhook: TransportChaos,
+    pub kv: StorageChaos,
+}
+
+impl ChaosFile {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+        let profile: Self =
+            toml::from_str(&text).with_context(|| format!("parse {}", path.display()))?;
+        profile.validate()?;
+        Ok(profile)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        let rates = [("rpc", self.rpc.rates()), ("webhook", self.webhook.rates())]
+            .into_iter()
+            .flat_map(|(section, rates)| rates.map(|r| (section, r)))
+            .chain(self.kv.rates().map(|r| ("kv", r)));
+        for (section, (name, rate)) in rates {
+            if !(0.0..=1.0).contains(&rate) {
+                bail!("{}.{} should be in [0, 1], got {}", section, name, rate);
+            }
+        }
+        Ok(())
+    }
+}
+
+// provider over the chain which is replaced as it grows
+#[derive(Clone)]
+struct Provider(Arc<Mutex<MockChain>>);
+
+impl Transport for Provider {
+    fn post(&self, _url: &str, _headers: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+        self.0.lock().unwrap().respond(body)
+    }
+}
+
+// webhook receiver, keeps the sequence number of every notification
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<u64>>>);
+
+impl Transport for Recorder {
+    fn post(&self, _url: &str, _headers: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+        let event: Event = serde_json::from_str(body).context("notification")?;
+        self.0.lock().unwrap().push(event.seq);
+        Ok(String::new())
+    }
+}
+
+/// Requests of a chaos transport and failures injected into them
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Injected {
+    pub requests: u64,
+    pub timeouts: u64,
+    pub server_errors: u64,
+    pub malformed: u64,
+    pub slow: u64,
+    pub duplicates: u64,
+}
+
+impl From<&ChaosCounters> for Injected {
+    fn from(c: &ChaosCounters) -> Self {
+        Self {
+            requests: c.requests.load(Ordering::Relaxed),
+            timeouts: c.timeouts.load(Ordering::Relaxed),
+            server_errors: c.server_errors.load(Ordering::Relaxed),
+            malformed: c.malformed.load(Ordering::Relaxed),
+            slow: c.slow.load(Ordering::Relaxed),
+            duplicates: c.duplicates.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl fmt::Display for Injected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} requests, {} timeouts, {} server errors, {} malformed, {} slow, {} duplicates",
+            self.requests,
+            self.timeouts,
+            self.server_errors,
+            self.malformed,
+            self.slow,
+            self.duplicates
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SoakReport {
+    /// replays the run with `--seed`
+    pub seed: u64,
+    pub rounds: u64,
+    pub head: u32,
+    pub stored: usize,
+    pub quarantined: usize,
+    pub published: usize,
+    /// steps which failed and were resumed from the stored checkpoint
+    pub failed_steps: u64,
+    /// batches rolled back by an injected commit failure
+    pub failed_commits: u64,
+    pub rpc: Injected,
+    pub webhook: Injected,
+    /// notifications of events which were delivered before
+    pub duplicate_notifications: usize,
+    pub violations: Vec<String>,
+}
+
+impl fmt::Display for SoakReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "seed {}, {} rounds up to block {}",
+            self.seed, self.rounds, self.head
+        )?;
+        writeln!(
+            f,
+            "stored {} blocks, quarantined {}, published {} logs",
+            self.stored, self.quarantined, self.published
+        )?;
+        writeln!(
+            f,
+            "{} failed steps resumed from the stored checkpoint, {} failed commits",
+            self.failed_steps, self.failed_commits
+        )?;
+        writeln!(f, "rpc: {}", self.rpc)?;
+        writeln!(
+            f,
+            "webhook: {}, {} duplicate notifications",
+            self.webhook, self.duplicate_notifications
+        )?;
+        if self.violations.is_empty() {
+            return writeln!(f, "no violations");
+        }
+        writeln!(f, "{} violations:", self.violations.len())?;
+        for v in &self.violations {
+            writeln!(f, "  {}", v)?;
+        }
+        Ok(())
+    }
+}
+
+/// What the soak left behind, checked against the chain by `check`
+#[derive(Debug, Clone, Default)]
+pub struct Observed {
+    /// hashes of the canonical blocks with logs by number
+    pub chain: BTreeMap<u32, H256>,
+    pub head: u32,
+    pub checkpoint: Option<u32>,
+    /// hashes of the stored blocks by number
+    pub stored: BTreeMap<u32, H256>,
+    pub quarantined: BTreeSet<u32>,
+    /// logs of the stored blocks
+    pub stored_logs: BTreeSet<LogId>,
+    /// logs of the issued events, as many times as they were issued
+    pub published: Vec<LogId>,
+    pub last_seq: u64,
+    /// sequence numbers received by the webhook in the order they came
+    pub delivered: Vec<u64>,
+    pub dead_letter: Vec<u64>,
+    /// posts the webhook chaos transport passed on, duplicates included
+    pub passed_posts: u64,
+}
+
+/// violated invariants: every block with logs is stored or quarantined up to
+/// the checkpoint at the head, every log of the stored blocks is published once
+/// and every event is delivered in order, counters agree with what was received
+pub fn check(o: &Observed) -> Vec<String> {
+    let mut out = vec![];
+    if o.checkpoint != Some(o.head) {
+        out.push(format!(
+            "checkpoint {:?} is not the chain head {}",
+            o.checkpoint, o.head
+        ));
+    }
+    for (number, hash) in &o.chain {
+        match o.stored.get(number) {
+            Some(stored) if stored == hash => {}
+            Some(stored) => out.push(format!(
+                "block {} is stored with hash {:?}, the chain has {:?}",
+                number, stored, hash
+            )),
+            None if o.quarantined.contains(number) => {}
+            None => out.push(format!(
+                "block {} is neither stored nor quarantined",
+                number
+            )),
+        }
+    }
+    for number in o.stored.keys() {
+        if !o.chain.contains_key(number) {
+            out.push(format!(
+                "block {} is stored but has no logs in the chain",
+                number
+            ));
+        }
+        if o.checkpoint.is_none_or(|c| *number > c) {
+            out.push(format!("block {} is stored above the checkpoint", number));
+        }
+    }
+
+    let mut published: BTreeMap<LogId, usize> = BTreeMap::new();
+    for id in &o.published {
+        *published.entry(*id).or_default() += 1;
+    }
+    for (id, times) in &published {
+        if *times > 1 {
+            out.push(format!("log {} is published {} times", id, times));
+        }
+        if !o.stored_logs.contains(id) {
+            out.push(format!(
+                "log {} is published but its block is not stored",
+                id
+            ));
+        }
+    }
+    for id in &o.stored_logs {
+        if !published.contains_key(id) {
+            out.push(format!("log {} of a stored block is not published", id));
+        }
+    }
+    if o.last_seq != o.published.len() as u64 {
+        out.push(format!(
+            "last event is {}, {} events are stored",
+            o.last_seq,
+            o.published.len()
+        ));
+    }
+
+    let received: BTreeSet<u64> = o.delivered.iter().copied().collect();
+    let missing: Vec<u64> = (1..=o.last_seq).filter(|s| !received.contains(s)).collect();
+    if let Some(first) = missing.first() {
+        out.push(format!(
+            "{} events are not delivered, the first is {}",
+            missing.len(),
+            first
+        ));
+    } else {
+        let mut unique = o.delivered.clone();
+        unique.dedup();
+        if !unique.iter().copied().eq(1..=o.last_seq) {
+            out.push("events are not delivered in order".to_string());
+        }
+    }
+    if !o.dead_letter.is_empty() {
+        out.push(format!("{} events are dead letters", o.dead_letter.len()));
+    }
+    if o.delivered.len() as u64 != o.passed_posts {
+        out.push(format!(
+            "webhook received {} notifications, the transport passed {}",
+            o.delivered.len(),
+            o.passed_posts
+        ));
+    }
+    out
+}
+
+// stream after the stored checkpoint, created after every failed step
+// as a restarted follow would do, since the failed stream is ahead of the storage
+async fn restart<K: KV + Send + Sync>(
+    args: &FollowArgs,
+    rpc: &ChaosTransport<Provider>,
+    sinks: &[Sink<K>],
+) -> anyhow::Result<EthLogsStream> {
+    let client = EthBatchClient::new(&args.rpc_addr).with_transport(rpc.clone());
+    Ok(resume(args, client, sinks)
+        .await?
+        .block_retries(BLOCK_RETRIES, Duration::ZERO))
+}
+
+/// grows the chain by `blocks_per_round` until the duration is over and follows it
+/// after every round, then checks what was stored, published and delivered
+pub async fn soak(args: &SoakArgs, chaos: &ChaosFile, seed: u64) -> anyhow::Result<SoakReport> {
+    let mut g = ChainGenerator::seeded(seed).density(4, 2);
+    let chain = Arc::new(Mutex::new(g.mock()));
+    // every transport and bucket has its own sequence of failures
+    let rpc = ChaosTransport::new(
+        Provider(chain.clone()),
+        chaos.rpc.profile(seed.wrapping_add(1)),
+    );
+    let recorder = Recorder::default();
+    let hook = ChaosTransport::new(
+        recorder.clone(),
+        chaos.webhook.profile(seed.wrapping_add(2)),
+    );
+    let hook_counters = hook.counters();
+    let notifier = Notifier::new(Webhook::new(hook), &[WEBHOOK.to_string()], usize::MAX);
+
+    let memory = MemoryKV::new();
+    let buckets = [
+        (BLOCKS, seed.wrapping_add(3)),
+        (CHECKPOINT, seed.wrapping_add(4)),
+    ]
+    .into_iter()
+    .map(|(name, seed)| {
+        let bucket = ChaosKV::new(memory.bucket(name), chaos.kv.profile(seed));
+        (name.to_string(), bucket)
+    })
+    .collect();
+    let sinks = [Sink {
+        address: None,
+        bucket: BLOCKS.to_string(),
+        writer: Writer::new(buckets, CHECKPOINT)?,
+    }];
+    // the same buckets past the chaos wrappers, to see what was committed
+    let plain = {
+        let buckets = [BLOCKS, CHECKPOINT]
+            .into_iter()
+            .map(|name| (name.to_string(), memory.bucket(name)))
+            .collect();
+        Writer::new(buckets, CHECKPOINT)?
+    };
+    let quarantine = QuarantineBucket::new(memory.bucket("soak_quarantine"));
+    let events = EventLog::new(memory.bucket("soak_events"));
+    let follow = FollowArgs {
+        rpc_addr: "http://mock".to_string(),
+        addresses: vec![],
+        from: 1,
+        batch_size: BATCH_SIZE,
+        poll_interval: HumaneDuration::default(),
+        max_value_bytes: None,
+        oversize: OversizePolicy::Error,
+        once: false,
+        per_contract: false,
+        webhooks: vec![WEBHOOK.to_string()],
+        config: None,
+        metrics_listen: None,
+    };
+
+    let mut report = SoakReport {
+        seed,
+        ..Default::default()
+    };
+    let started = Instant::now();
+    let mut stream: Option<EthLogsStream> = None;
+    let mut failures_in_a_row = 0;
+    while report.rounds == 0 || started.elapsed() < args.duration.0 {
+        g.generate(args.blocks_per_round);
+        *chain.lock().unwrap() = g.mock();
+        report.rounds += 1;
+        let mut polled = false;
+        loop {
+            let step = async {
+                let s = match stream.take() {
+                    Some(s) => s,
+                    None => {
+                        polled = true;
+                        restart(&follow, &rpc, &sinks).await?
+                    }
+                };
+                let s = stream.insert(s);
+                if !polled {
+                    s.poll()?;
+                    polled = true;
+                }
+                follow_step(s, &sinks, &quarantine).await
+            };
+            match step.await {
+                Ok(Some(step)) => {
+                    failures_in_a_row = 0;
+                    notify::publish_logs(&events, Some(&notifier), &step.logs).await?;
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    debug!("soak step failed: {:#}", e);
+                    stream = None;
+                    report.failed_steps += 1;
+                    let partial = partial_batch(&plain).await?;
+                    if let Some(first) = partial.first() {
+                        report.violations.push(format!(
+                            "failed step left {} blocks from {} above the checkpoint",
+                            partial.len(),
+                            first
+                        ));
+                    }
+                    failures_in_a_row += 1;
+                    if failures_in_a_row >= MAX_FAILURES_IN_A_ROW {
+                        return Err(e.context(format!(
+                            "{} steps failed in a row, seed {}",
+                            failures_in_a_row, seed
+                        )));
+                    }
+                }
+            }
+        }
+        notifier.flush().await;
+        info!(
+            round = report.rounds,
+            checkpoint = stream.as_ref().map(|s| s.checkpoint()),
+            failed_steps = report.failed_steps,
+            "soak round"
+        );
+    }
+    let mut attempts = 0;
+    while notifier.pending().await > 0 && attempts < MAX_FAILURES_IN_A_ROW {
+        notifier.flush().await;
+        attempts += 1;
+    }
+
+    let observed = observe(&g, &plain, &quarantine, &events, &notifier, &recorder).await?;
+    report.head = observed.head;
+    report.stored = observed.stored.len();
+    report.quarantined = observed.quarantined.len();
+    report.published = observed.published.len();
+    for name in [BLOCKS, CHECKPOINT] {
+        report.failed_commits += sinks[0].writer.bucket(name)?.failed_commits();
+    }
+    report.rpc = Injected::from(&*rpc.counters());
+    report.webhook = Injected::from(&*hook_counters);
+    let mut unique = observed.delivered.clone();
+    unique.dedup();
+    report.duplicate_notifications = observed.delivered.len() - unique.len();
+    report.violations.extend(check(&Observed {
+        passed_posts: hook_counters.delivered() + report.webhook.duplicates,
+        ..observed
+    }));
+    Ok(report)
+}
+
+// blocks stored above the stored checkpoint, a failed batch leaves none
+// when it is committed atomically
+async fn partial_batch(plain: &Writer<MemoryKV>) -> anyhow::Result<Vec<u32>> {
+    let from = match plain.checkpoint().await? {
+        Some(checkpoint) => checkpoint + 1,
+        None => 0,
+    };
+    Ok(plain
+        .bucket(BLOCKS)?
+        .keys(from, u32::MAX)
+        .await?
+        .unwrap_or_default())
+}
+
+// reads what was stored and delivered past the chaos wrappers
+async fn observe(
+    g: &ChainGenerator,
+    plain: &Writer<MemoryKV>,
+    quarantine: &QuarantineBucket<MemoryKV>,
+    events: &EventLog<MemoryKV>,
+    notifier: &Notifier<Webhook>,
+    recorder: &Recorder,
+) -> anyhow::Result<Observed> {
+    let mut o = Observed::default();
+    for b in g.canonical() {
+        let number = b.block.number.context("no block number")?.as_u32();
+        o.head = o.head.max(number);
+        if !b.log_ids().is_empty() {
+            o.chain
+                .insert(number, b.block.hash.context("no block hash")?);
+        }
+    }
+    o.checkpoint = plain.checkpoint().await?;
+
+    let fd = jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION)?;
+    let blocks = plain.bucket(BLOCKS)?;
+    for number in blocks.keys(0, u32::MAX).await?.unwrap_or_default() {
+        let blob = match blocks.get(number).await? {
+            Some(blob) if !blob.is_empty() => blob,
+            _ => continue,
+        };
+        let value = jsondp::decode_slice(&blob, &fd, &NoDictionary {})
+            .with_context(|| format!("block {}", number))?;
+        let block = BlockTransactions::from_value(value)?;
+        o.stored
+            .insert(number, block.block.hash.context("no block hash")?);
+        o.stored_logs.extend(block.log_ids());
+    }
+    o.quarantined = quarantine
+        .list()
+        .await?
+        .iter()
+        .map(|q| q.block_number as u32)
+        .collect();
+
+    for e in events.since(0, usize::MAX).await? {
+        let id: LogId = serde_json::from_value(e.payload["id"].clone())
+            .with_context(|| format!("event {}", e.seq))?;
+        o.published.push(id);
+    }
+    o.last_seq = events.load_last().await?;
+    o.delivered = recorder.0.lock().unwrap().clone();
+    o.dead_letter = notifier.dead_letter(WEBHOOK).await;
+    Ok(o)
+}
+
+/// loads the profile and prints the seed before the soak starts,
+/// so a run which does not finish can be replayed too
+pub async fn run(args: &SoakArgs) -> anyhow::Result<SoakReport> {
+    let chaos = ChaosFile::load(&args.chaos_profile)?;
+    let seed = match args.seed {
+        Some(seed) => seed,
+        None => SystemTime::now().duration_since(UNIX_EPOCH)?.as_nanos() as u64,
+    };
+    println!("soak seed {}", seed);
+    soak(args, &chaos, seed).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // one round, so the failures depend on the seed only
+    fn args() -> SoakArgs {
+        SoakArgs {
+            chaos_profile: PathBuf::from("chaos.toml"),
+            duration: "0s".parse().unwrap(),
+            seed: None,
+            blocks_per_round: 60,
+            json: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_soaks_without_violations() {
+        let chaos: ChaosFile = toml::from_str(
+            r#"
+            [rpc]
+            timeout_rate = 0.02
+            server_error_rate = 0.02
+            malformed_rate = 0.02
+            duplicate_rate = 0.05
+
+            [webhook]
+            timeout_rate = 0.1
+            server_error_rate = 0.1
+            duplicate_rate = 0.1
+
+            [kv]
+            read_error_rate = 0.02
+            write_error_rate = 0.05
+            commit_error_rate = 0.2
+            "#,
+        )
+        .unwrap();
+        chaos.validate().unwrap();
+        let report = soak(&args(), &chaos, 7).await.unwrap();
+        assert!(report.violations.is_empty(), "{}", report);
+        assert_eq!(report.rounds, 1);
+        assert!(report.failed_steps > 0);
+        assert!(report.failed_commits > 0, "{}", report);
+        assert!(report.duplicate_notifications > 0);
+        assert!(report.published > 0);
+        assert!(report.to_string().contains("seed 7"));
+    }
+
+    #[test]
+    fn it_rejects_rates_out_of_range() {
+        let chaos: ChaosFile = toml::from_str("[webhook]\nduplicate_rate = 1.5").unwrap();
+        let err = chaos.validate().unwrap_err();
+        assert!(
+            err.to_string().contains("webhook.duplicate_rate"),
+            "{}",
+            err
+        );
+        assert!(toml::from_str::<ChaosFile>("[rpc]\ntimeout = 0.1").is_err());
+    }
+
+    #[test]
+    fn it_reports_gaps_lost_events_and_duplicates() {
+        let hash = H256::from_low_u64_be;
+        let id = LogId::new(hash(2), 0);
+        let observed = Observed {
+            chain: BTreeMap::from([(1, hash(1)), (2, hash(2))]),
+            head: 3,
+            checkpoint: Some(3),
+            stored: BTreeMap::from([(1, hash(1)), (2, hash(2))]),
+            stored_logs: BTreeSet::from([id]),
+            published: vec![id],
+            last_seq: 1,
+            delivered: vec![1, 1],
+            passed_posts: 2,
+            ..Default::default()
+        };
+        assert_eq!(check(&observed), Vec::<String>::new());
+
+        let mut gap = observed.clone();
+        gap.stored.remove(&1);
+        assert_eq!(
+            check(&gap),
+            vec!["block 1 is neither stored nor quarantined".to_string()]
+        );
+        gap.quarantined.insert(1);
+        assert!(check(&gap).is_empty());
+
+        let lost = Observed {
+            delivered: vec![],
+            passed_posts: 0,
+            ..observed.clone()
+        };
+        assert_eq!(
+            check(&lost),
+            vec!["1 events are not delivered, the first is 1".to_string()]
+        );
+
+        let twice = Observed {
+            published: vec![id, id],
+            ..observed.clone()
+        };
+        let violations = check(&twice);
+        assert!(
+            violations[0].contains("published 2 times"),
+            "{:?}",
+            violations
+        );
+        assert!(
+            violations[1].contains("last event is 1"),
+            "{:?}",
+            violations
+        );
+
+        let behind = Observed {
+            checkpoint: Some(1),
+            passed_posts: 3,
+            ..observed
+        };
+        assert_eq!(
+            check(&behind),
+            vec![
+                "checkpoint Some(1) is not the chain head 3".to_string(),
+                "block 2 is stored above the checkpoint".to_string(),
+                "webhook received 2 notifications, the transport passed 3".to_string(),
+            ]
+        );
+    }
+}
@@ -0,0 +1,130 @@
+use crate::humane::HumaneDuration;
+use clap::Args;
+use eth_logs::{EndpointHealth, EthMultiClient};
+use serde::Serialize;
+use std::fmt;
+
+/// bucket keeping endpoint health between restarts
+pub const ENDPOINTS_TABLE: &str = "btxs_endpoints";
+
+/// Health of the JSON-RPC endpoints as persisted by the running indexer
+#[derive(Debug, Clone, Args)]
+pub struct StatusArgs {
+    /// JSON-RPC endpoints, in the configured order
+    #[arg(long, env = "RPC_ETH_ADDR", value_delimiter = ',')]
+    pub rpc_addr: Vec<String>,
+    /// time after which counts are halved, e.g. 30m, instead of the persisted one
+    #[arg(long)]
+    pub half_life: Option<HumaneDuration>,
+    /// print report as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointStatus {
+    pub endpoint: String,
+    pub score: f64,
+    pub successes: f64,
+    pub failures: f64,
+    pub last_failure: Option<u64>,
+    pub median_latency_ms: Option<u32>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    /// endpoints from the preferred to the worst
+    pub endpoints: Vec<EndpointStatus>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for e in &self.endpoints {
+            let latency = e
+                .median_latency_ms
+                .map_or("-".to_string(), |ms| format!("{}ms", ms));
+            writeln!(
+                f,
+                "{:<40} score {:.3} ok {:.1} failed {:.1} latency {}",
+                e.endpoint, e.score, e.successes, e.failures, latency
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn report(args: &StatusArgs, mut health: Option<EndpointHealth>) -> Report {
+    if let Some(half_life) = args.half_life {
+        let health = health.get_or_insert_with(EndpointHealth::default);
+        health.half_life = half_life.0.as_secs();
+    }
+    let client = EthMultiClient::new(&args.rpc_addr, health.clone());
+    let health = health.unwrap_or_default();
+    let mut scores = client.scores();
+    scores.sort_by(|a, b| b.1.total_cmp(&a.1));
+    Report {
+        endpoints: statuses(&health, scores),
+    }
+}
+
+/// statistics of the scored endpoints
+pub fn statuses(health: &EndpointHealth, scores: Vec<(String, f64)>) -> Vec<EndpointStatus> {
+    scores
+        .into_iter()
+        .map(|(endpoint, score)| {
+            let stats = health.endpoints.get(&endpoint).cloned().unwrap_or_default();
+            EndpointStatus {
+                score,
+                successes: stats.successes,
+                failures: stats.failures,
+                last_failure: stats.last_failure,
+                median_latency_ms: stats.median_latency(),
+                endpoint,
+            }
+        })
+        .collect()
+}
+
+pub async fn run(args: &StatusArgs, database_url: &str) -> anyhow::Result<Report> {
+    let bucket = kv::PostgresKV::try_new(database_url, ENDPOINTS_TABLE).await?;
+    let health = EndpointHealth::load(&bucket).await?;
+    Ok(report(args, health))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn it_lists_endpoints_by_score() {
+        let mut health = EndpointHealth::default();
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        health.record_failure("http://a", now);
+        health.record_success("http://b", Duration::from_millis(50), now);
+        let args = StatusArgs {
+            rpc_addr: vec!["http://a".to_string(), "http://b".to_string()],
+            half_life: None,
+            json: false,
+        };
+        let r = report(&args, Some(health.clone()));
+        let order: Vec<&str> = r.endpoints.iter().map(|e| e.endpoint.as_str()).collect();
+        assert_eq!(order, vec!["http://b", "http://a"]);
+        assert_eq!(r.endpoints[0].median_latency_ms, Some(50));
+        assert!(r.to_string().contains("latency 50ms"));
+
+        // a failure an hour ago counts less with a shorter half-life
+        let mut health = EndpointHealth::default();
+        health.record_failure("http://a", now - 3600);
+        let hour = report(&args, Some(health.clone())).endpoints[1].score;
+        let args = StatusArgs {
+            half_life: Some("10m".parse().unwrap()),
+            ..args
+        };
+        let ten_minutes = report(&args, Some(health)).endpoints[1].score;
+        assert!(ten_minutes > hour, "{} {}", ten_minutes, hour);
+    }
+}
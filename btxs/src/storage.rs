@@ -0,0 +1,51 @@
+use clap::{Args, Subcommand};
+use tracing::*;
+
+#[derive(Debug, Clone, Subcommand)]
+pub enum KvCommand {
+    /// show or migrate the partitioned layout of the blocks table
+    Partition(PartitionArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct PartitionArgs {
+    /// copy the plain table into the partitioned layout
+    #[arg(long)]
+    pub migrate: bool,
+    /// number of keys in every partition
+    #[arg(long, default_value_t = 1_000_000)]
+    pub width: u32,
+    /// number of keys copied in one statement
+    #[arg(long, default_value_t = 10_000)]
+    pub batch: u32,
+}
+
+pub async fn run(cmd: &KvCommand, database_url: &str, table_name: &str) -> anyhow::Result<()> {
+    match cmd {
+        KvCommand::Partition(args) => {
+            let storage = if args.migrate {
+                kv::PostgresKV::migrate_to_partitioned(
+                    database_url,
+                    table_name,
+                    args.width,
+                    args.batch,
+                )
+                .await?
+            } else {
+                kv::PostgresKV::try_new_partitioned(database_url, table_name, args.width).await?
+            };
+            let partitions = storage.partitions().await?;
+            info!(table_name, partitions = partitions.len(), "partitioned");
+            for index in partitions {
+                println!(
+                    "{}_p{}\t{}..{}",
+                    table_name,
+                    index,
+                    index as u64 * args.width as u64,
+                    (index as u64 + 1) * args.width as u64
+                );
+            }
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,499 @@
+use crate::humane::ByteSize;
+use crate::manifest::StoredFormat;
+use anyhow::{bail, Context};
+use clap::ValueEnum;
+use kv::{ValueTooLarge, KV};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::*;
+
+/// key of the checkpoint in its bucket. The value is the block number,
+/// followed by the format of the stored data as JSON
+pub const CHECKPOINT_KEY: u32 = 0;
+
+/// Writes of one pipeline step: blocks, secondary indexes and counters,
+/// and the checkpoint that is valid once all of them are stored
+#[derive(Debug, Clone, Default)]
+pub struct Batch {
+    /// bucket name, key and value
+    pub writes: Vec<(String, u32, Vec<u8>)>,
+    pub checkpoint: u32,
+}
+
+impl Batch {
+    pub fn new(checkpoint: u32) -> Self {
+        Self {
+            writes: vec![],
+            checkpoint,
+        }
+    }
+
+    pub fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> &mut Self {
+        self.writes.push((bucket.to_string(), n, v));
+        self
+    }
+}
+
+/// What the writer does with values over `max_value_bytes`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OversizePolicy {
+    /// fail the batch, the pipeline halts
+    #[default]
+    Error,
+    /// store the batch without the value, the caller quarantines it
+    Skip,
+    /// split the value into chunks, needs chunked storage
+    Chunk,
+}
+
+/// `[writer]` section of the configuration
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriterConfig {
+    /// largest value to be stored, like "16MiB", no limit when missing
+    pub max_value_bytes: Option<ByteSize>,
+    #[serde(default)]
+    pub oversize: OversizePolicy,
+}
+
+/// Value left out of the batch by the skip policy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Oversized {
+    pub bucket: String,
+    pub key: u32,
+    pub size: usize,
+    pub limit: usize,
+}
+
+impl Oversized {
+    /// reason to be recorded in the quarantine
+    pub fn reason(&self) -> String {
+        format!(
+            "value of {} is {} bytes, over the limit of {} bytes",
+            self.bucket, self.size, self.limit
+        )
+    }
+}
+
+/// Oversized values by the policy applied to them
+#[derive(Debug, Default)]
+pub struct OversizeMetrics {
+    pub errors: AtomicU64,
+    pub skipped: AtomicU64,
+}
+
+/// Stores batches so that the checkpoint is never ahead of the data.
+/// With a transactional backend the batch is committed atomically,
+/// otherwise data is written first and the checkpoint last, so a crash
+/// in the middle only replays the batch
+pub struct Writer<K: KV> {
+    buckets: BTreeMap<String, K>,
+    checkpoint_bucket: String,
+    config: WriterConfig,
+    // stored with the checkpoint
+    format: Vec<u8>,
+    pub metrics: OversizeMetrics,
+}
+
+impl<K: KV + Send + Sync> Writer<K> {
+    /// buckets of the same storage by name, one of them keeps the checkpoint
+    pub fn new(buckets: BTreeMap<String, K>, checkpoint_bucket: &str) -> anyhow::Result<Self> {
+        if !buckets.contains_key(checkpoint_bucket) {
+            anyhow::bail!("no bucket {} for the checkpoint", checkpoint_bucket);
+        }
+        Ok(Self {
+            buckets,
+            checkpoint_bucket: checkpoint_bucket.to_string(),
+            config: WriterConfig::default(),
+            format: serde_json::to_vec(&StoredFormat::current()?)?,
+            metrics: OversizeMetrics::default(),
+        })
+    }
+
+    pub fn with_config(mut self, config: WriterConfig) -> anyhow::Result<Self> {
+        if config.oversize == OversizePolicy::Chunk {
+            bail!("oversize policy chunk needs chunked storage, which is not available");
+        }
+        self.config = config;
+        Ok(self)
+    }
+
+    // applies the oversize policy, returns values left out of the batch
+    fn check_sizes(&self, batch: &mut Batch) -> anyhow::Result<Vec<Oversized>> {
+        let limit = match self.config.max_value_bytes {
+            Some(limit) => limit.as_usize(),
+            None => return Ok(vec![]),
+        };
+        let mut skipped = vec![];
+        for (bucket, key, v) in &batch.writes {
+            if v.len() <= limit {
+                continue;
+            }
+            let size = v.len();
+            match self.config.oversize {
+                OversizePolicy::Skip => {
+                    self.metrics.skipped.fetch_add(1, Ordering::Relaxed);
+                    warn!(
+                        bucket = bucket.as_str(),
+                        key, size, "oversized value skipped"
+                    );
+                    skipped.push(Oversized {
+                        bucket: bucket.clone(),
+                        key: *key,
+                        size,
+                        limit,
+                    });
+                }
+                OversizePolicy::Error | OversizePolicy::Chunk => {
+                    self.metrics.errors.fetch_add(1, Ordering::Relaxed);
+                    let key = *key;
+                    return Err(anyhow::Error::new(ValueTooLarge { key, size, limit })
+                        .context(format!("bucket {}", bucket)));
+                }
+            }
+        }
+        batch.writes.retain(|(_, _, v)| v.len() <= limit);
+        Ok(skipped)
+    }
+
+    /// bucket of the writer by its name
+    pub fn bucket(&self, name: &str) -> anyhow::Result<&K> {
+        self.buckets
+            .get(name)
+            .with_context(|| format!("unknown bucket {}", name))
+    }
+
+    async fn checkpoint_value(&self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.bucket(&self.checkpoint_bucket)?
+            .get(CHECKPOINT_KEY)
+            .await
+    }
+
+    /// last stored checkpoint
+    pub async fn checkpoint(&self) -> anyhow::Result<Option<u32>> {
+        match self.checkpoint_value().await? {
+            Some(bytes) => {
+                let b: [u8; 4] = bytes.get(..4).context("checkpoint")?.try_into()?;
+                Ok(Some(u32::from_le_bytes(b)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// format the checkpoint was stored with, None for checkpoints of older builds
+    pub async fn stored_format(&self) -> anyhow::Result<Option<StoredFormat>> {
+        match self.checkpoint_value().await? {
+            Some(bytes) if bytes.len() > 4 => Ok(Some(
+                serde_json::from_slice(&bytes[4..]).context("checkpoint format")?,
+            )),
+            _ => Ok(None),
+        }
+    }
+
+    /// fails when data before the checkpoint was stored in a format this build can't continue
+    pub async fn ensure_resumable(&self) -> anyhow::Result<()> {
+        if let Some(stored) = self.stored_format().await? {
+            StoredFormat::current()?
+                .ensure_compatible(&stored)
+                .with_context(|| format!("can't resume from {}", self.checkpoint_bucket))?;
+        }
+        Ok(())
+    }
+
+    /// stores the batch, returns the values left out by the skip policy
+    pub async fn write(&self, mut batch: Batch) -> anyhow::Result<Vec<Oversized>> {
+        for (bucket, _, _) in &batch.writes {
+            self.bucket(bucket)?;
+        }
+        let skipped = self.check_sizes(&mut batch)?;
+        let mut checkpoint = batch.checkpoint.to_le_bytes().to_vec();
+        checkpoint.extend_from_slice(&self.format);
+        let storage = self.bucket(&self.checkpoint_bucket)?;
+        let mut txn = match storage.transaction().await? {
+            Some(txn) => txn,
+            None => {
+                for (bucket, n, v) in batch.writes {
+                    self.bucket(&bucket)?.set(n, v).await?;
+                }
+                storage.set(CHECKPOINT_KEY, checkpoint).await?;
+                return Ok(skipped);
+            }
+        };
+        let mut result = Ok(());
+        for (bucket, n, v) in batch.writes {
+            result = txn.set(&bucket, n, v).await;
+            if result.is_err() {
+                break;
+            }
+        }
+        if result.is_ok() {
+            result = txn
+                .set(&self.checkpoint_bucket, CHECKPOINT_KEY, checkpoint)
+                .await;
+        }
+        match result {
+            Ok(()) => {
+                txn.commit().await?;
+                Ok(skipped)
+            }
+            Err(e) => {
+                if let Err(rollback) = txn.rollback().await {
+                    warn!("rollback failed: {:#}", rollback);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use eth_logs::fixtures::ChainGenerator;
+    use eth_logs::QuarantineBucket;
+    use jsondp::dictionary::NoDictionary;
+    use kv::{KvTxn, MemoryKV};
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Arc;
+
+    // storage that crashes after the given number of writes, across all its buckets
+    struct CrashKV {
+        inner: MemoryKV,
+        left: Arc<AtomicUsize>,
+        transactional: bool,
+    }
+
+    impl CrashKV {
+        fn tick(left: &AtomicUsize) -> anyhow::Result<()> {
+            left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .map(|_| ())
+                .map_err(|_| anyhow::anyhow!("crash"))
+        }
+    }
+
+    #[async_trait]
+    impl KV for CrashKV {
+        async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>> {
+            self.inner.get(n).await
+        }
+
+        async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+            Self::tick(&self.left)?;
+            self.inner.set(n, v).await
+        }
+
+        async fn transaction(&self) -> anyhow::Result<Option<Box<dyn KvTxn + '_>>> {
+            if !self.transactional {
+                return Ok(None);
+            }
+            let inner = self.inner.transaction().await?.unwrap();
+            Ok(Some(Box::new(CrashTxn {
+                inner,
+                left: &self.left,
+            })))
+        }
+    }
+
+    struct CrashTxn<'a> {
+        inner: Box<dyn KvTxn + 'a>,
+        left: &'a AtomicUsize,
+    }
+
+    #[async_trait]
+    impl KvTxn for CrashTxn<'_> {
+        async fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+            CrashKV::tick(self.left)?;
+            self.inner.set(bucket, n, v).await
+        }
+
+        async fn delete(&mut self, bucket: &str, n: u32) -> anyhow::Result<()> {
+            self.inner.delete(bucket, n).await
+        }
+
+        async fn commit(self: Box<Self>) -> anyhow::Result<()> {
+            self.inner.commit().await
+        }
+
+        async fn rollback(self: Box<Self>) -> anyhow::Result<()> {
+            self.inner.rollback().await
+        }
+    }
+
+    fn writer(crash_after: usize, transactional: bool) -> Writer<CrashKV> {
+        let memory = MemoryKV::new();
+        let left = Arc::new(AtomicUsize::new(crash_after));
+        let buckets = ["blocks", "index", "checkpoint"]
+            .into_iter()
+            .map(|name| {
+                let kv = CrashKV {
+                    inner: memory.bucket(name),
+                    left: left.clone(),
+                    transactional,
+                };
+                (name.to_string(), kv)
+            })
+            .collect();
+        Writer::new(buckets, "checkpoint").unwrap()
+    }
+
+    fn batch(number: u32) -> Batch {
+        let mut batch = Batch::new(number);
+        batch
+            .set("blocks", number, vec![1])
+            .set("index", number, vec![2])
+            .set("index", u32::MAX, number.to_le_bytes().to_vec());
+        batch
+    }
+
+    // every stored checkpoint must have all its data stored
+    async fn assert_consistent(w: &Writer<CrashKV>) {
+        if let Some(checkpoint) = w.checkpoint().await.unwrap() {
+            for number in 1..=checkpoint {
+                assert!(w.buckets["blocks"].get(number).await.unwrap().is_some());
+                assert!(w.buckets["index"].get(number).await.unwrap().is_some());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn it_never_stores_checkpoint_ahead_of_data() {
+        for transactional in [true, false] {
+            // 3 batches of 4 writes, crash at every position
+            for crash_after in 0..12 {
+                let w = writer(crash_after, transactional);
+                let mut stored = 0;
+                for number in 1..=3 {
+                    if w.write(batch(number)).await.is_err() {
+                        break;
+                    }
+                    stored = number;
+                }
+                assert_eq!(stored, crash_after as u32 / 4);
+                assert_consistent(&w).await;
+                let checkpoint = w.checkpoint().await.unwrap();
+                assert_eq!(checkpoint, (stored > 0).then_some(stored));
+                if transactional {
+                    // nothing of the failed batch is visible
+                    assert_eq!(w.buckets["blocks"].get(stored + 1).await.unwrap(), None);
+                }
+            }
+        }
+    }
+
+    // generated block that encodes to more than 16 KiB
+    fn oversized_block() -> (eth_logs::BlockTransactions, Vec<u8>) {
+        let mut g = ChainGenerator::seeded(3).density(200, 4);
+        let block = g.generate(1).remove(0);
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        let mut blob = vec![];
+        jsondp::encode(&block.to_value().unwrap(), &mut blob, &fd, &NoDictionary {}).unwrap();
+        assert!(blob.len() > 16384, "block of {} bytes", blob.len());
+        (block, blob)
+    }
+
+    fn limited(oversize: OversizePolicy) -> anyhow::Result<Writer<CrashKV>> {
+        writer(usize::MAX, true).with_config(WriterConfig {
+            max_value_bytes: Some("16KiB".parse().unwrap()),
+            oversize,
+        })
+    }
+
+    #[tokio::test]
+    async fn it_fails_oversized_values_by_default() {
+        let (_, blob) = oversized_block();
+        let w = limited(OversizePolicy::Error).unwrap();
+        w.write(batch(1)).await.unwrap();
+        let mut b = batch(2);
+        b.set("blocks", 2, blob.clone());
+        let err = w.write(b).await.unwrap_err();
+        let too_large = err.downcast_ref::<ValueTooLarge>().unwrap();
+        assert_eq!((too_large.key, too_large.size), (2, blob.len()));
+        assert_eq!(w.checkpoint().await.unwrap(), Some(1));
+        assert_eq!(w.metrics.errors.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn it_skips_and_quarantines_oversized_values() {
+        let (block, blob) = oversized_block();
+        let number = block.block.number.unwrap().as_u32();
+        let w = limited(OversizePolicy::Skip).unwrap();
+        let mut b = Batch::new(number);
+        b.set("blocks", number, blob.clone())
+            .set("index", number, vec![2]);
+        let skipped = w.write(b).await.unwrap();
+        assert_eq!(
+            skipped,
+            vec![Oversized {
+                bucket: "blocks".to_string(),
+                key: number,
+                size: blob.len(),
+                limit: 16384,
+            }]
+        );
+        // the rest of the batch and the checkpoint are stored
+        assert_eq!(w.checkpoint().await.unwrap(), Some(number));
+        assert_eq!(w.buckets["blocks"].get(number).await.unwrap(), None);
+        assert_eq!(w.buckets["index"].get(number).await.unwrap(), Some(vec![2]));
+        assert_eq!(w.metrics.skipped.load(Ordering::Relaxed), 1);
+
+        let quarantine = QuarantineBucket::new(MemoryKV::new());
+        let entry = crate::quarantine::oversized(&block, skipped[0].reason());
+        assert!(!entry.logs.is_empty());
+        quarantine.add(entry).await.unwrap();
+        let listed = quarantine.list().await.unwrap();
+        assert_eq!(listed[0].block_number, number as u64);
+        assert!(listed[0].error.contains("over the limit of 16384 bytes"));
+    }
+
+    #[tokio::test]
+    async fn it_refuses_to_resume_other_formats() {
+        let w = writer(usize::MAX, true);
+        w.ensure_resumable().await.unwrap();
+        w.write(batch(1)).await.unwrap();
+        assert_eq!(
+            w.stored_format().await.unwrap(),
+            Some(StoredFormat::current().unwrap())
+        );
+        w.ensure_resumable().await.unwrap();
+
+        // checkpoint of an older build keeps the number only
+        let storage = &w.buckets["checkpoint"];
+        storage
+            .set(CHECKPOINT_KEY, 7u32.to_le_bytes().to_vec())
+            .await
+            .unwrap();
+        assert_eq!(w.checkpoint().await.unwrap(), Some(7));
+        w.ensure_resumable().await.unwrap();
+
+        let mut value = 7u32.to_le_bytes().to_vec();
+        value.extend_from_slice(br#"{"version":1,"dictionary":"00"}"#);
+        storage.set(CHECKPOINT_KEY, value).await.unwrap();
+        let err = w.ensure_resumable().await.unwrap_err();
+        assert!(
+            format!("{:#}", err)
+                .contains("can't resume from checkpoint: stored with field dictionary 00"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn it_rejects_chunking_without_chunked_storage() {
+        let err = limited(OversizePolicy::Chunk).err().unwrap();
+        assert!(err.to_string().contains("chunked storage"));
+        let config: WriterConfig =
+            serde_json::from_str(r#"{"max_value_bytes": "1MiB", "oversize": "skip"}"#).unwrap();
+        assert_eq!(config.oversize, OversizePolicy::Skip);
+        assert_eq!(config.max_value_bytes, Some(ByteSize(1 << 20)));
+        assert_eq!(
+            serde_json::to_string(&config).unwrap(),
+            r#"{"max_value_bytes":"1MiB","oversize":"skip"}"#
+        );
+        // integers written before units are bytes
+        let config: WriterConfig = serde_json::from_str(r#"{"max_value_bytes": 1024}"#).unwrap();
+        assert_eq!(config.max_value_bytes, Some(ByteSize(1024)));
+    }
+}
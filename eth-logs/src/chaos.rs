@@ -0,0 +1,202 @@
+use crate::Transport;
+use anyhow::bail;
+use kv::chaos::Chaos;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::*;
+
+/// Failure injection settings of the transport, all rates are probabilities in [0, 1]
+#[derive(Debug, Clone, Default)]
+pub struct TransportChaosProfile {
+    /// seed of the random generator, same seed gives the same failures
+    pub seed: u64,
+    /// probability of the request timing out before it reaches the provider
+    pub timeout_rate: f64,
+    /// probability of the provider answering 503 without handling the request
+    pub server_error_rate: f64,
+    /// probability of the response being cut in the middle
+    pub malformed_rate: f64,
+    /// probability of the request being delayed by `slow_delay`
+    pub slow_rate: f64,
+    /// delay of slow requests
+    pub slow_delay: Duration,
+    /// probability of the request being sent twice, e.g. a notification
+    /// retried by a proxy after the first one was delivered
+    pub duplicate_rate: f64,
+}
+
+/// Requests seen by the chaos transport and failures injected into them
+#[derive(Debug, Default)]
+pub struct ChaosCounters {
+    pub requests: AtomicU64,
+    pub timeouts: AtomicU64,
+    pub server_errors: AtomicU64,
+    pub malformed: AtomicU64,
+    pub slow: AtomicU64,
+    pub duplicates: AtomicU64,
+}
+
+impl ChaosCounters {
+    /// requests which reached the inner transport, duplicates not counted
+    pub fn delivered(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+            - self.timeouts.load(Ordering::Relaxed)
+            - self.server_errors.load(Ordering::Relaxed)
+    }
+}
+
+/// Transport wrapper that injects failures, slow and duplicated requests.
+/// Clones share the random generator and the counters, so a client created
+/// again after a failure continues the same sequence of failures
+#[derive(Clone)]
+pub struct ChaosTransport<T: Transport> {
+    inner: T,
+    profile: TransportChaosProfile,
+    chaos: Arc<Chaos>,
+    counters: Arc<ChaosCounters>,
+}
+
+impl<T: Transport> ChaosTransport<T> {
+    pub fn new(inner: T, profile: TransportChaosProfile) -> Self {
+        // seed must be visible in the logs so the failing run can be replayed
+        info!(seed = profile.seed, "chaos transport enabled");
+        let chaos = Arc::new(Chaos::new(profile.seed));
+        Self {
+            inner,
+            profile,
+            chaos,
+            counters: Arc::default(),
+        }
+    }
+
+    pub fn counters(&self) -> Arc<ChaosCounters> {
+        self.counters.clone()
+    }
+
+    fn count(&self, counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl<T: Transport> Transport for ChaosTransport<T> {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+        let c = &self.counters;
+        self.count(&c.requests);
+        if self.chaos.roll(self.profile.slow_rate) {
+            self.count(&c.slow);
+            std::thread::sleep(self.profile.slow_delay);
+        }
+        if self.chaos.roll(self.profile.timeout_rate) {
+            self.count(&c.timeouts);
+            bail!("{}: chaos: injected timeout", url);
+        }
+        if self.chaos.roll(self.profile.server_error_rate) {
+            self.count(&c.server_errors);
+            bail!("{}: status code 503", url);
+        }
+        let mut response = self.inner.post(url, headers, body)?;
+        if self.chaos.roll(self.profile.duplicate_rate) {
+            self.count(&c.duplicates);
+            response = self.inner.post(url, headers, body)?;
+        }
+        if self.chaos.roll(self.profile.malformed_rate) {
+            self.count(&c.malformed);
+            response.truncate(response.len() / 2);
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fixtures::ChainGenerator;
+    use crate::{EthBatchClient, EthLogsStream};
+
+    #[test]
+    fn it_injects_every_failure() {
+        let g = {
+            let mut g = ChainGenerator::seeded(3);
+            g.generate(2);
+            g
+        };
+        let profile = TransportChaosProfile {
+            seed: 9,
+            timeout_rate: 0.2,
+            server_error_rate: 0.2,
+            malformed_rate: 0.2,
+            duplicate_rate: 0.2,
+            ..Default::default()
+        };
+        let transport = ChaosTransport::new(g.mock(), profile.clone());
+        let body = r#"{"jsonrpc":"2.0","id":"latest","method":"eth_blockNumber","params":[]}"#;
+        let results: Vec<String> = (0..200)
+            .map(|_| match transport.post("http://mock", &[], body) {
+                Ok(response) => response,
+                Err(e) => e.to_string(),
+            })
+            .collect();
+        let c = transport.counters();
+        for counter in [&c.timeouts, &c.server_errors, &c.malformed, &c.duplicates] {
+            assert!(counter.load(Ordering::Relaxed) > 0);
+        }
+        assert_eq!(c.requests.load(Ordering::Relaxed), 200);
+        assert!(results.iter().any(|r| r.contains("status code 503")));
+        assert!(results.iter().any(|r| r.contains("timeout")));
+        assert!(results
+            .iter()
+            .any(|r| serde_json::from_str::<serde_json::Value>(r).is_err()));
+
+        // the same seed replays the same failures
+        let again = ChaosTransport::new(g.mock(), profile);
+        let replayed: Vec<String> = (0..200)
+            .map(|_| match again.post("http://mock", &[], body) {
+                Ok(response) => response,
+                Err(e) => e.to_string(),
+            })
+            .collect();
+        assert_eq!(replayed, results);
+    }
+
+    #[test]
+    fn it_streams_every_block_when_ranges_are_retried() {
+        let mut g = ChainGenerator::seeded(5).density(4, 2);
+        g.generate(20);
+        let transport = ChaosTransport::new(
+            g.mock(),
+            TransportChaosProfile {
+                seed: 1,
+                timeout_rate: 0.1,
+                malformed_rate: 0.1,
+                ..Default::default()
+            },
+        );
+        let counters = transport.counters();
+        let client = EthBatchClient::new("http://mock").with_transport(transport);
+        let stream = EthLogsStream::new(client, 1, 4, vec![], None, None, None, None)
+            .unwrap()
+            .block_retries(100, Duration::ZERO);
+        let mut numbers = vec![];
+        let mut failures = 0;
+        loop {
+            match stream.next() {
+                Ok(Some(blocks)) => {
+                    numbers.extend(blocks.iter().map(|b| b.block.number.unwrap().as_u64()))
+                }
+                Ok(None) => break,
+                Err(_) => failures += 1,
+            }
+        }
+        assert!(failures > 0);
+        assert!(counters.malformed.load(Ordering::Relaxed) > 0);
+        let with_logs: Vec<u64> = g
+            .canonical()
+            .iter()
+            .filter(|b| !b.log_ids().is_empty())
+            .map(|b| b.block.number.unwrap().as_u64())
+            .collect();
+        assert_eq!(numbers, with_logs);
+        assert!(stream.take_quarantined().is_empty());
+    }
+}
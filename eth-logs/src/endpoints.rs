@@ -0,0 +1,281 @@
+use crate::{EthBatchClient, RpcBatchResponse, RpcSingleRequest};
+use anyhow::Context;
+use kv::KV;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::*;
+
+// latencies kept for the median
+const LATENCY_WINDOW: usize = 32;
+
+/// key of the health record in its bucket
+pub const HEALTH_KEY: u32 = 0;
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// Rolling statistics of one endpoint. Counts decay with the half-life
+/// of the health record, so old failures stop mattering
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EndpointStats {
+    pub successes: f64,
+    pub failures: f64,
+    /// unix time of the last failure
+    pub last_failure: Option<u64>,
+    /// unix time when the counts were decayed last
+    pub updated: u64,
+    /// latest latencies in milliseconds
+    pub latencies: VecDeque<u32>,
+}
+
+impl EndpointStats {
+    fn decay(&mut self, now: u64, half_life: u64) {
+        if now > self.updated && half_life > 0 {
+            let factor = 0.5f64.powf((now - self.updated) as f64 / half_life as f64);
+            self.successes *= factor;
+            self.failures *= factor;
+        }
+        self.updated = self.updated.max(now);
+    }
+
+    pub fn median_latency(&self) -> Option<u32> {
+        let mut sorted: Vec<u32> = self.latencies.iter().copied().collect();
+        sorted.sort_unstable();
+        sorted.get(sorted.len() / 2).copied()
+    }
+
+    /// higher is better: share of successful requests, lowered by slow responses
+    pub fn score(&self, now: u64, half_life: u64) -> f64 {
+        let mut s = self.clone();
+        s.decay(now, half_life);
+        let reliability = (s.successes + 1.0) / (s.successes + s.failures + 1.0);
+        let latency = s.median_latency().unwrap_or(0) as f64 / 1000.0;
+        reliability / (1.0 + latency)
+    }
+}
+
+/// Health of all endpoints, persisted between restarts
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EndpointHealth {
+    /// seconds after which counts are halved
+    pub half_life: u64,
+    pub endpoints: BTreeMap<String, EndpointStats>,
+}
+
+impl Default for EndpointHealth {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(3600))
+    }
+}
+
+impl EndpointHealth {
+    pub fn new(half_life: Duration) -> Self {
+        Self {
+            half_life: half_life.as_secs(),
+            endpoints: BTreeMap::new(),
+        }
+    }
+
+    fn stats(&mut self, endpoint: &str, now: u64) -> &mut EndpointStats {
+        let half_life = self.half_life;
+        let stats = self.endpoints.entry(endpoint.to_string()).or_default();
+        stats.decay(now, half_life);
+        stats
+    }
+
+    pub fn record_success(&mut self, endpoint: &str, latency: Duration, now: u64) {
+        let stats = self.stats(endpoint, now);
+        stats.successes += 1.0;
+        stats.latencies.push_back(latency.as_millis() as u32);
+        if stats.latencies.len() > LATENCY_WINDOW {
+            stats.latencies.pop_front();
+        }
+    }
+
+    pub fn record_failure(&mut self, endpoint: &str, now: u64) {
+        let stats = self.stats(endpoint, now);
+        stats.failures += 1.0;
+        stats.last_failure = Some(now);
+    }
+
+    /// scores of the endpoints, unknown endpoints get the best score
+    pub fn scores(&self, endpoints: &[String], now: u64) -> Vec<(String, f64)> {
+        endpoints
+            .iter()
+            .map(|e| {
+                let score = self
+                    .endpoints
+                    .get(e)
+                    .map_or(1.0, |s| s.score(now, self.half_life));
+                (e.clone(), score)
+            })
+            .collect()
+    }
+
+    /// endpoints from the best to the worst, configured order breaks ties
+    pub fn preferred_order(&self, endpoints: &[String], now: u64) -> Vec<String> {
+        let mut scored = self.scores(endpoints, now);
+        scored.sort_by(|a, b| b.1.total_cmp(&a.1));
+        scored.into_iter().map(|(e, _)| e).collect()
+    }
+
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(self)?)
+    }
+
+    pub fn from_bytes(input: &[u8]) -> anyhow::Result<Self> {
+        serde_json::from_slice(input).context("endpoint health")
+    }
+
+    /// writes into the dedicated bucket
+    pub async fn save<K: KV>(&self, bucket: &K) -> anyhow::Result<()> {
+        bucket.set(HEALTH_KEY, self.to_bytes()?).await
+    }
+
+    /// reads from the dedicated bucket, empty health if nothing was saved yet
+    pub async fn load<K: KV>(bucket: &K) -> anyhow::Result<Option<Self>> {
+        match bucket.get(HEALTH_KEY).await? {
+            Some(bytes) => Ok(Some(Self::from_bytes(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Client over several endpoints of the same chain. Requests go to the healthiest
+/// endpoint first and fail over to the next ones
+pub struct EthMultiClient {
+    clients: Vec<(String, EthBatchClient)>,
+    health: Mutex<EndpointHealth>,
+    // when the health was handed out for persisting last time
+    persisted: Mutex<Instant>,
+    persist_interval: Duration,
+}
+
+impl EthMultiClient {
+    /// creates the client, seeding health scores from the previous run
+    pub fn new(rpc_addrs: &[String], health: Option<EndpointHealth>) -> Self {
+        Self {
+            clients: rpc_addrs
+                .iter()
+                .map(|a| (a.clone(), EthBatchClient::new(a)))
+                .collect(),
+            health: Mutex::new(health.unwrap_or_default()),
+            persisted: Mutex::new(Instant::now()),
+            persist_interval: Duration::from_secs(300),
+        }
+    }
+
+    fn addrs(&self) -> Vec<String> {
+        self.clients.iter().map(|(a, _)| a.clone()).collect()
+    }
+
+    /// endpoints from the best to the worst
+    pub fn preferred_order(&self) -> Vec<String> {
+        self.health
+            .lock()
+            .unwrap()
+            .preferred_order(&self.addrs(), unix_now())
+    }
+
+    pub fn scores(&self) -> Vec<(String, f64)> {
+        self.health
+            .lock()
+            .unwrap()
+            .scores(&self.addrs(), unix_now())
+    }
+
+    pub fn get(&self, requests: Vec<RpcSingleRequest>) -> anyhow::Result<RpcBatchResponse> {
+        let mut last_error = None;
+        for addr in self.preferred_order() {
+            let (_, client) = self
+                .clients
+                .iter()
+                .find(|(a, _)| *a == addr)
+                .context("endpoint")?;
+            let started = Instant::now();
+            let result = client.get(requests.clone());
+            let mut health = self.health.lock().unwrap();
+            match result {
+                Ok(response) => {
+                    health.record_success(&addr, started.elapsed(), unix_now());
+                    return Ok(response);
+                }
+                Err(e) => {
+                    warn!(endpoint = addr.as_str(), "request failed: {:#}", e);
+                    health.record_failure(&addr, unix_now());
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("no endpoints configured")))
+    }
+
+    /// snapshot of the health to be saved, once in the persist interval
+    pub fn health_to_persist(&self) -> Option<EndpointHealth> {
+        let mut persisted = self.persisted.lock().unwrap();
+        if persisted.elapsed() < self.persist_interval {
+            return None;
+        }
+        *persisted = Instant::now();
+        Some(self.health.lock().unwrap().clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::MemoryKV;
+
+    fn endpoints() -> Vec<String> {
+        vec!["http://a".to_string(), "http://b".to_string()]
+    }
+
+    #[tokio::test]
+    async fn it_reloads_health_and_decays_failures() {
+        let t0 = 1_700_000_000;
+        let mut health = EndpointHealth::new(Duration::from_secs(3600));
+        for i in 0..10 {
+            health.record_failure("http://a", t0 + i);
+            health.record_success("http://b", Duration::from_millis(80), t0 + i);
+        }
+        health.record_success("http://a", Duration::from_millis(20), t0 + 10);
+
+        let bucket = MemoryKV::new();
+        assert_eq!(EndpointHealth::load(&bucket).await.unwrap(), None);
+        health.save(&bucket).await.unwrap();
+        let reloaded = EndpointHealth::load(&bucket).await.unwrap().unwrap();
+        // counts survive the round trip up to float formatting
+        let (a, b) = (
+            &reloaded.endpoints["http://a"],
+            &health.endpoints["http://a"],
+        );
+        assert!((a.failures - b.failures).abs() < 1e-9);
+        assert_eq!(a.latencies, b.latencies);
+
+        // flaky endpoint goes last right after restart
+        assert_eq!(
+            reloaded.preferred_order(&endpoints(), t0 + 60),
+            vec!["http://b", "http://a"]
+        );
+        assert_eq!(reloaded.endpoints["http://a"].last_failure, Some(t0 + 9));
+        // a day later old failures decayed and a faster endpoint wins again
+        assert_eq!(
+            reloaded.preferred_order(&endpoints(), t0 + 86400),
+            vec!["http://a", "http://b"]
+        );
+    }
+
+    #[test]
+    fn it_prefers_unknown_endpoints_in_configured_order() {
+        let health = EndpointHealth::default();
+        assert_eq!(health.preferred_order(&endpoints(), 0), endpoints());
+        let client = EthMultiClient::new(&endpoints(), Some(health));
+        assert_eq!(client.preferred_order(), endpoints());
+        assert!(client.health_to_persist().is_none());
+    }
+}
@@ -0,0 +1,698 @@
+//! Deterministic synthetic chain for tests and benchmarks.
+//!
+//! The same seed and settings give the same blocks, hashes and logs.
+//! `GENERATOR_VERSION` is bumped whenever a release changes the output for
+//! the same seed, golden tests should pin it. JSON follows the ethers types,
+//! so its formatting may change with ethers even when the version does not.
+
+use crate::{BlockTransactions, Transport};
+use anyhow::{bail, Context};
+use ethers::abi::ethereum_types::BloomInput;
+use ethers::types::transaction::eip2930::AccessList;
+use ethers::types::{
+    Address, Block, Bloom, Bytes, Log, Transaction, TransactionReceipt, TxHash, H256, U256, U64,
+};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+
+/// changes when the generator output for the same seed and settings changes
+pub const GENERATOR_VERSION: u32 = 1;
+
+const GENESIS_TIME: u64 = 1_700_000_000;
+const BLOCK_TIME: u64 = 12;
+const INITIAL_BASE_FEE: u64 = 1_000_000_000;
+
+// splitmix64, stable across platforms and releases
+#[derive(Debug, Clone)]
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    // number in 0..n, 0 for n == 0
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            0
+        } else {
+            self.next_u64() % n
+        }
+    }
+
+    fn h256(&mut self) -> H256 {
+        let mut b = [0u8; 32];
+        for chunk in b.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next_u64().to_be_bytes());
+        }
+        H256(b)
+    }
+
+    fn address(&mut self) -> Address {
+        Address::from(self.h256())
+    }
+
+    fn pick<T: Clone>(&mut self, items: &[T]) -> Option<T> {
+        if items.is_empty() {
+            return None;
+        }
+        Some(items[self.below(items.len() as u64) as usize].clone())
+    }
+}
+
+/// Replaces the last `depth` blocks with other ones before block `at` is generated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reorg {
+    pub at: u64,
+    pub depth: u64,
+}
+
+/// Generator of blocks with transactions, receipts and logs
+#[derive(Debug, Clone)]
+pub struct ChainGenerator {
+    rng: Rng,
+    chain_id: u64,
+    first_block: u64,
+    london_block: u64,
+    gas_limit: u64,
+    max_txs_per_block: u64,
+    max_logs_per_tx: u64,
+    contracts: Vec<Address>,
+    events: Vec<H256>,
+    tx_types: Vec<u64>,
+    reorgs: Vec<Reorg>,
+    canonical: Vec<BlockTransactions>,
+    orphaned: Vec<BlockTransactions>,
+}
+
+impl ChainGenerator {
+    /// generator with a few contracts and event signatures picked by the seed
+    pub fn seeded(seed: u64) -> Self {
+        let mut rng = Rng(seed);
+        let contracts = (0..3).map(|_| rng.address()).collect();
+        let events = (0..4).map(|_| rng.h256()).collect();
+        Self {
+            rng,
+            chain_id: 1,
+            first_block: 1,
+            london_block: 0,
+            gas_limit: 30_000_000,
+            max_txs_per_block: 8,
+            max_logs_per_tx: 3,
+            contracts,
+            events,
+            tx_types: vec![0, 1, 2],
+            reorgs: vec![],
+            canonical: vec![],
+            orphaned: vec![],
+        }
+    }
+
+    pub fn chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = chain_id;
+        self
+    }
+
+    /// number of the first generated block
+    pub fn first_block(mut self, number: u64) -> Self {
+        self.first_block = number;
+        self
+    }
+
+    /// first block with base fee and typed transactions, earlier blocks are legacy
+    pub fn london_block(mut self, number: u64) -> Self {
+        self.london_block = number;
+        self
+    }
+
+    pub fn gas_limit(mut self, gas_limit: u64) -> Self {
+        self.gas_limit = gas_limit;
+        self
+    }
+
+    /// density: up to `txs` transactions in a block, up to `logs` logs in a transaction
+    pub fn density(mut self, txs: u64, logs: u64) -> Self {
+        self.max_txs_per_block = txs;
+        self.max_logs_per_tx = logs;
+        self
+    }
+
+    /// contracts that receive transactions and emit logs
+    pub fn contracts(mut self, contracts: Vec<Address>) -> Self {
+        self.contracts = contracts;
+        self
+    }
+
+    /// event signatures used as the first topic of logs
+    pub fn events(mut self, events: Vec<H256>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// EIP-2718 types of generated transactions after London: 0, 1 or 2
+    pub fn tx_types(mut self, types: &[u64]) -> Self {
+        self.tx_types = types.to_vec();
+        self
+    }
+
+    pub fn reorg(mut self, at: u64, depth: u64) -> Self {
+        self.reorgs.push(Reorg { at, depth });
+        self
+    }
+
+    pub fn canonical(&self) -> &[BlockTransactions] {
+        &self.canonical
+    }
+
+    /// blocks replaced by reorgs
+    pub fn orphaned(&self) -> &[BlockTransactions] {
+        &self.orphaned
+    }
+
+    /// extends the canonical chain by `count` blocks. Returns the blocks in the order
+    /// they were produced, blocks replaced by a reorg come again with new hashes
+    pub fn generate(&mut self, count: u64) -> Vec<BlockTransactions> {
+        let mut out = vec![];
+        for _ in 0..count {
+            let number = self.next_number();
+            let reorgs: Vec<Reorg> = self
+                .reorgs
+                .iter()
+                .filter(|r| r.at == number)
+                .copied()
+                .collect();
+            for reorg in reorgs {
+                let depth = (reorg.depth as usize).min(self.canonical.len());
+                let replaced = self.canonical.split_off(self.canonical.len() - depth);
+                for old in replaced {
+                    let number = old.block.number.unwrap_or_default().as_u64();
+                    self.orphaned.push(old);
+                    let block = self.block(number);
+                    out.push(block.clone());
+                    self.canonical.push(block);
+                }
+            }
+            let block = self.block(number);
+            out.push(block.clone());
+            self.canonical.push(block);
+        }
+        out
+    }
+
+    fn next_number(&self) -> u64 {
+        self.canonical.last().map_or(self.first_block, |b| {
+            b.block.number.unwrap_or_default().as_u64() + 1
+        })
+    }
+
+    // base fee of the next block by EIP-1559
+    fn base_fee(&self, number: u64) -> Option<U256> {
+        if number < self.london_block {
+            return None;
+        }
+        let parent = match self.canonical.last() {
+            Some(parent) => &parent.block,
+            None => return Some(INITIAL_BASE_FEE.into()),
+        };
+        let base = match parent.base_fee_per_gas {
+            Some(base) => base,
+            None => return Some(INITIAL_BASE_FEE.into()),
+        };
+        let target = parent.gas_limit / 2;
+        let used = parent.gas_used;
+        Some(if used > target {
+            base + (base * (used - target) / target / 8).max(U256::one())
+        } else {
+            base - base * (target - used) / target / 8
+        })
+    }
+
+    fn block(&mut self, number: u64) -> BlockTransactions {
+        let parent_hash = self
+            .canonical
+            .last()
+            .and_then(|b| b.block.hash)
+            .unwrap_or_default();
+        let hash = self.rng.h256();
+        let base_fee = self.base_fee(number);
+        let miner = self.rng.address();
+        let mut bloom = Bloom::default();
+        let mut transactions = vec![];
+        let mut receipts = BTreeMap::new();
+        let mut cumulative = U256::zero();
+        let mut log_index = 0u64;
+        let count = self.rng.below(self.max_txs_per_block + 1);
+        for index in 0..count {
+            let tx_type = match base_fee {
+                Some(_) => self.rng.pick(&self.tx_types).unwrap_or(0),
+                None => 0,
+            };
+            let contract = self.rng.pick(&self.contracts);
+            let to = contract.unwrap_or_else(|| self.rng.address());
+            let gas_used = U256::from(21_000 + self.rng.below(200_000));
+            let priority = U256::from(self.rng.below(2_000_000_000));
+            let (gas_price, max_fee, max_priority) = match (tx_type, base_fee) {
+                (2, Some(base)) => {
+                    let max_fee = base * 2 + priority;
+                    (base + priority, Some(max_fee), Some(priority))
+                }
+                (_, Some(base)) => (base + priority, None, None),
+                (_, None) => (U256::from(INITIAL_BASE_FEE) + priority, None, None),
+            };
+            let tx = Transaction {
+                hash: self.rng.h256(),
+                nonce: self.rng.below(1000).into(),
+                block_hash: Some(hash),
+                block_number: Some(number.into()),
+                transaction_index: Some(index.into()),
+                from: self.rng.address(),
+                to: Some(to),
+                value: self.rng.below(1_000_000_000_000_000_000).into(),
+                gas_price: Some(gas_price),
+                gas: gas_used * 3 / 2,
+                input: Bytes::from(self.rng.next_u64().to_be_bytes().to_vec()),
+                v: U64::from(self.rng.below(2)),
+                r: U256::from_big_endian(self.rng.h256().as_bytes()),
+                s: U256::from_big_endian(self.rng.h256().as_bytes()),
+                transaction_type: Some(tx_type.into()),
+                access_list: (tx_type > 0).then(AccessList::default),
+                max_priority_fee_per_gas: max_priority,
+                max_fee_per_gas: max_fee,
+                chain_id: (tx_type > 0).then(|| self.chain_id.into()),
+                ..Default::default()
+            };
+            let mut logs = vec![];
+            if contract.is_some() {
+                for _ in 0..self.rng.below(self.max_logs_per_tx + 1) {
+                    let mut topics = vec![];
+                    topics.extend(self.rng.pick(&self.events));
+                    topics.push(self.rng.h256());
+                    let log = Log {
+                        address: to,
+                        topics,
+                        data: Bytes::from(self.rng.h256().as_bytes().to_vec()),
+                        block_hash: Some(hash),
+                        block_number: Some(number.into()),
+                        transaction_hash: Some(tx.hash),
+                        transaction_index: Some(index.into()),
+                        log_index: Some(log_index.into()),
+                        removed: Some(false),
+                        ..Default::default()
+                    };
+                    log_index += 1;
+                    logs.push(log);
+                }
+            }
+            let mut tx_bloom = Bloom::default();
+            for log in &logs {
+                tx_bloom.accrue(BloomInput::Raw(log.address.as_bytes()));
+                for topic in &log.topics {
+                    tx_bloom.accrue(BloomInput::Raw(topic.as_bytes()));
+                }
+            }
+            bloom.accrue_bloom(&tx_bloom);
+            cumulative += gas_used;
+            let receipt = TransactionReceipt {
+                transaction_hash: tx.hash,
+                transaction_index: index.into(),
+                block_hash: Some(hash),
+                block_number: Some(number.into()),
+                from: tx.from,
+                to: tx.to,
+                cumulative_gas_used: cumulative,
+                gas_used: Some(gas_used),
+                logs,
+                status: Some(1.into()),
+                logs_bloom: tx_bloom,
+                transaction_type: Some(tx_type.into()),
+                effective_gas_price: Some(gas_price),
+                ..Default::default()
+            };
+            receipts.insert(tx.hash, receipt);
+            transactions.push(tx);
+        }
+        let block = Block::<TxHash> {
+            hash: Some(hash),
+            parent_hash,
+            uncles_hash: self.rng.h256(),
+            author: Some(miner),
+            state_root: self.rng.h256(),
+            transactions_root: self.rng.h256(),
+            receipts_root: self.rng.h256(),
+            number: Some(number.into()),
+            gas_used: cumulative,
+            gas_limit: self.gas_limit.into(),
+            extra_data: Bytes::default(),
+            logs_bloom: Some(bloom),
+            timestamp: (GENESIS_TIME + number * BLOCK_TIME).into(),
+            difficulty: U256::zero(),
+            total_difficulty: Some(U256::zero()),
+            transactions: transactions.iter().map(|tx| tx.hash).collect(),
+            size: Some((540 + 120 * transactions.len()).into()),
+            mix_hash: Some(self.rng.h256()),
+            nonce: Some(Default::default()),
+            base_fee_per_gas: base_fee,
+            ..Default::default()
+        };
+        BlockTransactions {
+            block,
+            transactions,
+            receipts,
+        }
+    }
+
+    /// provider serving the generated chain
+    pub fn mock(&self) -> MockChain {
+        MockChain {
+            chain_id: self.chain_id,
+            canonical: self.canonical.clone(),
+            orphaned: self.orphaned.clone(),
+        }
+    }
+}
+
+/// Block in the JSON-RPC shape of eth_getBlockBy*, with transaction objects or hashes
+pub fn block_json(block: &BlockTransactions, full: bool) -> anyhow::Result<Value> {
+    let mut value = serde_json::to_value(&block.block)?;
+    if full {
+        value["transactions"] = serde_json::to_value(&block.transactions)?;
+    }
+    Ok(value)
+}
+
+/// logs of the block in transaction and log order
+pub fn block_logs(block: &BlockTransactions) -> impl Iterator<Item = &Log> {
+    block
+        .transactions
+        .iter()
+        .filter_map(|tx| block.receipts.get(&tx.hash))
+        .flat_map(|r| r.logs.iter())
+}
+
+/// JSON-RPC provider over a generated chain, for `EthBatchClient::with_transport`.
+/// Orphaned blocks are served by hash like real nodes do, but not by number or in logs
+#[derive(Debug, Clone)]
+pub struct MockChain {
+    chain_id: u64,
+    canonical: Vec<BlockTransactions>,
+    orphaned: Vec<BlockTransactions>,
+}
+
+fn quantity(n: u64) -> String {
+    format!("{:#x}", n)
+}
+
+fn parse_quantity(v: &Value) -> Option<u64> {
+    u64::from_str_radix(v.as_str()?.strip_prefix("0x")?, 16).ok()
+}
+
+// filter value of a single item or any of the array items, null matches everything
+fn matches_any(filter: &Value, item: &str) -> bool {
+    match filter {
+        Value::Null => true,
+        Value::String(s) => s.eq_ignore_ascii_case(item),
+        Value::Array(items) => items.is_empty() || items.iter().any(|f| matches_any(f, item)),
+        _ => false,
+    }
+}
+
+impl MockChain {
+    fn all(&self) -> impl Iterator<Item = &BlockTransactions> {
+        self.canonical.iter().chain(self.orphaned.iter())
+    }
+
+    fn latest(&self) -> u64 {
+        self.canonical
+            .last()
+            .map_or(0, |b| b.block.number.unwrap_or_default().as_u64())
+    }
+
+    fn by_number(&self, param: &Value) -> Option<&BlockTransactions> {
+        let number = match param.as_str() {
+            Some("latest") => self.latest(),
+            _ => parse_quantity(param)?,
+        };
+        self.canonical
+            .iter()
+            .find(|b| b.block.number == Some(number.into()))
+    }
+
+    fn logs(&self, filter: &Value) -> Vec<Log> {
+        let from = parse_quantity(&filter["fromBlock"]).unwrap_or(0);
+        let to = parse_quantity(&filter["toBlock"]).unwrap_or_else(|| self.latest());
+        let topics = filter["topics"].as_array().cloned().unwrap_or_default();
+        self.canonical
+            .iter()
+            .filter(|b| {
+                let n = b.block.number.unwrap_or_default().as_u64();
+                n >= from && n <= to
+            })
+            .flat_map(block_logs)
+            .filter(|log| matches_any(&filter["address"], &format!("{:?}", log.address)))
+            .filter(|log| {
+                topics
+                    .iter()
+                    .enumerate()
+                    .all(|(i, t)| match log.topics.get(i) {
+                        Some(topic) => matches_any(t, &format!("{:?}", topic)),
+                        None => t.is_null(),
+                    })
+            })
+            .cloned()
+            .collect()
+    }
+
+    fn call(&self, method: &str, params: &Value) -> anyhow::Result<Value> {
+        let hash = |i: usize| -> anyhow::Result<H256> {
+            params[i]
+                .as_str()
+                .context("hash param")?
+                .parse()
+                .context("hash")
+        };
+        Ok(match method {
+            "eth_blockNumber" => json!(quantity(self.latest())),
+            "eth_chainId" => json!(quantity(self.chain_id)),
+            "net_version" => json!(self.chain_id.to_string()),
+            "eth_getBlockByHash" => {
+                let hash = hash(0)?;
+                match self.all().find(|b| b.block.hash == Some(hash)) {
+                    Some(b) => block_json(b, params[1].as_bool().unwrap_or(false))?,
+                    None => Value::Null,
+                }
+            }
+            "eth_getBlockByNumber" => match self.by_number(&params[0]) {
+                Some(b) => block_json(b, params[1].as_bool().unwrap_or(false))?,
+                None => Value::Null,
+            },
+            "eth_getTransactionByHash" => {
+                let hash = hash(0)?;
+                let tx = self
+                    .all()
+                    .flat_map(|b| b.transactions.iter())
+                    .find(|tx| tx.hash == hash);
+                serde_json::to_value(tx)?
+            }
+            "eth_getTransactionReceipt" => {
+                let hash = hash(0)?;
+                let receipt = self.all().find_map(|b| b.receipts.get(&hash));
+                serde_json::to_value(receipt)?
+            }
+            "eth_getLogs" => serde_json::to_value(self.logs(&params[0]))?,
+            _ => bail!("method {} not found", method),
+        })
+    }
+
+    /// response body for a JSON-RPC request body, single or batch
+    pub fn respond(&self, body: &str) -> anyhow::Result<String> {
+        let request: Value = serde_json::from_str(body).context("request")?;
+        let respond_one = |r: &Value| {
+            let method = r["method"].as_str().unwrap_or_default();
+            match self.call(method, &r["params"]) {
+                Ok(result) => json!({"jsonrpc": "2.0", "id": r["id"], "result": result}),
+                Err(e) => json!({
+                    "jsonrpc": "2.0",
+                    "id": r["id"],
+                    "error": {"code": -32601, "message": format!("{:#}", e)}
+                }),
+            }
+        };
+        let response = match &request {
+            Value::Array(batch) => Value::Array(batch.iter().map(respond_one).collect()),
+            single => respond_one(single),
+        };
+        Ok(response.to_string())
+    }
+}
+
+impl Transport for MockChain {
+    fn post(&self, _url: &str, _headers: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+        self.respond(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_block, get_logs, EthBatchClient};
+
+    fn chain(seed: u64) -> ChainGenerator {
+        let mut g = ChainGenerator::seeded(seed).london_block(3).reorg(6, 2);
+        g.generate(8);
+        g
+    }
+
+    fn is_quantity(v: &Value) -> bool {
+        v.as_str().is_some_and(|s| {
+            s.strip_prefix("0x").is_some_and(|d| {
+                !d.is_empty()
+                    && (d == "0" || !d.starts_with('0'))
+                    && d.bytes().all(|c| c.is_ascii_hexdigit())
+            })
+        })
+    }
+
+    fn is_data(v: &Value, bytes: usize) -> bool {
+        v.as_str()
+            .is_some_and(|s| s.len() == 2 + bytes * 2 && s.starts_with("0x"))
+    }
+
+    #[test]
+    fn it_generates_the_same_chain_for_the_same_seed() {
+        let a = chain(7);
+        let b = chain(7);
+        let json = |g: &ChainGenerator| -> Vec<Value> {
+            g.canonical()
+                .iter()
+                .map(|b| b.to_value().unwrap())
+                .collect()
+        };
+        assert_eq!(json(&a), json(&b));
+        assert_ne!(json(&a), json(&chain(8)));
+        // pinned by GENERATOR_VERSION, update both together
+        assert_eq!(GENERATOR_VERSION, 1);
+        assert_eq!(
+            format!("{:?}", a.canonical()[0].block.hash.unwrap()),
+            "0xe6df52ffdf834b476a3f7fb9fcd4241df89c5aca8c448a780de2b0ab6b89f8ac"
+        );
+    }
+
+    #[test]
+    fn it_replaces_blocks_on_reorg() {
+        let g = chain(1);
+        let numbers: Vec<u64> = g
+            .canonical()
+            .iter()
+            .map(|b| b.block.number.unwrap().as_u64())
+            .collect();
+        assert_eq!(numbers, (1..=8).collect::<Vec<u64>>());
+        let orphaned: Vec<u64> = g
+            .orphaned()
+            .iter()
+            .map(|b| b.block.number.unwrap().as_u64())
+            .collect();
+        assert_eq!(orphaned, vec![4, 5]);
+        for pair in g.canonical().windows(2) {
+            assert_eq!(pair[1].block.parent_hash, pair[0].block.hash.unwrap());
+        }
+        assert_ne!(g.orphaned()[0].block.hash, g.canonical()[3].block.hash);
+    }
+
+    #[test]
+    fn it_follows_the_execution_api_shapes() {
+        let g = chain(3);
+        for b in g.canonical() {
+            let london = b.block.number.unwrap().as_u64() >= 3;
+            let block = block_json(b, true).unwrap();
+            for field in ["number", "gasUsed", "gasLimit", "timestamp", "size"] {
+                assert!(
+                    is_quantity(&block[field]),
+                    "block {}: {}",
+                    field,
+                    block[field]
+                );
+            }
+            for field in ["hash", "parentHash", "stateRoot", "receiptsRoot", "mixHash"] {
+                assert!(is_data(&block[field], 32), "block {}", field);
+            }
+            assert!(is_data(&block["logsBloom"], 256));
+            assert_eq!(is_quantity(&block["baseFeePerGas"]), london);
+            for tx in block["transactions"].as_array().unwrap() {
+                for field in [
+                    "nonce",
+                    "gas",
+                    "value",
+                    "type",
+                    "blockNumber",
+                    "transactionIndex",
+                ] {
+                    assert!(is_quantity(&tx[field]), "tx {}: {}", field, tx[field]);
+                }
+                assert!(is_data(&tx["hash"], 32) && is_data(&tx["from"], 20));
+                let typed = tx["type"] != "0x0";
+                assert!(typed == london || !typed);
+                assert_eq!(tx["accessList"].is_array(), typed);
+                assert_eq!(is_quantity(&tx["maxFeePerGas"]), tx["type"] == "0x2");
+            }
+            for r in b.receipts.values() {
+                let receipt = serde_json::to_value(r).unwrap();
+                for field in [
+                    "status",
+                    "cumulativeGasUsed",
+                    "gasUsed",
+                    "effectiveGasPrice",
+                    "type",
+                ] {
+                    assert!(is_quantity(&receipt[field]), "receipt {}", field);
+                }
+                for log in receipt["logs"].as_array().unwrap() {
+                    assert!(is_data(&log["address"], 20));
+                    assert!(is_quantity(&log["logIndex"]) && is_quantity(&log["blockNumber"]));
+                    assert_eq!(log["removed"], false);
+                }
+            }
+            // the stored document shape round trips
+            let restored = BlockTransactions::from_value(b.to_value().unwrap()).unwrap();
+            assert_eq!(restored.block, b.block);
+            assert_eq!(restored.transactions, b.transactions);
+        }
+    }
+
+    #[test]
+    fn it_serves_the_chain_over_json_rpc() {
+        let g = chain(5);
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        assert_eq!(client.connect().unwrap(), (1, 8));
+
+        let orphan = g.orphaned()[0].block.hash.unwrap();
+        let response = client.get(vec![get_block(orphan, false)]).unwrap();
+        assert!(!response.value(&format!("b{:?}", orphan)).unwrap().is_null());
+
+        let contract = g.contracts[0];
+        let response = client
+            .get(vec![get_logs(
+                vec![contract],
+                Some(1.into()),
+                Some(8.into()),
+                None,
+                None,
+                None,
+                None,
+            )])
+            .unwrap();
+        let logs: Vec<Log> = serde_json::from_value(response.value("l").unwrap()).unwrap();
+        let expected: Vec<Log> = g
+            .canonical()
+            .iter()
+            .flat_map(block_logs)
+            .filter(|l| l.address == contract)
+            .cloned()
+            .collect();
+        assert!(!expected.is_empty());
+        assert_eq!(logs, expected);
+    }
+}
@@ -1,5 +1,18 @@
+#[cfg(feature = "chaos")]
+pub mod chaos;
+mod endpoints;
 mod error;
+#[cfg(any(test, feature = "test-util"))]
+pub mod fixtures;
+mod log_id;
 mod param;
+mod quarantine;
+mod trace;
+
+pub use endpoints::{EndpointHealth, EndpointStats, EthMultiClient};
+pub use log_id::{LogId, SeenLogs};
+pub use quarantine::{QuarantineBucket, QuarantinedLogs};
+pub use trace::{with_incoming, SpanTraceSource, TraceParent, TraceSource};
 
 use crate::error::{Error, ErrorContainer};
 use anyhow::{bail, Context};
@@ -10,6 +23,8 @@ use param::Params;
 use serde::Serialize;
 use serde_json::Value;
 use std::collections::BTreeMap as Map;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
 use std::time::Duration;
 use tracing::*;
 
@@ -114,12 +129,18 @@ pub fn get_logs(
 }
 
 #[derive(Debug, Clone, Serialize)]
-pub struct RpcBatchResponse(Vec<serde_json::Value>);
+#[serde(transparent)]
+pub struct RpcBatchResponse {
+    items: Vec<serde_json::Value>,
+    /// trace id sent with the request, to find it in the provider logs
+    #[serde(skip)]
+    pub trace_id: Option<String>,
+}
 
 impl RpcBatchResponse {
     pub fn value(&self, id: &str) -> Result<Value, Error> {
         let found = self
-            .0
+            .items
             .iter()
             .find(|v| v["id"] == Value::String(id.to_string()));
         match found {
@@ -143,40 +164,86 @@ impl RpcBatchResponse {
     }
 }
 
+/// Sends the JSON body to the endpoint and returns the response body.
+/// Replaced in tests to run the client without network
+pub trait Transport: Send + Sync {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &str) -> anyhow::Result<String>;
+}
+
+/// HTTP transport with ureq
+pub struct UreqTransport {
+    agent: ureq::Agent,
+}
+
+impl Default for UreqTransport {
+    fn default() -> Self {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_read(Duration::from_secs(60))
+            .timeout_write(Duration::from_secs(5))
+            .build();
+        Self { agent }
+    }
+}
+
+impl Transport for UreqTransport {
+    fn post(&self, url: &str, headers: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+        let mut req = self.agent.post(url);
+        for (name, value) in headers {
+            req = req.set(name, value);
+        }
+        Ok(req.send_string(body)?.into_string()?)
+    }
+}
+
 /// Ethereum JSON-RPC client
 pub struct EthBatchClient {
     rpc_addr: String,
-    agent: ureq::Agent,
+    transport: Box<dyn Transport>,
+    trace: Option<Box<dyn TraceSource>>,
 }
 
 impl EthBatchClient {
     /// creates Ethereum client instance
     pub fn new(rpc_addr: &str) -> Self {
-        let agent = ureq::AgentBuilder::new()
-            .timeout_read(Duration::from_secs(60))
-            .timeout_write(Duration::from_secs(5))
-            .build();
         Self {
-            agent,
             rpc_addr: rpc_addr.to_string(),
+            transport: Box::new(UreqTransport::default()),
+            trace: None,
         }
     }
 
+    /// sends requests through another transport
+    pub fn with_transport(mut self, transport: impl Transport + 'static) -> Self {
+        self.transport = Box::new(transport);
+        self
+    }
+
+    /// adds `traceparent` header to every request
+    pub fn with_trace_source(mut self, source: impl TraceSource + 'static) -> Self {
+        self.trace = Some(Box::new(source));
+        self
+    }
+
     #[instrument(skip(self), level = "debug")]
     pub fn get(&self, requests: Vec<RpcSingleRequest>) -> anyhow::Result<RpcBatchResponse> {
-        let req = self
-            .agent
-            .post(&self.rpc_addr)
-            .set("Content-Type", "application/json");
+        let mut headers = vec![("Content-Type", "application/json".to_string())];
+        let parent = self.trace.as_ref().and_then(|t| t.current());
+        if let Some(parent) = &parent {
+            headers.push(("traceparent", parent.to_string()));
+        }
+        let trace_id = parent.map(|p| p.trace_id_hex());
+        debug!(trace_id = trace_id.as_deref(), "rpc request");
         let body = serde_json::to_string(&requests)?;
-        let response = req.send_string(&body)?;
-        let response_str = response.into_string()?;
+        let response_str = self.transport.post(&self.rpc_addr, &headers, &body)?;
         // check if the response is just a single error
         if let Ok(err) = serde_json::from_str::<ErrorContainer>(&response_str) {
             return Err(err.error.into());
         }
         let out: Vec<serde_json::Value> = serde_json::from_str(&response_str)?;
-        Ok(RpcBatchResponse(out))
+        Ok(RpcBatchResponse {
+            items: out,
+            trace_id,
+        })
     }
 
     /// try out connection to RPC and return chain id and latest block number if successful
@@ -213,24 +280,141 @@ impl EthBatchClient {
     }
 }
 
+#[derive(Debug, Clone)]
 pub struct BlockTransactions {
     pub block: Block<TxHash>,
     pub transactions: Vec<Transaction>,
     pub receipts: Map<TxHash, TransactionReceipt>,
 }
 
+impl BlockTransactions {
+    /// JSON document to be stored. Keeps every field of the block,
+    /// including uncles and withdrawals; receipts are listed in transaction order
+    pub fn to_value(&self) -> anyhow::Result<Value> {
+        let receipts: Vec<&TransactionReceipt> = self.receipts.values().collect();
+        Ok(serde_json::json!({
+            "block": self.block,
+            "transactions": self.transactions,
+            "receipts": receipts,
+        }))
+    }
+
+    /// block from eth_getBlockByNumber, with transaction objects or hashes, without receipts
+    pub fn from_block_value(mut value: Value) -> anyhow::Result<Self> {
+        let full = value["transactions"]
+            .as_array()
+            .is_some_and(|txs| txs.iter().any(|tx| tx.is_object()));
+        let mut transactions: Vec<Transaction> = vec![];
+        if full {
+            transactions =
+                serde_json::from_value(value["transactions"].take()).context("transactions")?;
+            value["transactions"] = serde_json::to_value(
+                transactions
+                    .iter()
+                    .map(|tx| tx.hash)
+                    .collect::<Vec<TxHash>>(),
+            )?;
+        }
+        let block: Block<TxHash> = serde_json::from_value(value).context("block")?;
+        Ok(Self {
+            block,
+            transactions,
+            receipts: Map::new(),
+        })
+    }
+
+    /// restores block with its transactions from the stored JSON document
+    pub fn from_value(value: Value) -> anyhow::Result<Self> {
+        let block: Block<TxHash> =
+            serde_json::from_value(value["block"].clone()).context("block")?;
+        let transactions: Vec<Transaction> =
+            serde_json::from_value(value["transactions"].clone()).context("transactions")?;
+        let receipts: Vec<TransactionReceipt> =
+            serde_json::from_value(value["receipts"].clone()).context("receipts")?;
+        Ok(Self {
+            block,
+            transactions,
+            receipts: receipts
+                .into_iter()
+                .map(|r| (r.transaction_hash, r))
+                .collect(),
+        })
+    }
+
+    /// ids of all logs in the receipts, in the order of transactions and logs
+    pub fn log_ids(&self) -> Vec<LogId> {
+        self.receipts
+            .values()
+            .flat_map(|r| r.logs.iter().filter_map(LogId::of))
+            .collect()
+    }
+
+    /// view of the block with only the transactions which receipts
+    /// have logs emitted by the given contract
+    pub fn for_address(&self, address: &Address) -> Option<BlockTransactions> {
+        let receipts: Map<TxHash, TransactionReceipt> = self
+            .receipts
+            .iter()
+            .filter(|(_, r)| r.logs.iter().any(|l| &l.address == address))
+            .map(|(hash, r)| (*hash, r.clone()))
+            .collect();
+        if receipts.is_empty() {
+            return None;
+        }
+        let transactions = self
+            .transactions
+            .iter()
+            .filter(|tx| receipts.contains_key(&tx.hash))
+            .cloned()
+            .collect();
+        Some(BlockTransactions {
+            block: self.block.clone(),
+            transactions,
+            receipts,
+        })
+    }
+}
+
+/// splits blocks fetched once for all contracts into per-contract views,
+/// so every contract can be written into its own bucket
+pub fn demultiplex(
+    blocks: &[BlockTransactions],
+    addresses: &[Address],
+) -> Map<Address, Vec<BlockTransactions>> {
+    let mut out = Map::new();
+    for address in addresses {
+        let views = blocks
+            .iter()
+            .filter_map(|b| b.for_address(address))
+            .collect();
+        out.insert(*address, views);
+    }
+    out
+}
+
 pub struct EthLogsStream {
     client: EthBatchClient,
-    latest_event_block: u64,
-    latest_block: u64,
+    latest_block: AtomicU64,
     batch_size: u64,
     addresses: Vec<Address>,
     topic0: Option<Topic>,
     topic1: Option<Topic>,
     topic2: Option<Topic>,
     topic3: Option<Topic>,
+    // logs of overlapping ranges after a rollback are returned once
+    seen: Mutex<SeenLogs>,
+    // last block of the processed range
+    checkpoint: Mutex<u64>,
+    block_retries: u32,
+    retry_delay: Duration,
+    // logs which blocks could not be fetched, waiting to be stored in the quarantine bucket
+    quarantined: Mutex<Vec<QuarantinedLogs>>,
+    quarantined_total: AtomicU64,
 }
 
+/// number of log ids remembered by the stream to drop duplicates
+pub const DEFAULT_DEDUP_WINDOW: usize = 10_000;
+
 impl EthLogsStream {
     // create stream
     pub fn new(
@@ -248,64 +432,180 @@ impl EthLogsStream {
         let latest_event_block = min_block - 1;
         Ok(Self {
             client,
-            latest_event_block,
-            latest_block,
+            latest_block: AtomicU64::new(latest_block),
             batch_size,
             addresses,
             topic0,
             topic1,
             topic2,
             topic3,
+            seen: Mutex::new(SeenLogs::new(DEFAULT_DEDUP_WINDOW)),
+            checkpoint: Mutex::new(latest_event_block),
+            block_retries: 3,
+            retry_delay: Duration::from_secs(1),
+            quarantined: Mutex::new(vec![]),
+            quarantined_total: AtomicU64::new(0),
         })
     }
 
-    pub fn next(&self) -> anyhow::Result<Option<BlockTransactions>> {
-        let mut current_block = self.latest_event_block;
-        while current_block < self.latest_block {
-            let to_block = std::cmp::min(current_block + self.batch_size, self.latest_block);
-            // 1st request download the logs
-            let requests = vec![get_logs(
-                self.addresses.clone(),
-                Some(current_block.into()),
-                Some(to_block.into()),
-                self.topic0.clone(),
-                self.topic1.clone(),
-                self.topic2.clone(),
-                self.topic3.clone(),
-            )];
-            println!("request: {:?}", requests);
-            let response = self.client.get(requests)?;
-            let logs: Vec<Log> = serde_json::from_value(response.value("logs")?)?;
-
-            let mut bm = Map::<H256, Block<TxHash>>::new();
-            for l in logs {
-                let blockHash = l.block_hash.context("no block hash")?;
-                // download block by its hash, it its not there already
-                if !bm.contains_key(&blockHash) {
-                    let requests = vec![get_block(blockHash, false)];
-                    let response = self.client.get(requests)?;
-                    let block: Block<TxHash> = serde_json::from_value(response.value("block")?)?;
-                    bm.insert(blockHash, block);
+    /// number of recent log ids to check for duplicates, 0 disables the check
+    pub fn dedup_window(mut self, window: usize) -> Self {
+        self.seen = Mutex::new(SeenLogs::new(window));
+        self
+    }
+
+    /// attempts to fetch a block referenced by logs before its logs are quarantined
+    pub fn block_retries(mut self, retries: u32, delay: Duration) -> Self {
+        self.block_retries = retries.max(1);
+        self.retry_delay = delay;
+        self
+    }
+
+    /// contracts which logs are followed, all of them when empty
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    /// last block of the processed range
+    pub fn checkpoint(&self) -> u64 {
+        *self.checkpoint.lock().unwrap()
+    }
+
+    /// quarantined logs since the previous call, to be stored in the quarantine bucket
+    pub fn take_quarantined(&self) -> Vec<QuarantinedLogs> {
+        std::mem::take(&mut *self.quarantined.lock().unwrap())
+    }
+
+    /// number of quarantined blocks since the start
+    pub fn quarantined_total(&self) -> u64 {
+        self.quarantined_total.load(Ordering::Relaxed)
+    }
+
+    fn fetch_block(&self, block_hash: H256) -> anyhow::Result<Block<TxHash>> {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let result = self
+                .client
+                .get(vec![get_block(block_hash, false)])
+                .and_then(|response| {
+                    let value = response.value(&format!("b{:?}", block_hash))?;
+                    if value.is_null() {
+                        bail!("block not found");
+                    }
+                    Ok(serde_json::from_value(value)?)
+                });
+            match result {
+                Ok(block) => return Ok(block),
+                Err(e) if attempt >= self.block_retries => return Err(e),
+                Err(e) => {
+                    debug!(attempt, "block {:?}: {:#}", block_hash, e);
+                    std::thread::sleep(self.retry_delay);
                 }
             }
-            // 2nds request:: get transactions and receipts, block by block
-            let mut txs = Vec::<Transaction>::new();
-            let mut receipts = Map::<TxHash, TransactionReceipt>::new();
-            for (_, block) in bm.iter() {
-                for tx in block.transactions.iter() {
-                    let hash = &tx.clone();
-                    let requests = vec![get_transaction(hash), get_receipt(hash)];
-                    let response = self.client.get(requests)?;
-                    let tx: Transaction = serde_json::from_value(response.value("transaction")?)?;
-                    let receipt: TransactionReceipt =
-                        serde_json::from_value(response.value("receipt")?)?;
-                    txs.push(tx);
-                    receipts.insert(tx.hash, receipt);
+        }
+    }
+
+    /// latest block of the chain as of the last poll
+    pub fn latest_block(&self) -> u64 {
+        self.latest_block.load(Ordering::Relaxed)
+    }
+
+    /// asks the provider for its latest block, returns it
+    pub fn poll(&self) -> anyhow::Result<u64> {
+        let (_, latest_block) = self.client.connect()?;
+        self.latest_block.store(latest_block, Ordering::Relaxed);
+        Ok(latest_block)
+    }
+
+    // transactions and receipts of the block
+    fn fetch_transactions(&self, block: Block<TxHash>) -> anyhow::Result<BlockTransactions> {
+        let mut out = BlockTransactions {
+            block,
+            transactions: vec![],
+            receipts: Map::new(),
+        };
+        for hash in out.block.transactions.clone() {
+            let requests = vec![get_transaction(hash), get_receipt(hash)];
+            let response = self.client.get(requests)?;
+            let tx: Transaction = serde_json::from_value(response.value(&format!("x{:?}", hash))?)?;
+            let receipt: TransactionReceipt =
+                serde_json::from_value(response.value(&format!("r{:?}", hash))?)?;
+            out.receipts.insert(tx.hash, receipt);
+            out.transactions.push(tx);
+        }
+        Ok(out)
+    }
+
+    /// blocks with logs of the next range after the checkpoint, in the order of numbers,
+    /// and moves the checkpoint to the end of the range. None when the stream
+    /// reached the latest block known from the last poll
+    pub fn next(&self) -> anyhow::Result<Option<Vec<BlockTransactions>>> {
+        let current_block = self.checkpoint();
+        let latest_block = self.latest_block.load(Ordering::Relaxed);
+        if current_block >= latest_block {
+            return Ok(None);
+        }
+        let to_block = std::cmp::min(current_block + self.batch_size, latest_block);
+        // 1st request download the logs
+        let requests = vec![get_logs(
+            self.addresses.clone(),
+            Some((current_block + 1).into()),
+            Some(to_block.into()),
+            self.topic0.clone(),
+            self.topic1.clone(),
+            self.topic2.clone(),
+            self.topic3.clone(),
+        )];
+        debug!("request: {:?}", requests);
+        let response = self.client.get(requests)?;
+        let logs: Vec<Log> = serde_json::from_value(response.value("l")?)?;
+        // logs are remembered once the range is done, so a failed range is retried whole
+        let mut seen = self.seen.lock().unwrap().clone();
+        let logs = seen.dedup(logs);
+
+        let mut by_block = Map::<H256, Vec<Log>>::new();
+        for l in logs {
+            let block_hash = l.block_hash.context("no block hash")?;
+            by_block.entry(block_hash).or_default().push(l);
+        }
+        let mut bm = Map::<u64, Block<TxHash>>::new();
+        for (block_hash, logs) in by_block {
+            // download block by its hash
+            match self.fetch_block(block_hash) {
+                Ok(block) => {
+                    bm.insert(block.number.map_or(0, |n| n.as_u64()), block);
+                }
+                Err(e) => {
+                    // provider served logs of a block it doesn't know,
+                    // keep them aside instead of retrying forever
+                    let block_number = logs[0].block_number.map_or(0, |n| n.as_u64());
+                    warn!(
+                        block_number,
+                        "quarantined logs of block {:?}: {:#}", block_hash, e
+                    );
+                    self.quarantined_total.fetch_add(1, Ordering::Relaxed);
+                    self.quarantined.lock().unwrap().push(QuarantinedLogs {
+                        block_hash,
+                        block_number,
+                        logs,
+                        error: format!("{:#}", e),
+                        quarantined_at: std::time::SystemTime::now()
+                            .duration_since(std::time::UNIX_EPOCH)
+                            .map_or(0, |d| d.as_secs()),
+                    });
                 }
-                current_block = block.number.as_u64();
             }
         }
-        Ok(None)
+        // 2nd request: get transactions and receipts, block by block
+        let mut blocks = vec![];
+        for (_, block) in bm {
+            blocks.push(self.fetch_transactions(block)?);
+        }
+        // checkpoint moves past quarantined blocks too
+        *self.seen.lock().unwrap() = seen;
+        *self.checkpoint.lock().unwrap() = to_block;
+        Ok(Some(blocks))
     }
 }
 
@@ -316,6 +616,289 @@ mod tests {
     use std::env;
     use std::str::FromStr;
 
+    fn tx_with_logs(n: u64, emitters: &[Address]) -> (Transaction, TransactionReceipt) {
+        let hash = H256::from_low_u64_be(n);
+        let tx = Transaction {
+            hash,
+            ..Default::default()
+        };
+        let receipt = TransactionReceipt {
+            transaction_hash: hash,
+            logs: emitters
+                .iter()
+                .map(|a| Log {
+                    address: *a,
+                    transaction_hash: Some(hash),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        (tx, receipt)
+    }
+
+    // stored form of the block: JSON encoded with jsondp and the blockchain dictionary
+    fn round_trip(b: &BlockTransactions) -> BlockTransactions {
+        let fd =
+            jsondp::blockchain::get_dictionary(jsondp::blockchain::DICTIONARY_VERSION).unwrap();
+        let vd = jsondp::dictionary::NoDictionary {};
+        let mut buf = Vec::new();
+        jsondp::encode(&b.to_value().unwrap(), &mut buf, &fd, &vd).unwrap();
+        let v = jsondp::decode(&mut buf.as_slice(), &fd, &vd).unwrap();
+        BlockTransactions::from_value(v).unwrap()
+    }
+
+    fn fixture(src: &str) -> BlockTransactions {
+        BlockTransactions {
+            block: serde_json::from_str(src).unwrap(),
+            transactions: vec![],
+            receipts: Map::new(),
+        }
+    }
+
+    // logsBloom is not part of the fixtures: 256-byte values hit the u8 length limit of jsondp
+    #[test]
+    fn it_keeps_uncles() {
+        let b = fixture(include_str!("../tests/fixtures/block_uncles.json"));
+        assert_eq!(b.block.uncles.len(), 2);
+        let out = round_trip(&b);
+        assert_eq!(out.block.uncles, b.block.uncles);
+        assert_eq!(out.block.uncles_hash, b.block.uncles_hash);
+        assert_eq!(out.block.hash, b.block.hash);
+    }
+
+    #[test]
+    fn it_keeps_withdrawals() {
+        let b = fixture(include_str!("../tests/fixtures/block_withdrawals.json"));
+        assert_eq!(b.block.withdrawals.as_ref().unwrap().len(), 2);
+        let out = round_trip(&b);
+        assert_eq!(out.block.withdrawals, b.block.withdrawals);
+        assert_eq!(out.block.withdrawals_root, b.block.withdrawals_root);
+        assert_eq!(out.block.hash, b.block.hash);
+    }
+
+    // headers of one request
+    type Headers = Vec<(String, String)>;
+
+    // records headers of every request and answers with the latest block
+    #[derive(Clone, Default)]
+    struct MockTransport {
+        headers: std::sync::Arc<std::sync::Mutex<Vec<Headers>>>,
+    }
+
+    impl Transport for MockTransport {
+        fn post(
+            &self,
+            _url: &str,
+            headers: &[(&str, String)],
+            _body: &str,
+        ) -> anyhow::Result<String> {
+            let headers = headers
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect();
+            self.headers.lock().unwrap().push(headers);
+            Ok(r#"[{"jsonrpc":"2.0","id":"latest","result":"0x10"}]"#.to_string())
+        }
+    }
+
+    #[test]
+    fn it_propagates_traceparent() {
+        let mock = MockTransport::default();
+        let client = EthBatchClient::new("http://mock")
+            .with_transport(mock.clone())
+            .with_trace_source(SpanTraceSource::new(7));
+        let subscriber = tracing_subscriber::registry();
+        let responses = tracing::subscriber::with_default(subscriber, || {
+            let a = info_span!("first").in_scope(|| client.get(vec![get_latest()]).unwrap());
+            let b = info_span!("second").in_scope(|| client.get(vec![get_latest()]).unwrap());
+            let incoming =
+                TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+                    .unwrap();
+            let c = with_incoming(Some(incoming), || {
+                info_span!("proxy").in_scope(|| client.get(vec![get_latest()]).unwrap())
+            });
+            vec![a, b, c]
+        });
+        assert_eq!(responses[0].value("latest").unwrap(), "0x10");
+
+        let sent: Vec<TraceParent> = mock
+            .headers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|h| {
+                let (_, v) = h.iter().find(|(k, _)| k == "traceparent").unwrap();
+                TraceParent::parse(v).unwrap()
+            })
+            .collect();
+        assert_eq!(sent.len(), 3);
+        assert_ne!(sent[0].trace_id, sent[1].trace_id);
+        assert_ne!(sent[0].span_id, sent[1].span_id);
+        assert_eq!(sent[2].trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(
+            responses[0].trace_id.as_deref(),
+            Some(sent[0].trace_id_hex().as_str())
+        );
+        assert!(format!("{:?}", responses[1]).contains(&sent[1].trace_id_hex()));
+
+        // no header without trace source
+        let plain = EthBatchClient::new("http://mock").with_transport(mock.clone());
+        let r = plain.get(vec![get_latest()]).unwrap();
+        assert_eq!(r.trace_id, None);
+        assert!(mock.headers.lock().unwrap()[3]
+            .iter()
+            .all(|(k, _)| k != "traceparent"));
+    }
+
+    // provider that serves logs of a block it doesn't know
+    #[derive(Clone, Default)]
+    struct ScriptedRpc {
+        logs: Vec<Log>,
+        blocks: Map<H256, Block<TxHash>>,
+        block_requests: std::sync::Arc<std::sync::Mutex<Vec<String>>>,
+    }
+
+    impl Transport for ScriptedRpc {
+        fn post(&self, _url: &str, _: &[(&str, String)], body: &str) -> anyhow::Result<String> {
+            let requests: Vec<Value> = serde_json::from_str(body)?;
+            let out: Vec<Value> = requests
+                .iter()
+                .map(|r| {
+                    let result = match r["method"].as_str().unwrap() {
+                        "net_version" => serde_json::json!("1"),
+                        "eth_blockNumber" => serde_json::json!("0x30"),
+                        "eth_getLogs" => serde_json::to_value(&self.logs).unwrap(),
+                        "eth_getBlockByHash" => {
+                            let hash = r["params"][0].as_str().unwrap().to_string();
+                            self.block_requests.lock().unwrap().push(hash.clone());
+                            let hash = H256::from_str(&hash).unwrap();
+                            serde_json::to_value(self.blocks.get(&hash)).unwrap()
+                        }
+                        m => panic!("unexpected {}", m),
+                    };
+                    serde_json::json!({"jsonrpc": "2.0", "id": r["id"], "result": result})
+                })
+                .collect();
+            Ok(serde_json::to_string(&out)?)
+        }
+    }
+
+    #[tokio::test]
+    async fn it_quarantines_logs_of_missing_blocks() {
+        let log = |block: u64, index: u64| Log {
+            block_hash: Some(H256::from_low_u64_be(block)),
+            block_number: Some(block.into()),
+            log_index: Some(index.into()),
+            ..Default::default()
+        };
+        let mut rpc = ScriptedRpc {
+            logs: vec![log(20, 0), log(25, 0), log(25, 1)],
+            ..Default::default()
+        };
+        let known = Block::<TxHash> {
+            hash: Some(H256::from_low_u64_be(20)),
+            number: Some(20.into()),
+            ..Default::default()
+        };
+        rpc.blocks.insert(H256::from_low_u64_be(20), known);
+
+        let client = EthBatchClient::new("http://mock").with_transport(rpc.clone());
+        let stream = EthLogsStream::new(client, 11, 100, vec![], None, None, None, None)
+            .unwrap()
+            .block_retries(3, Duration::ZERO);
+        assert_eq!(stream.checkpoint(), 10);
+        let blocks = stream.next().unwrap().unwrap();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].block.number, Some(20.into()));
+        // indexing went on past the bad reference
+        assert_eq!(stream.checkpoint(), 0x30);
+        assert!(stream.next().unwrap().is_none());
+        assert_eq!(stream.quarantined_total(), 1);
+        let missing = format!("{:?}", H256::from_low_u64_be(25));
+        let requests = rpc.block_requests.lock().unwrap().clone();
+        assert_eq!(requests.iter().filter(|h| **h == missing).count(), 3);
+
+        let bucket = QuarantineBucket::new(kv::MemoryKV::new());
+        for entry in stream.take_quarantined() {
+            bucket.add(entry).await.unwrap();
+        }
+        assert!(stream.take_quarantined().is_empty());
+        let listed = bucket.list().await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].block_number, 25);
+        assert_eq!(listed[0].logs.len(), 2);
+        assert_eq!(listed[0].error, "block not found");
+    }
+
+    #[test]
+    fn it_streams_blocks_with_logs() {
+        let contract = Address::from_low_u64_be(0xc0);
+        let mut g = fixtures::ChainGenerator::seeded(5)
+            .density(4, 2)
+            .contracts(vec![contract]);
+        g.generate(10);
+        let client = EthBatchClient::new("http://mock").with_transport(g.mock());
+        let stream = EthLogsStream::new(client, 1, 4, vec![contract], None, None, None, None)
+            .unwrap()
+            .dedup_window(0);
+        let mut numbers = vec![];
+        while let Some(blocks) = stream.next().unwrap() {
+            for b in blocks {
+                let expected = g
+                    .canonical()
+                    .iter()
+                    .find(|c| c.block.hash == b.block.hash)
+                    .unwrap();
+                assert_eq!(b.transactions.len(), expected.transactions.len());
+                assert_eq!(b.receipts.len(), expected.receipts.len());
+                numbers.push(b.block.number.unwrap().as_u64());
+            }
+        }
+        let with_logs: Vec<u64> = g
+            .canonical()
+            .iter()
+            .filter(|b| !b.log_ids().is_empty())
+            .map(|b| b.block.number.unwrap().as_u64())
+            .collect();
+        // every block once, even without deduplication
+        assert_eq!(numbers, with_logs);
+        let latest = g.canonical().last().unwrap().block.number.unwrap();
+        assert_eq!(stream.checkpoint(), latest.as_u64());
+    }
+
+    #[test]
+    fn it_demultiplexes_shared_blocks() {
+        let a = Address::from_low_u64_be(0xa);
+        let b = Address::from_low_u64_be(0xb);
+        let c = Address::from_low_u64_be(0xc);
+        let mut shared = BlockTransactions {
+            block: Block::default(),
+            transactions: vec![],
+            receipts: Map::new(),
+        };
+        for (n, emitters) in [(1, vec![a]), (2, vec![a, b]), (3, vec![b])] {
+            let (tx, receipt) = tx_with_logs(n, &emitters);
+            shared.receipts.insert(tx.hash, receipt);
+            shared.transactions.push(tx);
+        }
+
+        let out = demultiplex(&[shared], &[a, b, c]);
+        let hashes = |addr: &Address| -> Vec<u64> {
+            out[addr][0]
+                .transactions
+                .iter()
+                .map(|tx| tx.hash.to_low_u64_be())
+                .collect()
+        };
+        assert_eq!(out[&a].len(), 1);
+        assert_eq!(hashes(&a), vec![1, 2]);
+        assert_eq!(out[&b].len(), 1);
+        assert_eq!(hashes(&b), vec![2, 3]);
+        assert_eq!(out[&b][0].receipts.len(), 2);
+        assert!(out[&c].is_empty());
+    }
+
     #[test]
     #[ignore]
     fn it_reads_logs() {
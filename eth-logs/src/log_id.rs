@@ -0,0 +1,202 @@
+use anyhow::{bail, Context};
+use ethers::types::{Log, H256};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::str::FromStr;
+
+/// Unique identifier of the log: hash of the block and position of the log in it.
+/// String form is `0x<block hash>-<log index>`, binary form is 40 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LogId {
+    pub block_hash: H256,
+    pub log_index: u64,
+}
+
+impl LogId {
+    pub const SIZE: usize = 40;
+
+    pub fn new(block_hash: H256, log_index: u64) -> Self {
+        Self {
+            block_hash,
+            log_index,
+        }
+    }
+
+    /// id of the log, None for pending logs without block hash or index
+    pub fn of(log: &Log) -> Option<Self> {
+        Some(Self::new(log.block_hash?, log.log_index?.as_u64()))
+    }
+
+    /// block hash followed by big-endian log index
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        out[..32].copy_from_slice(self.block_hash.as_bytes());
+        out[32..].copy_from_slice(&self.log_index.to_be_bytes());
+        out
+    }
+
+    pub fn from_bytes(input: &[u8]) -> anyhow::Result<Self> {
+        if input.len() != Self::SIZE {
+            bail!("log id should be {} bytes, got {}", Self::SIZE, input.len());
+        }
+        let mut index = [0u8; 8];
+        index.copy_from_slice(&input[32..]);
+        Ok(Self::new(
+            H256::from_slice(&input[..32]),
+            u64::from_be_bytes(index),
+        ))
+    }
+}
+
+impl fmt::Display for LogId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}-{}", self.block_hash, self.log_index)
+    }
+}
+
+impl FromStr for LogId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (hash, index) = s
+            .split_once('-')
+            .context("log id should be <hash>-<index>")?;
+        let hash = H256::from_str(hash).context("log id block hash")?;
+        let index = index.parse().context("log id index")?;
+        Ok(Self::new(hash, index))
+    }
+}
+
+impl Serialize for LogId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for LogId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Ids of the recently seen logs, oldest are forgotten after `window` entries
+#[derive(Debug, Clone)]
+pub struct SeenLogs {
+    window: usize,
+    order: VecDeque<LogId>,
+    ids: HashSet<LogId>,
+}
+
+impl SeenLogs {
+    pub fn new(window: usize) -> Self {
+        Self {
+            window,
+            order: VecDeque::with_capacity(window),
+            ids: HashSet::with_capacity(window),
+        }
+    }
+
+    /// remembers the id, returns false if it was already seen within the window
+    pub fn insert(&mut self, id: LogId) -> bool {
+        if self.window == 0 {
+            return true;
+        }
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.window {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+
+    /// keeps only the logs that were not seen yet. Logs without id are kept
+    pub fn dedup(&mut self, logs: Vec<Log>) -> Vec<Log> {
+        logs.into_iter()
+            .filter(|log| LogId::of(log).is_none_or(|id| self.insert(id)))
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.order.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(block: u64, index: u64) -> Log {
+        Log {
+            block_hash: Some(H256::from_low_u64_be(block)),
+            block_number: Some(block.into()),
+            log_index: Some(index.into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn it_converts_log_id() {
+        let id = LogId::of(&log(0xabc, 7)).unwrap();
+        let s = id.to_string();
+        assert_eq!(
+            s,
+            "0x0000000000000000000000000000000000000000000000000000000000000abc-7"
+        );
+        assert_eq!(s.parse::<LogId>().unwrap(), id);
+        assert_eq!(LogId::from_bytes(&id.to_bytes()).unwrap(), id);
+        assert_eq!(serde_json::to_value(id).unwrap(), s.as_str());
+        assert!(LogId::from_bytes(&[0u8; 32]).is_err());
+        assert!("0xabc".parse::<LogId>().is_err());
+        assert_eq!(LogId::of(&Log::default()), None);
+    }
+
+    #[test]
+    fn it_drops_logs_of_overlapping_ranges() {
+        let mut seen = SeenLogs::new(100);
+        let range = |from: u64, to: u64| -> Vec<Log> {
+            (from..to)
+                .flat_map(|b| (0..3).map(move |i| log(b, i)))
+                .collect()
+        };
+        let first = seen.dedup(range(10, 20));
+        assert_eq!(first.len(), 30);
+        // replay after a checkpoint rollback overlaps the previous range
+        let replay = seen.dedup(range(15, 25));
+        assert_eq!(replay.len(), 15);
+        assert!(replay
+            .iter()
+            .all(|l| l.block_number.unwrap().as_u64() >= 20));
+
+        let mut ids: Vec<LogId> = first
+            .iter()
+            .chain(replay.iter())
+            .filter_map(LogId::of)
+            .collect();
+        let total = ids.len();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), total);
+    }
+
+    #[test]
+    fn it_forgets_beyond_window() {
+        let mut seen = SeenLogs::new(2);
+        let ids: Vec<LogId> = (0..3).map(|i| LogId::of(&log(1, i)).unwrap()).collect();
+        assert!(seen.insert(ids[0]));
+        assert!(seen.insert(ids[1]));
+        assert!(!seen.insert(ids[0]));
+        assert!(seen.insert(ids[2]));
+        assert_eq!(seen.len(), 2);
+        assert!(seen.insert(ids[0]));
+    }
+}
@@ -0,0 +1,135 @@
+use anyhow::Context;
+use ethers::types::{Log, H256};
+use kv::KV;
+use serde::{Deserialize, Serialize};
+
+// key of the list of quarantined block numbers, KV can't enumerate keys
+const INDEX_KEY: u32 = u32::MAX;
+
+/// Logs referencing a block that the provider would not serve
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QuarantinedLogs {
+    pub block_hash: H256,
+    pub block_number: u64,
+    pub logs: Vec<Log>,
+    /// last error of fetching the block
+    pub error: String,
+    /// unix time of quarantine
+    pub quarantined_at: u64,
+}
+
+/// Bucket of quarantined logs, keyed by block number.
+/// Every key keeps all block hashes quarantined for that number
+pub struct QuarantineBucket<K: KV> {
+    bucket: K,
+}
+
+impl<K: KV> QuarantineBucket<K> {
+    pub fn new(bucket: K) -> Self {
+        Self { bucket }
+    }
+
+    async fn index(&self) -> anyhow::Result<Vec<u32>> {
+        match self.bucket.get(INDEX_KEY).await? {
+            Some(bytes) => serde_json::from_slice(&bytes).context("quarantine index"),
+            None => Ok(vec![]),
+        }
+    }
+
+    async fn set_index(&self, index: &[u32]) -> anyhow::Result<()> {
+        self.bucket.set(INDEX_KEY, serde_json::to_vec(index)?).await
+    }
+
+    /// entries of one block number
+    pub async fn get(&self, number: u32) -> anyhow::Result<Vec<QuarantinedLogs>> {
+        match self.bucket.get(number).await? {
+            Some(bytes) if !bytes.is_empty() => {
+                serde_json::from_slice(&bytes).context("quarantined logs")
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    /// adds the entry, replacing the previous one of the same block hash
+    pub async fn add(&self, entry: QuarantinedLogs) -> anyhow::Result<()> {
+        let number = u32::try_from(entry.block_number).context("block number")?;
+        let mut entries = self.get(number).await?;
+        entries.retain(|e| e.block_hash != entry.block_hash);
+        entries.push(entry);
+        self.bucket
+            .set(number, serde_json::to_vec(&entries)?)
+            .await?;
+        let mut index = self.index().await?;
+        if let Err(pos) = index.binary_search(&number) {
+            index.insert(pos, number);
+            self.set_index(&index).await?;
+        }
+        Ok(())
+    }
+
+    /// removes the entry of the block hash, after it was reprocessed
+    pub async fn remove(&self, number: u32, block_hash: &H256) -> anyhow::Result<()> {
+        let mut entries = self.get(number).await?;
+        entries.retain(|e| &e.block_hash != block_hash);
+        if entries.is_empty() {
+            // KV has no delete, an empty value means no entries
+            self.bucket.set(number, vec![]).await?;
+            let mut index = self.index().await?;
+            index.retain(|n| *n != number);
+            self.set_index(&index).await?;
+        } else {
+            self.bucket
+                .set(number, serde_json::to_vec(&entries)?)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// all entries ordered by block number
+    pub async fn list(&self) -> anyhow::Result<Vec<QuarantinedLogs>> {
+        let mut out = vec![];
+        for number in self.index().await? {
+            out.extend(self.get(number).await?);
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kv::MemoryKV;
+
+    fn entry(number: u64, hash: u64) -> QuarantinedLogs {
+        QuarantinedLogs {
+            block_hash: H256::from_low_u64_be(hash),
+            block_number: number,
+            logs: vec![Log::default()],
+            error: "block not found".to_string(),
+            quarantined_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn it_keeps_quarantined_logs() {
+        let q = QuarantineBucket::new(MemoryKV::new());
+        q.add(entry(20, 2)).await.unwrap();
+        q.add(entry(10, 1)).await.unwrap();
+        q.add(entry(20, 3)).await.unwrap();
+        q.add(entry(20, 3)).await.unwrap();
+        let listed: Vec<u64> = q
+            .list()
+            .await
+            .unwrap()
+            .iter()
+            .map(|e| e.block_hash.to_low_u64_be())
+            .collect();
+        assert_eq!(listed, vec![1, 2, 3]);
+
+        q.remove(20, &H256::from_low_u64_be(2)).await.unwrap();
+        q.remove(10, &H256::from_low_u64_be(1)).await.unwrap();
+        let left = q.list().await.unwrap();
+        assert_eq!(left, vec![entry(20, 3)]);
+        assert!(q.get(10).await.unwrap().is_empty());
+    }
+}
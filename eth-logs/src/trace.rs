@@ -0,0 +1,154 @@
+use anyhow::{bail, Context};
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+/// W3C trace context of the outgoing request, sent as `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceParent {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceParent {
+    pub fn trace_id_hex(&self) -> String {
+        hex_of(&self.trace_id)
+    }
+
+    /// parses `00-<32 hex trace id>-<16 hex span id>-<2 hex flags>`
+    pub fn parse(s: &str) -> anyhow::Result<Self> {
+        let parts: Vec<&str> = s.trim().split('-').collect();
+        if parts.len() != 4 || parts[0] != "00" {
+            bail!("traceparent should be 00-<trace id>-<span id>-<flags>");
+        }
+        let mut trace_id = [0u8; 16];
+        let mut span_id = [0u8; 8];
+        let mut flags = [0u8; 1];
+        decode_hex(parts[1], &mut trace_id).context("trace id")?;
+        decode_hex(parts[2], &mut span_id).context("span id")?;
+        decode_hex(parts[3], &mut flags).context("flags")?;
+        if trace_id == [0u8; 16] || span_id == [0u8; 8] {
+            bail!("traceparent ids should not be zero");
+        }
+        Ok(Self {
+            trace_id,
+            span_id,
+            sampled: flags[0] & 1 == 1,
+        })
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "00-{}-{}-{:02x}",
+            hex_of(&self.trace_id),
+            hex_of(&self.span_id),
+            self.sampled as u8
+        )
+    }
+}
+
+fn hex_of(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(s: &str, out: &mut [u8]) -> anyhow::Result<()> {
+    if s.len() != out.len() * 2 || !s.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+        bail!("expected {} lowercase hex digits", out.len() * 2);
+    }
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+    }
+    Ok(())
+}
+
+/// Source of the trace context for outgoing requests
+pub trait TraceSource: Send + Sync {
+    /// context of the current request, None to send no header
+    fn current(&self) -> Option<TraceParent>;
+}
+
+thread_local! {
+    static INCOMING: Cell<Option<TraceParent>> = const { Cell::new(None) };
+}
+
+/// runs `f` with the trace context of the incoming request, so upstream calls
+/// made inside continue the same trace
+pub fn with_incoming<T>(parent: Option<TraceParent>, f: impl FnOnce() -> T) -> T {
+    let previous = INCOMING.with(|c| c.replace(parent));
+    let out = f();
+    INCOMING.with(|c| c.set(previous));
+    out
+}
+
+fn incoming() -> Option<TraceParent> {
+    INCOMING.with(|c| c.get())
+}
+
+/// Trace context derived from the current tracing span, without OpenTelemetry.
+/// Ids are hashed from the span id and the seed, the trace id of the incoming
+/// request is kept when there is one
+#[derive(Debug, Clone)]
+pub struct SpanTraceSource {
+    seed: u64,
+}
+
+impl SpanTraceSource {
+    /// seed should differ between processes, e.g. random or start time
+    pub fn new(seed: u64) -> Self {
+        Self { seed }
+    }
+
+    fn hash(&self, salt: u64, span: u64) -> u64 {
+        let mut h = DefaultHasher::new();
+        (self.seed, salt, span).hash(&mut h);
+        h.finish().max(1)
+    }
+}
+
+impl TraceSource for SpanTraceSource {
+    fn current(&self) -> Option<TraceParent> {
+        let span = tracing::Span::current().id()?.into_u64();
+        let trace_id = match incoming() {
+            Some(parent) => parent.trace_id,
+            None => {
+                let mut id = [0u8; 16];
+                id[..8].copy_from_slice(&self.hash(1, span).to_be_bytes());
+                id[8..].copy_from_slice(&self.hash(2, span).to_be_bytes());
+                id
+            }
+        };
+        Some(TraceParent {
+            trace_id,
+            span_id: self.hash(3, span).to_be_bytes(),
+            sampled: true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_formats_and_parses_traceparent() {
+        let s = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let t = TraceParent::parse(s).unwrap();
+        assert!(t.sampled);
+        assert_eq!(t.to_string(), s);
+        assert_eq!(t.trace_id_hex(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert!(
+            TraceParent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_err()
+        );
+        assert!(
+            TraceParent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_err()
+        );
+        assert!(
+            TraceParent::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_err()
+        );
+    }
+}
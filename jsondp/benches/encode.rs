@@ -0,0 +1,131 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use jsondp::blockchain::{get_dictionary, DICTIONARY_VERSION};
+use jsondp::dictionary::{MapDictionary, NoDictionary};
+use jsondp::EncodeOptions;
+use serde_json::{json, Value};
+use std::io::{Seek, SeekFrom};
+
+// block-like document with transactions and receipts
+fn fixture() -> Value {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/benches/fixtures/block.json");
+    let file = std::fs::read_to_string(path).unwrap();
+    serde_json::from_str(&file).unwrap()
+}
+
+// about 1 MB of JSON: transactions with calldata, logs and text
+fn synthetic_block() -> Value {
+    let transactions: Vec<Value> = (0..1200u32)
+        .map(|i| {
+            json!({
+                "hash": format!("0x{:064x}", i),
+                "from": format!("0x{:040x}", i * 7),
+                "to": format!("0x{:040x}", i * 13),
+                "value": format!("0x{:x}", i as u64 * 1_000_000_007),
+                "input": format!("0xa9059cbb{}", "00".repeat(64 + i as usize % 64)),
+                "memo": format!("transfer number {} of the synthetic block", i),
+                "logs": [{
+                    "topics": [format!("0x{:064x}", i + 1), format!("0x{:064x}", i + 2)],
+                    "data": format!("0x{:0128x}", i),
+                }],
+            })
+        })
+        .collect();
+    json!({"number": "0x10d4f2c", "transactions": transactions})
+}
+
+fn bench_encode(c: &mut Criterion) {
+    let block = fixture();
+    let fd = get_dictionary(DICTIONARY_VERSION).unwrap();
+    let nod = NoDictionary {};
+    let mut buf = Vec::with_capacity(1 << 20);
+    c.bench_function("encode block", |b| {
+        b.iter(|| {
+            buf.clear();
+            jsondp::encode(black_box(&block), &mut buf, &fd, &nod).unwrap();
+        })
+    });
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let block = fixture();
+    let fd = get_dictionary(DICTIONARY_VERSION).unwrap();
+    let nod = NoDictionary {};
+    let mut buf = vec![];
+    jsondp::encode(&block, &mut buf, &fd, &nod).unwrap();
+    c.bench_function("decode block", |b| {
+        b.iter(|| jsondp::decode_slice(black_box(&buf), &fd, &nod).unwrap())
+    });
+    c.bench_function("decode block borrowed", |b| {
+        b.iter(|| jsondp::decode_borrowed(black_box(&buf), &fd, &nod).unwrap())
+    });
+
+    let block = synthetic_block();
+    let mut buf = vec![];
+    jsondp::encode(&block, &mut buf, &fd, &nod).unwrap();
+    c.bench_function("decode synthetic block", |b| {
+        b.iter(|| jsondp::decode(&mut black_box(buf.as_slice()), &fd, &nod).unwrap())
+    });
+}
+
+// every write is a system call, as the file is not buffered
+fn bench_encode_to_file(c: &mut Criterion) {
+    let scalars: Vec<Value> = (0..100_000i64)
+        .map(|i| match i % 6 {
+            0 => json!(i * 100_000),
+            1 => json!(-i * 100_000),
+            2 => json!(i as f64 / 3.0),
+            3 => json!(i % 4 == 0),
+            4 => Value::Null,
+            _ => json!(format!("0x{:x}", i)),
+        })
+        .collect();
+    let scalars = Value::Array(scalars);
+    // the array is longer than u16
+    let opts = EncodeOptions {
+        varint_lengths: true,
+        ..Default::default()
+    };
+    let nod = NoDictionary {};
+    let path = std::env::temp_dir().join("jsondp-bench-scalars.bin");
+    let mut file = std::fs::File::create(&path).unwrap();
+    let mut group = c.benchmark_group("encode to file");
+    group.sample_size(10);
+    group.bench_function("100k scalars", |b| {
+        b.iter(|| {
+            file.set_len(0).unwrap();
+            file.seek(SeekFrom::Start(0)).unwrap();
+            jsondp::encode_with(black_box(&scalars), &mut file, &nod, &nod, &opts).unwrap();
+        })
+    });
+    group.finish();
+    std::fs::remove_file(&path).unwrap();
+}
+
+fn bench_load_dictionary(c: &mut Criterion) {
+    let mut d = MapDictionary::new();
+    for n in 0..1_000_000u64 {
+        d.insert(&format!("0x{:040x}", n.wrapping_mul(0x9e3779b97f4a7c15)));
+    }
+    let mut text = vec![];
+    d.write(&mut text).unwrap();
+    let mut binary = vec![];
+    d.write_binary(&mut binary).unwrap();
+    let mut group = c.benchmark_group("load dictionary");
+    group.sample_size(10);
+    group.bench_function("1m entries text", |b| {
+        b.iter(|| MapDictionary::from(&mut black_box(text.as_slice())).unwrap())
+    });
+    group.bench_function("1m entries binary", |b| {
+        b.iter(|| MapDictionary::from_binary(&mut black_box(binary.as_slice())).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_encode,
+    bench_decode,
+    bench_encode_to_file,
+    bench_load_dictionary
+);
+criterion_main!(benches);
@@ -0,0 +1,24 @@
+fn main() {
+    // C header for the ffi module, only the items of src/ffi.rs are exported.
+    // It is written into OUT_DIR, `JSONDP_WRITE_HEADER=1 cargo build --features ffi`
+    // refreshes the committed copy in include/jsondp.h
+    #[cfg(feature = "ffi")]
+    {
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let out = std::env::var("OUT_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-env-changed=JSONDP_WRITE_HEADER");
+        let header = cbindgen::Builder::new()
+            .with_src(format!("{}/src/ffi.rs", dir))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("JSONDP_H")
+            .with_sys_include("stddef.h")
+            .with_sys_include("stdint.h")
+            .generate()
+            .expect("could not generate C header");
+        header.write_to_file(format!("{}/jsondp.h", out));
+        if std::env::var_os("JSONDP_WRITE_HEADER").is_some() {
+            header.write_to_file(format!("{}/include/jsondp.h", dir));
+        }
+    }
+}
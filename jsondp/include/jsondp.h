@@ -0,0 +1,56 @@
+#ifndef JSONDP_H
+#define JSONDP_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+#include <stddef.h>
+#include <stdint.h>
+
+/**
+ * decoded successfully, `out_json` is set
+ */
+#define JSONDP_OK 0
+
+/**
+ * blob or dictionaries are invalid, `out_err` is set
+ */
+#define JSONDP_ERROR 1
+
+/**
+ * internal failure, `out_err` is set
+ */
+#define JSONDP_PANIC 2
+
+/**
+ * null output pointers, nothing is set
+ */
+#define JSONDP_INVALID_ARGUMENT 3
+
+/**
+ * Decodes the blob into JSON text, using field and value dictionaries in the binary form
+ * written by `MapDictionary::write_binary`. Null dictionary pointer means an empty dictionary.
+ *
+ * # Safety
+ * Every non-null pointer must be valid for reads of its length.
+ * `out_json` and `out_err` must be valid for writes.
+ */
+int32_t jsondp_decode(const uint8_t *bytes,
+                      uintptr_t len,
+                      const uint8_t *field_dict_bytes,
+                      uintptr_t fd_len,
+                      const uint8_t *value_dict_bytes,
+                      uintptr_t vd_len,
+                      char **out_json,
+                      char **out_err);
+
+/**
+ * Releases a string returned by `jsondp_decode`. Null is ignored.
+ *
+ * # Safety
+ * The pointer must come from `jsondp_decode` and must not be used or freed again.
+ */
+void jsondp_free_string(char *s);
+
+#endif  /* JSONDP_H */
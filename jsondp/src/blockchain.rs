@@ -1,8 +1,14 @@
+use crate::dictionary::DictionaryRead;
+use anyhow::{bail, Context};
 use lazy_static::lazy_static;
+use serde_json::Value;
+use sha3::{Digest, Keccak256};
 use std::collections::BTreeMap;
+use std::io::Write;
+use std::ops::RangeInclusive;
 
 lazy_static! {
-    static ref DICTIONARY: BTreeMap<&'static str, u32> = {
+    static ref DICTIONARY_V1: BTreeMap<&'static str, u32> = {
         let mut m = BTreeMap::new();
         m.insert("accessList", 44);
         m.insert("address", 53);
@@ -65,14 +71,430 @@ lazy_static! {
         m.insert("warpChunksProcessed", 8);
         m
     };
+    // post-merge and EIP-4844 fields, on top of version 1
+    static ref DICTIONARY_V2: BTreeMap<&'static str, u32> = {
+        let mut m = BTreeMap::new();
+        m.insert("withdrawals", 60);
+        m.insert("withdrawalsRoot", 61);
+        m.insert("index", 62);
+        m.insert("validatorIndex", 63);
+        m.insert("amount", 64);
+        m.insert("blobGasUsed", 65);
+        m.insert("excessBlobGas", 66);
+        m.insert("parentBeaconBlockRoot", 67);
+        m.insert("maxFeePerBlobGas", 68);
+        m.insert("blobVersionedHashes", 69);
+        m.insert("yParity", 70);
+        m.insert("blobGasPrice", 71);
+        m
+    };
 }
 
+/// Latest version of the field dictionary, to be stored with blobs encoded
+/// with it. Versions only add entries with new ids, so blobs encoded with
+/// an older version decode with later ones as well
+pub const DICTIONARY_VERSION: u8 = 2;
+
+/// Ids of the entries of `get_value_dictionary`, apart from the ids of the field
+/// dictionary and still one byte each. Ids that are learned should be outside of it
+pub const VALUE_IDS: RangeInclusive<u32> = 128..=255;
+
+// signatures of the events that are logged the most, topic0 is the keccak256 of it
+const EVENTS: [&str; 50] = [
+    // ERC-20, ERC-721, ERC-1155
+    "Transfer(address,address,uint256)",
+    "Approval(address,address,uint256)",
+    "ApprovalForAll(address,address,bool)",
+    "TransferSingle(address,address,address,uint256,uint256)",
+    "TransferBatch(address,address,address,uint256[],uint256[])",
+    // WETH
+    "Deposit(address,uint256)",
+    "Withdrawal(address,uint256)",
+    // Uniswap V2 and its forks
+    "Swap(address,uint256,uint256,uint256,uint256,address)",
+    "Sync(uint112,uint112)",
+    "Mint(address,uint256,uint256)",
+    "Burn(address,uint256,uint256,address)",
+    "PairCreated(address,address,address,uint256)",
+    // Uniswap V3
+    "Swap(address,address,int256,int256,uint160,uint128,int24)",
+    "Mint(address,address,int24,int24,uint128,uint256,uint256)",
+    "Burn(address,int24,int24,uint128,uint256,uint256)",
+    "Collect(address,address,int24,int24,uint128,uint128)",
+    "PoolCreated(address,address,uint24,int24,address)",
+    "IncreaseLiquidity(uint256,uint128,uint256,uint256)",
+    "DecreaseLiquidity(uint256,uint128,uint256,uint256)",
+    "Collect(uint256,address,uint256,uint256)",
+    // Curve and Balancer
+    "TokenExchange(address,int128,uint256,int128,uint256)",
+    "TokenExchangeUnderlying(address,int128,uint256,int128,uint256)",
+    "Swap(bytes32,address,address,uint256,uint256)",
+    // ERC-4626 vaults
+    "Deposit(address,address,uint256,uint256)",
+    "Withdraw(address,address,address,uint256,uint256)",
+    // Aave
+    "Supply(address,address,address,uint256,uint16)",
+    "Borrow(address,address,address,uint256,uint8,uint256,uint16)",
+    "Repay(address,address,address,uint256,bool)",
+    "LiquidationCall(address,address,address,uint256,uint256,address,bool)",
+    "FlashLoan(address,address,address,uint256,uint256,uint16)",
+    "ReserveDataUpdated(address,uint256,uint256,uint256,uint256,uint256)",
+    // ownership, proxies and access control
+    "OwnershipTransferred(address,address)",
+    "Upgraded(address)",
+    "AdminChanged(address,address)",
+    "BeaconUpgraded(address)",
+    "Initialized(uint8)",
+    "Paused(address)",
+    "Unpaused(address)",
+    "RoleGranted(bytes32,address,address)",
+    "RoleRevoked(bytes32,address,address)",
+    "RoleAdminChanged(bytes32,bytes32,bytes32)",
+    // governance tokens
+    "DelegateChanged(address,address,address)",
+    "DelegateVotesChanged(address,uint256,uint256)",
+    // staking rewards and airdrops
+    "Staked(address,uint256)",
+    "Withdrawn(address,uint256)",
+    "RewardPaid(address,uint256)",
+    "Claimed(uint256,address,uint256)",
+    // Chainlink, Safe and ERC-4337
+    "AnswerUpdated(int256,uint256,uint256)",
+    "ExecutionSuccess(bytes32,uint256)",
+    "UserOperationEvent(bytes32,address,address,uint256,bool,uint256,uint256)",
+];
+
+// addresses and words that are everywhere: zero and burn addresses,
+// the most used tokens and routers, and the zero word of topics and data
+const WELL_KNOWN: [&str; 12] = [
+    "0x0000000000000000000000000000000000000000",
+    "0x000000000000000000000000000000000000dead",
+    "0xdead000000000000000042069420694206942069",
+    "0xc02aaa39b223fe8d0a0e5c4f27ead9083c756cc2",
+    "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48",
+    "0xdac17f958d2ee523a2206206994597c13d831ec7",
+    "0x6b175474e89094c44da98b954eedeac495271d0f",
+    "0x2260fac5e5542a773aa44fbcfedf7c193bc2c599",
+    "0x7a250d5630b4cf539739df2c5dacb4c659f2488d",
+    "0xe592427a0aece92de3edee1f18e0157c05861564",
+    "0x68b3465833fb72a70ecdf485e0e4c7bd8665fc45",
+    "0x0000000000000000000000000000000000000000000000000000000000000000",
+];
+
 use super::MapDictionary;
 
-pub fn get_dictionary() -> MapDictionary {
+/// field dictionary of the version, `DICTIONARY_VERSION` for the latest
+pub fn get_dictionary(version: u8) -> anyhow::Result<MapDictionary> {
+    match version {
+        1 => Ok(get_dictionary_v1()),
+        2 => Ok(get_dictionary_v2()),
+        _ => bail!(
+            "unknown dictionary version {}, the latest is {}",
+            version,
+            DICTIONARY_VERSION
+        ),
+    }
+}
+
+/// fields of JSON-RPC responses with blocks, transactions, receipts and logs
+pub fn get_dictionary_v1() -> MapDictionary {
     let mut out = MapDictionary::new();
-    for (k, v) in DICTIONARY.iter() {
+    for (k, v) in DICTIONARY_V1.iter() {
         out.insert_as(k, *v);
     }
     out
 }
+
+/// fields of version 1, withdrawals and blob transactions
+pub fn get_dictionary_v2() -> MapDictionary {
+    let mut out = get_dictionary_v1();
+    for (k, v) in DICTIONARY_V2.iter() {
+        out.insert_as(k, *v);
+    }
+    out
+}
+
+/// writes the dictionary version ahead of the value encoded with the field
+/// dictionary of it, so `decode_versioned` picks the same one.
+/// Returns the number of bytes written
+pub fn encode_versioned<W: Write, D: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    version: u8,
+    vd: &D,
+) -> anyhow::Result<usize> {
+    let fd = get_dictionary(version)?;
+    w.write_all(&[version])
+        .context("write dictionary version")?;
+    Ok(1 + crate::encode(input, w, &fd, vd)?)
+}
+
+/// reads a value written by `encode_versioned`, with the field dictionary
+/// of the version written ahead of it
+pub fn decode_versioned<D: DictionaryRead>(input: &[u8], vd: &D) -> anyhow::Result<Value> {
+    let (version, blob) = match input.split_first() {
+        Some(x) => x,
+        None => bail!("no dictionary version"),
+    };
+    crate::decode_slice(blob, &get_dictionary(*version)?, vd)
+}
+
+/// Value dictionary of topic0 hashes of the most common events and of well-known
+/// addresses, kept as bytes, with ids of `VALUE_IDS`
+pub fn get_value_dictionary() -> MapDictionary {
+    let mut out = MapDictionary::new();
+    let hashes = EVENTS
+        .iter()
+        .map(|e| Keccak256::digest(e.as_bytes()).to_vec());
+    let known = WELL_KNOWN
+        .iter()
+        .map(|v| hex::decode(&v[2..]).unwrap_or_default());
+    for (id, item) in VALUE_IDS.zip(hashes.chain(known)) {
+        out.insert_bytes_as(&item, id);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::NoDictionary;
+    use crate::{decode_slice, encode, encoded_size};
+    use serde_json::json;
+
+    // names of all keys of the value and of its nested objects
+    fn keys(v: &serde_json::Value, out: &mut Vec<String>) {
+        match v {
+            serde_json::Value::Array(a) => a.iter().for_each(|x| keys(x, out)),
+            serde_json::Value::Object(o) => {
+                for (k, x) in o {
+                    out.push(k.clone());
+                    keys(x, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    const TRANSFER: &str = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+    #[test]
+    fn it_has_well_known_topics() {
+        let vd = get_value_dictionary();
+        assert_eq!(vd.ids().count(), EVENTS.len() + WELL_KNOWN.len());
+        assert!(vd.ids().all(|id| VALUE_IDS.contains(&id)));
+        let fd = get_dictionary(DICTIONARY_VERSION).unwrap();
+        assert!(fd.ids().all(|id| !VALUE_IDS.contains(&id)));
+        for topic in [
+            TRANSFER,
+            "0x8c5be1e5ebec7d5bd14f71427d1e84f3dd0314c0f7b2291e5b200ac8c7c3b925",
+            "0xe1fffcc4923d04b559f4d29a8bfc6cda04eb5b0d3c460751c2402c5c5cc9109c",
+            "0x7fcf532c15f0a6db0bd6d0e038bea71d30d808c7d98cb3bf7268a95bf5081b65",
+            "0xd78ad95fa46c994b6551d0da85fc275fe613ce37657fb8d5e3d130840159d822",
+            "0x1c411e9a96e071241c2f21f7726b17ae89e3cab4c78be50e062b03a9fffbbad1",
+        ] {
+            assert!(
+                vd.find_bytes(&hex::decode(&topic[2..]).unwrap()).is_some(),
+                "{}",
+                topic
+            );
+        }
+    }
+
+    #[test]
+    fn it_compresses_transfer_logs() {
+        let fd = get_dictionary(DICTIONARY_VERSION).unwrap();
+        let vd = get_value_dictionary();
+        let zero = format!("0x{:064x}", 0);
+        let to = "0x00000000000000000000000028c6c06298d514db089934071355e5743bf21d60";
+        let log = json!({
+            "address": "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+            "blockNumber": "0x010d4f2e",
+            "data": "0x0000000000000000000000000000000000000000000000000000000077359400",
+            "logIndex": "0x05",
+            "removed": false,
+            "topics": [TRANSFER, zero, to],
+            "transactionHash": "0x5c504ed432cb51138bcf09aa5e8a410dd4a1e204ef84bfed1be16dfba1b22060",
+        });
+        let nod = NoDictionary {};
+        // the signature and the zero topic are 2 bytes each instead of 33
+        for topic in [TRANSFER, zero.as_str()] {
+            assert_eq!(encoded_size(&json!(topic), &nod, &vd).unwrap(), 2);
+        }
+        let mut blob = vec![];
+        encode(&log, &mut blob, &fd, &vd).unwrap();
+        let without = encoded_size(&log, &fd, &nod).unwrap();
+        assert_eq!(without - blob.len(), 2 * (33 - 2) + (21 - 2));
+        let mut expected = log.clone();
+        expected["address"] = json!("0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48");
+        assert_eq!(decode_slice(&blob, &fd, &vd).unwrap(), expected);
+    }
+
+    #[test]
+    fn it_has_fields_of_cancun_blocks() {
+        let block: serde_json::Value =
+            serde_json::from_str(include_str!("../tests/fixtures/cancun_block.json")).unwrap();
+        let fd = get_dictionary(DICTIONARY_VERSION).unwrap();
+        let mut names = vec![];
+        keys(&block, &mut names);
+        for name in &names {
+            assert!(
+                fd.find_str(name).is_some(),
+                "{} is not in the dictionary",
+                name
+            );
+        }
+        // receipts have the blob gas price
+        assert_eq!(fd.find_str("blobGasPrice"), Some(71));
+        // ids of the first version stay where they were
+        assert_eq!(fd.find_str("status"), Some(59));
+        assert_eq!(fd.find_str("accessList"), Some(44));
+
+        let nod = NoDictionary {};
+        let mut blob = vec![];
+        encode(&block, &mut blob, &fd, &nod).unwrap();
+        // every key is a reference of one id byte instead of its name
+        let without = encoded_size(&block, &MapDictionary::new(), &nod).unwrap();
+        let inline: usize = names.iter().map(|n| n.len()).sum();
+        assert!(without - blob.len() >= inline, "{} {}", without, blob.len());
+        // quantities come back padded, the same as without the dictionary
+        let mut plain = vec![];
+        encode(&block, &mut plain, &MapDictionary::new(), &nod).unwrap();
+        let expected = decode_slice(&plain, &MapDictionary::new(), &nod).unwrap();
+        assert_eq!(decode_slice(&blob, &fd, &nod).unwrap(), expected);
+    }
+
+    #[test]
+    fn it_keeps_ids_of_every_version() {
+        let v1 = [
+            ("id", 1),
+            ("jsonrpc", 2),
+            ("result", 3),
+            ("currentBlock", 4),
+            ("highestBlock", 5),
+            ("startingBlock", 6),
+            ("warpChunksAmount", 7),
+            ("warpChunksProcessed", 8),
+            ("knownStates", 9),
+            ("pulledStates", 10),
+            ("baseFeePerGas", 11),
+            ("difficulty", 12),
+            ("extraData", 13),
+            ("gasLimit", 14),
+            ("gasUsed", 15),
+            ("hash", 16),
+            ("logsBloom", 17),
+            ("miner", 18),
+            ("mixHash", 19),
+            ("nonce", 20),
+            ("number", 21),
+            ("parentHash", 22),
+            ("receiptsRoot", 23),
+            ("sha3Uncles", 24),
+            ("size", 25),
+            ("stateRoot", 26),
+            ("timestamp", 27),
+            ("totalDifficulty", 28),
+            ("transactions", 29),
+            ("blockHash", 30),
+            ("blockNumber", 31),
+            ("chainId", 32),
+            ("from", 33),
+            ("gas", 34),
+            ("gasPrice", 35),
+            ("input", 36),
+            ("r", 37),
+            ("s", 38),
+            ("to", 39),
+            ("transactionIndex", 40),
+            ("type", 41),
+            ("v", 42),
+            ("value", 43),
+            ("accessList", 44),
+            ("maxFeePerGas", 45),
+            ("maxPriorityFeePerGas", 46),
+            ("transactionsRoot", 47),
+            ("uncles", 48),
+            ("contractAddress", 49),
+            ("cumulativeGasUsed", 50),
+            ("effectiveGasPrice", 51),
+            ("logs", 52),
+            ("address", 53),
+            ("data", 54),
+            ("logIndex", 55),
+            ("removed", 56),
+            ("topics", 57),
+            ("transactionHash", 58),
+            ("status", 59),
+        ];
+        let v2 = [
+            ("withdrawals", 60),
+            ("withdrawalsRoot", 61),
+            ("index", 62),
+            ("validatorIndex", 63),
+            ("amount", 64),
+            ("blobGasUsed", 65),
+            ("excessBlobGas", 66),
+            ("parentBeaconBlockRoot", 67),
+            ("maxFeePerBlobGas", 68),
+            ("blobVersionedHashes", 69),
+            ("yParity", 70),
+            ("blobGasPrice", 71),
+        ];
+        let pinned = |d: &MapDictionary| {
+            let ids: Vec<u32> = d.ids().collect();
+            ids.iter()
+                .map(|id| {
+                    (
+                        String::from_utf8_lossy(d.get(*id).unwrap()).into_owned(),
+                        *id,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected: Vec<(String, u32)> = v1.iter().map(|(k, id)| (k.to_string(), *id)).collect();
+        assert_eq!(pinned(&get_dictionary(1).unwrap()), expected);
+        let expected: Vec<(String, u32)> = v1
+            .iter()
+            .chain(v2.iter())
+            .map(|(k, id)| (k.to_string(), *id))
+            .collect();
+        assert_eq!(pinned(&get_dictionary(2).unwrap()), expected);
+        assert_eq!(
+            get_dictionary(DICTIONARY_VERSION).unwrap().fingerprint(),
+            get_dictionary_v2().fingerprint()
+        );
+        let err = get_dictionary(3).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown dictionary version 3, the latest is 2"
+        );
+    }
+
+    #[test]
+    fn it_decodes_with_the_recorded_version() {
+        let receipt = json!({"blockNumber": "0x01", "blobGasPrice": "0x01", "status": "0x01"});
+        let nod = NoDictionary {};
+        let mut old = vec![];
+        encode_versioned(&receipt, &mut old, 1, &nod).unwrap();
+        let mut new = vec![];
+        let written = encode_versioned(&receipt, &mut new, DICTIONARY_VERSION, &nod).unwrap();
+        assert_eq!(written, new.len());
+        assert_eq!((old[0], new[0]), (1, 2));
+        // the field of version 2 is a reference there
+        assert!(new.len() < old.len());
+        assert_eq!(decode_versioned(&old, &nod).unwrap(), receipt);
+        assert_eq!(decode_versioned(&new, &nod).unwrap(), receipt);
+        // blobs of version 1 decode with version 2 as well
+        let v2 = get_dictionary_v2();
+        assert_eq!(decode_slice(&old[1..], &v2, &nod).unwrap(), receipt);
+
+        let err = decode_versioned(&[9, 0], &nod).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown dictionary version 9, the latest is 2"
+        );
+        let err = decode_versioned(&[], &nod).unwrap_err();
+        assert_eq!(err.to_string(), "no dictionary version");
+    }
+}
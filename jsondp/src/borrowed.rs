@@ -0,0 +1,366 @@
+use crate::decode::{check_declared, next_len, next_u16, next_u32, next_u8, Item, NUMERIC};
+use crate::dictionary::{DictionaryRead, IdWidth};
+use crate::{push_path, Counting, DecodeOptions, Decoding};
+use anyhow::{bail, Context};
+use serde_json::{Map, Number, Value};
+use std::borrow::Cow;
+
+/// Value decoded from a blob in memory, the same as `serde_json::Value`
+/// with strings borrowed from the blob where it has them as they were written.
+/// Fields of objects are in the order of the blob
+#[derive(Debug, Clone, PartialEq)]
+pub enum BorrowedValue<'a> {
+    Null,
+    Bool(bool),
+    Number(Number),
+    String(Cow<'a, str>),
+    Array(Vec<BorrowedValue<'a>>),
+    Object(Vec<(Cow<'a, str>, BorrowedValue<'a>)>),
+}
+
+impl BorrowedValue<'_> {
+    /// the value `decode` returns for the same blob, later fields win over
+    /// the fields of the same name before them
+    pub fn to_owned(&self) -> Value {
+        match self {
+            Self::Null => Value::Null,
+            Self::Bool(b) => Value::Bool(*b),
+            Self::Number(n) => Value::Number(n.clone()),
+            Self::String(s) => Value::String(s.to_string()),
+            Self::Array(items) => Value::Array(items.iter().map(Self::to_owned).collect()),
+            Self::Object(fields) => {
+                let mut m = Map::new();
+                for (key, value) in fields {
+                    m.insert(key.to_string(), value.to_owned());
+                }
+                Value::Object(m)
+            }
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(s) => Some(s),
+            _ => None,
+        }
+    }
+}
+
+impl From<Value> for BorrowedValue<'_> {
+    fn from(value: Value) -> Self {
+        match value {
+            Value::Null => Self::Null,
+            Value::Bool(b) => Self::Bool(b),
+            Value::Number(n) => Self::Number(n),
+            Value::String(s) => Self::String(Cow::Owned(s)),
+            Value::Array(items) => Self::Array(items.into_iter().map(Self::from).collect()),
+            Value::Object(m) => Self::Object(
+                m.into_iter()
+                    .map(|(key, value)| (Cow::Owned(key), Self::from(value)))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// decode of a blob in memory, on top of the state of `decode`
+struct Borrowing<'a, 'd, D1, D2> {
+    state: Decoding<'d, D1, D2>,
+    // strings decoded so far, when the value has back-references to them
+    written: Option<Vec<Cow<'a, str>>>,
+}
+
+pub(crate) fn decode<'a, D1: DictionaryRead, D2: DictionaryRead>(
+    mut input: &'a [u8],
+    fd: &D1,
+    vd: &D2,
+    opts: &DecodeOptions,
+) -> anyhow::Result<BorrowedValue<'a>> {
+    let mut counting = Counting::new(&mut input, opts.max_bytes);
+    let mut borrowing = Borrowing {
+        state: Decoding::new(fd, vd, opts),
+        written: None,
+    };
+    borrowing.document(&mut counting)
+}
+
+impl<'a, D1: DictionaryRead, D2: DictionaryRead> Borrowing<'a, '_, D1, D2> {
+    fn document(
+        &mut self,
+        input: &mut Counting<'_, &'a [u8]>,
+    ) -> anyhow::Result<BorrowedValue<'a>> {
+        let value = self.state.header(input).and_then(|nb| {
+            self.written = self.state.written.take().map(|_| vec![]);
+            self.item(nb, input)
+        });
+        value.map_err(|e| self.state.locate(e))
+    }
+
+    fn value(&mut self, input: &mut Counting<'_, &'a [u8]>) -> anyhow::Result<BorrowedValue<'a>> {
+        let nb = self.state.prefix(input)?;
+        self.item(nb, input)
+    }
+
+    fn item(
+        &mut self,
+        nb: u8,
+        input: &mut Counting<'_, &'a [u8]>,
+    ) -> anyhow::Result<BorrowedValue<'a>> {
+        let offset = input.bytes - 1;
+        if let Some(s) = self.string(nb, input)? {
+            if let Some(written) = self.written.as_mut() {
+                written.push(Cow::Borrowed(s));
+            }
+            return Ok(BorrowedValue::String(Cow::Borrowed(s)));
+        }
+        match self.state.read_item(nb, input)? {
+            Item::Array(size) => {
+                self.state.enter(offset)?;
+                let mut vals = Vec::new();
+                for i in 0..size {
+                    let len = self.state.path.len();
+                    push_path(&mut self.state.path, &i.to_string());
+                    vals.push(self.value(input)?);
+                    self.state.path.truncate(len);
+                }
+                self.state.depth -= 1;
+                Ok(BorrowedValue::Array(vals))
+            }
+            Item::Object(size) => {
+                self.state.enter(offset)?;
+                let mut fields = Vec::new();
+                for _ in 0..size {
+                    let len = self.state.path.len();
+                    let field = self.field(input)?;
+                    let value = self.value(input)?;
+                    self.state.path.truncate(len);
+                    fields.push((field, value));
+                }
+                self.state.depth -= 1;
+                Ok(BorrowedValue::Object(fields))
+            }
+            Item::BackRef(index) => match self.written.as_ref().and_then(|w| w.get(index as usize))
+            {
+                Some(s) => Ok(BorrowedValue::String(s.clone())),
+                None => bail!("back-reference {} to no earlier string", index),
+            },
+            item => {
+                let string = matches!(
+                    item,
+                    Item::Str(_) | Item::Bytes(_) | Item::ValueRef(_) | Item::BytesRef(_)
+                );
+                let value = BorrowedValue::from(self.state.scalar_value(item)?);
+                if let (true, Some(written)) = (string, self.written.as_mut()) {
+                    if let BorrowedValue::String(s) = &value {
+                        written.push(s.clone());
+                    }
+                }
+                Ok(value)
+            }
+        }
+    }
+
+    // string after the prefix of ds, dws or dls, borrowed from the blob.
+    // None for other values, which are left to `read_item`, as are all
+    // values of strict decoding, which checks the item it reads
+    fn string(
+        &mut self,
+        nb: u8,
+        input: &mut Counting<'_, &'a [u8]>,
+    ) -> anyhow::Result<Option<&'a str>> {
+        if self.state.opts.strict_minimal {
+            return Ok(None);
+        }
+        self.state.at = input.bytes - 1;
+        let size = match nb & 0x1F {
+            20 if nb & (0x20 | NUMERIC) == 0 => next_len(nb, input)?.0,
+            24 => next_u16(input)? as usize,
+            28 => next_u32(input)? as usize,
+            _ => return Ok(None),
+        };
+        check_declared(size, input.remaining(), 0)?;
+        self.state.count(0)?;
+        let s = std::str::from_utf8(take(input, size)?).context("invalid UTF-8 in string")?;
+        Ok(Some(s))
+    }
+
+    // reads the key of the next field and pushes it to the path,
+    // string keys are borrowed as values are
+    fn field(&mut self, input: &mut Counting<'_, &'a [u8]>) -> anyhow::Result<Cow<'a, str>> {
+        let rest: &'a [u8] = input.inner;
+        let nb = match rest.first() {
+            Some(&nb) if !self.state.opts.strict_minimal && IdWidth::from_prefix(nb).is_none() => {
+                nb
+            }
+            _ => return self.state.field(input).map(Cow::Owned),
+        };
+        if !matches!(nb & 0x1F, 20 | 24) {
+            return self.state.field(input).map(Cow::Owned);
+        }
+        self.state.at = input.bytes;
+        next_u8(input)?;
+        let size = match nb & 0x1F {
+            20 => next_u8(input)? as usize,
+            _ => next_u16(input)? as usize,
+        };
+        check_declared(size, input.remaining(), 0)?;
+        let field = std::str::from_utf8(take(input, size)?).context("invalid UTF-8 in string")?;
+        push_path(&mut self.state.path, field);
+        Ok(Cow::Borrowed(field))
+    }
+}
+
+// the next bytes of the blob, which the reader moves past
+fn take<'a>(input: &mut Counting<'_, &'a [u8]>, size: usize) -> anyhow::Result<&'a [u8]> {
+    let rest: &'a [u8] = input.inner;
+    if size > rest.len() {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    let (taken, rest) = rest.split_at(size);
+    *input.inner = rest;
+    input.bytes += size as u64;
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{MapDictionary, NoDictionary};
+    use crate::{
+        decode_borrowed, decode_slice, decode_slice_with, encode_with, EncodeOptions, HexDetection,
+    };
+    use serde_json::json;
+
+    fn fixtures() -> Vec<Value> {
+        [
+            include_str!("../tests/fixtures/block.json"),
+            include_str!("../tests/fixtures/logs.json"),
+            include_str!("../tests/fixtures/receipts.json"),
+        ]
+        .iter()
+        .map(|text| serde_json::from_str(text).unwrap())
+        .collect()
+    }
+
+    // strings of the value that are borrowed
+    fn borrowed(value: &BorrowedValue, out: &mut Vec<String>) {
+        match value {
+            BorrowedValue::String(Cow::Borrowed(s)) => out.push(s.to_string()),
+            BorrowedValue::Array(items) => items.iter().for_each(|v| borrowed(v, out)),
+            BorrowedValue::Object(fields) => {
+                for (key, value) in fields {
+                    if let Cow::Borrowed(key) = key {
+                        out.push(key.to_string());
+                    }
+                    borrowed(value, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn it_decodes_fixtures_as_decode_does() {
+        let d = MapDictionary::from_static(&["blockHash", "transactions", "0x"]);
+        let nod = NoDictionary {};
+        let options = [
+            EncodeOptions::default(),
+            EncodeOptions {
+                back_references: true,
+                detect_timestamps: true,
+                ..Default::default()
+            },
+            EncodeOptions {
+                varint_lengths: true,
+                hex_widths: HexDetection::Never,
+                ..Default::default()
+            },
+        ];
+        for v in fixtures() {
+            for opts in &options {
+                let mut buf = vec![];
+                encode_with(&v, &mut buf, &d, &d, opts).unwrap();
+                let value = decode_borrowed(&buf, &d, &d).unwrap();
+                assert_eq!(value.to_owned(), decode_slice(&buf, &d, &d).unwrap());
+
+                let mut buf = vec![];
+                encode_with(&v, &mut buf, &nod, &nod, opts).unwrap();
+                let value = decode_borrowed(&buf, &nod, &nod).unwrap();
+                assert_eq!(value.to_owned(), decode_slice(&buf, &nod, &nod).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn it_borrows_strings_written_as_they_are() {
+        let d = MapDictionary::from_static(&["known"]);
+        let long = "x".repeat(300);
+        let v = json!({
+            "name": "example",
+            "known": "known",
+            "hash": "0x1234",
+            "long": long,
+            "again": "example",
+        });
+        let opts = EncodeOptions {
+            back_references: true,
+            ..Default::default()
+        };
+        let mut buf = vec![];
+        encode_with(&v, &mut buf, &d, &d, &opts).unwrap();
+        let value = decode_borrowed(&buf, &d, &d).unwrap();
+        assert_eq!(value.to_owned(), v);
+
+        let mut found = vec![];
+        borrowed(&value, &mut found);
+        found.sort();
+        // "known" is in the dictionary and the hex is bytes, "example" is borrowed twice
+        let mut expected = vec!["again", "example", "example", "hash", "long", "name", &long];
+        expected.sort();
+        assert_eq!(found, expected);
+        // the borrowed string is the one in the blob
+        let s = value.to_owned();
+        let at = buf.windows(7).position(|w| w == b"example").unwrap();
+        match &value {
+            BorrowedValue::Object(fields) => {
+                let name = fields.iter().find(|(key, _)| key == "name").unwrap();
+                assert_eq!(name.1.as_str(), s["name"].as_str());
+                assert_eq!(name.1.as_str().unwrap().as_ptr(), buf[at..].as_ptr());
+            }
+            _ => panic!("not an object"),
+        }
+    }
+
+    #[test]
+    fn it_fails_as_decode_slice_does() {
+        let d = NoDictionary {};
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        let v = json!({"name": "example", "list": ["one", "two"]});
+        let mut buf = vec![];
+        encode_with(&v, &mut buf, &d, &d, &EncodeOptions::default()).unwrap();
+        for end in 0..buf.len() {
+            let err = decode_borrowed(&buf[..end], &d, &d).unwrap_err();
+            let expected = decode_slice(&buf[..end], &d, &d).unwrap_err();
+            assert_eq!(format!("{:#}", err), format!("{:#}", expected));
+        }
+        // invalid UTF-8 in the value and in the key
+        let key = buf.windows(4).position(|w| w == b"list").unwrap();
+        for at in [buf.len() - 1, key] {
+            let mut damaged = buf.clone();
+            damaged[at] = 0xff;
+            let err = decode_borrowed(&damaged, &d, &d).unwrap_err();
+            let expected = decode_slice(&damaged, &d, &d).unwrap_err();
+            assert_eq!(format!("{:#}", err), format!("{:#}", expected));
+        }
+        // strict decoding checks the items, and gives the same value
+        let decoder = crate::Decoder::new(&d, &d).with_options(strict.clone());
+        let value = decoder.decode_borrowed(&buf).unwrap();
+        assert_eq!(
+            value.to_owned(),
+            decode_slice_with(&buf, &d, &d, &strict).unwrap()
+        );
+    }
+}
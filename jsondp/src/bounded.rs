@@ -0,0 +1,173 @@
+use crate::dictionary::{DictionaryRead, DictionaryWrite, MapDictionary};
+
+/// Dictionary that learns up to `max_entries` entries of up to `max_value_len`
+/// bytes each. Entries are never evicted, as blobs encoded with them would not
+/// decode any more: once it is full, new entries are refused and counted,
+/// and a growing count tells that it is time to train a new dictionary.
+/// Longer entries are refused as well, they are written inline just as well
+#[derive(Debug, Clone)]
+pub struct BoundedDictionary {
+    dict: MapDictionary,
+    max_entries: usize,
+    max_value_len: usize,
+    rejected: Rejected,
+}
+
+/// Entries a `BoundedDictionary` refused to learn
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rejected {
+    /// entries refused because the dictionary was full
+    pub full: u64,
+    /// entries longer than `max_value_len`
+    pub too_long: u64,
+}
+
+impl Rejected {
+    pub fn total(&self) -> u64 {
+        self.full + self.too_long
+    }
+}
+
+impl BoundedDictionary {
+    /// bounded dictionary on top of the entries of `dict`, which may be more than the limits
+    pub fn new(dict: MapDictionary, max_entries: usize, max_value_len: usize) -> Self {
+        Self {
+            dict,
+            max_entries,
+            max_value_len,
+            rejected: Rejected::default(),
+        }
+    }
+
+    /// entries refused since it was created or the counters were reset
+    pub fn rejected(&self) -> Rejected {
+        self.rejected
+    }
+
+    pub fn reset_rejected(&mut self) {
+        self.rejected = Rejected::default();
+    }
+
+    pub fn len(&self) -> usize {
+        self.dict.ids().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// no more entries are learned
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.max_entries
+    }
+
+    pub fn dictionary(&self) -> &MapDictionary {
+        &self.dict
+    }
+
+    pub fn into_dictionary(self) -> MapDictionary {
+        self.dict
+    }
+}
+
+impl DictionaryRead for BoundedDictionary {
+    fn get(&self, index: u32) -> Option<&[u8]> {
+        self.dict.get(index)
+    }
+    fn find_str(&self, value: &str) -> Option<u32> {
+        self.dict.find_str(value)
+    }
+    fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+        self.dict.find_bytes(value)
+    }
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        Some(self.dict.fingerprint())
+    }
+}
+
+/// entries that are there already are found, new ones are refused past the limits
+impl DictionaryWrite for BoundedDictionary {
+    fn insert(&mut self, bytes: &[u8]) -> Option<u32> {
+        if let Some(index) = self.dict.find_bytes(bytes) {
+            return Some(index);
+        }
+        if bytes.len() > self.max_value_len {
+            self.rejected.too_long += 1;
+            return None;
+        }
+        if self.is_full() {
+            self.rejected.full += 1;
+            return None;
+        }
+        DictionaryWrite::insert(&mut self.dict, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_slice, encode_learning, EncodeOptions};
+    use serde_json::json;
+
+    #[test]
+    fn it_refuses_entries_when_full() {
+        let mut fd = BoundedDictionary::new(MapDictionary::from_strings(vec!["logs"]), 3, 64);
+        let mut vd = MapDictionary::new();
+        let opts = EncodeOptions::default();
+        let first = json!({"logs": [{"address": "0x01", "data": "0x"}]});
+        let mut blob = vec![];
+        let learned = encode_learning(&first, &mut blob, &mut fd, &mut vd, &opts, false).unwrap();
+        assert_eq!(
+            learned.fields,
+            vec![(2, "address".to_string()), (3, "data".to_string())]
+        );
+        assert!(fd.is_full());
+        assert_eq!(fd.rejected(), Rejected::default());
+
+        let second = json!({"logs": [{"address": "0x02", "topics": [], "removed": false}]});
+        let mut blob = vec![];
+        let learned = encode_learning(&second, &mut blob, &mut fd, &mut vd, &opts, false).unwrap();
+        assert!(learned.fields.is_empty());
+        assert_eq!(
+            fd.rejected(),
+            Rejected {
+                full: 2,
+                too_long: 0
+            }
+        );
+        // refused keys are written inline
+        assert_eq!(decode_slice(&blob, &fd, &vd).unwrap(), second);
+
+        // known entries are still found
+        assert_eq!(fd.insert(b"address"), Some(2));
+        assert_eq!(fd.len(), 3);
+        fd.reset_rejected();
+        assert_eq!(fd.rejected().total(), 0);
+    }
+
+    #[test]
+    fn it_never_learns_long_values() {
+        let mut fd = MapDictionary::new();
+        let mut vd = BoundedDictionary::new(MapDictionary::new(), 100, 8);
+        let opts = EncodeOptions::default();
+        let memo = "a memo that is too long to learn";
+        let v = json!([
+            {"kind": "transfer", "memo": memo},
+            {"kind": "transfer", "memo": memo},
+            {"kind": "12345678", "memo": "123456789"},
+            {"kind": "12345678", "memo": "123456789"},
+        ]);
+        let mut blob = vec![];
+        let learned = encode_learning(&v, &mut blob, &mut fd, &mut vd, &opts, true).unwrap();
+        let values: Vec<&str> = learned.values.iter().map(|(_, v)| v.as_str()).collect();
+        assert_eq!(values, vec!["transfer", "12345678"]);
+        assert_eq!(
+            vd.rejected(),
+            Rejected {
+                full: 0,
+                too_long: 2
+            }
+        );
+        assert_eq!(vd.find_str(memo), None);
+    }
+}
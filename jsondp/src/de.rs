@@ -0,0 +1,536 @@
+use crate::decode::{item_of, key_of, next_u8, Item, Key, DEFAULT_MAX_DEPTH};
+use crate::dictionary::DictionaryRead;
+use crate::error::SerdeError;
+use crate::{verify_fingerprints, DecodeError, BACK_REFS_TAG, FINGERPRINT_TAG};
+use anyhow::anyhow;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::forward_to_deserialize_any;
+use serde_json::Number;
+use std::io::Read;
+
+type Result<T> = std::result::Result<T, SerdeError>;
+
+/// Deserializer of the encoded form, reads values into types
+/// the same way `decode_as` does, without building the JSON value
+pub struct Deserializer<'a, R, D1, D2> {
+    input: R,
+    fd: &'a D1,
+    vd: &'a D2,
+    // prefix that was read to look at the next value, but not consumed
+    peeked: Option<u8>,
+    // bytes consumed, for errors
+    offset: u64,
+    // containers around the current value
+    depth: usize,
+}
+
+impl<'a, R: Read, D1: DictionaryRead, D2: DictionaryRead> Deserializer<'a, R, D1, D2> {
+    pub fn new(input: R, fd: &'a D1, vd: &'a D2) -> Self {
+        Self {
+            input,
+            fd,
+            vd,
+            peeked: None,
+            offset: 0,
+            depth: 0,
+        }
+    }
+
+    // verifies the optional fingerprint header, the value follows it
+    fn header(&mut self) -> Result<()> {
+        let nb = self.prefix()?;
+        if nb == FINGERPRINT_TAG {
+            verify_fingerprints(&mut self.input, self.fd, self.vd)?;
+            self.offset += 32;
+        } else {
+            self.peeked = Some(nb);
+        }
+        if self.peek()? == BACK_REFS_TAG {
+            return Err(anyhow!("back-references are only read by decode").into());
+        }
+        Ok(())
+    }
+
+    fn prefix(&mut self) -> Result<u8> {
+        if let Some(nb) = self.peeked.take() {
+            return Ok(nb);
+        }
+        self.offset += 1;
+        Ok(next_u8(&mut self.input)?)
+    }
+
+    fn peek(&mut self) -> Result<u8> {
+        let nb = self.prefix()?;
+        self.peeked = Some(nb);
+        Ok(nb)
+    }
+
+    fn item(&mut self) -> Result<Item> {
+        let nb = self.prefix()?;
+        Ok(item_of(nb, &mut self.input, None)?)
+    }
+
+    // nesting is limited as in decoding, so crafted input can't overflow the stack
+    fn enter(&mut self) -> Result<()> {
+        if self.depth >= DEFAULT_MAX_DEPTH {
+            let offset = self.offset.saturating_sub(1);
+            return Err(anyhow::Error::from(DecodeError::MaxDepthExceeded { offset }).into());
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn value_str(&self, dict_id: u32) -> Result<&'a str> {
+        match self.vd.get(dict_id) {
+            Some(buf) => Ok(std::str::from_utf8(buf).map_err(anyhow::Error::from)?),
+            None => Err(anyhow!("value {} not found in dictionary", dict_id).into()),
+        }
+    }
+
+    fn key(&mut self) -> Result<String> {
+        let nb = self.prefix()?;
+        match key_of(nb, &mut self.input, None)? {
+            Key::Field(dict_id) => match self.fd.get(dict_id) {
+                Some(found) => Ok(std::str::from_utf8(found)
+                    .map_err(anyhow::Error::from)?
+                    .to_string()),
+                None => Err(anyhow!("field value {} not found in dictionary", dict_id).into()),
+            },
+            Key::Str(s) => Ok(s),
+            Key::Number(n) => Ok(n.to_string()),
+        }
+    }
+}
+
+/// reads a value of the type from the encoded form,
+/// same as `decode_as` without building the JSON value
+pub fn from_reader<T: DeserializeOwned, R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: R,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<T> {
+    let mut de = Deserializer::new(input, fd, vd);
+    de.header().map_err(|e| e.0)?;
+    T::deserialize(&mut de).map_err(|e| e.0)
+}
+
+/// same as `from_reader`, for a blob in memory
+pub fn from_slice<T: DeserializeOwned, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &[u8],
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<T> {
+    from_reader(input, fd, vd)
+}
+
+// integers over 64 bits are only made with arbitrary precision
+fn visit_number<'de, V: Visitor<'de>>(n: Number, visitor: V) -> Result<V::Value> {
+    if let Some(u) = n.as_u64() {
+        return visitor.visit_u64(u);
+    }
+    if let Some(i) = n.as_i64() {
+        return visitor.visit_i64(i);
+    }
+    #[cfg(feature = "arbitrary_precision")]
+    {
+        if let Ok(u) = n.as_str().parse() {
+            return visitor.visit_u128(u);
+        }
+        if let Ok(i) = n.as_str().parse() {
+            return visitor.visit_i128(i);
+        }
+    }
+    visitor.visit_f64(n.as_f64().unwrap_or_default())
+}
+
+fn hex_string(b: &[u8]) -> String {
+    format!("0x{}", hex::encode(b))
+}
+
+impl<'de, R: Read, D1: DictionaryRead, D2: DictionaryRead> de::Deserializer<'de>
+    for &mut Deserializer<'_, R, D1, D2>
+{
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.item()? {
+            Item::Null => visitor.visit_unit(),
+            Item::Bool(b) => visitor.visit_bool(b),
+            Item::Number(n) => visit_number(n, visitor),
+            Item::NonFinite(f) => visitor.visit_f64(f),
+            Item::Bytes(b) | Item::BigNumber(b) => visitor.visit_string(hex_string(&b)),
+            Item::Str(s) => visitor.visit_string(s),
+            Item::BackRef(_) => Err(anyhow!("back-references are only read by decode").into()),
+            Item::ValueRef(dict_id) => visitor.visit_str(self.value_str(dict_id)?),
+            Item::BytesRef(dict_id) => match self.vd.get(dict_id) {
+                Some(buf) => visitor.visit_string(hex_string(buf)),
+                None => Err(anyhow!("value {} not found in dictionary", dict_id).into()),
+            },
+            Item::Packed(nums) => {
+                let items = nums.into_iter().map(serde_json::Value::Number).collect();
+                let value = serde_json::Value::Array(items).deserialize_any(visitor);
+                Ok(value.map_err(anyhow::Error::from)?)
+            }
+            Item::Array(size) => {
+                self.enter()?;
+                let mut seq = Seq {
+                    de: self,
+                    left: size,
+                };
+                let value = visitor.visit_seq(&mut seq)?;
+                if seq.left > 0 {
+                    return Err(de::Error::invalid_length(size, &"fewer elements in array"));
+                }
+                self.depth -= 1;
+                Ok(value)
+            }
+            Item::Object(size) => {
+                self.enter()?;
+                let mut fields = Fields {
+                    de: self,
+                    left: size,
+                };
+                let value = visitor.visit_map(&mut fields)?;
+                if fields.left > 0 {
+                    return Err(de::Error::invalid_length(size, &"fewer fields in object"));
+                }
+                self.depth -= 1;
+                Ok(value)
+            }
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self.peek()? & 0x1F {
+            31 => {
+                self.peeked = None;
+                visitor.visit_none()
+            }
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // variants are strings, or objects of one field named after the variant
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.item()? {
+            Item::Str(s) => visitor.visit_enum(s.into_deserializer()),
+            Item::ValueRef(dict_id) => {
+                visitor.visit_enum(self.value_str(dict_id)?.into_deserializer())
+            }
+            Item::Object(1) => {
+                self.enter()?;
+                let value = visitor.visit_enum(Variant { de: &mut *self })?;
+                self.depth -= 1;
+                Ok(value)
+            }
+            _ => Err(de::Error::custom(
+                "expected a string or an object of one field",
+            )),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct Seq<'d, 'a, R, D1, D2> {
+    de: &'d mut Deserializer<'a, R, D1, D2>,
+    left: usize,
+}
+
+impl<'de, R: Read, D1: DictionaryRead, D2: DictionaryRead> de::SeqAccess<'de>
+    for Seq<'_, '_, R, D1, D2>
+{
+    type Error = SerdeError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<Option<T::Value>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+struct Fields<'d, 'a, R, D1, D2> {
+    de: &'d mut Deserializer<'a, R, D1, D2>,
+    left: usize,
+}
+
+impl<'de, R: Read, D1: DictionaryRead, D2: DictionaryRead> de::MapAccess<'de>
+    for Fields<'_, '_, R, D1, D2>
+{
+    type Error = SerdeError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(MapKey(self.de.key()?)).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+struct Variant<'d, 'a, R, D1, D2> {
+    de: &'d mut Deserializer<'a, R, D1, D2>,
+}
+
+impl<'de, R: Read, D1: DictionaryRead, D2: DictionaryRead> de::EnumAccess<'de>
+    for Variant<'_, '_, R, D1, D2>
+{
+    type Error = SerdeError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self)> {
+        let variant = seed.deserialize(MapKey(self.de.key()?))?;
+        Ok((variant, self))
+    }
+}
+
+impl<'de, R: Read, D1: DictionaryRead, D2: DictionaryRead> de::VariantAccess<'de>
+    for Variant<'_, '_, R, D1, D2>
+{
+    type Error = SerdeError;
+
+    fn unit_variant(self) -> Result<()> {
+        de::Deserialize::deserialize(self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        seed.deserialize(self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        de::Deserializer::deserialize_seq(self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        de::Deserializer::deserialize_map(self.de, visitor)
+    }
+}
+
+// object key, numbers are parsed from it as serde_json does
+struct MapKey(String);
+
+macro_rules! deserialize_parsed_key {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+                match self.0.parse() {
+                    Ok(n) => visitor.$visit(n),
+                    Err(_) => Err(de::Error::invalid_value(
+                        de::Unexpected::Str(&self.0),
+                        &visitor,
+                    )),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for MapKey {
+    type Error = SerdeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_parsed_key! {
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_bool => visit_bool,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 f32 f64 char str string bytes byte_buf unit unit_struct
+        seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{MapDictionary, NoDictionary};
+    use crate::to_vec;
+    use serde::{Deserialize, Serialize};
+    use std::collections::HashMap;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Event {
+        Empty,
+        Amount(i64),
+        Pair(u8, String),
+        Swap { sold: f64, bought: u32 },
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Id(u64);
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Record {
+        from: String,
+        hash: String,
+        id: Id,
+        block: Option<u64>,
+        memo: Option<String>,
+        events: Vec<Event>,
+        flags: HashMap<u32, bool>,
+        pair: (i8, char),
+        ratio: f32,
+    }
+
+    fn record() -> Record {
+        Record {
+            from: "0x95087266018b9637aff3d76d4e0cad7e52c19636".to_string(),
+            hash: "0xd8052f44b36869fa1f193ec2c97e6a36892840635dc347554efb8778a7a3935a".to_string(),
+            id: Id(70000),
+            block: Some(17_000_000),
+            memo: None,
+            events: vec![
+                Event::Empty,
+                Event::Amount(-5),
+                Event::Pair(7, "seven".to_string()),
+                Event::Swap {
+                    sold: 1.5,
+                    bought: 3,
+                },
+            ],
+            flags: HashMap::from([(1, true), (1000, false)]),
+            pair: (-1, 'x'),
+            ratio: 0.25,
+        }
+    }
+
+    #[test]
+    fn it_round_trips_types() {
+        let d = MapDictionary::from_static(&[
+            "from",
+            "events",
+            "Amount",
+            "0x95087266018b9637aff3d76d4e0cad7e52c19636",
+        ]);
+        let nod = NoDictionary {};
+        let r = record();
+        let buf = to_vec(&r, &d, &d).unwrap();
+        assert_eq!(from_slice::<Record, _, _>(&buf, &d, &d).unwrap(), r);
+        let buf = to_vec(&r, &nod, &nod).unwrap();
+        assert_eq!(
+            from_reader::<Record, _, _, _>(buf.as_slice(), &nod, &nod).unwrap(),
+            r
+        );
+
+        // same as going through the value
+        let mut input = buf.as_slice();
+        let via_value: Record = crate::decode_as(&mut input, &nod, &nod).unwrap();
+        assert_eq!(via_value, r);
+
+        // fingerprinted blobs are verified
+        let mut buf = vec![];
+        crate::encode_with_fingerprint(&serde_json::to_value(&r).unwrap(), &mut buf, &d, &d)
+            .unwrap();
+        assert_eq!(from_slice::<Record, _, _>(&buf, &d, &d).unwrap(), r);
+    }
+
+    #[test]
+    fn it_rejects_other_shapes() {
+        let nod = NoDictionary {};
+        let buf = to_vec(&vec![1, 2, 3], &nod, &nod).unwrap();
+        assert!(from_slice::<(u8, u8), _, _>(&buf, &nod, &nod).is_err());
+        assert!(from_slice::<Record, _, _>(&buf, &nod, &nod).is_err());
+        assert!(from_slice::<Vec<u8>, _, _>(&buf[..3], &nod, &nod).is_err());
+    }
+
+    #[cfg(feature = "arbitrary_precision")]
+    #[test]
+    fn it_round_trips_128_bit_integers() {
+        let nod = NoDictionary {};
+        let v = (u128::MAX, i128::MIN, 1u128 << 100);
+        let buf = to_vec(&v, &nod, &nod).unwrap();
+        assert_eq!(
+            from_slice::<(u128, i128, u128), _, _>(&buf, &nod, &nod).unwrap(),
+            v
+        );
+    }
+
+    #[cfg(feature = "eth")]
+    #[test]
+    fn it_round_trips_ethers_log() {
+        use ethers::types::{Address, Bytes, Log, H256, U256, U64};
+        let log = Log {
+            address: Address::repeat_byte(0x11),
+            topics: vec![H256::repeat_byte(0x22), H256::zero()],
+            // shorter hex would come back padded to the width, as in `decode`
+            data: Bytes::from(vec![7; 32]),
+            block_hash: Some(H256::repeat_byte(0x33)),
+            block_number: Some(U64::from(17_000_000)),
+            transaction_hash: Some(H256::repeat_byte(0x44)),
+            transaction_index: Some(U64::from(5)),
+            log_index: Some(U256::from(300)),
+            transaction_log_index: None,
+            log_type: None,
+            removed: Some(false),
+        };
+        let nod = NoDictionary {};
+        let buf = to_vec(&log, &nod, &nod).unwrap();
+        assert_eq!(from_slice::<Log, _, _>(&buf, &nod, &nod).unwrap(), log);
+    }
+}
@@ -1,4 +1,11 @@
-use std::io::{BufWriter, Read, Write};
+use crate::dictionary::{DictionaryRead, IdWidth};
+use crate::error::DecodeError;
+use crate::timestamp::Timestamp;
+use crate::{Counting, BACK_REFS_TAG, FINGERPRINT_TAG};
+use anyhow::{bail, Context};
+use serde_json::Number;
+use sha3::{Digest, Keccak256};
+use std::io::Read;
 
 pub(crate) fn next_i8<R: Read>(input: &mut R) -> anyhow::Result<i8> {
     Ok(next_u8(input)? as i8)
@@ -39,6 +46,10 @@ pub(crate) fn next_f64<R: Read>(input: &mut R) -> anyhow::Result<f64> {
     Ok(f64::from_bits(next_u64(input)?))
 }
 
+pub(crate) fn next_f32<R: Read>(input: &mut R) -> anyhow::Result<f32> {
+    Ok(f32::from_bits(next_u32(input)?))
+}
+
 pub(crate) fn next_i64<R: Read>(input: &mut R) -> anyhow::Result<i64> {
     Ok(next_u64(input)? as i64)
 }
@@ -57,42 +68,752 @@ pub(crate) fn next_u64<R: Read>(input: &mut R) -> anyhow::Result<u64> {
     Ok(out)
 }
 
-pub(crate) fn next_u128<R: Read>(input: &mut R) -> anyhow::Result<u128> {
-    let mut first = [0; 16];
-    input.read_exact(&mut first)?; // read exactly 2 bytes
-    let out: u128 = (first[15] as u128) << 120
-        | (first[14] as u128) << 112
-        | (first[13] as u128) << 104
-        | (first[12] as u128) << 96
-        | (first[11] as u128) << 88
-        | (first[10] as u128) << 80
-        | (first[9] as u128) << 72
-        | (first[8] as u128) << 64
-        | (first[7] as u128) << 56
-        | (first[6] as u128) << 48
-        | (first[5] as u128) << 40
-        | (first[4] as u128) << 32
-        | (first[3] as u128) << 24
-        | (first[2] as u128) << 16
-        | (first[1] as u128) << 8
-        | (first[0] as u128);
-    Ok(out)
+// sizes read at once, longer values grow with the data actually read,
+// as their size may come from crafted input
+const READ_AT_ONCE: usize = 1 << 16;
+
+/// reads the given number of bytes into a vector of that size
+pub(crate) fn next_bytes<R: Read>(input: &mut R, bytes_to_read: usize) -> anyhow::Result<Vec<u8>> {
+    if bytes_to_read <= READ_AT_ONCE {
+        let mut buf = vec![0; bytes_to_read];
+        input.read_exact(&mut buf)?;
+        return Ok(buf);
+    }
+    let mut buf = Vec::new();
+    input.take(bytes_to_read as u64).read_to_end(&mut buf)?;
+    if buf.len() < bytes_to_read {
+        return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
 }
 
-pub(crate) fn next<R: Read, W: Write>(
-    input: &mut R,
-    bytes_to_read: usize,
-    w: &mut W,
-) -> anyhow::Result<()> {
-    let mut buf = vec![0u8; bytes_to_read];
-    input.read_exact(&mut buf)?; // read exactly N bytes
-    w.write(&buf)?;
+// fails when the size read from the stream is over what is left of the input;
+// `limit` is the budget after the type prefix, `prefix` is the size of the length
+pub(crate) fn check_declared(size: usize, limit: Option<u64>, prefix: u64) -> anyhow::Result<()> {
+    if let Some(limit) = limit {
+        let remaining = limit.saturating_sub(prefix);
+        if size as u64 > remaining {
+            return Err(DecodeError::BudgetExceeded {
+                declared: size as u64,
+                remaining,
+                unit: "bytes",
+            }
+            .into());
+        }
+    }
     Ok(())
 }
 
 pub(crate) fn next_str<R: Read>(input: &mut R, bytes_to_read: usize) -> anyhow::Result<String> {
-    let mut buf = BufWriter::new(vec![]);
-    next(input, bytes_to_read, &mut buf)?;
-    let b = buf.into_inner()?;
-    Ok(String::from_utf8(b)?)
+    let b = next_bytes(input, bytes_to_read)?;
+    String::from_utf8(b).context("invalid UTF-8 in string")
+}
+
+/// width of the fixed-width bytes type (B8 to B256), None for other types
+pub(crate) fn fixed_width(nb: u8) -> Option<usize> {
+    match nb & 0x1F {
+        4 => Some(1),
+        12 => Some(2),
+        13 => Some(4),
+        11 => Some(8),
+        14 => Some(16),
+        15 => Some(20),
+        16 => Some(32),
+        _ => None,
+    }
+}
+
+/// Byte order of fixed-width bytes, it converts both ways. B8 and B16 are stored
+/// in the order of the value, wider types as a little-endian integer, i.e. reversed.
+/// Values shorter than the width are zero-extended, so they decode with leading zeros
+pub(crate) fn fixed_order(b: &mut [u8]) {
+    if b.len() > 2 {
+        b.reverse();
+    }
+}
+
+/// Object key as it is stored in the stream
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    /// id in the field dictionary
+    Field(u32),
+    /// inline string
+    Str(String),
+    /// key of decimal digits, stored as the number
+    Number(u64),
+}
+
+/// Single item of the encoded stream, with dictionary references not resolved.
+/// Containers only carry the number of children that follow them
+#[derive(Debug, Clone, PartialEq)]
+pub enum Item {
+    Null,
+    Bool(bool),
+    Number(Number),
+    /// bytes in big-endian order, rendered as 0x-prefixed hex
+    Bytes(Vec<u8>),
+    Str(String),
+    /// id in the value dictionary
+    ValueRef(u32),
+    /// id of raw bytes in the value dictionary, rendered as 0x-prefixed hex
+    BytesRef(u32),
+    /// bytes of a `{"type": "BigNumber", "hex": ...}` object
+    BigNumber(Vec<u8>),
+    /// index of an earlier string of the value, see `EncodeOptions::back_references`
+    BackRef(u32),
+    /// NaN or an infinity, which are not JSON numbers
+    NonFinite(f64),
+    /// elements of a packed array, which are read with its prefix
+    Packed(Vec<Number>),
+    Array(usize),
+    Object(usize),
+}
+
+/// bit of the db and dwb prefixes without the dictionary flag, marks bytes of a BigNumber object
+pub(crate) const BIG_NUMBER: u8 = 0x40;
+
+/// bit of the b128 prefix for 128-bit integers, and of the ds prefix
+/// without the dictionary flag for numbers kept as decimal text
+pub(crate) const NUMERIC: u8 = 0x40;
+
+/// bit of the numeric b128 prefix for i128, u128 without it
+pub(crate) const NEGATIVE: u8 = 0x80;
+
+/// bit of the f64 prefix for floats stored in 4 bytes, that f32 holds exactly
+pub(crate) const F32: u8 = 0x40;
+
+/// bit of the u32 and u64 prefixes for timestamp strings: u32 seconds
+/// or u64 milliseconds, rendered as RFC3339 in UTC
+pub(crate) const TIMESTAMP: u8 = 0x40;
+
+/// bit of the u32 timestamp prefix for seconds written as decimal digits
+pub(crate) const DIGITS: u8 = 0x80;
+
+/// bit of the db, ds, da and do prefixes without the dictionary flag
+/// for lengths in LEB128, so one tag takes any length
+pub(crate) const VARINT: u8 = 0x80;
+
+/// reads LEB128 integer in the fewest bytes, returns it with the number of bytes read
+pub(crate) fn next_varint<R: Read>(input: &mut R) -> anyhow::Result<(u64, u64)> {
+    let mut v = 0u64;
+    for i in 0..10 {
+        let b = next_u8(input)?;
+        if i == 9 && b > 1 {
+            bail!("varint over 64 bits");
+        }
+        v |= ((b & 0x7F) as u64) << (7 * i);
+        if b & 0x80 == 0 {
+            if b == 0 && i > 0 {
+                bail!("varint with trailing zeros");
+            }
+            return Ok((v, i + 1));
+        }
+    }
+    bail!("varint over 64 bits")
+}
+
+// length after the db, ds, da or do prefix and the number of bytes it took
+pub(crate) fn next_len<R: Read>(nb: u8, input: &mut R) -> anyhow::Result<(usize, u64)> {
+    if nb & VARINT == 0 {
+        return Ok((next_u8(input)? as usize, 1));
+    }
+    let (len, read) = next_varint(input)?;
+    Ok((usize::try_from(len)?, read))
+}
+
+/// bit of the da and dwa prefixes for arrays of numbers of one type: the type tag
+/// of the elements follows the length, then the elements without prefixes
+pub(crate) const PACKED: u8 = 0x40;
+
+// elements of the packed array after its length
+fn packed<R: Read>(size: usize, input: &mut R, limit: Option<u64>) -> anyhow::Result<Item> {
+    let ty = next_u8(input)?;
+    let width = match ty {
+        2 => 1,
+        5 => 2,
+        7 => 4,
+        9 | 17 => 8,
+        _ => bail!("invalid packed array of {}", type_name(ty)),
+    };
+    check_declared(size.saturating_mul(width), limit, 1)?;
+    // grows with the elements actually read, the size is not checked without a limit
+    let mut nums = Vec::with_capacity(size.min(4096));
+    for _ in 0..size {
+        let n = match ty {
+            2 => Number::from(next_u8(input)?),
+            5 => Number::from(next_u16(input)?),
+            7 => Number::from(next_u32(input)?),
+            9 => Number::from(next_u64(input)?),
+            _ => match Number::from_f64(next_f64(input)?) {
+                Some(n) => n,
+                None => bail!("non-finite float in packed array"),
+            },
+        };
+        nums.push(n);
+    }
+    Ok(Item::Packed(nums))
+}
+
+// number over 64 bits from its decimal text
+#[cfg(feature = "arbitrary_precision")]
+fn wide_number(nb: u8, text: String) -> anyhow::Result<Item> {
+    match text.parse() {
+        Ok(n) => Ok(Item::Number(n)),
+        Err(_) => bail!("invalid {} {}", type_name(nb), text),
+    }
+}
+
+#[cfg(not(feature = "arbitrary_precision"))]
+fn wide_number(nb: u8, _text: String) -> anyhow::Result<Item> {
+    bail!("{} needs the arbitrary_precision feature", type_name(nb))
+}
+
+// id of the value dictionary reference; references without width bits
+// were written before widths were signaled, and always have u32 ids
+fn value_id<R: Read>(nb: u8, input: &mut R) -> anyhow::Result<u32> {
+    IdWidth::from_prefix(nb).unwrap_or(IdWidth::U32).read(input)
+}
+
+fn value_id_name(width: Option<IdWidth>) -> &'static str {
+    match width {
+        Some(IdWidth::U8) => "u8 value id",
+        Some(IdWidth::U16) => "u16 value id",
+        Some(IdWidth::U32) => "u32 value id",
+        None => "u32 value id without width",
+    }
+}
+
+// hex of the bytes without the leading zero digits that were not in the source
+fn trim_hex(b: &[u8], trim: usize) -> anyhow::Result<String> {
+    let digits = hex::encode(b);
+    if trim >= digits.len() || digits.bytes().take(trim).any(|c| c != b'0') {
+        bail!("hex width drops {} digits of 0x{}", trim, digits);
+    }
+    Ok(format!("0x{}", &digits[trim..]))
+}
+
+/// EIP-55 form of the address: hex digits are uppercased where
+/// the nibble of keccak of the lowercase hex is 8 or more
+pub fn checksum_address(b: &[u8]) -> String {
+    let digits = hex::encode(b);
+    let hash = Keccak256::digest(digits.as_bytes());
+    let mixed: String = digits
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = match i % 2 {
+                0 => hash[i / 2] >> 4,
+                _ => hash[i / 2] & 0x0f,
+            };
+            match nibble {
+                8.. => c.to_ascii_uppercase(),
+                _ => c,
+            }
+        })
+        .collect();
+    format!("0x{}", mixed)
+}
+
+/// big-endian bytes of up to 8 as an integer, e.g. of a hex quantity
+pub(crate) fn quantity(b: &[u8]) -> u64 {
+    b.iter().fold(0, |n, x| (n << 8) | u64::from(*x))
+}
+
+/// name of the float that has no JSON number, as JavaScript writes it
+pub fn non_finite_name(f: f64) -> &'static str {
+    match f {
+        f if f.is_nan() => "NaN",
+        f if f > 0.0 => "Infinity",
+        _ => "-Infinity",
+    }
+}
+
+/// reads next item from the stream
+pub fn next_item<R: Read>(input: &mut R) -> anyhow::Result<Item> {
+    let nb = next_u8(input)?;
+    item_of(nb, input, None)
+}
+
+/// reads the rest of the item which type prefix is already consumed.
+/// `limit` is the number of bytes left in the input, declared sizes over it fail
+pub(crate) fn item_of<R: Read>(nb: u8, input: &mut R, limit: Option<u64>) -> anyhow::Result<Item> {
+    let use_vd = (nb & 0x20) > 0;
+    match nb & 0x1F {
+        0 => Ok(Item::Bool(false)),
+        1 => Ok(Item::Bool(true)),
+        7 if nb & TIMESTAMP > 0 => {
+            let secs = next_u32(input)?;
+            let t = match nb & DIGITS {
+                0 => Timestamp::Seconds(secs),
+                _ => Timestamp::Digits(secs),
+            };
+            Ok(Item::Str(t.render()))
+        }
+        9 if nb & TIMESTAMP > 0 => {
+            if nb & DIGITS > 0 {
+                bail!("invalid field type");
+            }
+            Ok(Item::Str(Timestamp::Millis(next_u64(input)?).render()))
+        }
+        2 => Ok(Item::Number(Number::from(next_u8(input)?))),
+        3 => Ok(Item::Number(Number::from(next_i8(input)?))),
+        5 => Ok(Item::Number(Number::from(next_u16(input)?))),
+        6 => Ok(Item::Number(Number::from(next_i16(input)?))),
+        7 => Ok(Item::Number(Number::from(next_u32(input)?))),
+        8 => Ok(Item::Number(Number::from(next_i32(input)?))),
+        9 => Ok(Item::Number(Number::from(next_u64(input)?))),
+        10 => Ok(Item::Number(Number::from(next_i64(input)?))),
+        14 if nb & NUMERIC > 0 => {
+            let mut b = [0u8; 16];
+            input.read_exact(&mut b)?;
+            let text = match nb & NEGATIVE {
+                0 => u128::from_le_bytes(b).to_string(),
+                _ => i128::from_le_bytes(b).to_string(),
+            };
+            wide_number(nb, text)
+        }
+        4 | 11..=16 => {
+            let mut b = vec![0u8; fixed_width(nb).unwrap_or_default()];
+            input.read_exact(&mut b)?;
+            fixed_order(&mut b);
+            Ok(Item::Bytes(b))
+        }
+        17 => {
+            let f = match nb & F32 {
+                0 => next_f64(input)?,
+                _ => next_f32(input)?.into(),
+            };
+            match Number::from_f64(f) {
+                Some(n) => Ok(Item::Number(n)),
+                None => Ok(Item::NonFinite(f)),
+            }
+        }
+        18 => Ok(Item::Number(Number::from(0))),
+        19 => {
+            if use_vd {
+                return Ok(Item::BytesRef(value_id(nb, input)?));
+            }
+            let (size, read) = next_len(nb, input)?;
+            check_declared(size, limit, read)?;
+            let b = next_bytes(input, size)?;
+            match nb & BIG_NUMBER {
+                0 => Ok(Item::Bytes(b)),
+                _ => Ok(Item::BigNumber(b)),
+            }
+        }
+        20 if !use_vd && nb & NUMERIC > 0 => {
+            let (size, read) = next_len(nb, input)?;
+            check_declared(size, limit, read)?;
+            wide_number(nb, next_str(input, size)?)
+        }
+        20 => {
+            if use_vd {
+                return Ok(Item::ValueRef(value_id(nb, input)?));
+            }
+            let (size, read) = next_len(nb, input)?;
+            check_declared(size, limit, read)?;
+            Ok(Item::Str(next_str(input, size)?))
+        }
+        23 => {
+            let size = next_u16(input)? as usize;
+            check_declared(size, limit, 2)?;
+            let b = next_bytes(input, size)?;
+            match nb & BIG_NUMBER {
+                0 => Ok(Item::Bytes(b)),
+                _ => Ok(Item::BigNumber(b)),
+            }
+        }
+        24 => {
+            let size = next_u16(input)? as usize;
+            check_declared(size, limit, 2)?;
+            Ok(Item::Str(next_str(input, size)?))
+        }
+        28 | 29 => {
+            let size = next_u32(input)? as usize;
+            check_declared(size, limit, 4)?;
+            match nb & 0x1F {
+                28 => Ok(Item::Str(next_str(input, size)?)),
+                _ => Ok(Item::Bytes(next_bytes(input, size)?)),
+            }
+        }
+        // every child takes at least one byte
+        21 | 22 | 25 | 26 => {
+            let (size, prefix) = match nb & 0x1F {
+                21 | 22 => next_len(nb, input)?,
+                _ => (next_u16(input)? as usize, 2),
+            };
+            check_declared(size, limit, prefix)?;
+            match nb & 0x1F {
+                21 | 25 if nb & PACKED > 0 => {
+                    packed(size, input, limit.map(|l| l.saturating_sub(prefix)))
+                }
+                21 | 25 => Ok(Item::Array(size)),
+                _ => Ok(Item::Object(size)),
+            }
+        }
+        // hex width has no flags, with the width of an index it is a back-reference
+        27 if IdWidth::from_prefix(nb).is_some() => Ok(Item::BackRef(value_id(nb, input)?)),
+        27 => {
+            let trim = next_u8(input)? as usize;
+            let nb = next_u8(input)?;
+            match item_of(nb, input, limit.map(|l| l.saturating_sub(2)))? {
+                Item::Bytes(b) => Ok(Item::Str(trim_hex(&b, trim)?)),
+                _ => bail!("hex width before {}", type_name(nb)),
+            }
+        }
+        31 => Ok(Item::Null),
+        _ => bail!("invalid field type"),
+    }
+}
+
+/// reads object key from the stream
+pub fn next_key<R: Read>(input: &mut R) -> anyhow::Result<Key> {
+    let nb = next_u8(input)?;
+    key_of(nb, input, None)
+}
+
+/// reads past one value, with the children of containers and the header of
+/// a document before it, without building it. Returns the number of bytes read
+pub fn skip_value<R: Read>(input: &mut R) -> anyhow::Result<u64> {
+    let mut input = Counting::new(input, None);
+    let mut nb = next_u8(&mut input)?;
+    if nb == FINGERPRINT_TAG {
+        input.read_exact(&mut [0u8; 32])?;
+        nb = next_u8(&mut input)?;
+    }
+    if nb == BACK_REFS_TAG {
+        nb = next_u8(&mut input)?;
+    }
+    let item = item_of(nb, &mut input, None)?;
+    read_children(item, &mut input).map_err(|(_, e)| e)?;
+    Ok(input.bytes)
+}
+
+/// reads the keys and values in the container of the item, if it is one,
+/// and returns the number of values read with the item. Errors come with
+/// the offset of the key or value that failed
+pub(crate) fn read_children<R: Read>(
+    mut item: Item,
+    input: &mut Counting<'_, R>,
+) -> Result<u64, (u64, anyhow::Error)> {
+    let mut values = 0;
+    // children left in the open containers, and if they are fields
+    let mut open: Vec<(usize, bool)> = vec![];
+    loop {
+        values += 1;
+        match item {
+            Item::Array(size) => open.push((size, false)),
+            Item::Object(size) => open.push((size, true)),
+            Item::Packed(nums) => values += nums.len() as u64,
+            _ => {}
+        }
+        while let Some((0, _)) = open.last() {
+            open.pop();
+        }
+        let fields = match open.last_mut() {
+            Some((left, fields)) => {
+                *left -= 1;
+                *fields
+            }
+            None => return Ok(values),
+        };
+        let mut offset = input.bytes;
+        if fields {
+            let nb = next_u8(input).map_err(|e| (offset, e))?;
+            let left = input.remaining();
+            key_of(nb, input, left).map_err(|e| (offset, e))?;
+            offset = input.bytes;
+        }
+        let nb = next_u8(input).map_err(|e| (offset, e))?;
+        let left = input.remaining();
+        item = item_of(nb, input, left).map_err(|e| (offset, e))?;
+    }
+}
+
+/// reads the rest of the key which prefix is already consumed,
+/// `limit` as in `item_of`
+pub(crate) fn key_of<R: Read>(nb: u8, input: &mut R, limit: Option<u64>) -> anyhow::Result<Key> {
+    let fprefix = nb & 0x1F;
+    if let Some(width) = IdWidth::from_prefix(nb) {
+        Ok(Key::Field(width.read(input)?))
+    } else if (nb & 0x20) > 0 && matches!(fprefix, 2 | 5 | 7 | 9 | 18) {
+        let n = match fprefix {
+            2 => next_u8(input)? as u64,
+            5 => next_u16(input)? as u64,
+            7 => next_u32(input)? as u64,
+            9 => next_u64(input)?,
+            _ => 0,
+        };
+        Ok(Key::Number(n))
+    } else if fprefix == 20 {
+        let sz = next_u8(input)? as usize;
+        check_declared(sz, limit, 1)?;
+        Ok(Key::Str(next_str(input, sz)?))
+    } else if fprefix == 24 {
+        let sz = next_u16(input)? as usize;
+        check_declared(sz, limit, 2)?;
+        Ok(Key::Str(next_str(input, sz)?))
+    } else {
+        bail!("only short strings are supported as column names so far");
+    }
+}
+
+/// nesting of arrays and objects allowed by default
+pub const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// Options of decoding
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    /// reject values that were not written in the smallest form,
+    /// so the same document always has the same bytes
+    pub strict_minimal: bool,
+    /// deepest nesting of arrays and objects, deeper input is rejected
+    pub max_depth: usize,
+    /// bytes the decoder may consume, declared sizes over what is left fail
+    /// before anything is read. Slice decoding defaults it to the slice length
+    pub max_bytes: Option<u64>,
+    /// values the decoder may produce, containers declaring more children
+    /// than what is left fail
+    pub max_items: Option<u64>,
+    /// write hex digits of bytes values in upper case, the prefix stays `0x`
+    pub hex_uppercase: bool,
+    /// write 20-byte values as EIP-55 checksummed addresses, over `hex_uppercase`
+    pub checksum_addresses: bool,
+    /// write NaN and infinities as "NaN", "Infinity" and "-Infinity", not null
+    pub non_finite_as_strings: bool,
+    /// write values that were encoded from BigNumber objects back as
+    /// `{"type": "BigNumber", "hex": ...}`, with hex as ethers writes it
+    pub restore_bignumber: bool,
+    /// write values of up to 8 fixed bytes, like `"0x11ab4f"` quantities of
+    /// JSON-RPC, as numbers. Wider values and other bytes stay hex strings
+    pub hex_quantities_as_numbers: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            strict_minimal: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_bytes: None,
+            max_items: None,
+            hex_uppercase: false,
+            checksum_addresses: false,
+            non_finite_as_strings: false,
+            restore_bignumber: false,
+            hex_quantities_as_numbers: false,
+        }
+    }
+}
+
+/// name of the type tag, for error messages
+pub(crate) fn type_name(nb: u8) -> &'static str {
+    match nb & 0x1F {
+        0 => "false",
+        1 => "true",
+        2 => "u8",
+        3 => "i8",
+        4 => "b8",
+        5 => "u16",
+        6 => "i16",
+        7 if nb & TIMESTAMP > 0 => "timestamp",
+        7 => "u32",
+        8 => "i32",
+        9 if nb & TIMESTAMP > 0 => "timestamp_ms",
+        9 => "u64",
+        10 => "i64",
+        11 => "b64",
+        12 => "b16",
+        13 => "b32",
+        14 if nb & NUMERIC > 0 && nb & NEGATIVE > 0 => "i128",
+        14 if nb & NUMERIC > 0 => "u128",
+        14 => "b128",
+        15 => "b160",
+        16 => "b256",
+        17 if nb & F32 > 0 => "f32",
+        17 => "f64",
+        18 => "zero",
+        19 if nb & 0x20 > 0 => "bytes dictionary reference",
+        19 if nb & VARINT > 0 => "varint db",
+        19 => "db",
+        20 if nb & 0x20 > 0 => "value dictionary reference",
+        20 if nb & NUMERIC > 0 => "decimal",
+        20 if nb & VARINT > 0 => "varint ds",
+        20 => "ds",
+        21 | 25 if nb & PACKED > 0 => "packed array",
+        21 if nb & VARINT > 0 => "varint da",
+        21 => "da",
+        22 if nb & VARINT > 0 => "varint do",
+        22 => "do",
+        23 => "dwb",
+        24 => "dws",
+        25 => "dwa",
+        26 => "dwo",
+        27 if IdWidth::from_prefix(nb).is_some() => "back-reference",
+        27 => "hex width",
+        28 => "dls",
+        29 => "dlb",
+        31 => "null",
+        _ => "unknown",
+    }
+}
+
+// smallest integer type the encoder picks for the number and its width in bytes:
+// signed unless the number is over i64::MAX
+fn minimal_int(n: &Number) -> Option<(&'static str, usize)> {
+    let Some(v) = n.as_i64() else {
+        return n.as_u64().map(|_| ("u64", 8));
+    };
+    Some(if v == 0 {
+        ("zero", 0)
+    } else if i8::try_from(v).is_ok() {
+        ("i8", 1)
+    } else if i16::try_from(v).is_ok() {
+        ("i16", 2)
+    } else if i32::try_from(v).is_ok() {
+        ("i32", 4)
+    } else {
+        ("i64", 8)
+    })
+}
+
+fn int_width(nb: u8) -> Option<usize> {
+    match nb & 0x1F {
+        18 => Some(0),
+        2 | 3 => Some(1),
+        5 | 6 => Some(2),
+        7 | 8 => Some(4),
+        9 | 10 => Some(8),
+        _ => None,
+    }
+}
+
+// hex that bytes decode to exactly: lowercase, even number of digits
+fn is_hex(s: &str) -> bool {
+    s.len() > 2
+        && s.len().is_multiple_of(2)
+        && s.starts_with("0x")
+        && s.bytes()
+            .skip(2)
+            .all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn non_minimal(path: &str, expected: &str, nb: u8) -> anyhow::Error {
+    DecodeError::NonMinimalEncoding {
+        path: path.to_string(),
+        expected: expected.to_string(),
+        found: type_name(nb).to_string(),
+    }
+    .into()
+}
+
+/// fails when the item with the given prefix could have been written in a smaller form
+pub(crate) fn check_minimal<D: DictionaryRead>(
+    nb: u8,
+    item: &Item,
+    vd: &D,
+    path: &str,
+) -> anyhow::Result<()> {
+    match item {
+        Item::Number(n) if nb & 0x1F == 17 && nb & F32 == 0 => {
+            let f = n.as_f64().unwrap_or_default();
+            if f as f32 as f64 == f {
+                return Err(non_minimal(path, "f32", nb));
+            }
+        }
+        Item::NonFinite(f) if nb & F32 == 0 && !f.is_nan() => {
+            return Err(non_minimal(path, "f32", nb));
+        }
+        Item::Number(n) => {
+            if let (Some((expected, min)), Some(width)) = (minimal_int(n), int_width(nb)) {
+                if width > min {
+                    return Err(non_minimal(path, expected, nb));
+                }
+            }
+        }
+        Item::ValueRef(id) | Item::BytesRef(id) | Item::BackRef(id) => {
+            let expected = Some(IdWidth::of(*id));
+            let found = IdWidth::from_prefix(nb);
+            if found != expected {
+                return Err(DecodeError::NonMinimalEncoding {
+                    path: path.to_string(),
+                    expected: value_id_name(expected).to_string(),
+                    found: value_id_name(found).to_string(),
+                }
+                .into());
+            }
+        }
+        Item::Bytes(b) | Item::BigNumber(b) if nb & 0x1F == 23 && b.len() <= u8::MAX as usize => {
+            return Err(non_minimal(path, "db", nb));
+        }
+        Item::Bytes(b) if nb & 0x1F == 29 && b.len() <= u16::MAX as usize => {
+            return Err(non_minimal(path, "dwb", nb));
+        }
+        // the exact width of hex was asked for
+        Item::Str(_) if nb & 0x1F == 27 => {}
+        Item::Str(s) => {
+            let in_dictionary = vd.find_str(s).or_else(|| vd.find_hex(s)).is_some();
+            if s.len() <= u8::MAX as usize && in_dictionary {
+                return Err(non_minimal(path, "value dictionary reference", nb));
+            }
+            if is_hex(s) {
+                return Err(non_minimal(path, "bytes", nb));
+            }
+            if nb & 0x1F == 24 && s.len() <= u8::MAX as usize {
+                return Err(non_minimal(path, "ds", nb));
+            }
+            if nb & 0x1F == 28 && s.len() <= u16::MAX as usize {
+                return Err(non_minimal(path, "dws", nb));
+            }
+        }
+        Item::Array(size) if nb & 0x1F == 25 && *size <= u8::MAX as usize => {
+            return Err(non_minimal(path, "da", nb));
+        }
+        Item::Object(size) if nb & 0x1F == 26 && *size <= u8::MAX as usize => {
+            return Err(non_minimal(path, "do", nb));
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// fails when the key with the given prefix could have been written in a smaller form
+pub(crate) fn check_minimal_key<D: DictionaryRead>(
+    nb: u8,
+    key: &Key,
+    fd: &D,
+    path: &str,
+) -> anyhow::Result<()> {
+    let width = |w: IdWidth| match w {
+        IdWidth::U8 => "u8 field id",
+        IdWidth::U16 => "u16 field id",
+        IdWidth::U32 => "u32 field id",
+    };
+    let found = match (IdWidth::from_prefix(nb), key) {
+        (Some(w), _) => width(w),
+        (_, Key::Number(_)) => type_name(nb),
+        _ if nb & 0x1F == 24 => "dws",
+        _ => "ds",
+    };
+    let expected = match key {
+        Key::Field(id) => width(IdWidth::of(*id)),
+        Key::Str(s) if fd.find_str(s).is_some() => "field id",
+        Key::Str(s) if crate::encode::numeric_key(s).is_some() => "numeric key",
+        Key::Str(s) if s.len() > u8::MAX as usize => "dws",
+        Key::Str(_) => "ds",
+        Key::Number(n) if fd.find_str(&n.to_string()).is_some() => "field id",
+        Key::Number(0) => "zero",
+        Key::Number(n) if *n > u32::MAX as u64 => "u64",
+        Key::Number(n) if *n > u16::MAX as u64 => "u32",
+        Key::Number(n) if *n > u8::MAX as u64 => "u16",
+        Key::Number(_) => "u8",
+    };
+    if expected != found {
+        return Err(DecodeError::NonMinimalEncoding {
+            path: path.to_string(),
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
+        .into());
+    }
+    Ok(())
 }
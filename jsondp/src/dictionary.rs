@@ -1,12 +1,184 @@
-use anyhow::Context;
+use crate::decode::next_varint;
+use crate::encode::write_varint;
+use crate::error::{MergeConflict, MergeError};
+use anyhow::{bail, Context};
 use serde_json::Value;
-use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::io::{BufRead, Read, Write};
+use std::ops::Bound;
 
 /// Trait to extract values from the dictionary
 pub trait DictionaryRead {
     fn get(&self, item_id: u32) -> Option<&[u8]>;
     fn find_str(&self, value: &str) -> Option<u32>;
+    /// looks up raw bytes of the value
+    fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+        self.find_str(std::str::from_utf8(value).ok()?)
+    }
+    /// looks up hex value in canonical form: lowercase, 0x-prefixed, even number of digits.
+    /// Accepts checksummed (mixed-case) values, and bare ones without 0x prefix
+    /// only as long as addresses and topics, so other strings keep their form
+    fn find_hex(&self, value: &str) -> Option<u32> {
+        self.find_str(&normalize_hex(lookup_hex(value)?)?)
+    }
+    /// looks up 0x-prefixed hex in canonical form, so checksummed addresses
+    /// find their lowercase entries. Other strings are looked up as they are
+    fn find_str_normalized(&self, value: &str) -> Option<u32> {
+        self.find_str(&value_entry(value))
+    }
+    /// identifies the set of entries, None if it is not known
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        None
+    }
+}
+
+// dictionaries are passed by reference or boxed as well, e.g. `&dyn DictionaryRead`
+// or `Box<dyn DictionaryRead>` chosen at runtime, to the generic functions
+macro_rules! forward_read {
+    ($($ty:ty),*) => {$(
+        impl<D: DictionaryRead + ?Sized> DictionaryRead for $ty {
+            fn get(&self, item_id: u32) -> Option<&[u8]> {
+                (**self).get(item_id)
+            }
+            fn find_str(&self, value: &str) -> Option<u32> {
+                (**self).find_str(value)
+            }
+            fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+                (**self).find_bytes(value)
+            }
+            fn find_hex(&self, value: &str) -> Option<u32> {
+                (**self).find_hex(value)
+            }
+            fn find_str_normalized(&self, value: &str) -> Option<u32> {
+                (**self).find_str_normalized(value)
+            }
+            fn fingerprint(&self) -> Option<[u8; 16]> {
+                (**self).fingerprint()
+            }
+        }
+    )*};
+}
+forward_read!(&D, Box<D>, std::sync::Arc<D>);
+
+/// Trait to add values to the dictionary
+pub trait DictionaryWrite: DictionaryRead {
+    /// adds the value unless it is there already, returns its id,
+    /// or None when the dictionary takes no more entries
+    fn insert(&mut self, bytes: &[u8]) -> Option<u32>;
+}
+
+/// Width of a dictionary id in the stream, carried by the two high bits
+/// of the prefix; the id follows the prefix in little-endian order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl IdWidth {
+    /// bits of the prefix that carry the width, none of them set means no dictionary
+    pub const MASK: u8 = 0xc0;
+
+    /// smallest width that fits the id
+    pub fn of(id: u32) -> Self {
+        if id > u16::MAX as u32 {
+            Self::U32
+        } else if id > u8::MAX as u32 {
+            Self::U16
+        } else {
+            Self::U8
+        }
+    }
+
+    /// width signaled by the prefix, None when the prefix has no dictionary id
+    pub fn from_prefix(nb: u8) -> Option<Self> {
+        match nb & Self::MASK {
+            0xc0 => Some(Self::U32),
+            0x80 => Some(Self::U16),
+            0x40 => Some(Self::U8),
+            _ => None,
+        }
+    }
+
+    /// high bits of the prefix
+    pub fn bits(self) -> u8 {
+        match self {
+            Self::U8 => 0x40,
+            Self::U16 => 0x80,
+            Self::U32 => 0xc0,
+        }
+    }
+
+    /// bytes of the id
+    pub fn size(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
+
+    /// reads the id in this width
+    pub(crate) fn read<R: Read>(self, input: &mut R) -> anyhow::Result<u32> {
+        use crate::decode::{next_u16, next_u32, next_u8};
+        Ok(match self {
+            Self::U8 => next_u8(input)? as u32,
+            Self::U16 => next_u16(input)? as u32,
+            Self::U32 => next_u32(input)?,
+        })
+    }
+}
+
+/// first 16 bytes of SHA-256 over (id, length, bytes) of every entry, in the order of ids
+pub fn fingerprint_of<'a, I: IntoIterator<Item = (u32, &'a [u8])>>(entries: I) -> [u8; 16] {
+    let mut hasher = Sha256::new();
+    for (id, value) in entries {
+        hasher.update(id.to_le_bytes());
+        hasher.update((value.len() as u32).to_le_bytes());
+        hasher.update(value);
+    }
+    let mut out = [0u8; 16];
+    out.copy_from_slice(&hasher.finalize()[..16]);
+    out
+}
+
+/// converts hex string (with or without 0x prefix) into lowercase 0x-prefixed form,
+/// left-padded to even length. Returns None if the value is not hex
+pub fn normalize_hex(value: &str) -> Option<Cow<'_, str>> {
+    let digits = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+        .unwrap_or(value);
+    if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let odd = digits.len() % 2 == 1;
+    if !odd && value.starts_with("0x") && !digits.bytes().any(|c| c.is_ascii_uppercase()) {
+        // already canonical
+        return Some(Cow::Borrowed(value));
+    }
+    let mut out = String::with_capacity(digits.len() + 3);
+    out.push_str("0x");
+    if odd {
+        out.push('0');
+    }
+    out.push_str(&digits.to_ascii_lowercase());
+    Some(Cow::Owned(out))
+}
+
+// digits of bare hex that are taken for an address or a topic
+const BARE_HEX_DIGITS: [usize; 2] = [40, 64];
+
+// value which `find_hex` looks up: 0x-prefixed hex, or bare hex of an address or a topic
+fn lookup_hex(value: &str) -> Option<&str> {
+    let prefixed = value.starts_with("0x") || value.starts_with("0X");
+    match prefixed || BARE_HEX_DIGITS.contains(&value.len()) {
+        true => Some(value),
+        false => None,
+    }
 }
 
 /// Dictionary implementation that doesn't store anything ever
@@ -19,15 +191,29 @@ impl DictionaryRead for NoDictionary {
     fn find_str(&self, _: &str) -> Option<u32> {
         None
     }
+    fn find_bytes(&self, _: &[u8]) -> Option<u32> {
+        None
+    }
+    fn find_hex(&self, _: &str) -> Option<u32> {
+        None
+    }
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        Some(fingerprint_of([]))
+    }
 }
 
-/// Dictionary implementation that stores dictionary in memory
+/// Dictionary implementation that stores dictionary in memory.
+/// Entries are bytes: strings, or raw values like addresses and topics,
+/// which are found by the bytes of 0x-prefixed values
 #[derive(Debug, Clone)]
 pub struct MapDictionary {
-    v: BTreeMap<u32, String>,
-    k: BTreeMap<String, u32>,
+    v: BTreeMap<u32, Vec<u8>>,
+    k: BTreeMap<Vec<u8>, u32>,
 }
 
+// starts the binary form of `MapDictionary`
+const BINARY_MAGIC: [u8; 4] = *b"JDD1";
+
 fn split_at_colon<'a>(s: &'a str) -> Option<(&'a str, &'a str)> {
     let i = s.find(':');
     // i is a byte index, not a character index.
@@ -36,6 +222,90 @@ fn split_at_colon<'a>(s: &'a str) -> Option<(&'a str, &'a str)> {
     i.map(|i| (&s[0..i], &s[i + 1..]))
 }
 
+// entries are written as they are, except for backslashes, line breaks, tabs,
+// spaces at either end (lines are trimmed), a quote at the start
+// (quoted entries are of the older format) and bytes that are not UTF-8
+fn escape_entry(item: &[u8]) -> Cow<'_, str> {
+    let plain = |c: char| !matches!(c, '\\' | '\n' | '\r' | '\t');
+    if let Ok(item) = std::str::from_utf8(item) {
+        if item.chars().all(plain) && !item.starts_with([' ', '"']) && !item.ends_with(' ') {
+            return Cow::Borrowed(item);
+        }
+    }
+    let mut out = String::with_capacity(item.len() + 2);
+    for chunk in item.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                '\t' => out.push_str("\\t"),
+                ' ' if out.is_empty() => out.push_str("\\s"),
+                '"' if out.is_empty() => out.push_str("\\\""),
+                c => out.push(c),
+            }
+        }
+        for b in chunk.invalid() {
+            out.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    if out.ends_with(' ') {
+        out.pop();
+        out.push_str("\\s");
+    }
+    Cow::Owned(out)
+}
+
+// reverse of `escape_entry`, and of `{:?}` the entries were written with before
+fn parse_entry(v: &str) -> anyhow::Result<Vec<u8>> {
+    let quoted = v.len() > 1 && v.starts_with('"') && v.ends_with('"');
+    let (v, quoted) = match quoted {
+        true => (&v[1..v.len() - 1], true),
+        false => (v, false),
+    };
+    let mut out = Vec::with_capacity(v.len());
+    let push = |out: &mut Vec<u8>, c: char| {
+        out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+    };
+    let mut chars = v.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            push(&mut out, c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push(b'\\'),
+            Some('n') => out.push(b'\n'),
+            Some('r') => out.push(b'\r'),
+            Some('t') => out.push(b'\t'),
+            Some('"') => out.push(b'"'),
+            Some('s') if !quoted => out.push(b' '),
+            Some('x') if !quoted => {
+                let code: String = chars.by_ref().take(2).collect();
+                let b = u8::from_str_radix(&code, 16)
+                    .ok()
+                    .filter(|_| code.len() == 2)
+                    .with_context(|| format!("invalid escape \\x{}", code))?;
+                out.push(b);
+            }
+            Some('\'') if quoted => out.push(b'\''),
+            Some('0') if quoted => out.push(0),
+            Some('u') if quoted => {
+                let code: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let c = code
+                    .strip_prefix('{')
+                    .and_then(|code| u32::from_str_radix(code, 16).ok())
+                    .and_then(char::from_u32)
+                    .with_context(|| format!("invalid escape \\u{}", code))?;
+                push(&mut out, c);
+            }
+            Some(c) => bail!("invalid escape \\{}", c),
+            None => bail!("escape at the end"),
+        }
+    }
+    Ok(out)
+}
+
 impl MapDictionary {
     pub fn new() -> Self {
         Self {
@@ -44,16 +314,46 @@ impl MapDictionary {
         }
     }
 
-    // protected: insert new element to the map
+    /// inserts the string, 0x-prefixed hex in canonical form to be found
+    /// by `find_str_normalized` in any case. Other strings are kept as they are
     pub fn insert(&mut self, item: &str) {
-        let index = (1 + self.v.len()) as u32;
-        self.insert_as(item, index);
+        self.insert_bytes(value_entry(item).as_bytes());
+    }
+
+    /// inserts raw value, e.g. 20 bytes of an address, which is found
+    /// by `find_bytes` and `find_hex` of its 0x-prefixed hex
+    pub fn insert_bytes(&mut self, item: &[u8]) {
+        // ids of removed entries are never given away again
+        let index = self.v.keys().next_back().map_or(1, |last| last + 1);
+        self.insert_bytes_as(item, index);
+    }
+
+    /// removes entry by its id, other entries keep their ids
+    pub fn remove(&mut self, index: u32) -> Option<Vec<u8>> {
+        let item = self.v.remove(&index)?;
+        self.k.remove(&item);
+        Some(item)
+    }
+
+    /// identifies the generation of the dictionary
+    pub fn fingerprint(&self) -> [u8; 16] {
+        fingerprint_of(self.v.iter().map(|(id, v)| (*id, v.as_slice())))
+    }
+
+    /// ids of all entries in ascending order
+    pub fn ids(&self) -> impl DoubleEndedIterator<Item = u32> + '_ {
+        self.v.keys().copied()
     }
 
     // insert with known index
     pub fn insert_as(&mut self, item: &str, index: u32) {
-        self.v.insert(index, item.to_string());
-        self.k.insert(item.to_string(), index);
+        self.insert_bytes_as(item.as_bytes(), index);
+    }
+
+    /// inserts raw value with known index
+    pub fn insert_bytes_as(&mut self, item: &[u8], index: u32) {
+        self.v.insert(index, item.to_vec());
+        self.k.insert(item.to_vec(), index);
     }
 
     /// creates dictionary from the slice of strings (useful for tests)
@@ -83,8 +383,9 @@ impl MapDictionary {
             }
             Value::Object(value) => {
                 for (k, v) in value {
+                    // keys are looked up as they are
                     if let None = self.find_str(k.as_str()) {
-                        self.insert(k.as_str());
+                        self.insert_bytes(k.as_bytes());
                     };
                     self.learn(v);
                 }
@@ -93,10 +394,190 @@ impl MapDictionary {
         };
     }
 
+    /// learn string values of at least `min_len` bytes from json value
+    pub fn learn_values(&mut self, input: &Value, min_len: usize) {
+        match input {
+            Value::Array(value) => {
+                for v in value {
+                    self.learn_values(v, min_len);
+                }
+            }
+            Value::Object(value) => {
+                for v in value.values() {
+                    self.learn_values(v, min_len);
+                }
+            }
+            Value::String(value) if value.len() >= min_len => {
+                let known = self.find_str_normalized(value).is_some();
+                if !known {
+                    self.insert(value);
+                }
+            }
+            _ => {}
+        };
+    }
+
+    /// json object of entries by their ids in decimal, e.g. `{"1": "alpha", "2": "beta"}`.
+    /// Entries that are not UTF-8 are objects of their hex, `{"bytes": "0x..."}`
+    pub fn to_json(&self) -> Value {
+        let entry = |item: &[u8]| match std::str::from_utf8(item) {
+            Ok(item) => Value::String(item.to_string()),
+            Err(_) => serde_json::json!({"bytes": format!("0x{}", hex::encode(item))}),
+        };
+        Value::Object(
+            self.v
+                .iter()
+                .map(|(id, item)| (id.to_string(), entry(item)))
+                .collect(),
+        )
+    }
+
+    /// from json object of the form of `to_json`. Keys that are the same id,
+    /// like "1" and "01", are rejected, as are values of other kinds
+    pub fn from_json(input: &Value) -> anyhow::Result<Self> {
+        let entries = match input {
+            Value::Object(entries) => entries,
+            _ => bail!("dictionary is not a json object"),
+        };
+        let mut out = Self::new();
+        for (k, v) in entries {
+            let index = k
+                .parse::<u32>()
+                .with_context(|| format!("invalid id '{}'", k))?;
+            let item = match v {
+                Value::String(item) => item.as_bytes().to_vec(),
+                Value::Object(o) if o.len() == 1 && o.contains_key("bytes") => o["bytes"]
+                    .as_str()
+                    .and_then(|hex| hex::decode(hex.strip_prefix("0x")?).ok())
+                    .with_context(|| format!("entry {} is not hex: {}", k, v))?,
+                _ => bail!("entry {} is not a string: {}", k, v),
+            };
+            if out.v.contains_key(&index) {
+                bail!("id {} is listed twice", index);
+            }
+            out.insert_bytes_as(&item, index);
+        }
+        Ok(out)
+    }
+
+    /// copies entries of `other` that are not here yet under their ids,
+    /// so blobs encoded with either dictionary decode with the result.
+    /// Strings that are here already keep their ids here. Fails without
+    /// changes when an id of `other` is bound to a different string here
+    pub fn merge(&mut self, other: &MapDictionary) -> Result<MergeReport, MergeError> {
+        let conflicts: Vec<MergeConflict> = other
+            .v
+            .iter()
+            .filter_map(|(id, theirs)| match self.v.get(id) {
+                Some(ours) if ours != theirs => Some(MergeConflict {
+                    id: *id,
+                    ours: String::from_utf8_lossy(ours).into_owned(),
+                    theirs: String::from_utf8_lossy(theirs).into_owned(),
+                }),
+                _ => None,
+            })
+            .collect();
+        if !conflicts.is_empty() {
+            return Err(MergeError::IdConflict { conflicts });
+        }
+        let mut report = MergeReport::default();
+        for (id, item) in &other.v {
+            let lossy = String::from_utf8_lossy(item).into_owned();
+            match self.k.get(item) {
+                Some(ours) => report.reused.push((*ours, lossy)),
+                None => {
+                    self.insert_bytes_as(item, *id);
+                    report.added.push((*id, lossy));
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// marker of the entries at the moment, for `delta_since`
+    pub fn snapshot(&self) -> DictSnapshot {
+        DictSnapshot {
+            last_id: self.v.keys().next_back().copied().unwrap_or(0),
+            len: self.v.len(),
+        }
+    }
+
+    /// entries added after the snapshot in the order of ids,
+    /// e.g. to save only what was learned since
+    pub fn delta_since(&self, snapshot: &DictSnapshot) -> Vec<(u32, Vec<u8>)> {
+        let after = (Bound::Excluded(snapshot.last_id), Bound::Unbounded);
+        let entries = self.v.range(after);
+        entries.map(|(id, item)| (*id, item.clone())).collect()
+    }
+
+    /// adds entries of `delta_since` of another copy of the dictionary under
+    /// their ids, entries that are here already with the same id are skipped.
+    /// Fails without changes when an id is bound to a different entry here,
+    /// or an entry is here with another id. Returns the number of entries added
+    pub fn apply_delta(&mut self, delta: &[(u32, Vec<u8>)]) -> Result<usize, MergeError> {
+        let lossy = |item: &[u8]| String::from_utf8_lossy(item).into_owned();
+        let mut conflicts = vec![];
+        for (id, theirs) in delta {
+            match (self.v.get(id), self.k.get(theirs)) {
+                (Some(ours), _) if ours != theirs => conflicts.push(MergeConflict {
+                    id: *id,
+                    ours: lossy(ours),
+                    theirs: lossy(theirs),
+                }),
+                (None, Some(ours)) => {
+                    return Err(MergeError::EntryMoved {
+                        entry: lossy(theirs),
+                        ours: *ours,
+                        theirs: *id,
+                    })
+                }
+                _ => {}
+            }
+        }
+        if !conflicts.is_empty() {
+            return Err(MergeError::IdConflict { conflicts });
+        }
+        let mut added = 0;
+        for (id, item) in delta {
+            if !self.v.contains_key(id) {
+                self.insert_bytes_as(item, *id);
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    /// dictionary of the same entries with ids given again by `frequencies`,
+    /// the most frequent first, so they take the fewest bytes. Entries without
+    /// a frequency follow in the order of their ids. Also returns every id here
+    /// mapped to its new id, to migrate blobs encoded with this dictionary
+    pub fn optimize(
+        &self,
+        frequencies: &BTreeMap<String, u64>,
+    ) -> (MapDictionary, BTreeMap<u32, u32>) {
+        let frequency = |item: &[u8]| {
+            let item = std::str::from_utf8(item).ok()?;
+            frequencies.get(item).copied()
+        };
+        let mut ranked: Vec<(u64, u32, &[u8])> = self
+            .v
+            .iter()
+            .map(|(id, item)| (frequency(item).unwrap_or(0), *id, item.as_slice()))
+            .collect();
+        ranked.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        let mut out = MapDictionary::new();
+        let mut remap = BTreeMap::new();
+        for (index, (_, old, item)) in (1..).zip(ranked) {
+            out.insert_bytes_as(item, index);
+            remap.insert(old, index);
+        }
+        (out, remap)
+    }
+
     /// save into writer stream
     pub fn write<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
         for (k, v) in &self.v {
-            w.write_fmt(format_args!("{}: {:?}\n", k, v))
+            w.write_fmt(format_args!("{}: {}\n", k, escape_entry(v)))
                 .context("dict write")?;
         }
         Ok(())
@@ -106,27 +587,353 @@ impl MapDictionary {
     pub fn from<R: Read>(r: &mut R) -> anyhow::Result<Self> {
         let lines = std::io::BufReader::new(r).lines();
         let mut out = Self::new();
-        for next_line in lines {
+        for (n, next_line) in lines.enumerate() {
             if let Ok(line) = next_line {
-                let ln = line.trim();
+                // other whitespace at the ends belongs to the entry
+                let ln = line.trim_matches([' ', '\t', '\r']);
                 if ln.len() > 0 && !ln.starts_with("#") {
                     if let Some((k, v)) = split_at_colon(ln) {
                         let index = k.parse::<u32>().context("invalid integer")?;
-                        out.insert_as(v, index);
+                        let item = parse_entry(v.trim_start_matches([' ', '\t']))
+                            .with_context(|| format!("dict line {}", n + 1))?;
+                        out.insert_bytes_as(&item, index);
                     }
                 }
             }
         }
         Ok(out)
     }
+
+    /// save into writer stream in the binary form: magic, number of entries,
+    /// id and length of each entry as varints followed by its bytes,
+    /// then crc32c of all of it
+    pub fn write_binary<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
+        let mut buf = Vec::with_capacity(16 + self.v.values().map(|v| v.len() + 4).sum::<usize>());
+        buf.extend_from_slice(&BINARY_MAGIC);
+        write_varint(self.v.len() as u64, &mut buf)?;
+        for (k, v) in &self.v {
+            write_varint(*k as u64, &mut buf)?;
+            write_varint(v.len() as u64, &mut buf)?;
+            buf.extend_from_slice(v);
+        }
+        let checksum = crc32c::crc32c(&buf);
+        buf.extend_from_slice(&checksum.to_le_bytes());
+        w.write_all(&buf).context("dict write")?;
+        Ok(())
+    }
+
+    /// from reader stream in the form of `write_binary`, the stream is read to
+    /// its end and is checked as a whole before any entry is taken from it
+    pub fn from_binary<R: Read>(r: &mut R) -> anyhow::Result<Self> {
+        let mut buf = vec![];
+        r.read_to_end(&mut buf).context("dict read")?;
+        if buf.len() < BINARY_MAGIC.len() + 5 || buf[..4] != BINARY_MAGIC {
+            bail!("not a binary dictionary");
+        }
+        let (body, stored) = buf.split_at(buf.len() - 4);
+        let stored = u32::from_le_bytes(stored.try_into()?);
+        let computed = crc32c::crc32c(body);
+        if stored != computed {
+            bail!(
+                "dict checksum mismatch: stored {:08x}, computed {:08x}, truncated or damaged",
+                stored,
+                computed
+            );
+        }
+        let mut input = &body[4..];
+        let (count, _) = next_varint(&mut input).context("number of entries")?;
+        // entries are read before the maps are built, which is faster than inserting them
+        let mut entries: Vec<(u32, Vec<u8>)> =
+            Vec::with_capacity(input.len().min(count as usize) / 2);
+        for n in 0..count {
+            let (index, _) = next_varint(&mut input).with_context(|| format!("entry {}", n))?;
+            let index = u32::try_from(index).with_context(|| format!("id of entry {}", n))?;
+            let (len, _) = next_varint(&mut input).with_context(|| format!("entry {}", n))?;
+            if len > input.len() as u64 {
+                bail!("entry {} of {} bytes is truncated", n, len);
+            }
+            let (item, rest) = input.split_at(len as usize);
+            // entries are written in the order of ids
+            if entries.last().is_some_and(|(last, _)| *last >= index) {
+                bail!("id {} of entry {} is out of order", index, n);
+            }
+            entries.push((index, item.to_vec()));
+            input = rest;
+        }
+        if !input.is_empty() {
+            bail!("{} bytes after the last entry", input.len());
+        }
+        Ok(Self {
+            k: entries
+                .iter()
+                .map(|(id, item)| (item.clone(), *id))
+                .collect(),
+            v: entries.into_iter().collect(),
+        })
+    }
 }
 
 impl DictionaryRead for MapDictionary {
     fn get(&self, index: u32) -> Option<&[u8]> {
-        self.v.get(&index).map(|x| x.as_str().as_bytes())
+        self.v.get(&index).map(|x| x.as_slice())
     }
     fn find_str(&self, value: &str) -> Option<u32> {
-        self.k.get(value).map(|x| *x)
+        self.k.get(value.as_bytes()).copied()
+    }
+    fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+        self.k.get(value).copied()
+    }
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        Some(MapDictionary::fingerprint(self))
+    }
+}
+
+impl DictionaryWrite for MapDictionary {
+    fn insert(&mut self, bytes: &[u8]) -> Option<u32> {
+        if let Some(index) = self.find_bytes(bytes) {
+            return Some(index);
+        }
+        let index = self.v.keys().next_back().map_or(1, |last| last + 1);
+        self.insert_bytes_as(bytes, index);
+        Some(index)
+    }
+}
+
+/// Entries of the other dictionary in `MapDictionary::merge`, with their ids in the result
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MergeReport {
+    pub added: Vec<(u32, String)>,
+    pub reused: Vec<(u32, String)>,
+}
+
+/// Highest id and number of entries of a `MapDictionary` at some moment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DictSnapshot {
+    pub last_id: u32,
+    pub len: usize,
+}
+
+/// Counts of string values and keys over many documents,
+/// to build a value dictionary of the ones that repeat
+/// and to give the frequent entries the short ids
+#[derive(Debug, Clone, Default)]
+pub struct DictionaryTrainer {
+    min_len: usize,
+    counts: HashMap<String, usize>,
+    keys: HashMap<String, usize>,
+}
+
+impl DictionaryTrainer {
+    /// counts strings of at least `min_len` bytes
+    pub fn new(min_len: usize) -> Self {
+        Self {
+            min_len,
+            counts: HashMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    /// counts string values and keys of the document
+    pub fn add(&mut self, input: &Value) {
+        match input {
+            Value::Array(value) => {
+                for v in value {
+                    self.add(v);
+                }
+            }
+            Value::Object(value) => {
+                for (k, v) in value {
+                    match self.keys.get_mut(k) {
+                        Some(count) => *count += 1,
+                        None => {
+                            self.keys.insert(k.clone(), 1);
+                        }
+                    }
+                    self.add(v);
+                }
+            }
+            Value::String(value) if value.len() >= self.min_len => {
+                *self
+                    .counts
+                    .entry(value_entry(value).into_owned())
+                    .or_default() += 1;
+            }
+            _ => {}
+        };
+    }
+
+    /// times every counted string value was seen, for `MapDictionary::optimize`
+    pub fn frequencies(&self) -> BTreeMap<String, u64> {
+        let counts = self.counts.iter();
+        counts.map(|(k, n)| (k.clone(), *n as u64)).collect()
+    }
+
+    /// times every key was seen, for `MapDictionary::optimize` of field dictionaries
+    pub fn key_frequencies(&self) -> BTreeMap<String, u64> {
+        let counts = self.keys.iter();
+        counts.map(|(k, n)| (k.clone(), *n as u64)).collect()
+    }
+
+    /// dictionary of strings seen at least `min_count` times, at most `max_entries`
+    /// of them. Strings that save the most bytes in total get the first ids,
+    /// which are the shortest; a reference is counted as 2 bytes
+    pub fn into_dictionary(self, min_count: usize, max_entries: usize) -> MapDictionary {
+        let nod = NoDictionary {};
+        let mut saved: Vec<(usize, String)> = self
+            .counts
+            .into_iter()
+            .filter(|(_, count)| *count >= min_count)
+            .filter_map(|(value, count)| {
+                let inline = crate::encoded_size(&Value::String(value.clone()), &nod, &nod).ok()?;
+                Some((count * inline.checked_sub(2).filter(|n| *n > 0)?, value))
+            })
+            .collect();
+        saved.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        let mut out = MapDictionary::new();
+        for (_, value) in saved.into_iter().take(max_entries) {
+            out.insert(&value);
+        }
+        out
+    }
+}
+
+// form of the string in value dictionaries: hex is looked up in canonical form
+pub(crate) fn value_entry(value: &str) -> Cow<'_, str> {
+    match value.starts_with("0x") {
+        true => normalize_hex(value).unwrap_or(Cow::Borrowed(value)),
+        false => Cow::Borrowed(value),
+    }
+}
+
+/// Entries added to the dictionaries by `encode_learning`, in the order they were added
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Learned {
+    pub fields: Vec<(u32, String)>,
+    pub values: Vec<(u32, String)>,
+}
+
+// strings shorter than this take no more inline than as a reference
+const LEARN_MIN_LEN: usize = 4;
+
+// object keys of the value that are written as strings,
+// and string values that it has more than once, in the order of the value
+pub(crate) fn learn_from<'v>(
+    input: &'v Value,
+    keys: &mut Vec<&'v str>,
+    seen: &mut HashMap<&'v str, usize>,
+    repeated: &mut Vec<&'v str>,
+) {
+    match input {
+        Value::Array(items) => {
+            for v in items {
+                learn_from(v, keys, seen, repeated);
+            }
+        }
+        Value::Object(m) => {
+            for (k, v) in m {
+                if crate::encode::numeric_key(k).is_none() {
+                    keys.push(k);
+                }
+                learn_from(v, keys, seen, repeated);
+            }
+        }
+        Value::String(s) if s.len() >= LEARN_MIN_LEN => {
+            let count = seen.entry(s).or_default();
+            *count += 1;
+            if *count == 2 {
+                repeated.push(s);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Dictionary of raw byte values, like addresses and topics.
+/// Keeps 20 or 32 bytes per entry instead of 42 or 66 characters of hex.
+/// Entries are found by the bytes of 0x-prefixed values only, they are never string references
+#[derive(Debug, Clone, Default)]
+pub struct BytesDictionary {
+    v: BTreeMap<u32, Vec<u8>>,
+    k: BTreeMap<Vec<u8>, u32>,
+}
+
+impl BytesDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        let index = self.v.keys().next_back().map_or(1, |last| last + 1);
+        self.insert_as(item, index);
+    }
+
+    pub fn insert_as(&mut self, item: &[u8], index: u32) {
+        self.v.insert(index, item.to_vec());
+        self.k.insert(item.to_vec(), index);
+    }
+
+    /// inserts bytes of the hex string, with or without 0x prefix
+    pub fn insert_hex(&mut self, value: &str) -> anyhow::Result<()> {
+        let normalized = normalize_hex(value).context("not a hex value")?;
+        self.insert(&hex::decode(&normalized[2..])?);
+        Ok(())
+    }
+
+    /// creates dictionary from hex strings
+    pub fn from_hex_strings(input: &[&str]) -> anyhow::Result<Self> {
+        let mut out = Self::new();
+        for item in input {
+            out.insert_hex(item)?;
+        }
+        Ok(out)
+    }
+
+    /// ids of all entries in ascending order
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.v.keys().copied()
+    }
+
+    /// identifies the generation of the dictionary
+    pub fn fingerprint(&self) -> [u8; 16] {
+        fingerprint_of(self.v.iter().map(|(id, v)| (*id, v.as_slice())))
+    }
+}
+
+impl DictionaryRead for BytesDictionary {
+    fn get(&self, index: u32) -> Option<&[u8]> {
+        self.v.get(&index).map(|x| x.as_slice())
+    }
+    fn find_str(&self, _: &str) -> Option<u32> {
+        None
+    }
+    fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+        self.k.get(value).copied()
+    }
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        Some(BytesDictionary::fingerprint(self))
+    }
+}
+
+/// Known generations of dictionaries, to pick the one a blob was encoded with
+#[derive(Debug, Clone, Default)]
+pub struct DictionaryStore {
+    generations: BTreeMap<[u8; 16], MapDictionary>,
+}
+
+impl DictionaryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// adds generation, returns its fingerprint
+    pub fn add(&mut self, dict: MapDictionary) -> [u8; 16] {
+        let fingerprint = dict.fingerprint();
+        self.generations.insert(fingerprint, dict);
+        fingerprint
+    }
+
+    pub fn by_fingerprint(&self, fingerprint: &[u8; 16]) -> Option<&MapDictionary> {
+        self.generations.get(fingerprint)
     }
 }
 
@@ -179,4 +986,462 @@ mod tests {
         assert_eq!(d2.k.len(), 4);
         assert_eq!(d2.v.len(), 4);
     }
+
+    #[test]
+    pub fn it_normalizes_hex() {
+        assert_eq!(normalize_hex("0x1ff").unwrap(), "0x01ff");
+        assert_eq!(normalize_hex("ABCD").unwrap(), "0xabcd");
+        assert_eq!(normalize_hex("0XaBc").unwrap(), "0x0abc");
+        assert!(normalize_hex("0x").is_none());
+        assert!(normalize_hex("hello").is_none());
+    }
+
+    #[test]
+    pub fn it_finds_hex_in_any_form() {
+        let addr = "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed";
+        let d = MapDictionary::from_strings(vec!["alpha", addr]);
+        assert_eq!(
+            d.find_str("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"),
+            None
+        );
+        assert_eq!(
+            d.find_hex("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"),
+            Some(2)
+        );
+        assert_eq!(
+            d.find_hex("5aaeb6053f3e94c9b9a09f33669435e7ef1beaed"),
+            Some(2)
+        );
+        assert_eq!(d.find_hex(addr), Some(2));
+        assert_eq!(d.find_hex("alpha"), None);
+        assert_eq!(d.get(2).unwrap(), addr.as_bytes());
+
+        // short bare hex is a plain string, not the hex entry
+        let d = MapDictionary::from_strings(vec!["0x01", "0xcafe", "0x2023"]);
+        for bare in ["1", "cafe", "CAFE", "2023"] {
+            assert_eq!(d.find_hex(bare), None, "{}", bare);
+        }
+        assert_eq!(d.find_hex("0xCAFE"), Some(2));
+        assert_eq!(d.find_hex("0x1"), Some(1));
+    }
+
+    #[test]
+    pub fn it_keeps_hex_entries_in_lowercase() {
+        let addr = "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed";
+        let checksummed = "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed";
+        let mut d = MapDictionary::from_strings(vec!["Alpha", addr]);
+        assert_eq!(d.find_str_normalized(checksummed), Some(2));
+        assert_eq!(d.find_str_normalized(addr), Some(2));
+        // other strings are case-sensitive
+        assert_eq!(d.find_str_normalized("Alpha"), Some(1));
+        assert_eq!(d.find_str_normalized("alpha"), None);
+        assert_eq!(d.find_str_normalized("0xylophone"), None);
+
+        d.insert("0xDEADbeef");
+        d.insert("Beta");
+        assert_eq!(d.get(3).unwrap(), b"0xdeadbeef");
+        assert_eq!(d.get(4).unwrap(), b"Beta");
+        assert_eq!(d.find_str_normalized("0xDeadBeef"), Some(3));
+
+        // keys are learned as they are
+        d.learn(&serde_json::json!({"0xABCD": 1}));
+        assert_eq!(d.find_str("0xABCD"), Some(5));
+        let vd = MapDictionary::from_strings(vec![checksummed]);
+        let mut blob = vec![];
+        crate::encode(&serde_json::json!([addr, checksummed]), &mut blob, &d, &vd).unwrap();
+        assert_eq!(blob.len(), 1 + 2 + 2 + 1);
+    }
+
+    // blocks of transfers of one token, each with its own hash
+    fn synthetic_blocks(count: usize) -> Vec<Value> {
+        (0..count)
+            .map(|n| {
+                serde_json::json!({
+                    "number": format!("0x{:x}", 1000 + n),
+                    "logs": [{
+                        "address": "0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48",
+                        "event": "transfer",
+                        "transactionHash": format!("0x{:064x}", n),
+                        "memo": format!("one-off note {}", n),
+                    }],
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    pub fn it_learns_values() {
+        let mut d = MapDictionary::new();
+        for v in synthetic_blocks(2) {
+            d.learn_values(&v, 8);
+        }
+        let addr = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+        assert_eq!(d.find_str(addr), Some(1));
+        assert!(d.find_str("transfer").is_some());
+        // "0x3e8" is shorter than min_len, no keys are learned
+        assert_eq!(d.find_hex("0x3e8"), None);
+        assert_eq!(d.find_str("address"), None);
+        assert_eq!(d.v.len(), 1 + 1 + 2 * 2);
+    }
+
+    #[test]
+    pub fn it_trains_on_repeated_values() {
+        let mut trainer = DictionaryTrainer::new(4);
+        for v in synthetic_blocks(10) {
+            trainer.add(&v);
+        }
+        let addr = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+        let d = trainer.clone().into_dictionary(2, 100);
+        // the address saves more than "transfer", so it gets the first id
+        assert_eq!(d.find_str(addr), Some(1));
+        assert_eq!(d.find_str("transfer"), Some(2));
+        assert_eq!(d.find_hex(&format!("0x{:064x}", 3)), None);
+        assert_eq!(d.find_str("one-off note 3"), None);
+        assert_eq!(d.v.len(), 2);
+
+        let d = trainer.clone().into_dictionary(2, 1);
+        assert_eq!(d.find_str(addr), Some(1));
+        assert_eq!(d.v.len(), 1);
+        assert_eq!(trainer.into_dictionary(11, 100).v.len(), 0);
+    }
+
+    #[test]
+    pub fn it_merges_dictionaries() {
+        let mut d = MapDictionary::from_strings(vec!["alpha", "beta"]);
+        let mut other = MapDictionary::new();
+        other.insert_as("beta", 2);
+        other.insert_as("gamma", 3);
+        other.insert_as("alpha", 7);
+        let report = d.merge(&other).unwrap();
+        assert_eq!(report.added, vec![(3, "gamma".to_string())]);
+        assert_eq!(
+            report.reused,
+            vec![(2, "beta".to_string()), (1, "alpha".to_string())]
+        );
+        assert_eq!(d.ids().collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert_eq!(d.find_str("gamma"), Some(3));
+
+        // merging again changes nothing
+        let fingerprint = d.fingerprint();
+        let report = d.merge(&other).unwrap();
+        assert!(report.added.is_empty());
+        assert_eq!(report.reused.len(), 3);
+        assert_eq!(d.fingerprint(), fingerprint);
+    }
+
+    #[test]
+    pub fn it_fails_to_merge_ids_of_other_strings() {
+        let mut d = MapDictionary::from_strings(vec!["alpha", "beta"]);
+        let other = MapDictionary::from_strings(vec!["alpha", "delta", "epsilon"]);
+        let fingerprint = d.fingerprint();
+        let err = d.merge(&other).unwrap_err();
+        assert_eq!(
+            err,
+            MergeError::IdConflict {
+                conflicts: vec![MergeConflict {
+                    id: 2,
+                    ours: "beta".to_string(),
+                    theirs: "delta".to_string(),
+                }]
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "1 ids are bound to different strings, first is 2: 'beta' and 'delta'"
+        );
+        // nothing is copied, not even entries without conflicts
+        assert_eq!(d.fingerprint(), fingerprint);
+        assert_eq!(d.find_str("epsilon"), None);
+    }
+
+    #[test]
+    pub fn it_applies_learned_delta_to_a_copy() {
+        let mut fd = MapDictionary::from_strings(vec!["blockNumber", "logs"]);
+        let mut vd = MapDictionary::new();
+        vd.insert_bytes(&[0xde, 0xad]);
+        let (fd_copy, vd_copy) = (fd.clone(), vd.clone());
+        let (fd_at, vd_at) = (fd.snapshot(), vd.snapshot());
+        assert_eq!(fd_at, DictSnapshot { last_id: 2, len: 2 });
+
+        let doc = serde_json::json!({
+            "blockNumber": "0x10",
+            "logs": [{"address": "0x1234", "topics": ["transfer", "transfer"]}],
+        });
+        let mut blob = vec![];
+        let opts = crate::EncodeOptions::default();
+        crate::encode_learning(&doc, &mut blob, &mut fd, &mut vd, &opts, true).unwrap();
+        let fields = fd.delta_since(&fd_at);
+        let ids: Vec<u32> = fields.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![3, 4]);
+        assert_eq!(fields[0].1, b"address");
+        assert_eq!(vd.delta_since(&vd_at), vec![(2, b"transfer".to_vec())]);
+
+        let (mut fd_copy, mut vd_copy) = (fd_copy, vd_copy);
+        assert_eq!(fd_copy.apply_delta(&fields).unwrap(), 2);
+        assert_eq!(vd_copy.apply_delta(&vd.delta_since(&vd_at)).unwrap(), 1);
+        assert_eq!(fd_copy.fingerprint(), fd.fingerprint());
+        assert_eq!(vd_copy.fingerprint(), vd.fingerprint());
+        assert_eq!(crate::decode_slice(&blob, &fd_copy, &vd_copy).unwrap(), doc);
+        // applying it again changes nothing
+        assert_eq!(fd_copy.apply_delta(&fields).unwrap(), 0);
+        assert!(fd.delta_since(&fd.snapshot()).is_empty());
+    }
+
+    #[test]
+    pub fn it_fails_to_apply_delta_of_other_dictionary() {
+        let mut d = MapDictionary::from_strings(vec!["alpha", "beta"]);
+        let fingerprint = d.fingerprint();
+        let delta = vec![(3, b"gamma".to_vec()), (2, b"delta".to_vec())];
+        let err = d.apply_delta(&delta).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "1 ids are bound to different strings, first is 2: 'beta' and 'delta'"
+        );
+        let delta = vec![(3, b"gamma".to_vec()), (4, b"alpha".to_vec())];
+        let err = d.apply_delta(&delta).unwrap_err();
+        assert_eq!(
+            err,
+            MergeError::EntryMoved {
+                entry: "alpha".to_string(),
+                ours: 1,
+                theirs: 4
+            }
+        );
+        assert_eq!(err.to_string(), "'alpha' has id 1 here, not 4");
+        assert_eq!(d.fingerprint(), fingerprint);
+    }
+
+    #[test]
+    pub fn it_round_trips_entries_with_special_characters() {
+        let entries = vec![
+            "my:key",
+            "say \"hi\"",
+            "\"quoted\"",
+            "two\nlines\r\n",
+            "tab\there",
+            "back\\slash\\n",
+            " padded ",
+            "\u{a0}nbsp\u{3000}",
+            "  ",
+            "",
+            "# not a comment",
+            "caf\u{e9} \u{1f980} \u{1b}[0m",
+        ];
+        let d = MapDictionary::from_strings(entries.clone());
+        let mut buf = vec![];
+        d.write(&mut buf).unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf).lines().count(), entries.len());
+        let d2 = MapDictionary::from(&mut buf.as_slice()).unwrap();
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(d2.find_str(entry), Some(i as u32 + 1), "{:?}", entry);
+        }
+        assert_eq!(d2.fingerprint(), d.fingerprint());
+        assert_eq!(
+            String::from_utf8(buf)
+                .unwrap()
+                .lines()
+                .take(2)
+                .collect::<Vec<_>>(),
+            vec!["1: my:key", "2: say \"hi\""]
+        );
+    }
+
+    #[test]
+    pub fn it_reads_quoted_entries() {
+        let entries = [
+            "my:key",
+            "say \"hi\"",
+            "two\nlines",
+            "caf\u{e9}\0\u{1b}",
+            "it's",
+        ];
+        let mut text = String::from("# written by older versions\n");
+        for (i, entry) in entries.iter().enumerate() {
+            text.push_str(&format!("{}: {:?}\n", i + 1, entry));
+        }
+        let d = MapDictionary::from(&mut text.as_bytes()).unwrap();
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(d.get(i as u32 + 1), Some(entry.as_bytes()));
+        }
+        assert!(MapDictionary::from(&mut &b"1: bad\\q"[..]).is_err());
+    }
+
+    #[test]
+    pub fn it_round_trips_binary_form() {
+        let mut d = MapDictionary::new();
+        for n in 0..200_000u32 {
+            d.insert(&format!(
+                "0x{:040x}",
+                (n as u64).wrapping_mul(0x9e3779b97f4a7c15)
+            ));
+        }
+        d.insert("caf\u{e9}\n\"quoted\"");
+        d.insert("");
+        d.remove(7);
+        d.insert_as("far", u32::MAX);
+        let mut buf = vec![];
+        d.write_binary(&mut buf).unwrap();
+        let d2 = MapDictionary::from_binary(&mut buf.as_slice()).unwrap();
+        assert_eq!(d2.fingerprint(), d.fingerprint());
+        assert_eq!(d2.find_str("far"), Some(u32::MAX));
+        assert_eq!(d2.get(7), None);
+
+        // a text file is not taken for a binary one
+        let mut text = vec![];
+        d.write(&mut text).unwrap();
+        assert!(MapDictionary::from_binary(&mut text.as_slice()).is_err());
+    }
+
+    #[test]
+    pub fn it_rejects_truncated_binary_form() {
+        let d = MapDictionary::from_strings(vec!["alpha", "beta", "gamma"]);
+        let mut buf = vec![];
+        d.write_binary(&mut buf).unwrap();
+        for end in 0..buf.len() {
+            assert!(
+                MapDictionary::from_binary(&mut &buf[..end]).is_err(),
+                "{}",
+                end
+            );
+        }
+        let mut damaged = buf.clone();
+        damaged[8] ^= 1;
+        let err = MapDictionary::from_binary(&mut damaged.as_slice()).unwrap_err();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        assert!(MapDictionary::from_binary(&mut buf.as_slice()).is_ok());
+    }
+
+    #[test]
+    pub fn it_round_trips_json_form() {
+        let mut d = MapDictionary::from_strings(vec!["alpha", "my:key", "caf\u{e9}\n\"q\"", ""]);
+        d.remove(2);
+        d.insert_as("far", 100);
+        let json = d.to_json();
+        assert_eq!(
+            json,
+            serde_json::json!({"1": "alpha", "3": "caf\u{e9}\n\"q\"", "4": "", "100": "far"})
+        );
+        let text = serde_json::to_string(&json).unwrap();
+        let d2 = MapDictionary::from_json(&Value::from_str(&text).unwrap()).unwrap();
+        assert_eq!(d2.fingerprint(), d.fingerprint());
+        assert_eq!(d2.to_json(), json);
+        assert_eq!(
+            MapDictionary::from_json(&serde_json::json!({}))
+                .unwrap()
+                .ids()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    pub fn it_rejects_invalid_json_form() {
+        let err =
+            MapDictionary::from_json(&serde_json::json!({"1": "alpha", "01": "beta"})).unwrap_err();
+        assert_eq!(err.to_string(), "id 1 is listed twice");
+        for input in [
+            serde_json::json!({"1": "alpha", "2": 2}),
+            serde_json::json!({"1": null}),
+            serde_json::json!({"one": "alpha"}),
+            serde_json::json!({"-1": "alpha"}),
+            serde_json::json!({"4294967296": "alpha"}),
+            serde_json::json!([[1, "alpha"]]),
+        ] {
+            assert!(MapDictionary::from_json(&input).is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    pub fn it_keeps_raw_bytes_in_every_form() {
+        let topic = hex::decode("ddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef")
+            .unwrap();
+        let mut d = MapDictionary::from_strings(vec!["alpha"]);
+        d.insert_bytes(&topic);
+        d.insert_bytes(b" a\xff\\ ");
+        d.insert_bytes(b"\"\x00");
+        assert_eq!(d.get(2), Some(topic.as_slice()));
+        assert_eq!(d.find_bytes(&topic), Some(2));
+        assert_eq!(d.find_str("alpha"), Some(1));
+        assert_eq!(d.find_bytes(b"alpha"), Some(1));
+
+        let mut text = vec![];
+        d.write(&mut text).unwrap();
+        let lines = String::from_utf8(text.clone()).unwrap();
+        assert!(lines.contains("3: \\sa\\xff\\\\\\s\n"), "{}", lines);
+        let from_text = MapDictionary::from(&mut text.as_slice()).unwrap();
+        assert_eq!(from_text.fingerprint(), d.fingerprint());
+
+        let mut binary = vec![];
+        d.write_binary(&mut binary).unwrap();
+        let from_binary = MapDictionary::from_binary(&mut binary.as_slice()).unwrap();
+        assert_eq!(from_binary.fingerprint(), d.fingerprint());
+
+        let json = d.to_json();
+        assert_eq!(json["1"], "alpha");
+        assert_eq!(
+            json["2"],
+            serde_json::json!({"bytes": format!("0x{}", hex::encode(&topic))})
+        );
+        let from_json = MapDictionary::from_json(&json).unwrap();
+        assert_eq!(from_json.fingerprint(), d.fingerprint());
+        assert!(MapDictionary::from_json(&serde_json::json!({"1": {"bytes": "zz"}})).is_err());
+        assert!(MapDictionary::from_json(&serde_json::json!({"1": {"bytes": 5}})).is_err());
+
+        assert_eq!(d.remove(2), Some(topic.clone()));
+        assert_eq!(d.find_bytes(&topic), None);
+    }
+
+    #[test]
+    pub fn it_gives_frequent_entries_short_ids() {
+        // keys of the blocks come after 300 keys that are never used
+        let mut keys: Vec<String> = (0..300).map(|n| format!("unused{}", n)).collect();
+        keys.extend(
+            [
+                "number",
+                "logs",
+                "address",
+                "event",
+                "transactionHash",
+                "memo",
+            ]
+            .map(String::from),
+        );
+        let fd = MapDictionary::from_strings(keys.iter().map(String::as_str).collect());
+        let nod = NoDictionary {};
+        let blocks = synthetic_blocks(20);
+        let size = |fd: &MapDictionary| {
+            let encoded = blocks
+                .iter()
+                .map(|v| crate::encoded_size(v, fd, &nod).unwrap());
+            encoded.sum::<usize>()
+        };
+
+        let mut trainer = DictionaryTrainer::new(4);
+        blocks.iter().for_each(|v| trainer.add(v));
+        assert_eq!(trainer.key_frequencies().get("logs"), Some(&20));
+        assert_eq!(trainer.frequencies().get("transfer"), Some(&20));
+        let (optimized, remap) = fd.optimize(&trainer.key_frequencies());
+        // every key of the blocks is used 20 times, and now takes one byte less
+        assert_eq!(size(&fd) - size(&optimized), 20 * 6);
+        // ties keep the order of the ids
+        assert_eq!(optimized.find_str("number"), Some(1));
+        assert_eq!(optimized.find_str("memo"), Some(6));
+        assert_eq!(optimized.find_str("unused0"), Some(7));
+        assert_eq!(
+            optimized.fingerprint(),
+            fd.optimize(&trainer.key_frequencies()).0.fingerprint()
+        );
+
+        // the remapping covers every entry, and maps them to the same bytes
+        assert_eq!(
+            remap.keys().copied().collect::<Vec<_>>(),
+            fd.ids().collect::<Vec<_>>()
+        );
+        let mut new_ids: Vec<u32> = remap.values().copied().collect();
+        new_ids.sort();
+        assert_eq!(new_ids, optimized.ids().collect::<Vec<_>>());
+        for (old, new) in &remap {
+            assert_eq!(fd.get(*old), optimized.get(*new));
+        }
+    }
 }
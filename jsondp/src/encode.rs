@@ -1,140 +1,838 @@
+use crate::decode::{fixed_order, fixed_width, BIG_NUMBER, F32, NEGATIVE, NUMERIC, PACKED, VARINT};
 use crate::dictionary::*;
-use anyhow::Context;
+use crate::timestamp::Timestamp;
+use crate::BACK_REFS_TAG;
+use anyhow::{bail, Context};
 use num::ToPrimitive;
 use serde_json::{Map, Number, Value};
+use std::collections::HashMap;
 use std::io::Write;
 
-fn encode_string<W: Write, D: DictionaryRead>(
+/// Options of encoding
+#[derive(Debug, Clone)]
+pub struct EncodeOptions {
+    /// keep hex strings exactly as written: the number of digits is stored
+    /// with values that would decode padded, e.g. "0x100" or "0x1a2b3c",
+    /// and hex with uppercase digits is stored as a string
+    pub preserve_hex_width: bool,
+    /// write `{"type": "BigNumber", "hex": ...}` objects as bytes
+    pub detect_bignumber: bool,
+    /// replace strings found in the value dictionary with references
+    pub use_value_dictionary: bool,
+    /// "0x..." strings of hex digits that are packed into bytes,
+    /// others are stored as written
+    pub hex_widths: HexDetection,
+    /// store RFC3339 strings in UTC, like "2023-07-01T12:00:00Z" or with milliseconds,
+    /// and unix seconds written as digits, like "1688212800", as timestamps.
+    /// Strings are recognized only when they decode back exactly
+    pub detect_timestamps: bool,
+    /// write strings repeated in the document as references to their first occurrence.
+    /// The blob is marked, so it decodes without other state, but only by `decode`
+    /// and `decode_salvage`, and not by versions before the marker
+    pub back_references: bool,
+    /// write lengths of strings, bytes, arrays and objects in LEB128 under one tag
+    /// of each kind, so there is no limit of u16. Blobs without it decode as before
+    pub varint_lengths: bool,
+    /// append a CRC32C of the payload to blobs of `write_framed_with`,
+    /// which is checked when they are read. Unframed blobs have no place for it
+    pub frame_checksum: bool,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        Self {
+            preserve_hex_width: false,
+            detect_bignumber: true,
+            use_value_dictionary: true,
+            hex_widths: HexDetection::All,
+            detect_timestamps: false,
+            back_references: false,
+            varint_lengths: false,
+            frame_checksum: false,
+        }
+    }
+}
+
+/// Which "0x..." strings of hex digits `EncodeOptions::hex_widths` packs into bytes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HexDetection {
+    /// all of them. "0x" is empty bytes, odd digits are padded with a zero
+    /// and values between the fixed widths decode zero-extended to the next one,
+    /// unless `preserve_hex_width`
+    #[default]
+    All,
+    /// the widths that decode as they are written: "0x", quantities of 1, 2, 4
+    /// and 8 bytes, 20-byte addresses and 32-byte hashes
+    CanonicalOnly,
+    /// none of them
+    Never,
+}
+
+impl HexDetection {
+    /// the number of hex digits after "0x" is packed
+    pub fn packs(self, digits: usize) -> bool {
+        match self {
+            Self::All => true,
+            Self::CanonicalOnly => matches!(digits, 0 | 2 | 4 | 8 | 16 | 40 | 64),
+            Self::Never => false,
+        }
+    }
+}
+
+/// Dictionary lookups of one encode, for one of the dictionaries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DictionaryStats {
+    /// keys or strings written as references to the dictionary
+    pub hits: u64,
+    /// keys or strings written inline
+    pub misses: u64,
+    /// bytes of the hits inline, less the bytes of their references
+    pub bytes_saved: u64,
+}
+
+/// Diagnostics of one encode, to see if the dictionaries pay off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EncodeStats {
+    /// bytes written
+    pub bytes: u64,
+    /// lookups of keys
+    pub fields: DictionaryStats,
+    /// lookups of string values, none without `use_value_dictionary`
+    pub values: DictionaryStats,
+}
+
+impl DictionaryStats {
+    fn count(&mut self, written: &[u8], inline: &[u8]) {
+        if written == inline {
+            self.misses += 1;
+        } else {
+            self.hits += 1;
+            self.bytes_saved += inline.len().saturating_sub(written.len()) as u64;
+        }
+    }
+}
+
+impl std::fmt::Display for DictionaryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} hits, {} misses, {} bytes saved",
+            self.hits, self.misses, self.bytes_saved
+        )
+    }
+}
+
+impl std::fmt::Display for EncodeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes; fields: {}; values: {}",
+            self.bytes, self.fields, self.values
+        )
+    }
+}
+
+/// Encoder with the dictionaries and options for every value it writes
+pub struct Encoder<'a, D1, D2> {
+    fd: &'a D1,
+    vd: &'a D2,
+    opts: EncodeOptions,
+}
+
+impl<'a, D1: DictionaryRead, D2: DictionaryRead> Encoder<'a, D1, D2> {
+    pub fn new(fd: &'a D1, vd: &'a D2) -> Self {
+        Self {
+            fd,
+            vd,
+            opts: EncodeOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, opts: EncodeOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    pub fn options(&self) -> &EncodeOptions {
+        &self.opts
+    }
+
+    /// writes the value and flushes the writer, returns the number of bytes written
+    pub fn encode<W: Write>(&self, value: &Value, w: &mut W) -> anyhow::Result<usize> {
+        let mut w = CountingWriter { inner: w, bytes: 0 };
+        let mut refs = BackRefs::new(self.opts.back_references);
+        if self.opts.back_references {
+            w.write_all(&[BACK_REFS_TAG])
+                .context("write back references tag")?;
+        }
+        encode_value_with(value, &mut w, self.fd, self.vd, &self.opts, &mut refs)?;
+        w.flush()?;
+        Ok(w.bytes)
+    }
+
+    /// same as `encode`, and counts dictionary lookups of keys and strings.
+    /// Every key and string is counted as if it had no back-reference,
+    /// it is encoded once more with and without the dictionary to count it
+    pub fn encode_with_stats<W: Write>(
+        &self,
+        value: &Value,
+        w: &mut W,
+    ) -> anyhow::Result<EncodeStats> {
+        let bytes = self.encode(value, w)?;
+        let mut stats = EncodeStats {
+            bytes: bytes as u64,
+            ..Default::default()
+        };
+        let mut bufs = (vec![], vec![]);
+        count_lookups(value, self.fd, self.vd, &self.opts, &mut stats, &mut bufs)?;
+        Ok(stats)
+    }
+
+    /// length of what `encode` writes for the value, nothing is written
+    pub fn encoded_size(&self, value: &Value) -> anyhow::Result<usize> {
+        self.encode(value, &mut std::io::sink())
+    }
+
+    /// starts an object of `len` fields that are written one by one,
+    /// see `ObjectEncoder`
+    pub fn begin_object<'w, W: Write>(
+        &self,
+        w: &'w mut W,
+        len: usize,
+    ) -> anyhow::Result<ObjectEncoder<'w, W, D1, D2>>
+    where
+        'a: 'w,
+    {
+        ObjectEncoder::begin_with(w, len, self.fd, self.vd, self.opts.clone())
+    }
+}
+
+/// Writes an object field by field, without the whole `Map` in memory.
+/// Sizes of the object and of nested containers are declared when they begin.
+/// The bytes are the same as of `encode` of the object when fields are written
+/// in the order of its `Map`, sorted by key, except that arrays begun with
+/// `begin_array` are never packed and objects of `begin_object` are never
+/// stored as BigNumber bytes
+pub struct ObjectEncoder<'w, W, D1, D2> {
+    w: CountingWriter<'w, W>,
+    fd: &'w D1,
+    vd: &'w D2,
+    opts: EncodeOptions,
+    refs: BackRefs,
+    // declared and written children of the open containers, the root first
+    open: Vec<Open>,
+    // the key is written and its value is next
+    keyed: bool,
+}
+
+struct Open {
+    declared: usize,
+    written: usize,
+    object: bool,
+}
+
+impl Open {
+    fn check(&self) -> anyhow::Result<()> {
+        if self.written < self.declared {
+            let what = if self.object { "object" } else { "array" };
+            bail!(
+                "{} of {} is ended after {}",
+                what,
+                self.declared,
+                self.written
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<'w, W: Write, D1: DictionaryRead, D2: DictionaryRead> ObjectEncoder<'w, W, D1, D2> {
+    /// writes the prefix of an object of `len` fields, with default options
+    pub fn begin(w: &'w mut W, len: usize, fd: &'w D1, vd: &'w D2) -> anyhow::Result<Self> {
+        Self::begin_with(w, len, fd, vd, EncodeOptions::default())
+    }
+
+    /// same as `begin`, with options
+    pub fn begin_with(
+        w: &'w mut W,
+        len: usize,
+        fd: &'w D1,
+        vd: &'w D2,
+        opts: EncodeOptions,
+    ) -> anyhow::Result<Self> {
+        let mut w = CountingWriter { inner: w, bytes: 0 };
+        if opts.back_references {
+            w.write_all(&[BACK_REFS_TAG])
+                .context("write back references tag")?;
+        }
+        encode_object_header(len, &mut w, &opts)?;
+        Ok(Self {
+            w,
+            fd,
+            vd,
+            refs: BackRefs::new(opts.back_references),
+            opts,
+            open: vec![Open {
+                declared: len,
+                written: 0,
+                object: true,
+            }],
+            keyed: false,
+        })
+    }
+
+    /// writes the key of the next field in the current object,
+    /// its value is written next
+    pub fn key(&mut self, key: &str) -> anyhow::Result<()> {
+        let open = self.current();
+        if !open.object {
+            bail!("key '{}' in an array", key);
+        }
+        if self.keyed {
+            bail!("key '{}' after a key without a value", key);
+        }
+        if open.written == open.declared {
+            bail!("object of {} has no room for '{}'", open.declared, key);
+        }
+        encode_key(key, &mut self.w, self.fd)?;
+        self.keyed = true;
+        Ok(())
+    }
+
+    /// writes the key and the value of the next field in the current object
+    pub fn field(&mut self, key: &str, value: &Value) -> anyhow::Result<()> {
+        self.key(key)?;
+        self.value(value)
+    }
+
+    /// writes the value after the key in the current object,
+    /// or the next element in the current array
+    pub fn value(&mut self, value: &Value) -> anyhow::Result<()> {
+        self.child()?;
+        encode_value_with(
+            value,
+            &mut self.w,
+            self.fd,
+            self.vd,
+            &self.opts,
+            &mut self.refs,
+        )
+    }
+
+    /// starts a nested object of `len` fields, in place of a value
+    pub fn begin_object(&mut self, len: usize) -> anyhow::Result<()> {
+        self.child()?;
+        encode_object_header(len, &mut self.w, &self.opts)?;
+        self.open.push(Open {
+            declared: len,
+            written: 0,
+            object: true,
+        });
+        Ok(())
+    }
+
+    /// starts a nested array of `len` elements, in place of a value
+    pub fn begin_array(&mut self, len: usize) -> anyhow::Result<()> {
+        self.child()?;
+        encode_array_header(len, &mut self.w, &self.opts)?;
+        self.open.push(Open {
+            declared: len,
+            written: 0,
+            object: false,
+        });
+        Ok(())
+    }
+
+    /// ends the nested container begun last, all its children must be written
+    pub fn end(&mut self) -> anyhow::Result<()> {
+        if self.open.len() == 1 {
+            bail!("the object is ended by finish");
+        }
+        self.current().check()?;
+        self.open.pop();
+        Ok(())
+    }
+
+    /// checks that all the fields are written and flushes the writer.
+    /// Returns the number of bytes written
+    pub fn finish(mut self) -> anyhow::Result<usize> {
+        if self.open.len() > 1 {
+            bail!("{} nested containers are not ended", self.open.len() - 1);
+        }
+        self.current().check()?;
+        self.w.flush()?;
+        Ok(self.w.bytes)
+    }
+
+    fn current(&self) -> &Open {
+        // the root is only removed by finish, which takes the encoder
+        self.open.last().expect("root object")
+    }
+
+    // counts the value about to be written in the current container
+    fn child(&mut self) -> anyhow::Result<()> {
+        let keyed = std::mem::take(&mut self.keyed);
+        let open = self.open.last_mut().expect("root object");
+        if open.object && !keyed {
+            bail!("value without a key in an object");
+        }
+        if !open.object && open.written == open.declared {
+            bail!("array of {} has no room for more", open.declared);
+        }
+        open.written += 1;
+        Ok(())
+    }
+}
+
+// writer that sums the bytes every write took
+struct CountingWriter<'w, W> {
+    inner: &'w mut W,
+    bytes: usize,
+}
+
+impl<W: Write> Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.bytes += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// digits the decoder drops from the front of the following bytes value
+fn encode_hex_width<W: Write>(trim: usize, w: &mut W) -> anyhow::Result<()> {
+    w.write_all(&[byte_prefix(FieldType::HEXW), trim as u8])
+        .context("write hex width")?;
+    Ok(())
+}
+
+// prefix and payload in one write, as writers may be unbuffered.
+// Payloads of scalars and lengths are 16 bytes at most
+fn write_prefixed<W: Write>(ch: u8, payload: &[u8], w: &mut W) -> std::io::Result<()> {
+    let mut buf = [0u8; 17];
+    buf[0] = ch;
+    buf[1..=payload.len()].copy_from_slice(payload);
+    w.write_all(&buf[..=payload.len()])
+}
+
+pub(crate) fn encode_string<W: Write, D: DictionaryRead>(
     value: &str,
     w: &mut W,
     vd: &D,
+    opts: &EncodeOptions,
 ) -> anyhow::Result<()> {
+    let preserve = opts.preserve_hex_width;
+    let use_vd = opts.use_value_dictionary;
+    let digits = value.as_bytes().get(2..).unwrap_or_default();
+    // "0x" alone, as empty calldata, is bytes of no length and decodes back as "0x"
+    if opts.hex_widths.packs(0) && value == "0x" {
+        let ch = byte_prefix(FieldType::DB { size: 0 });
+        if opts.varint_lengths {
+            return encode_varint_len(ch, 0, w);
+        }
+        w.write_all(&[ch, 0]).context("write db prefix")?;
+        return Ok(());
+    }
+    // "0x" followed by anything but hex digits, like "0xylophone", is an ordinary string
+    let hex = opts.hex_widths.packs(digits.len())
+        && with_0x(value.as_bytes())
+        && digits.iter().all(u8::is_ascii_hexdigit);
     // try to read "0x" as hex bytes
-    if with_0x(value.as_bytes()) {
-        let mut remainer: Vec<u8> = value.bytes().skip(2).collect();
-        let mut hexchars = if remainer.len() % 2 == 0 {
-            vec![]
-        } else {
-            vec![48] // '0'
-        };
-        hexchars.append(&mut remainer);
-        let out: Vec<u8> = hex::decode(&hexchars).context("hex decoding")?;
-        if out.len() > 256 {
-            let size: u16 = value.len() as u16;
+    if hex && !(preserve && digits.iter().any(u8::is_ascii_uppercase)) {
+        let bytes_num = digits.len().div_ceil(2);
+        // references decode to even number of digits, and are used when they are smaller
+        let odd = digits.len() % 2 == 1;
+        let packed = packed_size(bytes_num);
+        let refs = |id: &u32| use_vd && !(preserve && odd) && 1 + IdWidth::of(*id).size() < packed;
+        if let Some(dict_id) = vd.find_hex(value).filter(refs) {
+            // known hex value, possibly in another case
+            let ch = byte_prefix(FieldType::DS { size: 0 });
+            return encode_dict_ref(ch, dict_id, w);
+        }
+        if bytes_num <= 32 {
+            // quantities, addresses and hashes: no need to allocate
+            let mut buf = [0u8; 32];
+            let out = &mut buf[..bytes_num];
+            hex_to_slice(digits, out)?;
+            if let Some(dict_id) = vd.find_bytes(out).filter(refs) {
+                return encode_dict_ref(byte_prefix(FieldType::DB { size: 0 }), dict_id, w);
+            }
+            let width = fixed_width(byte_prefix(fixed_type(bytes_num))).context("fixed width")?;
+            if preserve && digits.len() < 2 * width {
+                encode_hex_width(2 * width - digits.len(), w)?;
+            }
+            encode_fixed_bytes(out, w)?;
+            return Ok(());
+        }
+        let mut out = vec![0u8; bytes_num];
+        hex_to_slice(digits, &mut out)?;
+        if let Some(dict_id) = vd.find_bytes(&out).filter(refs) {
+            return encode_dict_ref(byte_prefix(FieldType::DB { size: 0 }), dict_id, w);
+        }
+        if preserve && odd {
+            encode_hex_width(1, w)?;
+        }
+        if opts.varint_lengths {
+            encode_varint_len(byte_prefix(FieldType::DB { size: 0 }), out.len(), w)?;
+            w.write_all(&out).context("write db value")?;
+        } else if out.len() > u16::MAX as usize {
+            let size = long_size(out.len(), "bytes")?;
+            let ch: u8 = byte_prefix(FieldType::DLB { size });
+            write_prefixed(ch, &size.to_le_bytes(), w).context("write dlb prefix")?;
+            w.write_all(&out).context("write dlb value")?;
+        } else if out.len() > u8::MAX as usize {
+            let size = wide_size(out.len(), "bytes")?;
             let ch: u8 = byte_prefix(FieldType::DWB { size });
             let lo: u8 = (size & 0xFF) as u8;
             let hi: u8 = (size >> 8) as u8;
-            w.write(&[ch]).context("write dwb prefix")?;
-            w.write(&[lo, hi]).context("write dwb len")?;
-            w.write(&out).context("write dwb value")?;
+            w.write_all(&[ch, lo, hi]).context("write dwb prefix")?;
+            w.write_all(&out).context("write dwb value")?;
         } else {
-            let bytes_num: u8 = out.len() as u8;
-            if bytes_num == 1 {
-                let ch: u8 = byte_prefix(FieldType::B8);
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&out).context("write db value")?;
-            } else if bytes_num == 2 {
-                let ch: u8 = byte_prefix(FieldType::B16);
-                let b16 = out[0] as u16 | ((out[1] as u16) << 8);
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&b16.to_le_bytes()).context("write db value")?;
-            } else if bytes_num <= 4 {
-                let ch: u8 = byte_prefix(FieldType::B32);
-                let rev: Vec<u8> = out.iter().copied().rev().collect();
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&rev).context("write db value")?; // WRONG
-                for _ in 0..(4 - rev.len()) {
-                    w.write(&[0]).context("write db4 alignment")?;
-                }
-            } else if bytes_num <= 8 {
-                let ch: u8 = byte_prefix(FieldType::B64);
-                let rev: Vec<u8> = out.iter().copied().rev().collect();
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&rev).context("write db value")?;
-                for _ in 0..(8 - rev.len()) {
-                    w.write(&[0]).context("write db8 alignment")?;
-                }
-            } else if bytes_num <= 16 {
-                let ch: u8 = byte_prefix(FieldType::B128);
-                let rev: Vec<u8> = out.iter().copied().rev().collect();
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&rev).context("write db value")?;
-                for _ in 0..(16 - rev.len()) {
-                    w.write(&[0]).context("write db16 alignment")?;
-                }
-            } else if bytes_num <= 20 {
-                let ch: u8 = byte_prefix(FieldType::B160);
-                let rev: Vec<u8> = out.iter().copied().rev().collect();
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&rev).context("write db value")?;
-                for _ in 0..(20 - rev.len()) {
-                    w.write(&[0]).context("write db20 alignment")?;
-                }
-            } else if bytes_num <= 32 {
-                let ch: u8 = byte_prefix(FieldType::B256);
-                let rev: Vec<u8> = out.iter().copied().rev().collect();
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&rev).context("write db value")?;
-                for _ in 0..(32 - rev.len()) {
-                    w.write(&[0]).context("write db32 alignment")?;
-                }
-            } else {
-                // in this case we are preserving the order
-                let ch: u8 = byte_prefix(FieldType::DB { size: bytes_num });
-                w.write(&[ch]).context("write db prefix")?;
-                w.write(&[bytes_num]).context("write db len")?;
-                w.write(&out).context("write db value")?;
-            }
+            // in this case we are preserving the order
+            let size = out.len() as u8;
+            let ch: u8 = byte_prefix(FieldType::DB { size });
+            w.write_all(&[ch, size]).context("write db prefix")?;
+            w.write_all(&out).context("write db value")?;
         }
         return Ok(());
     }
 
-    if value.len() > 256 {
-        let size: u16 = value.len() as u16;
+    let varint = opts.varint_lengths;
+    if !varint && value.len() > u16::MAX as usize {
+        let size = long_size(value.len(), "string")?;
+        let ch: u8 = byte_prefix(FieldType::DLS { size });
+        write_prefixed(ch, &size.to_le_bytes(), w).context("write dls prefix")?;
+        w.write_all(value.as_bytes()).context("write dls value")?;
+        return Ok(());
+    }
+    if !varint && value.len() > u8::MAX as usize {
+        let size = wide_size(value.len(), "string")?;
         let ch: u8 = byte_prefix(FieldType::DWS { size });
         let lo: u8 = (size & 0xFF) as u8;
         let hi: u8 = (size >> 8) as u8;
-        w.write(&[ch]).context("write ds prefix")?;
-        w.write(&[lo, hi]).context("write ds len")?;
-        w.write(value.as_bytes()).context("write ds value")?;
+        w.write_all(&[ch, lo, hi]).context("write dws prefix")?;
+        w.write_all(value.as_bytes()).context("write ds value")?;
         return Ok(());
     }
-    let size: u8 = value.len() as u8;
-    let ch = byte_prefix(FieldType::DS { size });
-    match vd.find_str(value) {
+    let ch = byte_prefix(FieldType::DS { size: 0 });
+    // long strings are not looked up, as in the u16 and u32 forms
+    let found = match (use_vd && value.len() <= u8::MAX as usize, preserve) {
+        (false, _) => None,
+        (true, true) => vd.find_str(value),
+        (true, false) => vd.find_str(value).or_else(|| vd.find_hex(value)),
+    };
+    match found {
         Some(dict_id) => {
-            // we are lucky to have that value in a dictionary, dictionary is always u32?
-            w.write(&[ch | 0x20]).context("write str dict prefix")?;
-            w.write(&dict_id.to_le_bytes()).context("write str dict")?; // might not work
+            // we are lucky to have that value in a dictionary
+            encode_dict_ref(ch, dict_id, w)?;
         }
         None => {
+            let timestamp = Some(value)
+                .filter(|_| opts.detect_timestamps)
+                .and_then(Timestamp::parse)
+                .filter(|t| t.size() < 2 + value.len());
+            if let Some(t) = timestamp {
+                return t.write(w);
+            }
             // we didn't manage to find that value in the dictionary
-            w.write(&[ch, value.len() as u8])
-                .context("write str prefix")?;
-            w.write(value.as_bytes()).context("write str")?;
+            if varint {
+                encode_varint_len(ch, value.len(), w)?;
+            } else {
+                w.write_all(&[ch, value.len() as u8])
+                    .context("write str prefix")?;
+            }
+            w.write_all(value.as_bytes()).context("write str")?;
+        }
+    }
+    Ok(())
+}
+
+/// Strings written so far in the document, in the order the decoder sees them:
+/// every string value that is not a back-reference, dictionary references included
+#[derive(Debug, Default)]
+pub(crate) struct BackRefs {
+    // index of the first occurrence, None when back-references are off
+    ids: Option<HashMap<String, u32>>,
+    written: u32,
+}
+
+impl BackRefs {
+    pub(crate) fn new(on: bool) -> Self {
+        Self {
+            ids: on.then(HashMap::new),
+            written: 0,
+        }
+    }
+}
+
+// back-reference to the earlier string, when it is smaller than the string itself
+fn encode_string_ref<W: Write, D: DictionaryRead>(
+    value: &str,
+    w: &mut W,
+    vd: &D,
+    opts: &EncodeOptions,
+    refs: &mut BackRefs,
+) -> anyhow::Result<()> {
+    let ids = match refs.ids.as_mut() {
+        Some(ids) => ids,
+        None => return encode_string(value, w, vd, opts),
+    };
+    if let Some(index) = ids.get(value) {
+        let width = IdWidth::of(*index);
+        let mut full = CountingWriter {
+            inner: &mut std::io::sink(),
+            bytes: 0,
+        };
+        encode_string(value, &mut full, vd, opts)?;
+        if 1 + width.size() < full.bytes {
+            let ch = byte_prefix(FieldType::HEXW) | width.bits();
+            let id = &index.to_le_bytes()[..width.size()];
+            write_prefixed(ch, id, w).context("write back reference")?;
+            return Ok(());
         }
     }
+    encode_string(value, w, vd, opts)?;
+    ids.entry(value.to_string()).or_insert(refs.written);
+    refs.written += 1;
+    Ok(())
+}
+
+// value dictionary reference: prefix with the flag and the width of the id, as for keys
+fn encode_dict_ref<W: Write>(ch: u8, dict_id: u32, w: &mut W) -> anyhow::Result<()> {
+    let width = IdWidth::of(dict_id);
+    let id = &dict_id.to_le_bytes()[..width.size()];
+    write_prefixed(ch | 0x20 | width.bits(), id, w).context("write dict ref")?;
+    Ok(())
+}
+
+// decodes hex digits into the slice, odd number of digits is padded with leading zero
+fn hex_to_slice(digits: &[u8], out: &mut [u8]) -> anyhow::Result<()> {
+    if digits.len() % 2 == 1 {
+        hex::decode_to_slice([b'0', digits[0]], &mut out[..1]).context("hex decoding")?;
+        hex::decode_to_slice(&digits[1..], &mut out[1..]).context("hex decoding")?;
+    } else {
+        hex::decode_to_slice(digits, out).context("hex decoding")?;
+    }
+    Ok(())
+}
+
+// bytes taken by the hex value of the given length without the dictionary
+fn packed_size(bytes_num: usize) -> usize {
+    match bytes_num {
+        0..=32 => 1 + fixed_width(byte_prefix(fixed_type(bytes_num))).unwrap_or(32),
+        n if n <= u8::MAX as usize => 2 + n,
+        n if n <= u16::MAX as usize => 3 + n,
+        n => 5 + n,
+    }
+}
+
+// smallest fixed width type for up to 32 bytes
+fn fixed_type(len: usize) -> FieldType {
+    match len {
+        1 => FieldType::B8,
+        2 => FieldType::B16,
+        3..=4 => FieldType::B32,
+        5..=8 => FieldType::B64,
+        9..=16 => FieldType::B128,
+        17..=20 => FieldType::B160,
+        _ => FieldType::B256,
+    }
+}
+
+// writes up to 32 bytes as a fixed width value in one call, in the order of `fixed_order`
+fn encode_fixed_bytes<W: Write>(out: &[u8], w: &mut W) -> anyhow::Result<()> {
+    let prefix = byte_prefix(fixed_type(out.len()));
+    let width = fixed_width(prefix).context("fixed width")?;
+    let mut frame = [0u8; 33];
+    frame[0] = prefix;
+    frame[1 + width - out.len()..=width].copy_from_slice(out);
+    fixed_order(&mut frame[1..=width]);
+    w.write_all(&frame[..=width]).context("write db value")?;
     Ok(())
 }
 
+// LEB128, 7 bits a byte from the lowest, into the buffer. Returns the bytes taken
+fn put_varint(mut v: u64, buf: &mut [u8; 10]) -> usize {
+    let mut n = 0;
+    loop {
+        buf[n] = (v & 0x7F) as u8;
+        v >>= 7;
+        if v == 0 {
+            break;
+        }
+        buf[n] |= 0x80;
+        n += 1;
+    }
+    n + 1
+}
+
+pub(crate) fn write_varint<W: Write>(v: u64, w: &mut W) -> anyhow::Result<()> {
+    let mut buf = [0u8; 10];
+    let n = put_varint(v, &mut buf);
+    w.write_all(&buf[..n]).context("write varint")?;
+    Ok(())
+}
+
+// prefix of the short form with the varint bit, and the length
+fn encode_varint_len<W: Write>(ch: u8, len: usize, w: &mut W) -> anyhow::Result<()> {
+    let mut buf = [0u8; 10];
+    let n = put_varint(len as u64, &mut buf);
+    write_prefixed(ch | VARINT, &buf[..n], w).context("write varint prefix")?;
+    Ok(())
+}
+
+// prefix and size of the array, items follow it
+pub(crate) fn encode_array_header<W: Write>(
+    len: usize,
+    w: &mut W,
+    opts: &EncodeOptions,
+) -> anyhow::Result<()> {
+    array_header(len, 0, w, opts)
+}
+
+fn array_header<W: Write>(
+    len: usize,
+    flags: u8,
+    w: &mut W,
+    opts: &EncodeOptions,
+) -> anyhow::Result<()> {
+    if opts.varint_lengths {
+        encode_varint_len(byte_prefix(FieldType::DA { size: 0 }) | flags, len, w)?;
+    } else if len > u8::MAX as usize {
+        let size = wide_size(len, "array")?;
+        let ch: u8 = byte_prefix(FieldType::DWA { size }) | flags;
+        write_prefixed(ch, &size.to_le_bytes(), w).context("write dwa prefix")?;
+    } else {
+        let size: u8 = len as u8;
+        let ch = byte_prefix(FieldType::DA { size }) | flags;
+        w.write_all(&[ch, size]).context("write da")?;
+    }
+    Ok(())
+}
+
+// type of the elements of the packed array: floats, or unsigned integers of the widest one
+fn packed_type(nums: &[&Number]) -> Option<FieldType> {
+    if nums.iter().all(|n| n.is_f64() && exact_f64(n)) {
+        return Some(FieldType::F64);
+    }
+    let mut max = 0;
+    for n in nums {
+        max = max.max(n.as_u64()?);
+    }
+    Some(match max {
+        0..=0xFF => FieldType::U8,
+        0x100..=0xFFFF => FieldType::U16,
+        0x1_0000..=0xFFFF_FFFF => FieldType::U32,
+        _ => FieldType::U64,
+    })
+}
+
+/// writes the array of numbers packed, when that is smaller than with a prefix per element.
+/// Returns false when nothing was written
+pub(crate) fn encode_packed<W: Write>(
+    nums: &[&Number],
+    w: &mut W,
+    opts: &EncodeOptions,
+) -> anyhow::Result<bool> {
+    let ty = match packed_type(nums) {
+        Some(ty) if !nums.is_empty() => ty,
+        _ => return Ok(false),
+    };
+    let ch = byte_prefix(ty);
+    let width = match ch {
+        2 => 1,
+        5 => 2,
+        7 => 4,
+        _ => 8,
+    };
+    let mut unpacked = CountingWriter {
+        inner: &mut std::io::sink(),
+        bytes: 0,
+    };
+    for n in nums {
+        encode_number(n, &mut unpacked)?;
+    }
+    if 1 + width * nums.len() >= unpacked.bytes {
+        return Ok(false);
+    }
+    array_header(nums.len(), PACKED, w, opts)?;
+    w.write_all(&[ch]).context("write packed type")?;
+    for n in nums {
+        match ch {
+            2 => w.write_all(&[n.as_u64().unwrap_or_default() as u8]),
+            5 => w.write_all(&(n.as_u64().unwrap_or_default() as u16).to_le_bytes()),
+            7 => w.write_all(&(n.as_u64().unwrap_or_default() as u32).to_le_bytes()),
+            9 => w.write_all(&n.as_u64().unwrap_or_default().to_le_bytes()),
+            _ => w.write_all(&n.as_f64().unwrap_or_default().to_le_bytes()),
+        }
+        .context("write packed element")?;
+    }
+    Ok(true)
+}
+
 fn encode_array<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
     value: &Vec<Value>,
     w: &mut W,
     fd: &D1,
     vd: &D2,
+    opts: &EncodeOptions,
+    refs: &mut BackRefs,
 ) -> anyhow::Result<()> {
-    if value.len() > 256 {
-        let size: u16 = value.len() as u16;
-        let ch: u8 = byte_prefix(FieldType::DWA { size });
-        w.write(&[ch]).context("write dwa prefix")?;
-        w.write(&size.to_le_bytes()).context("write dwa len")?;
-    } else {
-        let size: u8 = value.len() as u8;
-        let ch = byte_prefix(FieldType::DA { size });
-        w.write(&[ch, size]).context("write da")?;
+    let nums: Option<Vec<&Number>> = value.iter().map(Value::as_number).collect();
+    if let Some(nums) = nums {
+        if encode_packed(&nums, w, opts)? {
+            return Ok(());
+        }
     }
+    encode_array_header(value.len(), w, opts)?;
     for item in value {
-        encode_value(item, w, fd, vd)?;
+        encode_value_with(item, w, fd, vd, opts, refs)?;
+    }
+    Ok(())
+}
+
+// counts keys and strings of the value in `stats`, by comparing their bytes
+// with the dictionaries and without them
+fn count_lookups<D1: DictionaryRead, D2: DictionaryRead>(
+    value: &Value,
+    fd: &D1,
+    vd: &D2,
+    opts: &EncodeOptions,
+    stats: &mut EncodeStats,
+    bufs: &mut (Vec<u8>, Vec<u8>),
+) -> anyhow::Result<()> {
+    let nod = NoDictionary {};
+    match value {
+        Value::String(value) if opts.use_value_dictionary => {
+            bufs.0.clear();
+            bufs.1.clear();
+            encode_string(value, &mut bufs.0, vd, opts)?;
+            encode_string(value, &mut bufs.1, &nod, opts)?;
+            stats.values.count(&bufs.0, &bufs.1);
+        }
+        Value::Array(value) => {
+            for item in value {
+                count_lookups(item, fd, vd, opts, stats, bufs)?;
+            }
+        }
+        Value::Object(value) => {
+            // BigNumber objects are written as bytes, without keys
+            if opts.detect_bignumber && big_number(value).ok().flatten().is_some() {
+                return Ok(());
+            }
+            for (k, v) in value {
+                bufs.0.clear();
+                bufs.1.clear();
+                encode_key(k, &mut bufs.0, fd)?;
+                encode_key(k, &mut bufs.1, &nod)?;
+                stats.fields.count(&bufs.0, &bufs.1);
+                count_lookups(v, fd, vd, opts, stats, bufs)?;
+            }
+        }
+        _ => {}
     }
     Ok(())
 }
@@ -143,34 +841,29 @@ fn with_0x(input: &[u8]) -> bool {
     input.len() > 2 && input[0] == ('0' as u8) && input[1] == ('x' as u8)
 }
 
+// ethers BigNumber: only the `type` of "BigNumber" and the 0x-prefixed `hex`,
+// other objects with these keys keep all their fields
 fn big_number(value: &Map<String, Value>) -> anyhow::Result<Option<Vec<u8>>> {
-    if value.len() == 2 {
-        if let Some(t) = value.get("type") {
-            if let Value::String(s) = t {
-                if s.as_str() != "BigNumber" {
-                    return Ok(None);
-                }
-            }
-        }
-        if let Some(v) = value.get("hex") {
-            if let Value::String(s) = v {
-                let hexstr = s.as_str().as_bytes();
-                if !with_0x(hexstr) {
-                    return Ok(None);
-                }
-                let mut remained: Vec<u8> = s.as_str().bytes().skip(2).collect();
-                let mut hexchars = if remained.len() % 2 == 0 {
-                    vec![]
-                } else {
-                    vec![48] // '0'
-                };
-                hexchars.append(&mut remained);
-                let out: Vec<u8> = hex::decode(&hexchars)?;
-                return Ok(Some(out));
-            }
-        }
+    if value.len() != 2 || value.get("type").and_then(Value::as_str) != Some("BigNumber") {
+        return Ok(None);
+    }
+    let s = match value.get("hex") {
+        Some(Value::String(s)) => s,
+        _ => return Ok(None),
+    };
+    let hexstr = s.as_str().as_bytes();
+    if !with_0x(hexstr) {
+        return Ok(None);
     }
-    Ok(None)
+    let mut remained: Vec<u8> = s.as_str().bytes().skip(2).collect();
+    let mut hexchars = if remained.len() % 2 == 0 {
+        vec![]
+    } else {
+        vec![48] // '0'
+    };
+    hexchars.append(&mut remained);
+    let out: Vec<u8> = hex::decode(&hexchars)?;
+    Ok(Some(out))
 }
 
 fn encode_object<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
@@ -178,113 +871,251 @@ fn encode_object<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
     w: &mut W,
     fd: &D1,
     vd: &D2,
+    opts: &EncodeOptions,
+    refs: &mut BackRefs,
 ) -> anyhow::Result<()> {
-    if let Ok(Some(out)) = big_number(value) {
-        // treat known objects, like BigNumber specially; should be just bytes
+    if let Some(out) = big_number(value)
+        .ok()
+        .flatten()
+        .filter(|_| opts.detect_bignumber)
+    {
+        // treat known objects, like BigNumber specially; should be just bytes,
+        // marked so the decoder may restore the object
+        if out.len() > u8::MAX as usize {
+            let size = wide_size(out.len(), "big number")?;
+            let ch: u8 = byte_prefix(FieldType::DWB { size }) | BIG_NUMBER;
+            write_prefixed(ch, &size.to_le_bytes(), w).context("write bn dwb prefix")?;
+            w.write_all(&out).context("write bn dwb value")?;
+            return Ok(());
+        }
         let size: u8 = out.len() as u8;
-        let ch: u8 = byte_prefix(FieldType::DB { size });
-        w.write(&[ch]).context("write bn db prefix")?;
-        w.write(&[size]).context("write bn db len")?;
-        w.write(&out).context("write bn db value")?;
+        let ch: u8 = byte_prefix(FieldType::DB { size }) | BIG_NUMBER;
+        w.write_all(&[ch, size]).context("write bn db prefix")?;
+        w.write_all(&out).context("write bn db value")?;
         return Ok(());
     }
 
-    let size: u8 = value.len() as u8;
-    let ch = byte_prefix(FieldType::DO { size });
-    w.write(&[ch, size]).context("write do")?;
+    encode_object_header(value.len(), w, opts)?;
     for (k, v) in value {
-        match fd.find_str(&k.as_str()) {
-            Some(dict_id) => {
-                if dict_id > std::u16::MAX as u32 {
-                    w.write(&[0xc0 | byte_prefix(FieldType::U32)])
-                        .context("do u32 prefix")?;
-                    w.write(&dict_id.to_le_bytes()).context("do u32")?;
-                } else if dict_id > std::u8::MAX as u32 {
-                    w.write(&[0x80 | byte_prefix(FieldType::U16)])
-                        .context("do u16 prefix")?;
-                    w.write(&(dict_id as u16).to_le_bytes()).context("do u16")?;
-                } else {
-                    w.write(&[0x40 | byte_prefix(FieldType::U8)])
-                        .context("do u8 prefix")?;
-                    w.write(&[dict_id as u8]).context("do u8")?;
-                };
-            }
-            None => {
-                encode_string(k.as_str(), w, vd)?;
-            }
-        };
-        encode_value(&v, w, fd, vd)?;
+        encode_key(k, w, fd)?;
+        encode_value_with(&v, w, fd, vd, opts, refs)?;
+    }
+    Ok(())
+}
+
+// prefix and size of the object, fields follow it
+pub(crate) fn encode_object_header<W: Write>(
+    len: usize,
+    w: &mut W,
+    opts: &EncodeOptions,
+) -> anyhow::Result<()> {
+    if opts.varint_lengths {
+        encode_varint_len(byte_prefix(FieldType::DO { size: 0 }), len, w)?;
+    } else if len > u8::MAX as usize {
+        let size = wide_size(len, "object")?;
+        let ch: u8 = byte_prefix(FieldType::DWO { size });
+        write_prefixed(ch, &size.to_le_bytes(), w).context("write dwo prefix")?;
+    } else {
+        let size: u8 = len as u8;
+        let ch = byte_prefix(FieldType::DO { size });
+        w.write_all(&[ch, size]).context("write do")?;
+    }
+    Ok(())
+}
+
+// field id when the key is in the dictionary, number or inline string otherwise
+pub(crate) fn encode_key<W: Write, D: DictionaryRead>(
+    k: &str,
+    w: &mut W,
+    fd: &D,
+) -> anyhow::Result<()> {
+    match fd.find_bytes(k.as_bytes()) {
+        Some(dict_id) => {
+            // low bits are the integer type of the width, decoders ignore them
+            let width = IdWidth::of(dict_id);
+            let ft = match width {
+                IdWidth::U8 => FieldType::U8,
+                IdWidth::U16 => FieldType::U16,
+                IdWidth::U32 => FieldType::U32,
+            };
+            let id = &dict_id.to_le_bytes()[..width.size()];
+            write_prefixed(width.bits() | byte_prefix(ft), id, w).context("write field id")?;
+        }
+        None => match numeric_key(k) {
+            Some(n) => encode_numeric_key(n, w)?,
+            None => encode_key_string(k, w)?,
+        },
+    };
+    Ok(())
+}
+
+// keys like "1005" that are read back from the number exactly,
+// "007" or "" stay strings
+pub(crate) fn numeric_key(k: &str) -> Option<u64> {
+    let b = k.as_bytes();
+    if b.is_empty() || !b.iter().all(|c| c.is_ascii_digit()) || (b.len() > 1 && b[0] == b'0') {
+        return None;
+    }
+    k.parse().ok()
+}
+
+// size of a dw* type, anything longer would have the size truncated
+fn wide_size(len: usize, what: &str) -> anyhow::Result<u16> {
+    match u16::try_from(len) {
+        Ok(size) => Ok(size),
+        Err(_) => bail!("{} of {} is too long to encode", what, len),
+    }
+}
+
+// size of a dl* type
+fn long_size(len: usize, what: &str) -> anyhow::Result<u32> {
+    match u32::try_from(len) {
+        Ok(size) => Ok(size),
+        Err(_) => bail!("{} of {} is too long to encode", what, len),
+    }
+}
+
+// keys are always inline strings, so "0x..." keys come back as written
+fn encode_key_string<W: Write>(k: &str, w: &mut W) -> anyhow::Result<()> {
+    if k.len() > u8::MAX as usize {
+        let size = wide_size(k.len(), "object key")?;
+        write_prefixed(byte_prefix(FieldType::DWS { size }), &size.to_le_bytes(), w)
+            .context("write dws key prefix")?;
+    } else {
+        let size = k.len() as u8;
+        w.write_all(&[byte_prefix(FieldType::DS { size }), size])
+            .context("write ds key prefix")?;
+    }
+    w.write_all(k.as_bytes()).context("write key")?;
+    Ok(())
+}
+
+// number prefix with the key flag, which is never set on string keys
+fn encode_numeric_key<W: Write>(n: u64, w: &mut W) -> anyhow::Result<()> {
+    let bytes = n.to_le_bytes();
+    let (ft, width) = if n == 0 {
+        (FieldType::ZERO, 0)
+    } else if n <= u8::MAX as u64 {
+        (FieldType::U8, 1)
+    } else if n <= u16::MAX as u64 {
+        (FieldType::U16, 2)
+    } else if n <= u32::MAX as u64 {
+        (FieldType::U32, 4)
+    } else {
+        (FieldType::U64, 8)
+    };
+    write_prefixed(byte_prefix(ft) | 0x20, &bytes[..width], w).context("write numeric key")?;
+    Ok(())
+}
+
+// floats that f32 holds exactly take 4 bytes. Also writes NaN and infinities,
+// which JSON values can't hold but serialized types can
+pub(crate) fn encode_f64<W: Write>(v: f64, w: &mut W) -> anyhow::Result<()> {
+    if v as f32 as f64 == v {
+        let ch = byte_prefix(FieldType::F32);
+        write_prefixed(ch, &(v as f32).to_le_bytes(), w).context("write f32")?;
+        return Ok(());
     }
+    let ch = byte_prefix(FieldType::F64);
+    write_prefixed(ch, &v.to_le_bytes(), w).context("write f64")?;
     Ok(())
 }
 
-fn encode_number<W: Write>(value: &Number, w: &mut W) -> anyhow::Result<()> {
+pub(crate) fn encode_number<W: Write>(value: &Number, w: &mut W) -> anyhow::Result<()> {
     if value.is_i64() {
         let v: i64 = value.as_i64().context("bad i64")?;
         if v == 0i64 {
             let ch = byte_prefix(FieldType::ZERO);
-            w.write(&[ch]).context("write 0i64")?;
+            w.write_all(&[ch]).context("write 0i64")?;
         } else if let Some(v8) = v.to_i8() {
             let ch = byte_prefix(FieldType::I8);
-            w.write(&[ch, v8 as u8]).context("write i8")?;
+            w.write_all(&[ch, v8 as u8]).context("write i8")?;
         } else if let Some(v16) = v.to_i16() {
             let ch = byte_prefix(FieldType::I16);
             let lo: u8 = (v16 & 0xFF) as u8;
             let hi: u8 = (v16 >> 8) as u8;
-            w.write(&[ch, lo, hi]).context("write i16")?;
+            w.write_all(&[ch, lo, hi]).context("write i16")?;
         } else if let Some(v32) = v.to_i32() {
             let ch = byte_prefix(FieldType::I32);
-            w.write(&[ch]).context("write i32 prefix")?;
-            w.write(&v32.to_le_bytes()).context("write i32")?;
+            write_prefixed(ch, &v32.to_le_bytes(), w).context("write i32")?;
         } else {
             let ch = byte_prefix(FieldType::I64);
-            w.write(&[ch]).context("write i64 prefix")?;
-            w.write(&v.to_le_bytes()).context("write i64")?;
+            write_prefixed(ch, &v.to_le_bytes(), w).context("write i64")?;
         }
     } else if value.is_u64() {
         let v: u64 = value.as_u64().context("bad u64")?;
         if v == 0u64 {
             let ch = byte_prefix(FieldType::ZERO);
-            w.write(&[ch]).context("write 0u64")?;
+            w.write_all(&[ch]).context("write 0u64")?;
         } else if let Some(v8) = v.to_u8() {
             let ch = byte_prefix(FieldType::U8);
-            w.write(&[ch, v8]).context("write u8")?;
+            w.write_all(&[ch, v8]).context("write u8")?;
         } else if let Some(v16) = v.to_u16() {
             let ch = byte_prefix(FieldType::U16);
             let lo: u8 = (v16 & 0xFF) as u8;
             let hi: u8 = (v16 >> 8) as u8;
-            w.write(&[ch, lo, hi]).context("write u16")?;
+            w.write_all(&[ch, lo, hi]).context("write u16")?;
         } else if let Some(v32) = v.to_u32() {
             let ch = byte_prefix(FieldType::U32);
-            w.write(&[ch]).context("write u32 prefix")?;
-            w.write(&v32.to_le_bytes()).context("write u32")?;
+            write_prefixed(ch, &v32.to_le_bytes(), w).context("write u32")?;
         } else {
             let ch = byte_prefix(FieldType::U64);
-            w.write(&[ch]).context("write u64 prefix")?;
-            w.write(&v.to_le_bytes()).context("write u64")?;
-        }
-    } else if value.is_f64() {
-        let ch = byte_prefix(FieldType::F64);
-        let b = value.as_f64().context("f64")?.to_le_bytes();
-        w.write(&[ch]).context("write f64 prefix")?;
-        w.write(&b).context("write f64")?;
+            write_prefixed(ch, &v.to_le_bytes(), w).context("write u64")?;
+        }
+    } else if value.is_f64() && exact_f64(value) {
+        encode_f64(value.as_f64().context("f64")?, w)?;
     } else {
-        return Err(anyhow::Error::msg("number parsing failure"));
+        encode_wide_number(value, w)?;
     };
 
     Ok(())
 }
 
-pub(crate) fn encode_value<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+// arbitrary precision numbers may have more digits than f64 keeps
+#[cfg(feature = "arbitrary_precision")]
+fn exact_f64(value: &Number) -> bool {
+    let f = value.as_f64().and_then(Number::from_f64);
+    f.is_some_and(|f| f.to_string() == value.to_string())
+}
+
+#[cfg(not(feature = "arbitrary_precision"))]
+fn exact_f64(_value: &Number) -> bool {
+    true
+}
+
+// numbers over 64 bits, which are only made with arbitrary precision:
+// 128-bit integers are b128 with the numeric bit, the rest is decimal text
+fn encode_wide_number<W: Write>(value: &Number, w: &mut W) -> anyhow::Result<()> {
+    let text = value.to_string();
+    let (flags, b) = match (text.parse::<u128>(), text.parse::<i128>()) {
+        (Ok(u), _) => (NUMERIC, u.to_le_bytes()),
+        (_, Ok(i)) => (NUMERIC | NEGATIVE, i.to_le_bytes()),
+        _ if text.len() <= u8::MAX as usize => {
+            let ch = byte_prefix(FieldType::DS { size: 0 }) | NUMERIC;
+            w.write_all(&[ch, text.len() as u8])
+                .context("write decimal prefix")?;
+            w.write_all(text.as_bytes()).context("write decimal")?;
+            return Ok(());
+        }
+        _ => bail!("number of {} digits is too long to encode", text.len()),
+    };
+    let ch = byte_prefix(FieldType::B128) | flags;
+    write_prefixed(ch, &b, w).context("write 128-bit integer")?;
+    Ok(())
+}
+
+pub(crate) fn encode_value_with<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
     input: &Value,
     w: &mut W,
     fd: &D1,
     vd: &D2,
+    opts: &EncodeOptions,
+    refs: &mut BackRefs,
 ) -> anyhow::Result<()> {
     match input {
         Value::Null => {
             let ch: u8 = byte_prefix(FieldType::NULL);
-            w.write(&[ch]).context("write null")?;
+            w.write_all(&[ch]).context("write null")?;
         }
         Value::Bool(value) => {
             let ch: u8 = byte_prefix(if *value {
@@ -292,19 +1123,19 @@ pub(crate) fn encode_value<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
             } else {
                 FieldType::FALSE
             });
-            w.write(&[ch]).context("write bool")?;
+            w.write_all(&[ch]).context("write bool")?;
         }
         Value::Number(value) => {
             encode_number(value, w)?;
         }
         Value::String(value) => {
-            encode_string(&value.as_str(), w, vd)?;
+            encode_string_ref(&value.as_str(), w, vd, opts, refs)?;
         }
         Value::Array(value) => {
-            encode_array(value, w, fd, vd)?;
+            encode_array(value, w, fd, vd, opts, refs)?;
         }
         Value::Object(value) => {
-            encode_object(value, w, fd, vd)?;
+            encode_object(value, w, fd, vd, opts, refs)?;
         }
     };
     Ok(())
@@ -330,15 +1161,40 @@ pub enum FieldType {
     B160,
     B256,
     F64,
+    F32,
     ZERO,
-    DB { size: u8 },
-    DS { size: u8 },
-    DA { size: u8 },
-    DO { size: u8 },
-    DWB { size: u16 },
-    DWS { size: u16 },
-    DWA { size: u16 },
-    DWO { size: u16 },
+    DB {
+        size: u8,
+    },
+    DS {
+        size: u8,
+    },
+    DA {
+        size: u8,
+    },
+    DO {
+        size: u8,
+    },
+    DWB {
+        size: u16,
+    },
+    DWS {
+        size: u16,
+    },
+    DWA {
+        size: u16,
+    },
+    DWO {
+        size: u16,
+    },
+    /// digits to drop from the front of the following bytes value
+    HEXW,
+    DLS {
+        size: u32,
+    },
+    DLB {
+        size: u32,
+    },
     NULL,
 }
 
@@ -362,6 +1218,7 @@ fn byte_prefix(input: FieldType) -> u8 {
         FieldType::B160 => 15,
         FieldType::B256 => 16,
         FieldType::F64 => 17,
+        FieldType::F32 => 17 | F32,
         FieldType::ZERO => 18,
         FieldType::DS { size: _ } => 20,
         FieldType::DB { size: _ } => 19,
@@ -371,6 +1228,9 @@ fn byte_prefix(input: FieldType) -> u8 {
         FieldType::DWB { size: _ } => 23,
         FieldType::DWA { size: _ } => 25,
         FieldType::DWO { size: _ } => 26,
+        FieldType::HEXW => 27,
+        FieldType::DLS { size: _ } => 28,
+        FieldType::DLB { size: _ } => 29,
         FieldType::NULL => 31,
     }
 }
@@ -386,7 +1246,7 @@ mod tests {
     fn enc(input: &Value) -> anyhow::Result<Vec<u8>> {
         let nod = NoDictionary {};
         let mut buf = BufWriter::new(Vec::new());
-        encode_value(input, &mut buf, &nod, &nod).unwrap();
+        Encoder::new(&nod, &nod).encode(input, &mut buf).unwrap();
         let v = buf.into_inner().unwrap();
         Ok(v)
     }
@@ -395,7 +1255,7 @@ mod tests {
     fn enc_d(input: &Value) -> anyhow::Result<Vec<u8>> {
         let d = MapDictionary::from_strings(vec!["alpha", "beta", "gamma", "delta", "epsilon"]);
         let mut buf = BufWriter::new(Vec::new());
-        encode_value(input, &mut buf, &d, &d).unwrap();
+        Encoder::new(&d, &d).encode(input, &mut buf).unwrap();
         let v = buf.into_inner().unwrap();
         Ok(v)
     }
@@ -413,6 +1273,49 @@ mod tests {
         assert_eq!(n[0], 31u8);
     }
 
+    // writer that counts the calls
+    struct Calls(Vec<usize>);
+
+    impl Write for Calls {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.push(buf.len());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_writes_scalars_and_headers_at_once() {
+        let d = MapDictionary::from_strings(vec!["alpha"]);
+        let varint = EncodeOptions {
+            varint_lengths: true,
+            ..Default::default()
+        };
+        for (v, opts) in [
+            (json!(70_000), EncodeOptions::default()),
+            (json!(-5_000_000_000i64), EncodeOptions::default()),
+            (json!(1.1), EncodeOptions::default()),
+            (json!(1.5), EncodeOptions::default()),
+            (json!("alpha"), EncodeOptions::default()),
+            (json!([]), varint.clone()),
+            (json!({}), varint),
+        ] {
+            let mut calls = Calls(vec![]);
+            Encoder::new(&d, &d)
+                .with_options(opts)
+                .encode(&v, &mut calls)
+                .unwrap();
+            assert_eq!(calls.0.len(), 1, "{}: {:?}", v, calls.0);
+        }
+        let mut calls = Calls(vec![]);
+        let long = Value::Array(vec![Value::Null; 300]);
+        Encoder::new(&d, &d).encode(&long, &mut calls).unwrap();
+        assert_eq!(calls.0[..2], [3, 1]);
+    }
+
     #[test]
     fn it_encodes_numbers() {
         let z = enc(&json!(0)).unwrap();
@@ -456,12 +1359,15 @@ mod tests {
 
     #[test]
     fn it_encodes_floats() {
+        // floats that f32 holds exactly take 4 bytes
         let z = enc(&json!(0.0)).unwrap();
-        assert_eq!(z.len(), 9);
+        assert_eq!(z.len(), 5);
         let one = enc(&json!(-1.0)).unwrap();
-        assert_eq!(one.len(), 9);
+        assert_eq!(one.len(), 5);
         let exp = enc(&json!(1.5e10)).unwrap();
         assert_eq!(exp.len(), 9);
+        let tenth = enc(&json!(0.1)).unwrap();
+        assert_eq!(tenth.len(), 9);
     }
 
     #[test]
@@ -511,18 +1417,214 @@ mod tests {
         println!("d2.len = {}", d2.len());
     }
 
+    // writer that takes at most 3 bytes per call, like a pipe or a socket
+    struct ShortWrites(Vec<u8>);
+
+    impl Write for ShortWrites {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let n = buf.len().min(3);
+            self.0.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn it_encodes_into_short_writes() {
+        let d = MapDictionary::from_strings(vec!["alpha", "beta", "gamma", "delta", "epsilon"]);
+        let v = json!({
+            "alpha": "x".repeat(1000),
+            "beta": (0..300).collect::<Vec<_>>(),
+            "gamma": {"type": "BigNumber", "hex": "0x1ff"},
+            "hash": "0xd8052f44b36869fa1f193ec2c97e6a36892840635dc347554efb8778a7a3935a",
+            "n": [-70000, 1.5, u64::MAX, null, true],
+        });
+        let mut w = ShortWrites(vec![]);
+        let n = Encoder::new(&d, &d).encode(&v, &mut w).unwrap();
+        assert_eq!(n, w.0.len());
+        assert_eq!(w.0, enc_d(&v).unwrap());
+        let mut expected = v.clone();
+        expected["gamma"] = json!("0x01ff");
+        assert_eq!(
+            crate::decode(&mut w.0.as_slice(), &d, &d).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn it_encodes_objects_field_by_field() {
+        let d = MapDictionary::from_strings(vec!["hash", "from", "transactions"]);
+        let tx = |i: u64| json!({"from": format!("0x{:040x}", i), "hash": format!("0x{:064x}", i)});
+        let v = json!({
+            "hash": format!("0x{:064x}", 1000),
+            "miner": format!("0x{:040x}", 1),
+            "number": "0x10",
+            "transactions": [tx(1), tx(2)],
+            "uncles": [],
+        });
+        let varint = EncodeOptions {
+            back_references: true,
+            varint_lengths: true,
+            ..Default::default()
+        };
+        for opts in [EncodeOptions::default(), varint] {
+            let encoder = Encoder::new(&d, &d).with_options(opts);
+            let mut expected = vec![];
+            encoder.encode(&v, &mut expected).unwrap();
+
+            let mut buf = vec![];
+            let mut o = encoder.begin_object(&mut buf, 5).unwrap();
+            o.field("hash", &v["hash"]).unwrap();
+            o.field("miner", &v["miner"]).unwrap();
+            o.field("number", &v["number"]).unwrap();
+            o.key("transactions").unwrap();
+            o.begin_array(2).unwrap();
+            for i in 1..=2 {
+                o.begin_object(2).unwrap();
+                o.field("from", &tx(i)["from"]).unwrap();
+                o.field("hash", &tx(i)["hash"]).unwrap();
+                o.end().unwrap();
+            }
+            o.end().unwrap();
+            o.field("uncles", &json!([])).unwrap();
+            assert_eq!(o.finish().unwrap(), expected.len());
+            assert_eq!(buf, expected);
+        }
+    }
+
+    #[test]
+    fn it_checks_declared_sizes_field_by_field() {
+        let nod = NoDictionary {};
+        let mut buf = vec![];
+        let err = |r: anyhow::Result<()>| r.unwrap_err().to_string();
+
+        let o = ObjectEncoder::begin(&mut buf, 2, &nod, &nod).unwrap();
+        assert_eq!(
+            o.finish().unwrap_err().to_string(),
+            "object of 2 is ended after 0"
+        );
+
+        let mut o = ObjectEncoder::begin(&mut buf, 1, &nod, &nod).unwrap();
+        assert_eq!(err(o.value(&json!(1))), "value without a key in an object");
+        o.field("a", &json!(1)).unwrap();
+        assert_eq!(err(o.key("b")), "object of 1 has no room for 'b'");
+        assert_eq!(err(o.end()), "the object is ended by finish");
+
+        let mut o = ObjectEncoder::begin(&mut buf, 1, &nod, &nod).unwrap();
+        o.key("a").unwrap();
+        assert_eq!(err(o.key("b")), "key 'b' after a key without a value");
+        o.begin_array(1).unwrap();
+        assert_eq!(err(o.key("c")), "key 'c' in an array");
+        o.value(&json!(null)).unwrap();
+        assert_eq!(
+            err(o.value(&json!(null))),
+            "array of 1 has no room for more"
+        );
+        o.begin_object(0).unwrap_err();
+        let e = o.finish().unwrap_err();
+        assert_eq!(e.to_string(), "1 nested containers are not ended");
+    }
+
     #[test]
     fn it_encodes_array() {
-        let a = enc(&json!([0, 1, 2])).unwrap();
+        let a = enc(&json!([0, -1, 2])).unwrap();
         assert_eq!(a.len(), 1 + 1 + (1 + 2 + 2));
 
         let s = enc(&json!(["one", "two", "three"])).unwrap();
         assert_eq!(s.len(), 2 + (2 + 3) + (2 + 3) + (2 + 5));
 
         let b = enc_d(&json!(["alpha", "beta", "gamma"])).unwrap();
-        assert_eq!(b.len(), 2 + (1 + 4) + (1 + 4) + (1 + 4));
+        // dictionary ids under 256 take one byte
+        assert_eq!(b.len(), 2 + (1 + 1) + (1 + 1) + (1 + 1));
         assert!(b[2] > 0x20);
-        assert!(b[2 + 5] > 0x20);
-        assert!(b[2 + 5 + 5] > 0x20);
+        assert!(b[2 + 2] > 0x20);
+        assert!(b[2 + 2 + 2] > 0x20);
+    }
+
+    #[test]
+    fn it_counts_dictionary_lookups() {
+        let addr = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
+        let v = json!({
+            "blockNumber": "0x10",
+            "from": addr,
+            "to": "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            "logs": [{"address": addr.to_uppercase().replace("0X", "0x"), "removed": false}],
+            "value": {"type": "BigNumber", "hex": "0x01"},
+        });
+        let fd = MapDictionary::from_strings(vec!["blockNumber", "from", "logs", "type"]);
+        let vd = MapDictionary::from_strings(vec![addr]);
+        let nod = NoDictionary {};
+        let mut buf = vec![];
+        let stats = Encoder::new(&fd, &vd)
+            .encode_with_stats(&v, &mut buf)
+            .unwrap();
+        assert_eq!(stats.bytes, buf.len() as u64);
+        let fields = DictionaryStats {
+            hits: 3,
+            misses: 4,
+            bytes_saved: 11 + 4 + 4,
+        };
+        assert_eq!(stats.fields, fields);
+        assert_eq!((stats.values.hits, stats.values.misses), (2, 2));
+        // what is saved is what the blob is smaller by
+        let inline = Encoder::new(&nod, &nod).encoded_size(&v).unwrap() as u64;
+        assert_eq!(
+            stats.fields.bytes_saved + stats.values.bytes_saved,
+            inline - stats.bytes
+        );
+        assert_eq!(
+            stats.to_string(),
+            format!(
+                "{} bytes; fields: 3 hits, 4 misses, 19 bytes saved; values: 2 hits, 2 misses, {} bytes saved",
+                stats.bytes, stats.values.bytes_saved
+            )
+        );
+
+        let opts = EncodeOptions {
+            use_value_dictionary: false,
+            ..Default::default()
+        };
+        let stats = Encoder::new(&fd, &vd)
+            .with_options(opts)
+            .encode_with_stats(&v, &mut vec![])
+            .unwrap();
+        assert_eq!(stats.fields, fields);
+        assert_eq!(stats.values, DictionaryStats::default());
+    }
+
+    #[test]
+    fn it_counts_dictionary_lookups_of_fixture() {
+        let v: Value = serde_json::from_str(include_str!("../tests/fixtures/block.json")).unwrap();
+        fn keys(v: &Value, known: &[&str], out: &mut (u64, u64)) {
+            match v {
+                Value::Array(items) => items.iter().for_each(|item| keys(item, known, out)),
+                Value::Object(fields) => {
+                    for (k, v) in fields {
+                        match known.contains(&k.as_str()) {
+                            true => out.0 += 1,
+                            false => out.1 += 1,
+                        }
+                        keys(v, known, out);
+                    }
+                }
+                _ => {}
+            }
+        }
+        let known = ["hash", "from", "to", "input", "transactions"];
+        let mut expected = (0, 0);
+        keys(&v, &known, &mut expected);
+        let fd = MapDictionary::from_static(&known);
+        let nod = NoDictionary {};
+        let stats = Encoder::new(&fd, &nod)
+            .encode_with_stats(&v, &mut std::io::sink())
+            .unwrap();
+        assert_eq!((stats.fields.hits, stats.fields.misses), expected);
+        assert!(stats.fields.hits > 0 && stats.fields.misses > 0);
+        assert_eq!(stats.values.hits, 0);
+        let inline = Encoder::new(&nod, &nod).encoded_size(&v).unwrap() as u64;
+        assert_eq!(stats.fields.bytes_saved, inline - stats.bytes);
     }
 }
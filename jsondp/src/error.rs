@@ -0,0 +1,101 @@
+use thiserror::Error;
+
+/// Errors of decoding that callers may want to handle,
+/// they are returned wrapped into `anyhow::Error`
+#[derive(Debug, Clone, PartialEq, Error)]
+pub enum DecodeError {
+    /// value at the path is valid, but was not written in the smallest form
+    #[error("non-minimal encoding at '{path}': expected {expected}, found {found}")]
+    NonMinimalEncoding {
+        path: String,
+        expected: String,
+        found: String,
+    },
+    /// decoded value is not of the shape of the requested type
+    #[error("decoded value does not deserialize into {target}: {message}")]
+    Shape {
+        target: &'static str,
+        message: String,
+    },
+    /// blob was encoded with another generation of the dictionary
+    #[error(
+        "{dictionary} dictionary fingerprint mismatch: expected {}, got {}",
+        hex::encode(expected),
+        hex::encode(got)
+    )]
+    DictionaryFingerprintMismatch {
+        dictionary: &'static str,
+        expected: [u8; 16],
+        got: [u8; 16],
+    },
+    /// arrays and objects are nested deeper than `DecodeOptions::max_depth`
+    #[error("max nesting depth exceeded at offset {offset}")]
+    MaxDepthExceeded { offset: u64 },
+    /// size read from the input is over what `DecodeOptions` allows to be left
+    #[error(
+        "declared size exceeds remaining budget: {declared} {unit} declared, {remaining} left"
+    )]
+    BudgetExceeded {
+        declared: u64,
+        remaining: u64,
+        unit: &'static str,
+    },
+    /// input ended in the middle of the value that starts at the offset
+    #[error("value starting at offset {offset} is truncated")]
+    Truncated { offset: u64 },
+    /// checksum of the frame at the offset does not match its payload
+    #[error("checksum mismatch at offset {offset}: stored {stored:08x}, computed {computed:08x}")]
+    ChecksumMismatch {
+        offset: u64,
+        stored: u32,
+        computed: u32,
+    },
+}
+
+/// Id that is bound to different strings in the two dictionaries of a merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MergeConflict {
+    pub id: u32,
+    pub ours: String,
+    pub theirs: String,
+}
+
+/// Error of `MapDictionary::merge` and `MapDictionary::apply_delta`,
+/// the dictionary is left as it was
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum MergeError {
+    /// ids of the other dictionary are taken by other strings,
+    /// blobs encoded with it would decode into wrong values
+    #[error(
+        "{} ids are bound to different strings, first is {}: '{}' and '{}'",
+        conflicts.len(),
+        conflicts[0].id,
+        conflicts[0].ours,
+        conflicts[0].theirs
+    )]
+    IdConflict { conflicts: Vec<MergeConflict> },
+    /// the entry is here with another id, in `MapDictionary::apply_delta`
+    #[error("'{entry}' has id {ours} here, not {theirs}")]
+    EntryMoved {
+        entry: String,
+        ours: u32,
+        theirs: u32,
+    },
+}
+
+/// Error of the serde serializer and deserializer of the encoded form
+#[derive(Debug, Error)]
+#[error(transparent)]
+pub struct SerdeError(#[from] pub(crate) anyhow::Error);
+
+impl serde::ser::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self(anyhow::anyhow!("{}", msg))
+    }
+}
+
+impl serde::de::Error for SerdeError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        Self(anyhow::anyhow!("{}", msg))
+    }
+}
@@ -0,0 +1,115 @@
+use crate::blockchain::{get_dictionary, DICTIONARY_VERSION};
+use crate::dictionary::NoDictionary;
+use ethers::types::{Block, Log, TransactionReceipt, TxHash};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::BufReader;
+
+// goes through serde_json::Value, ethers types are serialized as in JSON-RPC
+fn encode_typed<T: Serialize>(input: &T) -> anyhow::Result<Vec<u8>> {
+    let value = serde_json::to_value(input)?;
+    let mut out = Vec::new();
+    let fd = get_dictionary(DICTIONARY_VERSION)?;
+    crate::encode(&value, &mut out, &fd, &NoDictionary {})?;
+    Ok(out)
+}
+
+fn decode_typed<T: DeserializeOwned>(input: &[u8]) -> anyhow::Result<T> {
+    let fd = get_dictionary(DICTIONARY_VERSION)?;
+    crate::decode_as(&mut BufReader::new(input), &fd, &NoDictionary {})
+}
+
+/// encodes logs with the blockchain field dictionary
+pub fn encode_logs(logs: &[Log]) -> anyhow::Result<Vec<u8>> {
+    encode_typed(&logs)
+}
+
+pub fn decode_logs(input: &[u8]) -> anyhow::Result<Vec<Log>> {
+    decode_typed(input)
+}
+
+/// encodes receipts with the blockchain field dictionary
+pub fn encode_receipts(receipts: &[TransactionReceipt]) -> anyhow::Result<Vec<u8>> {
+    encode_typed(&receipts)
+}
+
+pub fn decode_receipts(input: &[u8]) -> anyhow::Result<Vec<TransactionReceipt>> {
+    decode_typed(input)
+}
+
+/// encodes block with transaction hashes with the blockchain field dictionary
+pub fn encode_block(block: &Block<TxHash>) -> anyhow::Result<Vec<u8>> {
+    encode_typed(block)
+}
+
+pub fn decode_block(input: &[u8]) -> anyhow::Result<Block<TxHash>> {
+    decode_typed(input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::DecodeError;
+
+    #[test]
+    fn it_round_trips_logs() {
+        let logs: Vec<Log> =
+            serde_json::from_str(include_str!("../tests/fixtures/logs.json")).unwrap();
+        let buf = encode_logs(&logs).unwrap();
+        assert_eq!(decode_logs(&buf).unwrap(), logs);
+        assert!(buf.len() < serde_json::to_vec(&logs).unwrap().len() / 2);
+    }
+
+    #[test]
+    fn it_round_trips_receipts() {
+        let receipts: Vec<TransactionReceipt> =
+            serde_json::from_str(include_str!("../tests/fixtures/receipts.json")).unwrap();
+        let buf = encode_receipts(&receipts).unwrap();
+        assert_eq!(decode_receipts(&buf).unwrap(), receipts);
+        assert!(buf.len() < serde_json::to_vec(&receipts).unwrap().len() / 2);
+    }
+
+    #[test]
+    fn it_round_trips_block() {
+        let block: Block<TxHash> =
+            serde_json::from_str(include_str!("../tests/fixtures/block.json")).unwrap();
+        let buf = encode_block(&block).unwrap();
+        assert_eq!(decode_block(&buf).unwrap(), block);
+        assert!(buf.len() < serde_json::to_vec(&block).unwrap().len() / 2);
+    }
+
+    #[test]
+    fn it_decodes_log_as_type() {
+        let logs: Vec<Log> =
+            serde_json::from_str(include_str!("../tests/fixtures/logs.json")).unwrap();
+        let mut value = serde_json::to_value(&logs[0]).unwrap();
+        let fd = get_dictionary(DICTIONARY_VERSION).unwrap();
+        let mut buf = Vec::new();
+        crate::encode(&value, &mut buf, &fd, &NoDictionary {}).unwrap();
+        let log: Log = crate::decode_as(&mut buf.as_slice(), &fd, &NoDictionary {}).unwrap();
+        assert_eq!(log, logs[0]);
+
+        value.as_object_mut().unwrap().remove("topics");
+        let mut buf = Vec::new();
+        crate::encode(&value, &mut buf, &fd, &NoDictionary {}).unwrap();
+        let err = crate::decode_as::<Log, _, _, _>(&mut buf.as_slice(), &fd, &NoDictionary {})
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("missing field `topics`"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn it_reports_unexpected_shape() {
+        let logs: Vec<Log> =
+            serde_json::from_str(include_str!("../tests/fixtures/logs.json")).unwrap();
+        let buf = encode_logs(&logs).unwrap();
+        let err = decode_block(&buf).unwrap_err();
+        match err.downcast_ref::<DecodeError>() {
+            Some(DecodeError::Shape { target, .. }) => assert!(target.contains("Block")),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! C interface to decode stored blobs from other languages.
+//! Build the library with `cargo rustc -p jsondp --features ffi --release --crate-type cdylib`,
+//! the header is `include/jsondp.h`, refreshed with `JSONDP_WRITE_HEADER=1 cargo build --features ffi`.
+//!
+//! Ownership: input buffers stay owned by the caller and are only read during the call.
+//! Strings returned through `out_json` and `out_err` are owned by the caller
+//! and must be released with `jsondp_free_string`, not with `free()`
+use crate::dictionary::MapDictionary;
+use std::ffi::{c_char, CString};
+use std::io::BufReader;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+/// decoded successfully, `out_json` is set
+pub const JSONDP_OK: i32 = 0;
+/// blob or dictionaries are invalid, `out_err` is set
+pub const JSONDP_ERROR: i32 = 1;
+/// internal failure, `out_err` is set
+pub const JSONDP_PANIC: i32 = 2;
+/// null output pointers, nothing is set
+pub const JSONDP_INVALID_ARGUMENT: i32 = 3;
+
+unsafe fn slice<'a>(ptr: *const u8, len: usize) -> &'a [u8] {
+    if ptr.is_null() || len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(ptr, len)
+    }
+}
+
+// nothing given is an empty dictionary
+fn dictionary(mut input: &[u8]) -> anyhow::Result<MapDictionary> {
+    if input.is_empty() {
+        return Ok(MapDictionary::new());
+    }
+    MapDictionary::from_binary(&mut input)
+}
+
+fn to_c_string(s: String) -> *mut c_char {
+    // JSON text has no NUL bytes, error messages might
+    CString::new(s.replace('\0', " "))
+        .map(CString::into_raw)
+        .unwrap_or(std::ptr::null_mut())
+}
+
+/// Decodes the blob into JSON text, using field and value dictionaries in the binary form
+/// written by `MapDictionary::write_binary`. Null dictionary pointer means an empty dictionary.
+///
+/// # Safety
+/// Every non-null pointer must be valid for reads of its length.
+/// `out_json` and `out_err` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn jsondp_decode(
+    bytes: *const u8,
+    len: usize,
+    field_dict_bytes: *const u8,
+    fd_len: usize,
+    value_dict_bytes: *const u8,
+    vd_len: usize,
+    out_json: *mut *mut c_char,
+    out_err: *mut *mut c_char,
+) -> i32 {
+    if out_json.is_null() || out_err.is_null() {
+        return JSONDP_INVALID_ARGUMENT;
+    }
+    *out_json = std::ptr::null_mut();
+    *out_err = std::ptr::null_mut();
+    let input = slice(bytes, len);
+    let fd_input = slice(field_dict_bytes, fd_len);
+    let vd_input = slice(value_dict_bytes, vd_len);
+
+    let result = catch_unwind(AssertUnwindSafe(|| -> anyhow::Result<String> {
+        let fd = dictionary(fd_input)?;
+        let vd = dictionary(vd_input)?;
+        let value = crate::decode(&mut BufReader::new(input), &fd, &vd)?;
+        Ok(serde_json::to_string(&value)?)
+    }));
+    match result {
+        Ok(Ok(json)) => {
+            *out_json = to_c_string(json);
+            JSONDP_OK
+        }
+        Ok(Err(e)) => {
+            *out_err = to_c_string(format!("{:#}", e));
+            JSONDP_ERROR
+        }
+        Err(_) => {
+            *out_err = to_c_string("panic while decoding".to_string());
+            JSONDP_PANIC
+        }
+    }
+}
+
+/// Releases a string returned by `jsondp_decode`. Null is ignored.
+///
+/// # Safety
+/// The pointer must come from `jsondp_decode` and must not be used or freed again.
+#[no_mangle]
+pub unsafe extern "C" fn jsondp_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::ffi::CStr;
+
+    // calls decode the way C code would
+    fn call(blob: &[u8], fd: &[u8], vd: &[u8]) -> (i32, Option<String>, Option<String>) {
+        let mut out_json: *mut c_char = std::ptr::null_mut();
+        let mut out_err: *mut c_char = std::ptr::null_mut();
+        let fd_ptr = if fd.is_empty() {
+            std::ptr::null()
+        } else {
+            fd.as_ptr()
+        };
+        let rc = unsafe {
+            jsondp_decode(
+                blob.as_ptr(),
+                blob.len(),
+                fd_ptr,
+                fd.len(),
+                vd.as_ptr(),
+                vd.len(),
+                &mut out_json,
+                &mut out_err,
+            )
+        };
+        let take = |p: *mut c_char| {
+            if p.is_null() {
+                return None;
+            }
+            let s = unsafe { CStr::from_ptr(p) }.to_str().unwrap().to_string();
+            unsafe { jsondp_free_string(p) };
+            Some(s)
+        };
+        (rc, take(out_json), take(out_err))
+    }
+
+    #[test]
+    fn it_decodes_through_ffi() {
+        let d = MapDictionary::from_strings(vec!["alpha", "beta"]);
+        let mut dict = Vec::new();
+        d.write_binary(&mut dict).unwrap();
+        let mut blob = Vec::new();
+        crate::encode(&json!({"alpha": ["beta", 7]}), &mut blob, &d, &d).unwrap();
+
+        let (rc, out, err) = call(&blob, &dict, &dict);
+        assert_eq!(rc, JSONDP_OK);
+        assert_eq!(err, None);
+        assert_eq!(out.unwrap(), r#"{"alpha":["beta",7]}"#);
+    }
+
+    #[test]
+    fn it_returns_errors_through_ffi() {
+        let d = MapDictionary::from_strings(vec!["alpha", "beta"]);
+        let mut blob = Vec::new();
+        crate::encode(&json!({"alpha": 1}), &mut blob, &d, &d).unwrap();
+
+        // no field dictionary given
+        let (rc, out, err) = call(&blob, &[], &[]);
+        assert_eq!(rc, JSONDP_ERROR);
+        assert_eq!(out, None);
+        assert!(err.unwrap().contains("not found in dictionary"));
+
+        // dictionaries in the text form are not accepted
+        let (rc, _, err) = call(&blob, b"1:alpha\n2:beta\n", &[]);
+        assert_eq!(rc, JSONDP_ERROR);
+        assert!(err.unwrap().contains("not a binary dictionary"));
+
+        let rc = unsafe {
+            jsondp_decode(
+                blob.as_ptr(),
+                blob.len(),
+                std::ptr::null(),
+                0,
+                std::ptr::null(),
+                0,
+                std::ptr::null_mut(),
+                std::ptr::null_mut(),
+            )
+        };
+        assert_eq!(rc, JSONDP_INVALID_ARGUMENT);
+    }
+
+    #[test]
+    fn it_matches_the_committed_header() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/jsondp.h"));
+        assert_eq!(
+            include_str!("../include/jsondp.h"),
+            generated,
+            "include/jsondp.h is stale, run `JSONDP_WRITE_HEADER=1 cargo build --features ffi`"
+        );
+    }
+}
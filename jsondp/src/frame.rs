@@ -0,0 +1,431 @@
+use crate::decode::next_varint;
+use crate::dictionary::{BytesDictionary, DictionaryRead, MapDictionary};
+use crate::encode::write_varint;
+use crate::{encode_with, DecodeError, Decoder, EncodeOptions};
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+
+/// first bytes of every framed blob
+pub const MAGIC: [u8; 4] = *b"JDP1";
+
+/// version of the frame header `write_framed` writes
+pub const VERSION: u8 = 1;
+
+/// payload is compressed with zstd, see `encode_compressed`
+pub const FLAG_COMPRESSED: u8 = 0x01;
+
+/// entries of the dictionaries the value uses are embedded ahead of it
+pub const FLAG_DICTIONARY: u8 = 0x02;
+
+/// CRC32C of the payload follows it, in 4 bytes outside of the payload length
+pub const FLAG_CHECKSUM: u8 = 0x04;
+
+/// magic, version, flags and u32 payload length
+pub const HEADER_SIZE: usize = 10;
+
+/// writes the encoded value after a header of the magic, version, flag bits
+/// and the payload length, so it can be told apart from other data.
+/// Returns the number of bytes written, the header included
+pub fn write_framed<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<usize> {
+    write_framed_with(input, w, fd, vd, &EncodeOptions::default())
+}
+
+/// same as `write_framed`, with options. `EncodeOptions::frame_checksum`
+/// appends the checksum of the payload after it
+pub fn write_framed_with<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+    opts: &EncodeOptions,
+) -> anyhow::Result<usize> {
+    let mut payload = vec![];
+    encode_with(input, &mut payload, fd, vd, opts)?;
+    let flags = match opts.frame_checksum {
+        true => FLAG_CHECKSUM,
+        false => 0,
+    };
+    write_frame(flags, &payload, w)
+}
+
+/// same as `write_framed`, with the entries of both dictionaries the value
+/// refers to written into the frame, so it is read back by `decode_self_describing`
+/// without the dictionaries. Returns the number of bytes written, the header included
+pub fn encode_self_describing<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<usize> {
+    let mut value = vec![];
+    encode_with(input, &mut value, fd, vd, &EncodeOptions::default())?;
+    // decoding asks for exactly the entries the value refers to
+    let fields = Recording::new(fd);
+    let values = Recording::new(vd);
+    Decoder::new(&fields, &values)
+        .decode_slice(&value)
+        .context("decode to find dictionary entries")?;
+    let mut payload = vec![];
+    fields.write(&mut payload)?;
+    values.write(&mut payload)?;
+    payload.extend_from_slice(&value);
+    write_frame(FLAG_DICTIONARY, &payload, w)
+}
+
+/// same as `write_framed`, with the payload compressed by zstd at the level,
+/// 0 for its default. Frames of it are read by `read_framed` as well
+#[cfg(feature = "zstd")]
+pub fn encode_compressed<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+    level: i32,
+) -> anyhow::Result<usize> {
+    let mut payload = vec![];
+    encode_with(input, &mut payload, fd, vd, &EncodeOptions::default())?;
+    let compressed = zstd::bulk::compress(&payload, level).context("compress payload")?;
+    write_frame(FLAG_COMPRESSED, &compressed, w)
+}
+
+/// reads a value written by `encode_compressed`, frames without compression fail
+#[cfg(feature = "zstd")]
+pub fn decode_compressed<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    r: &mut R,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Value> {
+    let (flags, payload) = read_frame(r)?;
+    if flags & FLAG_COMPRESSED == 0 {
+        bail!("frame is not compressed");
+    }
+    decode_payload(&payload, fd, vd)
+}
+
+fn write_frame<W: Write>(flags: u8, payload: &[u8], w: &mut W) -> anyhow::Result<usize> {
+    let len = match u32::try_from(payload.len()) {
+        Ok(len) => len,
+        Err(_) => bail!("payload of {} bytes is too long to frame", payload.len()),
+    };
+    let mut header = [0u8; HEADER_SIZE];
+    header[..4].copy_from_slice(&MAGIC);
+    header[4] = VERSION;
+    header[5] = flags;
+    header[6..].copy_from_slice(&len.to_le_bytes());
+    w.write_all(&header).context("write frame header")?;
+    w.write_all(payload).context("write frame payload")?;
+    if flags & FLAG_CHECKSUM == 0 {
+        return Ok(HEADER_SIZE + payload.len());
+    }
+    w.write_all(&crc32c::crc32c(payload).to_le_bytes())
+        .context("write frame checksum")?;
+    Ok(HEADER_SIZE + payload.len() + 4)
+}
+
+/// reads a value written by `write_framed`. Fails on other magic bytes,
+/// unknown versions and flags, payloads shorter than the header declares
+/// and, in frames with a checksum, payloads that don't match it.
+/// Compressed frames are read with the zstd feature only.
+/// Frames with embedded dictionaries are read with them instead of the given ones
+pub fn read_framed<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    r: &mut R,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Value> {
+    let (flags, payload) = read_frame(r)?;
+    if flags & FLAG_DICTIONARY != 0 {
+        return decode_embedded(&payload);
+    }
+    decode_payload(&payload, fd, vd)
+}
+
+/// reads a value written by `encode_self_describing`, with no dictionaries at hand
+pub fn decode_self_describing<R: Read>(r: &mut R) -> anyhow::Result<Value> {
+    let (flags, payload) = read_frame(r)?;
+    if flags & FLAG_DICTIONARY == 0 {
+        bail!("frame has no embedded dictionaries");
+    }
+    decode_embedded(&payload)
+}
+
+// flags and payload of the frame, decompressed
+pub(crate) fn read_frame<R: Read>(r: &mut R) -> anyhow::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; HEADER_SIZE];
+    r.read_exact(&mut header)
+        .context("frame header is truncated")?;
+    if header[..4] != MAGIC {
+        bail!(
+            "not a framed blob: magic {} instead of {}",
+            hex::encode(&header[..4]),
+            hex::encode(MAGIC)
+        );
+    }
+    if header[4] != VERSION {
+        bail!("unsupported frame version {}", header[4]);
+    }
+    let flags = header[5];
+    if flags & !(FLAG_COMPRESSED | FLAG_DICTIONARY | FLAG_CHECKSUM) != 0 {
+        bail!("unsupported frame flags {:#04x}", flags);
+    }
+    let mut len = [0u8; 4];
+    len.copy_from_slice(&header[6..]);
+    let len = u32::from_le_bytes(len) as u64;
+    let mut payload = vec![];
+    r.take(len)
+        .read_to_end(&mut payload)
+        .context("read frame payload")?;
+    if (payload.len() as u64) < len {
+        bail!(
+            "frame payload is truncated: {} of {} bytes",
+            payload.len(),
+            len
+        );
+    }
+    if flags & FLAG_CHECKSUM != 0 {
+        let mut stored = [0u8; 4];
+        r.read_exact(&mut stored)
+            .context("frame checksum is truncated")?;
+        let stored = u32::from_le_bytes(stored);
+        let computed = crc32c::crc32c(&payload);
+        if stored != computed {
+            return Err(DecodeError::ChecksumMismatch {
+                offset: HEADER_SIZE as u64 + len,
+                stored,
+                computed,
+            }
+            .into());
+        }
+    }
+    if flags & FLAG_COMPRESSED != 0 {
+        return Ok((flags, decompress(&payload)?));
+    }
+    Ok((flags, payload))
+}
+
+#[cfg(feature = "zstd")]
+fn decompress(payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+    zstd::stream::decode_all(payload).context("decompress frame payload")
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress(_: &[u8]) -> anyhow::Result<Vec<u8>> {
+    bail!("compressed frames are read with the zstd feature only")
+}
+
+fn decode_payload<D1: DictionaryRead, D2: DictionaryRead>(
+    mut rest: &[u8],
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Value> {
+    let value = Decoder::new(fd, vd).decode(&mut rest)?;
+    if !rest.is_empty() {
+        bail!("{} bytes left in the frame after the value", rest.len());
+    }
+    Ok(value)
+}
+
+// dictionaries of the entries at the start of the payload, then the value
+fn decode_embedded(mut rest: &[u8]) -> anyhow::Result<Value> {
+    let mut fd = MapDictionary::new();
+    for (id, entry) in read_entries(&mut rest).context("field dictionary section")? {
+        match std::str::from_utf8(entry) {
+            Ok(s) => fd.insert_as(s, id),
+            Err(_) => bail!("field dictionary entry {} is not UTF-8", id),
+        }
+    }
+    let mut vd = BytesDictionary::new();
+    for (id, entry) in read_entries(&mut rest).context("value dictionary section")? {
+        vd.insert_as(entry, id);
+    }
+    decode_payload(rest, &fd, &vd)
+}
+
+// count, then ids and lengths of the entries, all in varints, and their bytes
+pub(crate) fn read_entries<'a>(rest: &mut &'a [u8]) -> anyhow::Result<Vec<(u32, &'a [u8])>> {
+    let (count, _) = next_varint(rest)?;
+    let mut out = vec![];
+    for _ in 0..count {
+        let (id, _) = next_varint(rest)?;
+        let id = match u32::try_from(id) {
+            Ok(id) => id,
+            Err(_) => bail!("dictionary id {} is over u32", id),
+        };
+        let (len, _) = next_varint(rest)?;
+        if len > rest.len() as u64 {
+            bail!("dictionary entry {} is truncated", id);
+        }
+        let (entry, tail) = rest.split_at(len as usize);
+        out.push((id, entry));
+        *rest = tail;
+    }
+    Ok(out)
+}
+
+// dictionary that remembers the ids it was asked for
+struct Recording<'a, D> {
+    inner: &'a D,
+    used: RefCell<BTreeSet<u32>>,
+}
+
+impl<'a, D: DictionaryRead> Recording<'a, D> {
+    fn new(inner: &'a D) -> Self {
+        Self {
+            inner,
+            used: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
+        let used = self.used.borrow();
+        write_varint(used.len() as u64, w)?;
+        for &id in used.iter() {
+            let entry = self.inner.get(id).unwrap_or_default();
+            write_varint(id as u64, w)?;
+            write_varint(entry.len() as u64, w)?;
+            w.write_all(entry).context("write dictionary entry")?;
+        }
+        Ok(())
+    }
+}
+
+impl<D: DictionaryRead> DictionaryRead for Recording<'_, D> {
+    fn get(&self, item_id: u32) -> Option<&[u8]> {
+        let entry = self.inner.get(item_id)?;
+        self.used.borrow_mut().insert(item_id);
+        Some(entry)
+    }
+    fn find_str(&self, value: &str) -> Option<u32> {
+        self.inner.find_str(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::NoDictionary;
+    use serde_json::json;
+
+    #[test]
+    fn it_decodes_self_describing_without_dictionaries() {
+        let addr = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
+        let fd = MapDictionary::from_static(&["blockNumber", "address", "unused_field"]);
+        let vd = BytesDictionary::from_hex_strings(&[addr]).unwrap();
+        let v = json!({"blockNumber": "0x10", "address": addr, "data": [addr, addr]});
+
+        let mut buf = vec![];
+        let n = encode_self_describing(&v, &mut buf, &fd, &vd).unwrap();
+        assert_eq!(n, buf.len());
+        assert_eq!(buf[5], FLAG_DICTIONARY);
+        // only the entries the value refers to are embedded
+        let at = |s: &[u8]| buf.windows(s.len()).any(|w| w == s);
+        assert!(at(b"blockNumber") && at(b"address") && !at(b"unused_field"));
+        assert!(at(&hex::decode(&addr[2..]).unwrap()));
+
+        assert_eq!(decode_self_describing(&mut buf.as_slice()).unwrap(), v);
+        let nod = NoDictionary {};
+        assert_eq!(read_framed(&mut buf.as_slice(), &nod, &nod).unwrap(), v);
+        // the payload without the section needs the dictionaries
+        let mut plain = vec![];
+        write_framed(&v, &mut plain, &fd, &vd).unwrap();
+        assert!(read_framed(&mut plain.as_slice(), &nod, &nod).is_err());
+        assert_eq!(read_framed(&mut plain.as_slice(), &fd, &vd).unwrap(), v);
+    }
+
+    #[test]
+    fn it_reads_what_it_writes() {
+        let d = NoDictionary {};
+        for v in [json!(false), json!({"a": [1, "two", null]})] {
+            let mut buf = vec![];
+            let n = write_framed(&v, &mut buf, &d, &d).unwrap();
+            assert_eq!(n, buf.len());
+            assert_eq!(buf[..6], *b"JDP1\x01\x00");
+            assert_eq!(read_framed(&mut buf.as_slice(), &d, &d).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn it_rejects_bad_frames() {
+        let d = NoDictionary {};
+        let mut buf = vec![];
+        write_framed(&json!("hello"), &mut buf, &d, &d).unwrap();
+        let read = |b: &[u8]| read_framed(&mut &b[..], &d, &d).unwrap_err().to_string();
+
+        assert_eq!(read(&buf[..6]), "frame header is truncated");
+        assert_eq!(
+            read(&buf[..buf.len() - 1]),
+            "frame payload is truncated: 6 of 7 bytes"
+        );
+        // unframed blobs, e.g. of `false`
+        assert_eq!(
+            read(&[0u8; 12]),
+            "not a framed blob: magic 00000000 instead of 4a445031"
+        );
+
+        let mut other = buf.clone();
+        other[4] = 2;
+        assert_eq!(read(&other), "unsupported frame version 2");
+        let mut other = buf.clone();
+        // the value is read as a dictionary section
+        other[5] = FLAG_DICTIONARY;
+        let err = read_framed(&mut other.as_slice(), &d, &d).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "field dictionary section: dictionary entry 5 is truncated"
+        );
+        let err = decode_self_describing(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err.to_string(), "frame has no embedded dictionaries");
+        let mut other = buf.clone();
+        other[5] = 0x80;
+        assert_eq!(read(&other), "unsupported frame flags 0x80");
+        let mut other = buf.clone();
+        other[6] += 1;
+        other.push(0);
+        assert_eq!(read(&other), "1 bytes left in the frame after the value");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn it_reads_compressed_and_plain_frames() {
+        let d = NoDictionary {};
+        let input = format!("0x{}", "60806040".repeat(500));
+        let v = json!(vec![json!({"input": input, "gas": 21000}); 20]);
+
+        let mut plain = vec![];
+        write_framed(&v, &mut plain, &d, &d).unwrap();
+        let mut compressed = vec![];
+        let n = encode_compressed(&v, &mut compressed, &d, &d, 3).unwrap();
+        assert_eq!(n, compressed.len());
+        assert_eq!(compressed[5], FLAG_COMPRESSED);
+        assert!(compressed.len() * 50 < plain.len(), "{}", compressed.len());
+
+        for buf in [&plain, &compressed] {
+            assert_eq!(read_framed(&mut buf.as_slice(), &d, &d).unwrap(), v);
+        }
+        let read = decode_compressed(&mut compressed.as_slice(), &d, &d).unwrap();
+        assert_eq!(read, v);
+        let err = decode_compressed(&mut plain.as_slice(), &d, &d).unwrap_err();
+        assert_eq!(err.to_string(), "frame is not compressed");
+    }
+
+    #[cfg(not(feature = "zstd"))]
+    #[test]
+    fn it_rejects_compressed_frames_without_zstd() {
+        let d = NoDictionary {};
+        let mut buf = vec![];
+        write_framed(&json!("hello"), &mut buf, &d, &d).unwrap();
+        buf[5] = FLAG_COMPRESSED;
+        let err = read_framed(&mut buf.as_slice(), &d, &d).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "compressed frames are read with the zstd feature only"
+        );
+    }
+}
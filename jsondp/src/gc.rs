@@ -0,0 +1,159 @@
+use crate::decode::{next_item, next_key, Item, Key};
+use crate::dictionary::MapDictionary;
+use std::collections::BTreeMap;
+use std::io::Read;
+
+/// Number of references to every dictionary id in the scanned blobs
+#[derive(Debug, Clone, Default)]
+pub struct DictionaryUsage {
+    /// references to the field dictionary (object keys)
+    pub fields: BTreeMap<u32, u64>,
+    /// references to the value dictionary (strings)
+    pub values: BTreeMap<u32, u64>,
+}
+
+impl DictionaryUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// walks one encoded value without resolving dictionaries
+    /// and counts the references it contains
+    pub fn scan<R: Read>(&mut self, input: &mut R) -> anyhow::Result<()> {
+        match next_item(input)? {
+            Item::ValueRef(dict_id) | Item::BytesRef(dict_id) => {
+                *self.values.entry(dict_id).or_default() += 1;
+            }
+            Item::Array(size) => {
+                for _ in 0..size {
+                    self.scan(input)?;
+                }
+            }
+            Item::Object(size) => {
+                for _ in 0..size {
+                    if let Key::Field(dict_id) = next_key(input)? {
+                        *self.fields.entry(dict_id).or_default() += 1;
+                    }
+                    self.scan(input)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// adds counts from another scan, e.g. of another range of blobs
+    pub fn merge(&mut self, other: &DictionaryUsage) {
+        for (id, n) in &other.fields {
+            *self.fields.entry(*id).or_default() += n;
+        }
+        for (id, n) in &other.values {
+            *self.values.entry(*id).or_default() += n;
+        }
+    }
+}
+
+/// Entries of the dictionary that are candidates for removal
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GcReport {
+    /// ids that were never referenced
+    pub unreferenced: Vec<u32>,
+    /// ids that were referenced less than the threshold, with their counts
+    pub rare: Vec<(u32, u64)>,
+}
+
+impl GcReport {
+    /// compares dictionary with the usage counts of the same dictionary
+    pub fn new(dict: &MapDictionary, counts: &BTreeMap<u32, u64>, rare_below: u64) -> Self {
+        let mut out = Self::default();
+        for id in dict.ids() {
+            match counts.get(&id) {
+                None => out.unreferenced.push(id),
+                Some(n) if *n < rare_below => out.rare.push((id, *n)),
+                _ => {}
+            }
+        }
+        out
+    }
+
+    /// new generation of the dictionary without unreferenced entries.
+    /// Remaining entries keep their ids, so stored blobs don't need to be reencoded
+    pub fn apply(&self, dict: &MapDictionary) -> MapDictionary {
+        let mut out = dict.clone();
+        for id in &self.unreferenced {
+            out.remove(*id);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::DictionaryRead;
+    use crate::encode;
+    use serde_json::{json, Value};
+    use std::io::BufReader;
+
+    fn enc(input: &Value, fd: &MapDictionary, vd: &MapDictionary) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode(input, &mut buf, fd, vd).unwrap();
+        buf
+    }
+
+    #[test]
+    fn it_counts_references() {
+        let fd = MapDictionary::from_strings(vec!["from", "to"]);
+        let vd = MapDictionary::from_strings(vec!["alice", "bob"]);
+        let row = enc(
+            &json!({"from": "alice", "to": "bob", "memo": ["alice", 1]}),
+            &fd,
+            &vd,
+        );
+        let mut usage = DictionaryUsage::new();
+        usage.scan(&mut BufReader::new(row.as_slice())).unwrap();
+        assert_eq!(usage.fields.get(&1), Some(&1));
+        assert_eq!(usage.fields.get(&2), Some(&1));
+        assert_eq!(usage.values.get(&1), Some(&2));
+        assert_eq!(usage.values.get(&2), Some(&1));
+    }
+
+    #[test]
+    fn it_removes_exactly_the_junk() {
+        let fd = MapDictionary::from_strings(vec!["from", "to", "value"]);
+        let mut vd = MapDictionary::from_strings(vec!["alice", "bob", "carol"]);
+        let rows: Vec<Vec<u8>> = [
+            json!({"from": "alice", "to": "bob", "value": 1}),
+            json!({"from": "bob", "to": "carol", "value": 2}),
+        ]
+        .iter()
+        .map(|v| enc(v, &fd, &vd))
+        .collect();
+
+        // junk learned later, e.g. during an airdrop
+        vd.insert("airdrop1");
+        vd.insert("airdrop2");
+        let junk = [
+            vd.find_str("airdrop1").unwrap(),
+            vd.find_str("airdrop2").unwrap(),
+        ];
+
+        let mut usage = DictionaryUsage::new();
+        for row in &rows {
+            let mut part = DictionaryUsage::new();
+            part.scan(&mut BufReader::new(row.as_slice())).unwrap();
+            usage.merge(&part);
+        }
+        let report = GcReport::new(&vd, &usage.values, 2);
+        assert_eq!(report.unreferenced, junk);
+        assert_eq!(report.rare, vec![(1, 1), (3, 1)]);
+
+        let next = report.apply(&vd);
+        assert_eq!(next.ids().collect::<Vec<u32>>(), vec![1, 2, 3]);
+        assert_eq!(next.find_str("carol"), Some(3));
+        // blobs still decode with the new generation
+        for row in &rows {
+            crate::decode(&mut BufReader::new(row.as_slice()), &fd, &next).unwrap();
+        }
+    }
+}
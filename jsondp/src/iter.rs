@@ -0,0 +1,134 @@
+use crate::dictionary::DictionaryRead;
+use crate::{decode_with_stats, DecodeError, DecodeOptions};
+use serde_json::Value;
+use std::io::{ErrorKind, Read};
+
+/// Iterator over values written back-to-back into one stream.
+/// Ends when the stream ends between values; a value cut in the middle
+/// yields `DecodeError::Truncated`, and nothing is read after an error
+pub struct DecodeIter<'d, R, D1, D2> {
+    input: R,
+    fd: &'d D1,
+    vd: &'d D2,
+    opts: DecodeOptions,
+    // bytes consumed by the previous values
+    offset: u64,
+    done: bool,
+}
+
+/// iterates over concatenated values of the reader
+pub fn iter_decode<'d, R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: R,
+    fd: &'d D1,
+    vd: &'d D2,
+) -> DecodeIter<'d, R, D1, D2> {
+    DecodeIter {
+        input,
+        fd,
+        vd,
+        opts: DecodeOptions::default(),
+        offset: 0,
+        done: false,
+    }
+}
+
+impl<R: Read, D1: DictionaryRead, D2: DictionaryRead> DecodeIter<'_, R, D1, D2> {
+    /// options for every value, budgets apply to each value separately
+    pub fn with_options(mut self, opts: DecodeOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    /// bytes consumed by the values yielded so far
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // first byte of the next value, None at the end of the stream
+    fn first(&mut self) -> std::io::Result<Option<u8>> {
+        let mut first = [0u8; 1];
+        loop {
+            match self.input.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(first[0])),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl<R: Read, D1: DictionaryRead, D2: DictionaryRead> Iterator for DecodeIter<'_, R, D1, D2> {
+    type Item = anyhow::Result<Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let nb = match self.first() {
+            Ok(Some(nb)) => nb,
+            Ok(None) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e.into()));
+            }
+        };
+        let first = [nb];
+        let mut input = first.as_slice().chain(&mut self.input);
+        let (value, stats) = decode_with_stats(&mut input, self.fd, self.vd, &self.opts);
+        let offset = self.offset;
+        self.offset += stats.bytes;
+        Some(value.map_err(|e| {
+            // the position of the next value is unknown after a failure
+            self.done = true;
+            match e.downcast_ref::<std::io::Error>() {
+                Some(io) if io.kind() == ErrorKind::UnexpectedEof => {
+                    DecodeError::Truncated { offset }.into()
+                }
+                _ => e,
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::NoDictionary;
+    use crate::encode;
+    use serde_json::json;
+
+    #[test]
+    fn it_yields_concatenated_values() {
+        let d = NoDictionary {};
+        let values = [json!({"a": 1}), json!([true, null]), json!("x")];
+        let mut blob = vec![];
+        for value in &values {
+            encode(value, &mut blob, &d, &d).unwrap();
+        }
+        let mut iter = iter_decode(blob.as_slice(), &d, &d);
+        for value in &values {
+            assert_eq!(&iter.next().unwrap().unwrap(), value);
+        }
+        assert!(iter.next().is_none());
+        assert_eq!(iter.offset(), blob.len() as u64);
+        assert!(iter_decode(&[][..], &d, &d).next().is_none());
+
+        // the last value loses its last byte
+        let first = blob.len() - 3;
+        let mut iter = iter_decode(&blob[..blob.len() - 1], &d, &d);
+        assert!(iter.next().unwrap().is_ok());
+        assert!(iter.next().unwrap().is_ok());
+        let err = iter.next().unwrap().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::Truncated {
+                offset: first as u64
+            })
+        );
+        assert!(iter.next().is_none());
+    }
+}
@@ -0,0 +1,182 @@
+use crate::dictionary::{fingerprint_of, DictionaryRead, DictionaryWrite};
+use anyhow::{bail, Context};
+use kv::KV;
+use std::collections::BTreeMap;
+
+// key of the last id written, entries after it are not read
+const LAST_ID: u32 = 0;
+
+/// Dictionary kept in a kv table, so it survives restarts and is shared with
+/// other processes: ids are keys, values are the bytes of the entries.
+/// `KV` is async and `DictionaryRead` is not, so entries are read into memory
+/// by `load_all` and `refresh`, and entries added by `insert` stay in memory
+/// until `flush`. Entries are added by one process at a time
+pub struct KvDictionary<K> {
+    kv: K,
+    v: BTreeMap<u32, Vec<u8>>,
+    k: BTreeMap<Vec<u8>, u32>,
+    // last id in the table
+    stored: u32,
+}
+
+impl<K: KV> KvDictionary<K> {
+    /// reads all entries of the table
+    pub async fn load_all(kv: K) -> anyhow::Result<Self> {
+        let mut out = Self {
+            kv,
+            v: BTreeMap::new(),
+            k: BTreeMap::new(),
+            stored: 0,
+        };
+        out.refresh().await?;
+        Ok(out)
+    }
+
+    /// reads entries that were added to the table since it was read,
+    /// e.g. by the encoder in another process. Fails when there are entries
+    /// not flushed yet, as their ids may be taken
+    pub async fn refresh(&mut self) -> anyhow::Result<usize> {
+        if self.pending() > 0 {
+            bail!("{} entries are not flushed", self.pending());
+        }
+        let last = match self.kv.get(LAST_ID).await? {
+            Some(b) => u32::from_le_bytes(b.as_slice().try_into().context("last id")?),
+            None => 0,
+        };
+        let mut read = 0;
+        for id in self.stored + 1..=last {
+            // removed entries leave gaps
+            if let Some(value) = self.kv.get(id).await? {
+                self.k.insert(value.clone(), id);
+                self.v.insert(id, value);
+                read += 1;
+            }
+        }
+        self.stored = self.stored.max(last);
+        Ok(read)
+    }
+
+    /// entries inserted and not written to the table yet
+    pub fn pending(&self) -> usize {
+        self.v.range(self.stored + 1..).count()
+    }
+
+    /// writes the entries inserted since the last flush, then the last id,
+    /// so the table never has the last id of an entry that is not there
+    pub async fn flush(&mut self) -> anyhow::Result<usize> {
+        let last = match self.v.keys().next_back() {
+            Some(last) if *last > self.stored => *last,
+            _ => return Ok(0),
+        };
+        let mut written = 0;
+        for (id, value) in self.v.range(self.stored + 1..) {
+            self.kv.set(*id, value.clone()).await?;
+            written += 1;
+        }
+        self.kv.set(LAST_ID, last.to_le_bytes().to_vec()).await?;
+        self.stored = last;
+        Ok(written)
+    }
+}
+
+impl<K> KvDictionary<K> {
+    /// identifies the generation of the dictionary
+    pub fn fingerprint(&self) -> [u8; 16] {
+        fingerprint_of(self.v.iter().map(|(id, v)| (*id, v.as_slice())))
+    }
+
+    /// ids of all entries in ascending order
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.v.keys().copied()
+    }
+}
+
+impl<K> DictionaryRead for KvDictionary<K> {
+    fn get(&self, index: u32) -> Option<&[u8]> {
+        self.v.get(&index).map(|x| x.as_slice())
+    }
+    fn find_str(&self, value: &str) -> Option<u32> {
+        self.k.get(value.as_bytes()).copied()
+    }
+    fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+        self.k.get(value).copied()
+    }
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        Some(KvDictionary::fingerprint(self))
+    }
+}
+
+/// ids are given after the last one in memory or in the table
+impl<K> DictionaryWrite for KvDictionary<K> {
+    fn insert(&mut self, bytes: &[u8]) -> Option<u32> {
+        if let Some(index) = self.k.get(bytes) {
+            return Some(*index);
+        }
+        let last = self.v.keys().next_back().copied().unwrap_or(0);
+        let index = last.max(self.stored) + 1;
+        self.v.insert(index, bytes.to_vec());
+        self.k.insert(bytes.to_vec(), index);
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::MapDictionary;
+    use crate::{decode_slice, encode, encode_learning, EncodeOptions};
+    use kv::MemoryKV;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn it_keeps_learned_entries_across_restarts() {
+        let table = MemoryKV::new().bucket("fields");
+        let mut fd = KvDictionary::load_all(table.bucket("fields"))
+            .await
+            .unwrap();
+        assert_eq!(fd.ids().count(), 0);
+
+        let v = json!({"blockNumber": "0x10", "logIndex": 3, "removed": false});
+        let mut vd = MapDictionary::new();
+        let opts = EncodeOptions::default();
+        let mut blob = vec![];
+        let learned = encode_learning(&v, &mut blob, &mut fd, &mut vd, &opts, false).unwrap();
+        assert_eq!(learned.fields.len(), 3);
+        assert_eq!(fd.pending(), 3);
+        // nothing is in the table before flush
+        assert!(table.is_empty());
+        assert_eq!(fd.flush().await.unwrap(), 3);
+        assert_eq!(fd.flush().await.unwrap(), 0);
+        assert_eq!(table.len(), 4);
+
+        // another process reads the table and decodes with it
+        let reader = KvDictionary::load_all(table.bucket("fields"))
+            .await
+            .unwrap();
+        assert_eq!(reader.fingerprint(), fd.fingerprint());
+        let mut blob = vec![];
+        encode(&v, &mut blob, &fd, &vd).unwrap();
+        assert_eq!(decode_slice(&blob, &reader, &vd).unwrap(), v);
+    }
+
+    #[tokio::test]
+    async fn it_refreshes_entries_added_elsewhere() {
+        let table = MemoryKV::new();
+        let mut writer = KvDictionary::load_all(table.bucket("")).await.unwrap();
+        let mut reader = KvDictionary::load_all(table.bucket("")).await.unwrap();
+        assert_eq!(writer.insert(b"alpha"), Some(1));
+        assert_eq!(writer.insert(b"beta"), Some(2));
+        assert_eq!(writer.insert(b"alpha"), Some(1));
+        writer.flush().await.unwrap();
+
+        assert_eq!(reader.find_str("beta"), None);
+        assert_eq!(reader.refresh().await.unwrap(), 2);
+        assert_eq!(reader.find_str("beta"), Some(2));
+        assert_eq!(reader.get(1), Some(&b"alpha"[..]));
+
+        // ids of the table are not given again, and unflushed entries block refresh
+        assert_eq!(reader.insert(b"gamma"), Some(3));
+        let err = reader.refresh().await.unwrap_err();
+        assert_eq!(err.to_string(), "1 entries are not flushed");
+    }
+}
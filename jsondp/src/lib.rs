@@ -1,53 +1,710 @@
-use anyhow::{bail, Context};
-use serde_json::{Map, Number, Value};
-use std::io::{BufWriter, Read, Write};
+use anyhow::bail;
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::io::{Read, Write};
 
+#[cfg(feature = "eth")]
 pub mod blockchain;
+pub mod borrowed;
+pub mod bounded;
+pub mod de;
 pub mod decode;
 pub mod dictionary;
 pub mod encode;
+pub mod error;
+#[cfg(feature = "eth")]
+pub mod eth;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frame;
+pub mod gc;
+pub mod iter;
+#[cfg(feature = "kv")]
+pub mod kvdict;
+pub mod salvage;
+pub mod ser;
+pub mod shared;
+pub mod stable;
+mod timestamp;
+pub mod verify;
+pub mod visit;
 
+pub use borrowed::BorrowedValue;
+pub use de::{from_reader, from_slice};
+pub use decode::DecodeOptions;
 use decode::*;
 use dictionary::*;
+pub use encode::{
+    DictionaryStats, EncodeOptions, EncodeStats, Encoder, HexDetection, ObjectEncoder,
+};
+pub use error::{DecodeError, MergeError, SerdeError};
+#[cfg(feature = "zstd")]
+pub use frame::{decode_compressed, encode_compressed};
+pub use frame::{
+    decode_self_describing, encode_self_describing, read_framed, write_framed, write_framed_with,
+};
+pub use iter::{iter_decode, DecodeIter};
+#[cfg(feature = "kv")]
+pub use kvdict::KvDictionary;
+pub use salvage::{decode_salvage, SalvageError, Salvaged};
+pub use ser::{to_vec, to_writer};
+pub use verify::{verify, VerifyReport};
+pub use visit::{visit, Control, ScalarRef, Visitor};
 
-pub fn decode_object<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
-    input: &mut R,
-    size: usize,
-    fd: &D1,
-    vd: &D2,
-) -> anyhow::Result<Map<String, Value>> {
-    let mut m = Map::new();
-    for _ in 0..size {
+// trace diagnostics of decoding, compiled out without the tracing feature
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    };
+}
+
+/// Diagnostics of one decode
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DecodeStats {
+    /// bytes read from the input, including the fingerprint header
+    pub bytes: u64,
+    /// values decoded, containers included
+    pub values: u64,
+    /// field and value dictionary lookups that found the entry
+    pub dictionary_hits: u64,
+    /// lookups that did not, decoding fails on the first one
+    pub dictionary_misses: u64,
+}
+
+// reader that counts consumed bytes and ends at the byte budget
+pub(crate) struct Counting<'a, R> {
+    pub(crate) inner: &'a mut R,
+    pub(crate) bytes: u64,
+    pub(crate) limit: Option<u64>,
+}
+
+impl<'a, R> Counting<'a, R> {
+    pub(crate) fn new(inner: &'a mut R, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            limit,
+        }
+    }
+
+    // bytes left of the budget
+    pub(crate) fn remaining(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_sub(self.bytes))
+    }
+}
+
+impl<R: Read> Read for Counting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = match self.remaining() {
+            Some(left) => buf.len().min(left.try_into().unwrap_or(usize::MAX)),
+            None => buf.len(),
+        };
+        let n = self.inner.read(&mut buf[..len])?;
+        self.bytes += n as u64;
+        Ok(n)
+    }
+}
+
+// state of one decode
+struct Decoding<'a, D1, D2> {
+    fd: &'a D1,
+    vd: &'a D2,
+    opts: &'a DecodeOptions,
+    // JSON pointer of the current value, for errors
+    path: String,
+    // containers around the current value
+    depth: usize,
+    // children declared by open containers and not decoded yet
+    pending: u64,
+    stats: DecodeStats,
+    // strings decoded so far, when the value has back-references to them
+    written: Option<Vec<Value>>,
+    // offset of the key or value read last, for errors
+    at: u64,
+}
+
+impl<'a, D1: DictionaryRead, D2: DictionaryRead> Decoding<'a, D1, D2> {
+    fn new(fd: &'a D1, vd: &'a D2, opts: &'a DecodeOptions) -> Self {
+        Self {
+            fd,
+            vd,
+            opts,
+            path: String::new(),
+            depth: 0,
+            pending: 0,
+            stats: DecodeStats::default(),
+            written: None,
+            at: 0,
+        }
+    }
+
+    // the error with the offset and path of the key or value that failed,
+    // which are left as they were when it was read
+    fn locate(&self, e: anyhow::Error) -> anyhow::Error {
+        e.context(format!("at offset {}, path '{}'", self.at, self.path))
+    }
+
+    // nesting is limited, so crafted input can't overflow the stack
+    fn enter(&mut self, offset: u64) -> anyhow::Result<()> {
+        if self.depth >= self.opts.max_depth {
+            return Err(DecodeError::MaxDepthExceeded { offset }.into());
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    fn lookup<'d, D: DictionaryRead>(&mut self, d: &'d D, dict_id: u32) -> Option<&'d [u8]> {
+        let found = d.get(dict_id);
+        match found {
+            Some(_) => self.stats.dictionary_hits += 1,
+            None => self.stats.dictionary_misses += 1,
+        }
+        found
+    }
+
+    // reads the key of the next field and pushes it to the path
+    fn field<R: Read>(&mut self, input: &mut Counting<'_, R>) -> anyhow::Result<String> {
+        self.at = input.bytes;
         let nb = next_u8(input)?;
-        let use_fd = (nb & 0xc0) > 0;
-
-        let fprefix = nb & 0x1F;
-        let field = if use_fd {
-            let dict_id = if (nb & 0xc0) == 0xc0 {
-                next_u32(input)?
-            } else if (nb & 0xc0) == 0x80 {
-                next_u16(input)? as u32
-            } else {
-                next_u8(input)? as u32
-            };
-            match fd.get(dict_id) {
+        let key = key_of(nb, input, input.remaining())?;
+        let field = match &key {
+            Key::Field(dict_id) => match self.lookup(self.fd, *dict_id) {
                 Some(found) => std::str::from_utf8(found)?.to_string(),
                 None => bail!(format!("field value {} not found in dictionary", dict_id)),
+            },
+            Key::Str(s) => s.clone(),
+            Key::Number(n) => n.to_string(),
+        };
+        trace!(field = field.as_str(), "decoded object field");
+        push_path(&mut self.path, &field);
+        if self.opts.strict_minimal {
+            check_minimal_key(nb, &key, self.fd, &self.path)?;
+        }
+        Ok(field)
+    }
+
+    fn object<R: Read>(
+        &mut self,
+        input: &mut Counting<'_, R>,
+        size: usize,
+    ) -> anyhow::Result<Map<String, Value>> {
+        let mut m = Map::new();
+        for _ in 0..size {
+            let len = self.path.len();
+            let field = self.field(input)?;
+            let value = self.value(input)?;
+            self.path.truncate(len);
+            m.insert(field, value);
+        }
+        Ok(m)
+    }
+
+    fn value<R: Read>(&mut self, input: &mut Counting<'_, R>) -> anyhow::Result<Value> {
+        let nb = self.prefix(input)?;
+        self.item(nb, input)
+    }
+
+    // type prefix of the next value
+    fn prefix<R: Read>(&mut self, input: &mut Counting<'_, R>) -> anyhow::Result<u8> {
+        self.at = input.bytes;
+        next_u8(input)
+    }
+
+    // reads the item after the prefix, checking it against the options
+    fn read_item<R: Read>(&mut self, nb: u8, input: &mut Counting<'_, R>) -> anyhow::Result<Item> {
+        trace!(nb, path = self.path.as_str(), "decoding item");
+        self.at = input.bytes - 1;
+        let item = item_of(nb, input, input.remaining())?;
+        if self.opts.strict_minimal {
+            check_minimal(nb, &item, self.vd, &self.path)?;
+        }
+        let declared = match item {
+            Item::Array(size) | Item::Object(size) => size as u64,
+            Item::Packed(ref nums) => nums.len() as u64,
+            _ => 0,
+        };
+        self.count(declared)?;
+        // elements of packed arrays are read with them
+        if let Item::Packed(nums) = &item {
+            self.stats.values += nums.len() as u64;
+            self.pending = self.pending.saturating_sub(nums.len() as u64);
+        }
+        Ok(item)
+    }
+
+    // counts the value read and the children it declares against the item budget
+    fn count(&mut self, declared: u64) -> anyhow::Result<()> {
+        self.stats.values += 1;
+        self.pending = self.pending.saturating_sub(1);
+        if let Some(max) = self.opts.max_items {
+            let remaining = max.saturating_sub(self.stats.values + self.pending);
+            if self.stats.values > max || declared > remaining {
+                return Err(DecodeError::BudgetExceeded {
+                    declared: declared.max(1),
+                    remaining,
+                    unit: "items",
+                }
+                .into());
+            }
+            self.pending += declared;
+        }
+        Ok(())
+    }
+
+    // bytes value formatted as the options ask, 20 bytes are an address
+    fn bytes(&self, b: &[u8]) -> Value {
+        if self.opts.checksum_addresses && b.len() == 20 {
+            return Value::String(checksum_address(b));
+        }
+        self.hex(b)
+    }
+
+    fn hex(&self, b: &[u8]) -> Value {
+        let digits = match self.opts.hex_uppercase {
+            true => hex::encode_upper(b),
+            false => hex::encode(b),
+        };
+        Value::String(format!("0x{}", digits))
+    }
+
+    // B8 to B64 values, with the option to write them as numbers
+    fn is_quantity(&self, nb: u8) -> bool {
+        self.opts.hex_quantities_as_numbers && fixed_width(nb).is_some_and(|w| w <= 8)
+    }
+
+    // ethers writes the hex of BigNumber lowercase, in the fewest whole bytes
+    fn big_number(&self, b: &[u8]) -> Value {
+        if !self.opts.restore_bignumber {
+            return self.hex(b);
+        }
+        let start = b.iter().position(|x| *x != 0).unwrap_or(b.len());
+        let hex = match &b[start..] {
+            [] => "0x00".to_string(),
+            rest => format!("0x{}", hex::encode(rest)),
+        };
+        serde_json::json!({"type": "BigNumber", "hex": hex})
+    }
+
+    // value of an item that is not a container, strings are kept for back-references
+    fn scalar(&mut self, nb: u8, item: Item) -> anyhow::Result<Value> {
+        let string = matches!(
+            item,
+            Item::Str(_) | Item::Bytes(_) | Item::ValueRef(_) | Item::BytesRef(_)
+        );
+        let value = match item {
+            Item::Bytes(b) if self.is_quantity(nb) => Value::from(quantity(&b)),
+            item => self.scalar_value(item)?,
+        };
+        if let (true, Some(written)) = (string, self.written.as_mut()) {
+            written.push(value.clone());
+        }
+        Ok(value)
+    }
+
+    fn scalar_value(&mut self, item: Item) -> anyhow::Result<Value> {
+        match item {
+            Item::Null => Ok(Value::Null),
+            Item::Bool(b) => Ok(Value::Bool(b)),
+            Item::Number(n) => Ok(Value::Number(n)),
+            Item::Bytes(b) => Ok(self.bytes(&b)),
+            Item::BigNumber(b) => Ok(self.big_number(&b)),
+            Item::NonFinite(f) if self.opts.non_finite_as_strings => {
+                Ok(Value::String(non_finite_name(f).to_string()))
+            }
+            Item::NonFinite(_) => Ok(Value::Null),
+            Item::Str(s) => Ok(Value::String(s)),
+            Item::ValueRef(dict_id) => match self.lookup(self.vd, dict_id) {
+                Some(buf) => Ok(Value::String(std::str::from_utf8(buf)?.to_string())),
+                None => bail!(format!("value {} not found in dictionary", dict_id)),
+            },
+            Item::BytesRef(dict_id) => match self.lookup(self.vd, dict_id) {
+                Some(buf) => Ok(self.bytes(buf)),
+                None => bail!(format!("value {} not found in dictionary", dict_id)),
+            },
+            Item::Packed(nums) => Ok(Value::Array(nums.into_iter().map(Value::Number).collect())),
+            Item::BackRef(index) => match self.written.as_ref().and_then(|w| w.get(index as usize))
+            {
+                Some(value) => Ok(value.clone()),
+                None => bail!("back-reference {} to no earlier string", index),
+            },
+            Item::Array(_) | Item::Object(_) => unreachable!("containers are not scalars"),
+        }
+    }
+
+    fn item<R: Read>(&mut self, nb: u8, input: &mut Counting<'_, R>) -> anyhow::Result<Value> {
+        let offset = input.bytes - 1;
+        match self.read_item(nb, input)? {
+            Item::Array(size) => {
+                self.enter(offset)?;
+                let mut vals = Vec::new();
+                for i in 0..size {
+                    let len = self.path.len();
+                    push_path(&mut self.path, &i.to_string());
+                    vals.push(self.value(input)?);
+                    self.path.truncate(len);
+                }
+                self.depth -= 1;
+                Ok(Value::Array(vals))
+            }
+            Item::Object(size) => {
+                trace!(size, "decoding object");
+                self.enter(offset)?;
+                let m = self.object(input, size)?;
+                self.depth -= 1;
+                Ok(Value::Object(m))
+            }
+            item => self.scalar(nb, item),
+        }
+    }
+
+    // value at the path of keys and indexes, siblings before it are skipped.
+    // Values with back-references are decoded in full, as skipped strings are referred to.
+    // Containers on the path and in the skipped siblings count towards the depth limit
+    fn find<R: Read>(
+        &mut self,
+        input: &mut Counting<'_, R>,
+        path: &[&str],
+        target: Target,
+    ) -> anyhow::Result<Option<Value>> {
+        let mut nb = self.header(input)?;
+        if self.written.is_some() {
+            let value = self.item(nb, input)?;
+            return Ok(value_at(value, path).and_then(|v| target.of(v)));
+        }
+        for (i, segment) in path.iter().enumerate() {
+            self.enter(input.bytes - 1)?;
+            match self.read_item(nb, input)? {
+                Item::Object(size) => {
+                    let mut found = false;
+                    for _ in 0..size {
+                        let len = self.path.len();
+                        if self.field(input)? == *segment {
+                            found = true;
+                            break;
+                        }
+                        self.path.truncate(len);
+                        self.skip(input)?;
+                    }
+                    if !found {
+                        return Ok(None);
+                    }
+                }
+                Item::Array(size) => {
+                    match segment.parse::<usize>() {
+                        Ok(index) if index < size && index.to_string() == *segment => {
+                            for _ in 0..index {
+                                self.skip(input)?;
+                            }
+                        }
+                        _ => return Ok(None),
+                    }
+                    push_path(&mut self.path, segment);
+                }
+                item => {
+                    let value = self.scalar(nb, item)?;
+                    return Ok(value_at(value, &path[i..]).and_then(|v| target.of(v)));
+                }
+            }
+            nb = self.prefix(input)?;
+        }
+        match target {
+            Target::Value => self.item(nb, input).map(Some),
+            Target::Len => match self.read_item(nb, input)? {
+                Item::Array(size) | Item::Object(size) => Ok(Some(Value::from(size))),
+                Item::Packed(nums) => Ok(Some(Value::from(nums.len()))),
+                _ => Ok(None),
+            },
+        }
+    }
+
+    // reads past the next value without building it
+    fn skip<R: Read>(&mut self, input: &mut Counting<'_, R>) -> anyhow::Result<()> {
+        let nb = self.prefix(input)?;
+        let offset = input.bytes - 1;
+        let left = input.remaining();
+        let (size, fields) = match item_of(nb, input, left)? {
+            Item::Array(size) => (size, false),
+            Item::Object(size) => (size, true),
+            _ => return Ok(()),
+        };
+        self.enter(offset)?;
+        for _ in 0..size {
+            if fields {
+                let nb = next_u8(input)?;
+                key_of(nb, input, input.remaining())?;
+            }
+            self.skip(input)?;
+        }
+        self.depth -= 1;
+        Ok(())
+    }
+
+    // same as `item`, writing JSON text instead of building the value
+    fn write_item<R: Read, W: Write>(
+        &mut self,
+        nb: u8,
+        input: &mut Counting<'_, R>,
+        w: &mut W,
+    ) -> anyhow::Result<()> {
+        let offset = input.bytes - 1;
+        match self.read_item(nb, input)? {
+            Item::Array(size) => {
+                self.enter(offset)?;
+                w.write_all(b"[")?;
+                for i in 0..size {
+                    if i > 0 {
+                        w.write_all(b",")?;
+                    }
+                    let len = self.path.len();
+                    push_path(&mut self.path, &i.to_string());
+                    let nb = self.prefix(input)?;
+                    self.write_item(nb, input, w)?;
+                    self.path.truncate(len);
+                }
+                self.depth -= 1;
+                w.write_all(b"]")?;
+            }
+            Item::Object(size) => {
+                self.enter(offset)?;
+                w.write_all(b"{")?;
+                for i in 0..size {
+                    if i > 0 {
+                        w.write_all(b",")?;
+                    }
+                    let len = self.path.len();
+                    let field = self.field(input)?;
+                    serde_json::to_writer(&mut *w, &field)?;
+                    w.write_all(b":")?;
+                    let nb = self.prefix(input)?;
+                    self.write_item(nb, input, w)?;
+                    self.path.truncate(len);
+                }
+                self.depth -= 1;
+                w.write_all(b"}")?;
             }
-        } else if fprefix == 20 {
-            // expect fprefix to be field name... but thus could be a number actually
-            let sz = next_u8(input)? as usize;
-            next_str(input, sz)?
-        } else {
-            bail!("only short strings are supported as column names so far");
+            item => serde_json::to_writer(&mut *w, &self.scalar(nb, item)?)?,
+        }
+        Ok(())
+    }
+
+    // prefix of the value after the optional fingerprint header and back-references tag
+    fn header<R: Read>(&mut self, input: &mut Counting<'_, R>) -> anyhow::Result<u8> {
+        let mut nb = next_u8(input)?;
+        if nb == FINGERPRINT_TAG {
+            verify_fingerprints(input, self.fd, self.vd)?;
+            nb = next_u8(input)?;
+        }
+        if nb == BACK_REFS_TAG {
+            self.written = Some(vec![]);
+            nb = next_u8(input)?;
+        }
+        Ok(nb)
+    }
+
+    fn document<R: Read>(&mut self, input: &mut Counting<'_, R>) -> anyhow::Result<Value> {
+        let value = self.header(input).and_then(|nb| self.item(nb, input));
+        value.map_err(|e| self.locate(e))
+    }
+}
+
+/// Decoder with the dictionaries and options for every blob it reads
+pub struct Decoder<'a, D1, D2> {
+    fd: &'a D1,
+    vd: &'a D2,
+    opts: DecodeOptions,
+}
+
+impl<'a, D1: DictionaryRead, D2: DictionaryRead> Decoder<'a, D1, D2> {
+    pub fn new(fd: &'a D1, vd: &'a D2) -> Self {
+        Self {
+            fd,
+            vd,
+            opts: DecodeOptions::default(),
+        }
+    }
+
+    pub fn with_options(mut self, opts: DecodeOptions) -> Self {
+        self.opts = opts;
+        self
+    }
+
+    pub fn options(&self) -> &DecodeOptions {
+        &self.opts
+    }
+
+    pub fn decode<R: Read>(&self, input: &mut R) -> anyhow::Result<Value> {
+        self.decode_with_stats(input).0
+    }
+
+    /// same as `decode`, with diagnostics of the decode.
+    /// Stats are returned also when decoding fails, e.g. to see the dictionary miss
+    pub fn decode_with_stats<R: Read>(
+        &self,
+        input: &mut R,
+    ) -> (anyhow::Result<Value>, DecodeStats) {
+        let mut counting = Counting::new(input, self.opts.max_bytes);
+        let mut decoding = Decoding::new(self.fd, self.vd, &self.opts);
+        let value = decoding.document(&mut counting);
+        let stats = DecodeStats {
+            bytes: counting.bytes,
+            ..decoding.stats
+        };
+        (value, stats)
+    }
+
+    /// decodes fields of an object whose size was read already,
+    /// keys are inserted in the order they were written
+    pub fn decode_object<R: Read>(
+        &self,
+        input: &mut R,
+        size: usize,
+    ) -> anyhow::Result<Map<String, Value>> {
+        let mut input = Counting::new(input, self.opts.max_bytes);
+        let mut decoding = Decoding::new(self.fd, self.vd, &self.opts);
+        // the object itself is the first level
+        decoding.enter(0)?;
+        let m = decoding.object(&mut input, size);
+        m.map_err(|e| decoding.locate(e))
+    }
+
+    /// same as `decode`, for a blob in memory. Declared sizes are checked
+    /// against the length of the blob, so garbage fails before it is read.
+    /// The byte budget defaults to the blob length
+    pub fn decode_slice(&self, mut input: &[u8]) -> anyhow::Result<Value> {
+        let opts = DecodeOptions {
+            max_bytes: Some(self.opts.max_bytes.unwrap_or(input.len() as u64)),
+            ..self.opts.clone()
+        };
+        let mut decoding = Decoding::new(self.fd, self.vd, &opts);
+        let mut counting = Counting::new(&mut input, opts.max_bytes);
+        decoding.document(&mut counting)
+    }
+
+    /// same as `decode_slice`, with the strings that the blob has as they were written
+    /// borrowed from it. Dictionary references, hex and timestamps are new strings
+    pub fn decode_borrowed<'s>(&self, input: &'s [u8]) -> anyhow::Result<BorrowedValue<'s>> {
+        let opts = DecodeOptions {
+            max_bytes: Some(self.opts.max_bytes.unwrap_or(input.len() as u64)),
+            ..self.opts.clone()
+        };
+        borrowed::decode(input, self.fd, self.vd, &opts)
+    }
+
+    /// same as `decode_path`, with diagnostics of the decode
+    pub fn decode_path_with_stats(
+        &self,
+        input: &[u8],
+        path: &str,
+    ) -> (anyhow::Result<Option<Value>>, DecodeStats) {
+        self.find(input, path, Target::Value)
+    }
+
+    fn find(
+        &self,
+        mut input: &[u8],
+        path: &str,
+        target: Target,
+    ) -> (anyhow::Result<Option<Value>>, DecodeStats) {
+        let opts = DecodeOptions {
+            max_bytes: Some(self.opts.max_bytes.unwrap_or(input.len() as u64)),
+            ..self.opts.clone()
+        };
+        let segments: Vec<&str> = match path {
+            "" => vec![],
+            path => path.split('.').collect(),
+        };
+        let mut counting = Counting::new(&mut input, opts.max_bytes);
+        let mut decoding = Decoding::new(self.fd, self.vd, &opts);
+        let value = decoding
+            .find(&mut counting, &segments, target)
+            .map_err(|e| decoding.locate(e));
+        let stats = DecodeStats {
+            bytes: counting.bytes,
+            ..decoding.stats
+        };
+        (value, stats)
+    }
+
+    /// same as `decode_path` of the crate
+    pub fn decode_path(&self, input: &[u8], path: &str) -> anyhow::Result<Option<Value>> {
+        self.decode_path_with_stats(input, path).0
+    }
+
+    /// same as `len_at` of the crate
+    pub fn len_at(&self, input: &[u8], path: &str) -> anyhow::Result<Option<usize>> {
+        let (found, _) = self.find(input, path, Target::Len);
+        Ok(found?.and_then(|v| v.as_u64()).map(|n| n as usize))
+    }
+
+    /// writes encoded value as compact JSON text, without building the value in memory.
+    /// Fields are written in the order of the blob, which is the order `decode`
+    /// sorts them into for blobs made by `encode`
+    pub fn decode_to_writer<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        w: &mut W,
+    ) -> anyhow::Result<()> {
+        let mut input = Counting::new(input, self.opts.max_bytes);
+        let mut decoding = Decoding::new(self.fd, self.vd, &self.opts);
+        let written = decoding
+            .header(&mut input)
+            .and_then(|nb| decoding.write_item(nb, &mut input, w));
+        written.map_err(|e| decoding.locate(e))?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+// what `find` reads of the value at the path
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Target {
+    Value,
+    /// number of elements or fields, the children are skipped
+    Len,
+}
+
+impl Target {
+    fn of(self, value: Value) -> Option<Value> {
+        match (self, value) {
+            (Self::Value, value) => Some(value),
+            (Self::Len, Value::Array(a)) => Some(Value::from(a.len())),
+            (Self::Len, Value::Object(m)) => Some(Value::from(m.len())),
+            (Self::Len, _) => None,
+        }
+    }
+}
+
+fn value_at(mut value: Value, path: &[&str]) -> Option<Value> {
+    for segment in path {
+        value = match value {
+            Value::Object(mut m) => m.remove(*segment)?,
+            Value::Array(mut a) => match segment.parse::<usize>() {
+                Ok(index) if index < a.len() && index.to_string() == *segment => {
+                    a.swap_remove(index)
+                }
+                _ => return None,
+            },
+            _ => return None,
         };
-        println!("DECODED OBJECT FIELD {:?}", field);
-        let value = decode(input, fd, vd)?;
-        println!("DECODED OBJECT VALUE {:?}", value);
-        m.insert(field, value);
     }
-    println!("MAP {:?}", m);
-    Ok(m)
+    Some(value)
+}
+
+pub fn decode_object<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    size: usize,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Map<String, Value>> {
+    Decoder::new(fd, vd).decode_object(input, size)
+}
+
+// appends JSON pointer segment
+pub(crate) fn push_path(path: &mut String, segment: &str) {
+    path.push('/');
+    path.push_str(&segment.replace('~', "~0").replace('/', "~1"));
 }
 
 /// converts encoded bytes from Buffer into JSON value,
@@ -57,139 +714,258 @@ pub fn decode<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
     fd: &D1,
     vd: &D2,
 ) -> anyhow::Result<Value> {
-    let nb = next_u8(input)?;
-    let use_vd = (nb & 0x20) > 0;
-    println!("NB={} USE VD={}", nb, use_vd);
-    match nb & 0x1F {
-        0 => Ok(Value::Bool(false)),
-        1 => Ok(Value::Bool(true)),
-        2 => Ok(Value::Number(Number::from(next_u8(input)?))),
-        3 => Ok(Value::Number(Number::from(next_i8(input)?))),
-        4 => Ok(Value::String(format!(
-            "0x{}",
-            hex::encode(&[next_u8(input)?])
-        ))),
-        5 => Ok(Value::Number(Number::from(next_u16(input)?))),
-        6 => Ok(Value::Number(Number::from(next_i16(input)?))),
-        7 => Ok(Value::Number(Number::from(next_u32(input)?))),
-        8 => Ok(Value::Number(Number::from(next_i32(input)?))),
-        9 => Ok(Value::Number(Number::from(next_u64(input)?))),
-        10 => Ok(Value::Number(Number::from(next_i64(input)?))),
-        11 => Ok(Value::String(format!(
-            "0x{}",
-            hex::encode(next_u64(input)?.to_be_bytes()),
-        ))),
-        12 => Ok(Value::String(format!(
-            "0x{}",
-            hex::encode(next_u16(input)?.to_le_bytes()),
-        ))),
-        13 => Ok(Value::String(format!(
-            "0x{}",
-            hex::encode(next_u32(input)?.to_be_bytes()),
-        ))),
-        14 => Ok(Value::String(format!(
-            "0x{}",
-            hex::encode(next_u128(input)?.to_be_bytes()),
-        ))),
-        15 => {
-            let lo = next_u32(input)?;
-            let hi = next_u128(input)?;
-            Ok(Value::String(format!(
-                "0x{}{}",
-                hex::encode(hi.to_be_bytes()),
-                hex::encode(lo.to_be_bytes()),
-            )))
-        }
-        16 => {
-            let lo = next_u128(input)?;
-            let hi = next_u128(input)?;
-            Ok(Value::String(format!(
-                "0x{}{}",
-                hex::encode(hi.to_be_bytes()),
-                hex::encode(lo.to_be_bytes()),
-            )))
-        }
-        17 => {
-            let n = Number::from_f64(next_f64(input)?).context("no item")?;
-            Ok(Value::Number(n))
-        }
-        18 => Ok(Value::Number(Number::from(0))),
-        19 => {
-            let size = next_u8(input)? as usize;
-            let mut buf = BufWriter::new(Vec::new());
-            next(input, size, &mut buf)?;
-            let b = buf.into_inner()?;
-            Ok(Value::String(format!("0x{}", hex::encode(&b))))
-        }
-        20 => {
-            if use_vd {
-                let dict_id = next_u32(input)?;
-                if let Some(buf) = vd.get(dict_id) {
-                    let s = std::str::from_utf8(buf)?.to_string();
-                    return Ok(Value::String(s));
-                }
-            }
-            let size = next_u8(input)? as usize;
-            let mut buf = BufWriter::new(Vec::new());
-            next(input, size, &mut buf)?;
-            let s = String::from_utf8(buf.into_inner()?)?;
-            Ok(Value::String(s))
-        }
-        23 => {
-            let size = next_u16(input)? as usize;
-            let mut buf = BufWriter::new(Vec::new());
-            next(input, size, &mut buf)?;
-            let b = buf.into_inner().unwrap();
-            Ok(Value::String(format!("0x{}", hex::encode(&b))))
-        }
-        24 => {
-            let size = next_u16(input)? as usize;
-            let mut buf = BufWriter::new(Vec::new());
-            next(input, size, &mut buf)?;
-            let s = String::from_utf8(buf.into_inner().unwrap()).unwrap();
-            Ok(Value::String(s))
-        }
-        21 => {
-            let size = next_u8(input)? as usize;
-            let mut vals = Vec::new();
-            for _ in 0..size {
-                vals.push(decode(input, fd, vd)?);
-            }
-            Ok(Value::Array(vals))
+    Decoder::new(fd, vd).decode(input)
+}
+
+/// decodes into a type of known shape, e.g. a block or a log.
+/// Fails with `DecodeError::Shape` when the value doesn't deserialize into it
+pub fn decode_as<T: DeserializeOwned, R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<T> {
+    // goes through Value for now
+    let value = decode(input, fd, vd)?;
+    serde_json::from_value(value).map_err(|e| {
+        DecodeError::Shape {
+            target: std::any::type_name::<T>(),
+            message: e.to_string(),
         }
-        25 => {
-            let size = next_u16(input)? as usize;
-            let mut vals = Vec::new();
-            for _ in 0..size {
-                vals.push(decode(input, fd, vd)?);
+        .into()
+    })
+}
+
+/// same as `decode`, with options
+pub fn decode_with<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    fd: &D1,
+    vd: &D2,
+    opts: &DecodeOptions,
+) -> anyhow::Result<Value> {
+    decode_with_stats(input, fd, vd, opts).0
+}
+
+/// same as `decode_with`, with diagnostics of the decode.
+/// Stats are returned also when decoding fails, e.g. to see the dictionary miss
+pub fn decode_with_stats<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    fd: &D1,
+    vd: &D2,
+    opts: &DecodeOptions,
+) -> (anyhow::Result<Value>, DecodeStats) {
+    Decoder::new(fd, vd)
+        .with_options(opts.clone())
+        .decode_with_stats(input)
+}
+
+/// writes encoded value as compact JSON text, without building the value in memory.
+/// Fields are written in the order of the blob, which is the order `decode`
+/// sorts them into for blobs made by `encode`
+pub fn decode_to_writer<R: Read, W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<()> {
+    Decoder::new(fd, vd).decode_to_writer(input, w)
+}
+
+/// same as `decode`, for a blob in memory. Declared sizes are checked
+/// against the length of the blob, so garbage fails before it is read
+pub fn decode_slice<D1: DictionaryRead, D2: DictionaryRead>(
+    input: &[u8],
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Value> {
+    Decoder::new(fd, vd).decode_slice(input)
+}
+
+/// same as `decode_slice`, with options. The byte budget defaults to the blob length
+pub fn decode_slice_with<D1: DictionaryRead, D2: DictionaryRead>(
+    input: &[u8],
+    fd: &D1,
+    vd: &D2,
+    opts: &DecodeOptions,
+) -> anyhow::Result<Value> {
+    Decoder::new(fd, vd)
+        .with_options(opts.clone())
+        .decode_slice(input)
+}
+
+/// same as `decode_slice`, with the strings that the blob has as they were written
+/// borrowed from it. `BorrowedValue::to_owned` gives the value `decode` returns
+pub fn decode_borrowed<'s, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &'s [u8],
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<BorrowedValue<'s>> {
+    Decoder::new(fd, vd).decode_borrowed(input)
+}
+
+/// decodes only the value at the path of object keys and array indexes separated
+/// by dots, e.g. "result.transactions.3.hash", skipping the values before it.
+/// The empty path is the whole value. Returns None when the path is not in the value
+pub fn decode_path<D1: DictionaryRead, D2: DictionaryRead>(
+    input: &[u8],
+    path: &str,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Option<Value>> {
+    Decoder::new(fd, vd).decode_path(input, path)
+}
+
+/// number of elements or fields of the array or object at the path, as in
+/// `decode_path`, without decoding them. None when the path is not in the value
+/// or the value there is not a container
+pub fn len_at<D1: DictionaryRead, D2: DictionaryRead>(
+    input: &[u8],
+    path: &str,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Option<usize>> {
+    Decoder::new(fd, vd).len_at(input, path)
+}
+
+/// first byte of blobs with dictionary fingerprints, it is never a type prefix of a value
+pub(crate) const FINGERPRINT_TAG: u8 = 0xF0;
+
+/// first byte of values with back-references to earlier strings, after the optional
+/// fingerprint header. Decoders keep the strings of the value only after it
+pub(crate) const BACK_REFS_TAG: u8 = 0xF1;
+
+pub(crate) fn verify_fingerprints<R: Read, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 32];
+    input.read_exact(&mut buf)?;
+    for (dictionary, stored, supplied) in [
+        ("field", &buf[..16], fd.fingerprint()),
+        ("value", &buf[16..], vd.fingerprint()),
+    ] {
+        let mut expected = [0u8; 16];
+        expected.copy_from_slice(stored);
+        match supplied {
+            Some(got) if got == expected => {}
+            Some(got) => {
+                return Err(DecodeError::DictionaryFingerprintMismatch {
+                    dictionary,
+                    expected,
+                    got,
+                }
+                .into())
             }
-            Ok(Value::Array(vals))
+            None => bail!("{} dictionary has no fingerprint to verify", dictionary),
         }
-        22 => {
-            let size = next_u8(input)? as usize;
-            println!("DECODING OBJECT OF SIZE {}", size);
-            Ok(Value::Object(decode_object(input, size, fd, vd)?))
-        }
-        26 => {
-            let size = next_u16(input)? as usize;
-            Ok(Value::Object(decode_object(input, size, fd, vd)?))
-        }
-        31 => Ok(Value::Null),
-        _ => bail!("invalid field type"),
     }
+    Ok(())
+}
+
+/// fingerprints of field and value dictionaries the blob was encoded with,
+/// None for blobs without them
+pub fn fingerprints(input: &[u8]) -> Option<([u8; 16], [u8; 16])> {
+    if input.len() < 33 || input[0] != FINGERPRINT_TAG {
+        return None;
+    }
+    let mut fd = [0u8; 16];
+    let mut vd = [0u8; 16];
+    fd.copy_from_slice(&input[1..17]);
+    vd.copy_from_slice(&input[17..33]);
+    Some((fd, vd))
+}
+
+/// same as `encode`, but the value is prefixed with fingerprints of the dictionaries,
+/// so decoding with other dictionaries fails instead of returning wrong data.
+/// Returns the number of bytes written, the header included
+pub fn encode_with_fingerprint<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<usize> {
+    let (f, v) = match (fd.fingerprint(), vd.fingerprint()) {
+        (Some(f), Some(v)) => (f, v),
+        _ => bail!("dictionary has no fingerprint"),
+    };
+    let mut header = [0u8; 33];
+    header[0] = FINGERPRINT_TAG;
+    header[1..17].copy_from_slice(&f);
+    header[17..].copy_from_slice(&v);
+    w.write_all(&header)?;
+    Ok(header.len() + encode(input, w, fd, vd)?)
 }
 
 /// converts JSON value into encoded bytes using given writer,
-/// field and value dictionaries
+/// field and value dictionaries. Returns the number of bytes written.
+/// Keys of objects are written in the order their `Map` iterates them:
+/// sorted, or as inserted with the preserve_order feature
 pub fn encode<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
     input: &Value,
     w: &mut W,
     fd: &D1,
     vd: &D2,
-) -> anyhow::Result<()> {
-    encode::encode_value(input, w, fd, vd)?;
-    w.flush()?;
-    Ok(())
+) -> anyhow::Result<usize> {
+    Encoder::new(fd, vd).encode(input, w)
+}
+
+/// length of the `encode` output for the value, without writing it
+pub fn encoded_size<D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<usize> {
+    Encoder::new(fd, vd).encoded_size(input)
+}
+
+/// same as `encode`, with options
+pub fn encode_with<W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &Value,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+    opts: &EncodeOptions,
+) -> anyhow::Result<usize> {
+    Encoder::new(fd, vd)
+        .with_options(opts.clone())
+        .encode(input, w)
+}
+
+/// encodes the value with the dictionaries as they are, then adds to them the keys
+/// of the value they don't have and, with `learn_values`, the strings it repeats.
+/// Ids are only added, so the blob decodes with the dictionaries before or after.
+/// Entries the dictionaries refuse, e.g. when they are full, are left out.
+/// Returns the added entries for the caller to persist
+pub fn encode_learning<W: Write, D1: DictionaryWrite, D2: DictionaryWrite>(
+    input: &Value,
+    w: &mut W,
+    fd: &mut D1,
+    vd: &mut D2,
+    opts: &EncodeOptions,
+    learn_values: bool,
+) -> anyhow::Result<Learned> {
+    encode_with(input, w, &*fd, &*vd, opts)?;
+    let (mut keys, mut repeated) = (vec![], vec![]);
+    learn_from(input, &mut keys, &mut HashMap::new(), &mut repeated);
+    let mut learned = Learned::default();
+    for k in keys {
+        if fd.find_bytes(k.as_bytes()).is_some() {
+            continue;
+        }
+        if let Some(id) = fd.insert(k.as_bytes()) {
+            learned.fields.push((id, k.to_string()));
+        }
+    }
+    for value in repeated.into_iter().filter(|_| learn_values) {
+        let value = value_entry(value);
+        if vd.find_str(&value).is_some() {
+            continue;
+        }
+        if let Some(id) = vd.insert(value.as_bytes()) {
+            learned.values.push((id, value.to_string()));
+        }
+    }
+    Ok(learned)
 }
 
 #[cfg(test)]
@@ -213,7 +989,7 @@ mod tests {
     fn enc(input: &Value) -> anyhow::Result<Vec<u8>> {
         let nod = NoDictionary {};
         let mut buf = BufWriter::new(Vec::new());
-        encode::encode_value(input, &mut buf, &nod, &nod).unwrap();
+        encode(input, &mut buf, &nod, &nod).unwrap();
         let v = buf.into_inner().unwrap();
         Ok(v)
     }
@@ -222,23 +998,44 @@ mod tests {
     fn enc_d(input: &Value) -> anyhow::Result<Vec<u8>> {
         let d = MapDictionary::from_static(D);
         let mut buf = BufWriter::new(Vec::new());
-        encode::encode_value(input, &mut buf, &d, &d).unwrap();
+        encode(input, &mut buf, &d, &d).unwrap();
         let v = buf.into_inner().unwrap();
         Ok(v)
     }
 
-    // decode without dictionary
+    // lengths under separate tags of u8 to u32, and in LEB128
+    fn framings() -> [EncodeOptions; 2] {
+        let varint = EncodeOptions {
+            varint_lengths: true,
+            ..Default::default()
+        };
+        [EncodeOptions::default(), varint]
+    }
+
+    // encode with the options and the dictionary
+    fn enc_with<D: DictionaryRead>(input: &Value, d: &D, opts: &EncodeOptions) -> Vec<u8> {
+        let mut buf = vec![];
+        encode_with(input, &mut buf, d, d, opts).unwrap();
+        buf
+    }
+
+    // decode, checking that streaming decode writes the same text
+    fn dec_with<D: DictionaryRead>(input: &[u8], d: &D) -> anyhow::Result<Value> {
+        let value = decode(&mut BufReader::new(input), d, d)?;
+        let mut text = vec![];
+        decode_to_writer(&mut BufReader::new(input), &mut text, d, d)?;
+        assert_eq!(String::from_utf8(text)?, serde_json::to_string(&value)?);
+        Ok(value)
+    }
+
+    // decode without dictionary
     fn dec(input: &[u8]) -> anyhow::Result<Value> {
-        let nod = NoDictionary {};
-        let mut buf = BufReader::new(input);
-        decode(&mut buf, &nod, &nod)
+        dec_with(input, &NoDictionary {})
     }
 
     // decode with sample dictionary
     fn dec_d(input: &[u8]) -> anyhow::Result<Value> {
-        let d = MapDictionary::from_static(D);
-        let mut buf = BufReader::new(input);
-        decode(&mut buf, &d, &d)
+        dec_with(input, &MapDictionary::from_static(D))
     }
 
     #[test]
@@ -283,29 +1080,601 @@ mod tests {
 
     #[test]
     fn it_encodes_decodes_strings() {
-        let alpha = enc_d(&json!("alpha")).unwrap();
-        assert_eq!(dec_d(&alpha).unwrap().as_str().unwrap(), "alpha");
+        let d = MapDictionary::from_static(D);
+        let nod = NoDictionary {};
+        for opts in framings() {
+            let alpha = enc_with(&json!("alpha"), &d, &opts);
+            assert_eq!(dec_d(&alpha).unwrap().as_str().unwrap(), "alpha");
 
-        let hello = enc(&json!("Hello")).unwrap();
-        assert_eq!(dec(&hello).unwrap().as_str().unwrap(), "Hello");
+            let hello = enc_with(&json!("Hello"), &nod, &opts);
+            assert_eq!(dec(&hello).unwrap().as_str().unwrap(), "Hello");
 
-        let lipsum = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. Praesent nec magna fermentum, tincidunt orci quis, mollis lacus. Phasellus vitae vestibulum purus. Suspendisse sit amet lacus a nisl condimentum eleifend. Sed magna lectus, placerat ac sapien ac, interdum pellentesque sapien. Praesent eleifend, odio sit amet dignissim imperdiet, nunc risus laoreet urna, nec egestas quam nibh sit amet massa. Morbi lacinia molestie elit, nec sollicitudin erat. Vestibulum accumsan neque et ornare turpis duis.";
-        let lorem = enc(&json!(lipsum)).unwrap();
-        assert_eq!(dec(&lorem).unwrap().as_str().unwrap(), lipsum);
+            let lipsum = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. Praesent nec magna fermentum, tincidunt orci quis, mollis lacus. Phasellus vitae vestibulum purus. Suspendisse sit amet lacus a nisl condimentum eleifend. Sed magna lectus, placerat ac sapien ac, interdum pellentesque sapien. Praesent eleifend, odio sit amet dignissim imperdiet, nunc risus laoreet urna, nec egestas quam nibh sit amet massa. Morbi lacinia molestie elit, nec sollicitudin erat. Vestibulum accumsan neque et ornare turpis duis.";
+            let lorem = enc_with(&json!(lipsum), &nod, &opts);
+            assert_eq!(dec(&lorem).unwrap().as_str().unwrap(), lipsum);
+        }
     }
 
     #[test]
     fn it_encodes_decodes_hex() {
-        let longhex = "0x95087266018b9637aff3d76d4e0cad7eff52c10963600a895087266018b9637aff3d76d4e0cad7eff52c10963600a8";
-        let lh = enc(&json!(longhex)).unwrap();
-        assert_eq!(dec(&lh).unwrap().as_str().unwrap(), longhex);
+        let d = MapDictionary::from_static(D);
+        let nod = NoDictionary {};
+        for opts in framings() {
+            let longhex = "0x95087266018b9637aff3d76d4e0cad7eff52c10963600a895087266018b9637aff3d76d4e0cad7eff52c10963600a8";
+            let lh = enc_with(&json!(longhex), &nod, &opts);
+            assert_eq!(dec(&lh).unwrap().as_str().unwrap(), longhex);
+
+            let addr = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
+            let just_addr = enc_with(&json!(addr), &nod, &opts);
+            assert_eq!(dec(&just_addr).unwrap().as_str().unwrap(), addr);
+
+            let dict_addr = enc_with(&json!(addr), &d, &opts);
+            assert_eq!(dec_d(&dict_addr).unwrap().as_str().unwrap(), addr);
+        }
+    }
+
+    #[test]
+    fn it_round_trips_hex_of_every_length() {
+        let widths = [1, 2, 4, 4, 8, 8, 8, 8, 16, 16, 16, 16, 16, 16, 16, 16];
+        let widths = widths.iter().chain(&[20; 4]).chain(&[32; 12]);
+        for (len, width) in (1..=32).zip(widths) {
+            for pattern in [0x00ff00ffu32, 0x12345678, 0xff00ff00, 0x01020304] {
+                let bytes: Vec<u8> = (0..len)
+                    .map(|i| pattern.to_be_bytes()[i % 4] ^ (i / 4) as u8)
+                    .collect();
+                let encoded = enc(&json!(format!("0x{}", hex::encode(&bytes)))).unwrap();
+                assert_eq!(encoded.len(), 1 + width, "{} bytes", len);
+                // shorter values are padded with leading zeros to the width
+                let mut padded = vec![0u8; width - len];
+                padded.extend(&bytes);
+                let expected = format!("0x{}", hex::encode(&padded));
+                assert_eq!(dec(&encoded).unwrap(), json!(expected), "{} bytes", len);
+            }
+        }
+        // the order on the wire, blobs already stored depend on it
+        assert_eq!(enc(&json!("0x1234")).unwrap(), [12, 0x12, 0x34]);
+        assert_eq!(enc(&json!("0x1a2b3c")).unwrap(), [13, 0x3c, 0x2b, 0x1a, 0]);
+        assert_eq!(
+            enc(&json!("0x00ff00ff01")).unwrap(),
+            [11, 1, 0xff, 0, 0xff, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn it_preserves_hex_width_on_request() {
+        let preserve = EncodeOptions {
+            preserve_hex_width: true,
+            ..Default::default()
+        };
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        let d = MapDictionary::from_static(D);
+        let long_odd = format!("0x1{}", "ab".repeat(40));
+        for v in [
+            "0x100",
+            "0x1a2b3c",
+            "0x00ff",
+            "0x0",
+            "0x000",
+            "0xABC",
+            "0x95087266018b9637aff3d76d4e0cad7e52c19636",
+            "0x5087266018b9637aff3d76d4e0cad7e52c19636",
+            &long_odd,
+        ] {
+            let v = json!({ "alpha": [v] });
+            for d in [&d, &MapDictionary::new()] {
+                let mut encoded = vec![];
+                encode_with(&v, &mut encoded, d, d, &preserve).unwrap();
+                assert_eq!(dec_with(&encoded, d).unwrap(), v);
+                assert_eq!(
+                    decode_with(&mut encoded.as_slice(), d, d, &strict).unwrap(),
+                    v
+                );
+            }
+        }
+        // compact by default
+        assert_eq!(
+            dec(&enc(&json!("0x100")).unwrap()).unwrap(),
+            json!("0x0100")
+        );
+        assert_eq!(dec(&enc(&json!("0x0")).unwrap()).unwrap(), json!("0x00"));
+        let mut encoded = vec![];
+        encode_with(&json!("0x1a2b3c"), &mut encoded, &d, &d, &preserve).unwrap();
+        assert_eq!(encoded, [27, 2, 13, 0x3c, 0x2b, 0x1a, 0]);
+        // only zeros can be dropped
+        assert!(dec(&[27, 3, 13, 0x3c, 0x2b, 0x1a, 0]).is_err());
+        assert!(dec(&[27, 1, 31]).is_err());
+    }
+
+    #[test]
+    fn it_round_trips_empty_and_single_nibble_hex() {
+        let d = MapDictionary::from_static(D);
+        let preserve = EncodeOptions {
+            preserve_hex_width: true,
+            ..Default::default()
+        };
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        // empty calldata is empty bytes in every mode
+        assert_eq!(enc(&json!("0x")).unwrap(), [19, 0]);
+        assert_eq!(enc_with(&json!("0x"), &d, &framings()[1]), [19 | 0x80, 0]);
+        assert_eq!(enc_with(&json!("0x"), &d, &preserve), [19, 0]);
+        // one nibble is padded to a byte, unless the width is preserved
+        assert_eq!(enc(&json!("0x0")).unwrap(), [4, 0]);
+        assert_eq!(enc(&json!("0x00")).unwrap(), [4, 0]);
+        assert_eq!(enc_with(&json!("0x0"), &d, &preserve), [27, 1, 4, 0]);
+        for (v, compact) in [("0x", "0x"), ("0x0", "0x00"), ("0x00", "0x00")] {
+            let tx = |input| json!({"input": input, "data": [input]});
+            for opts in framings().iter().chain([&preserve]) {
+                let expected = match opts.preserve_hex_width {
+                    true => tx(v),
+                    false => tx(compact),
+                };
+                let encoded = enc_with(&tx(v), &d, opts);
+                assert_eq!(dec_with(&encoded, &d).unwrap(), expected);
+                let decoded = decode_with(&mut encoded.as_slice(), &d, &d, &strict).unwrap();
+                assert_eq!(decoded, expected);
+            }
+        }
+        // "0x" written as a string by earlier versions still decodes
+        assert_eq!(dec(&[20, 2, b'0', b'x']).unwrap(), json!("0x"));
+    }
+
+    #[test]
+    fn it_keeps_strings_that_only_look_like_hex() {
+        let d = MapDictionary::from_static(D);
+        let preserve = EncodeOptions {
+            preserve_hex_width: true,
+            ..Default::default()
+        };
+        for v in ["0xylophone", "0x-team", "0x12g4", "0x 1", "0X12"] {
+            let v = json!({ "alpha": [v, {"name": v}] });
+            for opts in framings().iter().chain([&preserve]) {
+                let encoded = enc_with(&v, &d, opts);
+                assert_eq!(dec_with(&encoded, &d).unwrap(), v);
+            }
+        }
+        // stored as a string, as written
+        assert_eq!(enc(&json!("0xylophone")).unwrap()[..2], [20, 10]);
+
+        // valid hex is still packed
+        assert_eq!(enc(&json!("0x1")).unwrap(), [4, 1]);
+        assert_eq!(enc(&json!("0xAbCd")).unwrap(), [12, 0xab, 0xcd]);
+        for v in ["0x1", "0xAbCd"] {
+            let mut encoded = vec![];
+            encode_with(&json!(v), &mut encoded, &d, &d, &preserve).unwrap();
+            assert_eq!(dec_with(&encoded, &d).unwrap(), json!(v));
+        }
+        assert_eq!(
+            dec(&enc(&json!("0xAbCd")).unwrap()).unwrap(),
+            json!("0xabcd")
+        );
+    }
+
+    #[test]
+    fn it_encodes_with_options() {
+        let d = MapDictionary::from_static(D);
+        let encoded = |opts: EncodeOptions, v: &Value| {
+            let mut buf = vec![];
+            Encoder::new(&d, &d)
+                .with_options(opts)
+                .encode(v, &mut buf)
+                .unwrap();
+            assert_eq!(dec_with(&buf, &d).unwrap(), *v);
+            buf
+        };
+        let plain = EncodeOptions {
+            hex_widths: HexDetection::Never,
+            ..Default::default()
+        };
+        assert_eq!(encoded(plain, &json!("0x100")), b"\x14\x050x100");
+
+        let no_dictionary = EncodeOptions {
+            use_value_dictionary: false,
+            ..Default::default()
+        };
+        let v = json!(["alpha", "0x95087266018b9637aff3d76d4e0cad7e52c19636"]);
+        assert_eq!(encoded(no_dictionary, &v).len(), 2 + 7 + 21);
+        assert_eq!(encoded(EncodeOptions::default(), &v).len(), 2 + 2 + 2);
+
+        let bn = json!({"type": "BigNumber", "hex": "0x01ff"});
+        let keep = EncodeOptions {
+            detect_bignumber: false,
+            ..Default::default()
+        };
+        assert_eq!(encoded(keep, &bn)[0], 22);
+        let mut buf = vec![];
+        encode(&bn, &mut buf, &d, &d).unwrap();
+        assert_eq!(dec_d(&buf).unwrap(), json!("0x01ff"));
+    }
+
+    #[test]
+    fn it_packs_only_canonical_hex_widths() {
+        let nod = NoDictionary {};
+        let encoded = |hex_widths, v: &str| {
+            let opts = EncodeOptions {
+                hex_widths,
+                ..Default::default()
+            };
+            let mut buf = vec![];
+            encode_with(&json!(v), &mut buf, &nod, &nod, &opts).unwrap();
+            let decoded = decode_slice(&buf, &nod, &nod).unwrap();
+            (buf, decoded.as_str().unwrap().to_string())
+        };
+        // 7 digits are packed into 4 bytes, which decode with a leading zero
+        let (buf, decoded) = encoded(HexDetection::All, "0x1234567");
+        assert_eq!((buf.len(), decoded.as_str()), (5, "0x01234567"));
+        let (buf, decoded) = encoded(HexDetection::CanonicalOnly, "0x1234567");
+        assert_eq!((buf[0], decoded.as_str()), (20, "0x1234567"));
+
+        let hash = "0xd8052f44b36869fa1f193ec2c97e6a36892840635dc347554efb8778a7a3935a";
+        let address = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
+        for v in [
+            "0x",
+            "0x01",
+            "0x0100",
+            "0x0001a4f3",
+            "0x00000001bfd3a5b1",
+            address,
+            hash,
+        ] {
+            let (buf, decoded) = encoded(HexDetection::CanonicalOnly, v);
+            assert_eq!(decoded, v);
+            assert_eq!(buf, encoded(HexDetection::All, v).0, "{}", v);
+        }
+        // odd digits and widths between the fixed ones, like short git ids, are kept as written
+        for v in ["0x1", "0x100", "0x1a2b3c", "0x4b825dc642cb"] {
+            let (buf, decoded) = encoded(HexDetection::CanonicalOnly, v);
+            assert_eq!((buf[0], decoded.as_str()), (20, v));
+        }
+        for v in ["0x", "0x01", hash] {
+            let (buf, decoded) = encoded(HexDetection::Never, v);
+            assert_eq!((buf[0], decoded.as_str()), (20, v));
+        }
+    }
+
+    #[test]
+    fn it_round_trips_timestamps() {
+        let nod = NoDictionary {};
+        let detect = EncodeOptions {
+            detect_timestamps: true,
+            ..Default::default()
+        };
+        let v = json!({
+            "at": "2023-07-01T12:00:00Z",
+            "exact": "2023-07-01T12:00:00.250Z",
+            "unix": "1688212800",
+            "offset": "2023-07-01T12:00:00+02:00",
+            "micros": "2023-07-01T12:00:00.250000Z",
+            "number": 1688212800,
+        });
+        let mut buf = vec![];
+        Encoder::new(&nod, &nod)
+            .with_options(detect)
+            .encode(&v, &mut buf)
+            .unwrap();
+        assert_eq!(dec(&buf).unwrap(), v);
+        let plain = enc(&v).unwrap();
+        assert_eq!(plain.len() - buf.len(), (22 - 5) + (26 - 9) + (12 - 5));
+
+        // seconds and digits share the u32 value and differ in the prefix
+        let mut at = vec![];
+        let mut unix = vec![];
+        let enc_with = |v: &Value, buf: &mut Vec<u8>| {
+            Encoder::new(&nod, &nod)
+                .with_options(EncodeOptions {
+                    detect_timestamps: true,
+                    ..Default::default()
+                })
+                .encode(v, buf)
+                .unwrap()
+        };
+        enc_with(&json!("2023-07-01T12:00:00Z"), &mut at);
+        enc_with(&json!("1688212800"), &mut unix);
+        assert_eq!(at[1..], unix[1..]);
+        assert_ne!(at[0], unix[0]);
+        assert_eq!(at[1..], 1688212800u32.to_le_bytes());
+    }
+
+    #[test]
+    fn it_skips_values_without_decoding() {
+        let d = MapDictionary::from_static(D);
+        let addr = D[5];
+        let v = json!({
+            "alpha": [addr, "0xdeadbeef", [1, 2, 3], {"beta": null}],
+            "long key that is not in the dictionary": {"gamma": [1.5, -2, true]},
+            "at": "2023-07-01T12:00:00Z",
+            "12": "repeated", "13": "repeated",
+            "big": {"type": "BigNumber", "hex": "0x0de0b6b3a7640000"},
+            "text": "x".repeat(300),
+        });
+        let next = json!(["next", 1]);
+        for opts in [
+            EncodeOptions::default(),
+            EncodeOptions {
+                detect_timestamps: true,
+                back_references: true,
+                varint_lengths: true,
+                ..Default::default()
+            },
+        ] {
+            let mut buf = enc_with(&v, &d, &opts);
+            let first = buf.len() as u64;
+            encode_with_fingerprint(&next, &mut buf, &d, &d).unwrap();
+            let mut input = buf.as_slice();
+            assert_eq!(skip_value(&mut input).unwrap(), first);
+            // the header of the next document is skipped with it
+            assert_eq!(
+                decode_slice_with(input, &d, &d, &Default::default()).unwrap(),
+                next
+            );
+            assert_eq!(skip_value(&mut input).unwrap(), buf.len() as u64 - first);
+            assert!(input.is_empty());
+        }
+
+        // fields are found by skipping the values before them
+        let buf = enc_d(&v).unwrap();
+        let mut input = buf.as_slice();
+        assert_eq!(next_item(&mut input).unwrap(), Item::Object(7));
+        loop {
+            let key = next_key(&mut input).unwrap();
+            if key == Key::Str("text".to_string()) {
+                break;
+            }
+            skip_value(&mut input).unwrap();
+        }
+        assert_eq!(decode(&mut input, &d, &d).unwrap(), v["text"]);
+        assert!(input.is_empty());
+    }
+
+    #[test]
+    fn it_decodes_values_at_paths() {
+        let d = MapDictionary::from_static(D);
+        let mut block: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/block.json")).unwrap();
+        block["transactions"] = (0..200)
+            .map(|i| {
+                json!({
+                    "hash": format!("0x{:064x}", i),
+                    "from": D[5],
+                    "nonce": i,
+                    "input": format!("0x{}", "a9059cbb".repeat(20)),
+                })
+            })
+            .collect();
+        let v = json!({"jsonrpc": "2.0", "id": 1, "result": block});
+        let buf = enc_d(&v).unwrap();
+        let (decoded, full) = Decoder::new(&d, &d).decode_with_stats(&mut buf.as_slice());
+        let decoded = decoded.unwrap();
+
+        for path in [
+            "result.transactions.3.hash",
+            "result.transactions.199",
+            "result.number",
+            "result.uncles",
+            "id",
+            "",
+        ] {
+            let pointer = format!("/{}", path.replace('.', "/"));
+            let expected = decoded.pointer(pointer.trim_end_matches('/')).cloned();
+            assert!(expected.is_some(), "{}", path);
+            assert_eq!(
+                decode_path(&buf, path, &d, &d).unwrap(),
+                expected,
+                "{}",
+                path
+            );
+        }
+        for path in [
+            "result.transactions.200",
+            "result.transactions.03",
+            "result.transactions.x",
+            "result.missing.hash",
+            "id.x",
+        ] {
+            assert_eq!(decode_path(&buf, path, &d, &d).unwrap(), None, "{}", path);
+        }
+
+        // only the values on the path and the one at it are built
+        let (found, stats) =
+            Decoder::new(&d, &d).decode_path_with_stats(&buf, "result.transactions.3.hash");
+        assert_eq!(
+            found.unwrap(),
+            Some(decoded["result"]["transactions"][3]["hash"].clone())
+        );
+        assert_eq!(stats.values, 5);
+        assert!(full.values > 1000, "{}", full.values);
+        // and the values after it are not read
+        assert!(
+            stats.bytes * 10 < buf.len() as u64,
+            "{} of {}",
+            stats.bytes,
+            buf.len()
+        );
+
+        // values with back-references are decoded in full
+        let opts = EncodeOptions {
+            back_references: true,
+            ..Default::default()
+        };
+        let buf = enc_with(&v, &d, &opts);
+        let found = decode_path(&buf, "result.transactions.3.from", &d, &d).unwrap();
+        assert_eq!(found, Some(json!(D[5])));
+        assert_eq!(
+            len_at(&buf, "result.transactions", &d, &d).unwrap(),
+            Some(200)
+        );
+    }
 
+    #[test]
+    fn it_counts_children_at_paths() {
+        let d = MapDictionary::from_static(D);
+        let v = json!({"block": {"number": 7, "transactions": [{"hash": "0x01"}, "0x02"]}});
+        let buf = enc_d(&v).unwrap();
+        assert_eq!(len_at(&buf, "block.transactions", &d, &d).unwrap(), Some(2));
+        assert_eq!(len_at(&buf, "block", &d, &d).unwrap(), Some(2));
+        assert_eq!(
+            len_at(&buf, "block.transactions.0", &d, &d).unwrap(),
+            Some(1)
+        );
+        assert_eq!(len_at(&buf, "block.number", &d, &d).unwrap(), None);
+        assert_eq!(len_at(&buf, "block.missing", &d, &d).unwrap(), None);
+        let packed = enc_d(&json!({"xs": [1, 2, 3, 4, 5, 6, 7, 8]})).unwrap();
+        assert_eq!(len_at(&packed, "xs", &d, &d).unwrap(), Some(8));
+    }
+
+    #[test]
+    fn it_writes_back_references() {
+        let d = MapDictionary::from_static(D);
+        let refs = |v: &Value, opts: EncodeOptions| {
+            let mut buf = vec![];
+            let opts = EncodeOptions {
+                back_references: true,
+                ..opts
+            };
+            encode_with(v, &mut buf, &d, &d, &opts).unwrap();
+            buf
+        };
+        let hash = "0xd8052f44b36869fa1f193ec2c97e6a36892840635dc347554efb8778a7a3935a";
+        let v = json!(vec![hash; 50]);
+        let plain = enc_d(&v).unwrap();
+        let buf = refs(&v, EncodeOptions::default());
+        assert_eq!(buf[0], BACK_REFS_TAG);
+        assert!(
+            buf.len() * 5 <= plain.len(),
+            "{} of {}",
+            buf.len(),
+            plain.len()
+        );
+        assert_eq!(dec_d(&buf).unwrap(), v);
+        assert_eq!(dec_strict(&buf).unwrap(), v);
+
+        // references count dictionary values and every form of strings,
+        // over 256 strings take u16 indexes
+        let mut items: Vec<Value> = (0..300).map(|i| json!(format!("s{}", i))).collect();
+        items.extend([
+            json!("alpha"),
+            json!("0x1ff"),
+            json!("2023-07-01T12:00:00Z"),
+            json!("a"),
+            json!({"type": "BigNumber", "hex": "0x1ff"}),
+        ]);
+        let v = json!({"first": items, "again": items});
+        for opts in [
+            EncodeOptions::default(),
+            EncodeOptions {
+                preserve_hex_width: true,
+                detect_timestamps: true,
+                ..Default::default()
+            },
+        ] {
+            // decodes as without back-references, "0x1ff" is padded without preserving
+            let mut plain = vec![];
+            encode_with(&v, &mut plain, &d, &d, &opts).unwrap();
+            let expected = dec_d(&plain).unwrap();
+            let buf = refs(&v, opts);
+            assert!(buf.len() < plain.len());
+            assert_eq!(dec_d(&buf).unwrap(), expected);
+            let salvaged = decode_salvage(&buf, &d, &d).unwrap();
+            assert!(salvaged.is_complete());
+            assert_eq!(salvaged.value, expected);
+        }
+        let buf = refs(&v, EncodeOptions::default());
+        let err = from_slice::<Value, _, _>(&buf, &d, &d).unwrap_err();
+        assert_eq!(err.to_string(), "back-references are only read by decode");
+
+        // index past the strings read so far
+        let bad = [BACK_REFS_TAG, 21, 2, 0x14, 1, b'a', 27 | 0x40, 1];
+        let err = decode_slice(&bad, &d, &d).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "at offset 6, path '/1': back-reference 1 to no earlier string"
+        );
+    }
+
+    // dictionary chosen at runtime, e.g. by a command line flag
+    fn runtime_dictionary(use_dictionary: bool) -> Box<dyn DictionaryRead> {
+        match use_dictionary {
+            true => Box::new(MapDictionary::from_static(D)),
+            false => Box::new(NoDictionary {}),
+        }
+    }
+
+    #[test]
+    fn it_encodes_with_dictionaries_chosen_at_runtime() {
+        let v = json!({"alpha": ["beta", "gamma"], "other": "delta"});
+        let mut sizes = vec![];
+        for flag in [false, true] {
+            let fd = runtime_dictionary(flag);
+            let vd = runtime_dictionary(flag);
+            let mut blob = vec![];
+            encode(&v, &mut blob, &fd, &vd).unwrap();
+            assert_eq!(decode_slice(&blob, &fd, &vd).unwrap(), v);
+            // as references to trait objects as well
+            let (fd, vd): (&dyn DictionaryRead, &dyn DictionaryRead) = (&*fd, &*vd);
+            assert_eq!(decode(&mut blob.as_slice(), &fd, &vd).unwrap(), v);
+            assert!(fd.fingerprint().is_some());
+            sizes.push(blob.len());
+        }
+        assert!(sizes[1] < sizes[0], "{:?}", sizes);
+    }
+
+    #[cfg(feature = "preserve_order")]
+    #[test]
+    fn it_preserves_order_of_keys() {
+        let v: Value = serde_json::from_str(r#"{"z":1,"a":2,"m":{"y":3,"b":4}}"#).unwrap();
+        let nod = NoDictionary {};
+        let mut blob = vec![];
+        encode(&v, &mut blob, &nod, &nod).unwrap();
+        let decoded = decode_slice(&blob, &nod, &nod).unwrap();
+        assert_eq!(
+            serde_json::to_string(&decoded).unwrap(),
+            r#"{"z":1,"a":2,"m":{"y":3,"b":4}}"#
+        );
+    }
+
+    #[test]
+    fn it_finds_checksummed_hex_in_dictionary() {
         let addr = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
-        let just_addr = enc(&json!(addr)).unwrap();
-        assert_eq!(dec(&just_addr).unwrap().as_str().unwrap(), addr);
+        let checksummed = "0x95087266018B9637aFf3D76d4E0Cad7e52c19636";
+        let bare = "95087266018b9637aff3d76d4e0cad7e52c19636";
+
+        // without dictionary the address takes the full 20 bytes
+        assert_eq!(enc(&json!(checksummed)).unwrap().len(), 21);
+
+        let mixed = enc_d(&json!(checksummed)).unwrap();
+        assert_eq!(mixed.len(), 2);
+        assert_eq!(dec_d(&mixed).unwrap().as_str().unwrap(), addr);
+
+        let no_prefix = enc_d(&json!(bare)).unwrap();
+        assert_eq!(no_prefix.len(), 2);
+        assert_eq!(dec_d(&no_prefix).unwrap().as_str().unwrap(), addr);
+
+        let hits = [addr, checksummed, bare]
+            .iter()
+            .filter(|v| enc_d(&json!(v)).unwrap().len() < 21)
+            .count();
+        assert_eq!(hits, 3);
+    }
 
-        let dict_addr = enc_d(&json!(addr)).unwrap();
-        assert_eq!(dec_d(&dict_addr).unwrap().as_str().unwrap(), addr);
+    #[test]
+    fn it_keeps_short_bare_hex_strings() {
+        let vd = MapDictionary::from_strings(vec![
+            "0x01",
+            "0xcafe",
+            "0x2023",
+            "0x95087266018b9637aff3d76d4e0cad7e52c19636",
+        ]);
+        let nod = NoDictionary {};
+        for v in ["1", "2023", "cafe"] {
+            let mut buf = Vec::new();
+            encode(&json!(v), &mut buf, &nod, &vd).unwrap();
+            assert_eq!(decode_slice(&buf, &nod, &vd).unwrap(), json!(v));
+        }
     }
 
     #[test]
@@ -318,30 +1687,433 @@ mod tests {
         assert_eq!(dec(&e).unwrap().as_f64().unwrap(), 1.5e10);
     }
 
+    #[test]
+    fn it_round_trips_f32_floats() {
+        let tiny = f32::from_bits(1) as f64;
+        for (f, len) in [(1.5, 5), (0.1, 9), (f32::MAX as f64, 5), (tiny, 5)] {
+            let buf = enc(&json!(f)).unwrap();
+            assert_eq!(buf.len(), len, "{}", f);
+            assert_eq!(dec(&buf).unwrap().as_f64().unwrap(), f);
+        }
+        // plain f64 of the value f32 holds is not minimal
+        let mut buf = vec![17];
+        buf.extend_from_slice(&1.5f64.to_le_bytes());
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        let nod = NoDictionary {};
+        assert!(decode_slice_with(&buf, &nod, &nod, &strict).is_err());
+        assert_eq!(decode_slice(&buf, &nod, &nod).unwrap(), json!(1.5));
+    }
+
+    #[test]
+    fn it_decodes_non_finite_floats() {
+        let nod = NoDictionary {};
+        let strings = DecodeOptions {
+            non_finite_as_strings: true,
+            ..Default::default()
+        };
+        for (f, name) in [
+            (f64::NAN, "NaN"),
+            (f64::INFINITY, "Infinity"),
+            (f64::NEG_INFINITY, "-Infinity"),
+        ] {
+            let buf = to_vec(&vec![f, 1.5], &nod, &nod).unwrap();
+            assert_eq!(buf[2] & 0x1F, 17);
+            assert_eq!(dec(&buf).unwrap(), json!([null, 1.5]));
+            let decoded = decode_slice_with(&buf, &nod, &nod, &strings).unwrap();
+            assert_eq!(decoded, json!([name, 1.5]));
+            let back: Vec<f64> = from_slice(&buf, &nod, &nod).unwrap();
+            assert_eq!(back[0].to_bits(), f.to_bits());
+            let mut text = vec![];
+            decode_to_writer(&mut buf.as_slice(), &mut text, &nod, &nod).unwrap();
+            assert_eq!(text, b"[null,1.5]");
+        }
+    }
+
+    #[cfg(feature = "arbitrary_precision")]
+    #[test]
+    fn it_round_trips_numbers_over_64_bits() {
+        let two_100 = (1u128 << 100).to_string();
+        for (text, prefix, len) in [
+            (two_100.as_str(), 0x4e, 17),
+            ("-1267650600228229401496703205376", 0xce, 17),
+            ("123456789012345678901234567890", 0x4e, 17),
+            ("1361129467683753853853498429727072845824", 0x54, 42),
+            ("3.14159265358979323846264338327950288", 0x54, 39),
+        ] {
+            let v: Value = serde_json::from_str(&format!("[{}]", text)).unwrap();
+            let buf = enc(&v).unwrap();
+            assert_eq!((buf[2], buf.len() - 2), (prefix, len), "{}", text);
+            assert_eq!(dec(&buf).unwrap(), v);
+            assert_eq!(dec(&buf).unwrap().to_string(), format!("[{}]", text));
+        }
+        // numbers that f64 keeps exactly stay f64
+        let v: Value = serde_json::from_str("1.5").unwrap();
+        assert_eq!(enc(&v).unwrap()[0] & 0x1F, 17);
+    }
+
+    #[cfg(not(feature = "arbitrary_precision"))]
+    #[test]
+    fn it_needs_arbitrary_precision_for_numbers_over_64_bits() {
+        let mut buf = vec![0x4e];
+        buf.extend((1u128 << 100).to_le_bytes());
+        let err = dec(&buf).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "u128 needs the arbitrary_precision feature"
+        );
+    }
+
     #[test]
     fn it_encodes_decodes_array() {
-        let z = enc(&json!([0, 1, 2, 3])).unwrap();
-        let out_z = dec(&z).unwrap().to_string();
-        assert_eq!(out_z, "[0,1,2,3]");
+        let d = MapDictionary::from_static(D);
+        for opts in framings() {
+            let z = enc_with(&json!([0, 1, 2, 3]), &NoDictionary {}, &opts);
+            let out_z = dec(&z).unwrap().to_string();
+            assert_eq!(out_z, "[0,1,2,3]");
 
-        let wd = enc_d(&json!(["alpha", 1, "omega"])).unwrap();
-        println!("{:?}", wd);
-        assert_eq!(dec_d(&wd).unwrap().to_string(), "[\"alpha\",1,\"omega\"]");
+            let wd = enc_with(&json!(["alpha", 1, "omega"]), &d, &opts);
+            assert_eq!(dec_d(&wd).unwrap().to_string(), "[\"alpha\",1,\"omega\"]");
+        }
     }
 
     #[test]
     fn it_encodes_decodes_object() {
-        let i1 = "{\"one\":\"example\",\"two\":1}";
-        let v = Value::from_str(i1).unwrap();
+        let d = MapDictionary::from_static(D);
+        for opts in framings() {
+            let i1 = "{\"one\":\"example\",\"two\":1}";
+            let v = Value::from_str(i1).unwrap();
+            let encoded = enc_with(&v, &NoDictionary {}, &opts);
+            assert_eq!(dec(&encoded).unwrap().to_string(), i1);
+
+            let s2 = "{\"alpha\":\"test\",\"beta\":1,\"epsilon\":{\"gamma\":\"hello\"},\"no\":\"0x01ff\"}";
+            let v2 = Value::from_str(s2).unwrap();
+            let d2 = enc_with(&v2, &d, &opts);
+            assert_eq!(dec_d(&d2).unwrap().to_string(), s2);
+        }
+    }
+
+    #[test]
+    fn it_encodes_decodes_long_keys() {
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        let d = NoDictionary {};
+        for (len, opts) in [10, 255, 256, 1000]
+            .into_iter()
+            .flat_map(|len| framings().map(|opts| (len, opts)))
+        {
+            let key = "k".repeat(len);
+            let v = json!({ key.as_str(): "x".repeat(len) });
+            let encoded = enc_with(&v, &d, &opts);
+            assert_eq!(dec(&encoded).unwrap(), v, "key of {}", len);
+            assert_eq!(
+                decode_with(&mut encoded.as_slice(), &d, &d, &strict).unwrap(),
+                v
+            );
+            // after the do prefix and size, of one byte in both framings
+            let m = decode_object(&mut &encoded[2..], 1, &d, &d).unwrap();
+            assert_eq!(m.get(&key), v.get(&key));
+        }
+    }
+
+    #[test]
+    fn it_packs_arrays_of_numbers() {
+        let bytes: Vec<u8> = (0..1000).map(|i| (i % 256) as u8).collect();
+        let v = json!(bytes);
+        let packed = enc(&v).unwrap();
+        // dwa prefix and length, element type, then the elements
+        assert_eq!(packed.len(), 3 + 1 + 1000);
+        assert_eq!(packed[0], 25 | PACKED);
+        assert_eq!(dec_strict(&packed).unwrap(), v);
+
+        let unpacked: Vec<u8> = [25, 0xe8, 0x03]
+            .into_iter()
+            .chain(bytes.iter().flat_map(|b| match b {
+                0 => vec![18],
+                b => vec![2, *b],
+            }))
+            .collect();
+        assert_eq!(dec(&unpacked).unwrap(), dec(&packed).unwrap());
+
+        for (v, len) in [
+            (json!([0, 1, 2]), 2 + 1 + 3),
+            (json!([70000, 80000, 90000]), 2 + 1 + 3 * 4),
+            (json!([u64::MAX, u64::MAX - 1]), 2 + 1 + 2 * 8),
+            // elements of other widths are smaller with prefixes
+            (json!([1, 300, 70000]), 2 + 2 + 3 + 5),
+            (json!([0.1, 0.2]), 2 + 1 + 2 * 8),
+            // as are f32 floats and negative numbers
+            (json!([1.5, 2.5]), 2 + 2 * 5),
+            (json!([1, -1, 2]), 2 + 2 * 3),
+            (json!([1, 2.5]), 2 + 2 + 5),
+            (json!([1]), 2 + 2),
+        ] {
+            let buf = enc(&v).unwrap();
+            assert_eq!(buf.len(), len, "{}", v);
+            assert_eq!(dec_strict(&buf).unwrap(), v);
+        }
+    }
+
+    #[test]
+    fn it_round_trips_sizes_around_u8() {
+        for len in [254, 255, 256, 257] {
+            let wide = len > 255;
+            let array = json!(vec![1; len]);
+            let encoded = enc(&array).unwrap();
+            // arrays of numbers are packed, with the same sizes
+            assert_eq!(encoded[0] & 0x1F, if wide { 25 } else { 21 });
+            assert_eq!(dec_strict(&encoded).unwrap(), array, "array of {}", len);
+
+            let string = json!("s".repeat(len));
+            let encoded = enc(&string).unwrap();
+            assert_eq!(encoded[0], if wide { 24 } else { 20 });
+            assert_eq!(dec_strict(&encoded).unwrap(), string, "string of {}", len);
+
+            let blob = json!(format!("0x{}", "ab".repeat(len)));
+            let encoded = enc(&blob).unwrap();
+            assert_eq!(encoded[0], if wide { 23 } else { 19 });
+            assert_eq!(dec_strict(&encoded).unwrap(), blob, "bytes of {}", len);
+
+            // varint lengths take one tag, and a second byte from 128
+            let varint = &framings()[1];
+            for (v, tag) in [(&array, 21 | PACKED), (&string, 20), (&blob, 19)] {
+                let encoded = enc_with(v, &NoDictionary {}, varint);
+                assert_eq!(
+                    encoded[..3],
+                    [tag | VARINT, 0x80 | len as u8, (len >> 7) as u8]
+                );
+                assert_eq!(dec_strict(&encoded).unwrap(), *v, "varint of {}", len);
+            }
+        }
+    }
+
+    #[test]
+    fn it_encodes_values_over_64k() {
+        let lorem = "Lorem ipsum dolor sit amet, consectetur adipiscing elit. ";
+        let text = json!(lorem.repeat(100 << 10).get(..100 << 10).unwrap());
+        let encoded = enc(&text).unwrap();
+        assert_eq!(encoded[..5], [28, 0, 0x90, 1, 0]);
+        assert_eq!(dec_strict(&encoded).unwrap(), text);
+
+        let input = json!(format!("0x{}", "60806040".repeat(25 << 10)));
+        let encoded = enc(&input).unwrap();
+        assert_eq!(encoded[..5], [29, 0, 0x90, 1, 0]);
+        assert_eq!(encoded.len(), 5 + (100 << 10));
+        assert_eq!(dec_strict(&encoded).unwrap(), input);
+
+        // the long forms are only minimal above u16
+        assert!(dec_strict(&[28, 1, 0, 0, 0, b'a']).is_err());
+        assert_eq!(dec(&[29, 1, 0, 0, 0, 0xab]).unwrap(), json!("0xab"));
+
+        let varint = &framings()[1];
+        for v in [&text, &input] {
+            let encoded = enc_with(v, &NoDictionary {}, varint);
+            assert_eq!(encoded[1..4], [0x80, 0xa0, 0x06]);
+            assert_eq!(dec_strict(&encoded).unwrap(), *v);
+        }
+        // LEB128 in the fewest bytes
+        assert_eq!(dec(&[20 | VARINT, 1, b'a']).unwrap(), json!("a"));
+        let err = dec(&[20 | VARINT, 0x81, 0, b'a']).unwrap_err();
+        assert_eq!(err.root_cause().to_string(), "varint with trailing zeros");
+    }
+
+    #[test]
+    fn it_encodes_objects_of_any_size() {
+        for len in [255, 256, 1000] {
+            let m: Map<String, Value> = (0..len).map(|i| (format!("k{}", i), json!(i))).collect();
+            let v = Value::Object(m);
+            let encoded = enc(&v).unwrap();
+            assert_eq!(encoded[0], if len > 255 { 26 } else { 22 });
+            assert_eq!(dec(&encoded).unwrap(), v, "{} keys", len);
+            assert_eq!(dec_strict(&encoded).unwrap(), v);
+            let encoded = enc_with(&v, &NoDictionary {}, &framings()[1]);
+            assert_eq!(encoded[0], 22 | VARINT);
+            assert_eq!(dec_strict(&encoded).unwrap(), v);
+        }
+        // sizes over u16 can't be written, unless in varint lengths
+        let mut buf = vec![];
+        let err = encode(
+            &json!(vec![0; 70_000]),
+            &mut buf,
+            &NoDictionary {},
+            &NoDictionary {},
+        );
+        assert_eq!(
+            err.unwrap_err().to_string(),
+            "array of 70000 is too long to encode"
+        );
+        let v = json!(vec![Value::Null; 70_000]);
+        let encoded = enc_with(&v, &NoDictionary {}, &framings()[1]);
+        assert_eq!(encoded.len(), 1 + 3 + 70_000);
+        assert_eq!(dec_strict(&encoded).unwrap(), v);
+        // long strings are not looked up in the dictionary
+        let long = "x".repeat(256);
+        let d = MapDictionary::from_strings(vec![&long]);
+        let mut buf = vec![];
+        encode(&json!(long), &mut buf, &d, &d).unwrap();
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        let out = decode_with(&mut buf.as_slice(), &d, &d, &strict).unwrap();
+        assert_eq!(out, json!(long));
+    }
+
+    #[test]
+    fn it_encodes_numeric_keys() {
+        let mut m = Map::new();
+        for i in 1..=1000u64 {
+            m.insert(i.to_string(), json!(1));
+        }
+        let numeric = Value::Object(m.clone());
+        let encoded = enc(&numeric).unwrap();
+        assert_eq!(dec(&encoded).unwrap(), numeric);
+        // inline strings take 2 bytes of prefix and the digits
+        let strings: usize = m.keys().map(|k| 2 + k.len()).sum();
+        let keys: usize = (1..=1000).map(|i| if i <= 255 { 2 } else { 3 }).sum();
+        assert_eq!(encoded.len(), 3 + keys + 2 * 1000);
+        assert_eq!((strings, keys), (4893, 2745));
+
+        // leading zeros and numbers beyond u64 stay strings
+        let v =
+            json!({"0": 1, "007": 2, "18446744073709551615": 3, "18446744073709551616": 4, "": 5});
         let encoded = enc(&v).unwrap();
-        assert_eq!(dec(&encoded).unwrap().to_string(), i1);
+        assert_eq!(dec(&encoded).unwrap(), v);
+        assert_eq!(dec_strict(&encoded).unwrap(), v);
+        assert_eq!(dec_strict(&enc(&numeric).unwrap()).unwrap(), numeric);
+        // "" and "007" inline, "0" is one byte, u64::MAX is nine, the overflow is inline
+        assert_eq!(encoded.len(), 2 + 2 + 1 + 5 + 9 + 22 + 5 * 2);
+    }
+
+    #[test]
+    fn it_encodes_field_ids_of_every_width() {
+        let mut d = MapDictionary::new();
+        d.insert_as("short", 10);
+        d.insert_as("middle", 300);
+        d.insert_as("long", 70_000);
+        for (field, id, width, len) in [
+            ("short", 10, IdWidth::U8, 1),
+            ("middle", 300, IdWidth::U16, 2),
+            ("long", 70_000, IdWidth::U32, 4),
+        ] {
+            let v = json!({ field: true });
+            let mut encoded = vec![];
+            encode(&v, &mut encoded, &d, &d).unwrap();
+            // do prefix and size, the key, the value
+            assert_eq!(encoded.len(), 2 + 1 + len + 1, "{}", field);
+            assert_eq!(IdWidth::from_prefix(encoded[2]), Some(width));
+            assert_eq!(IdWidth::of(id), width);
+            assert_eq!(dec_with(&encoded, &d).unwrap(), v);
+            let strict = DecodeOptions {
+                strict_minimal: true,
+                ..Default::default()
+            };
+            assert_eq!(
+                decode_with(&mut encoded.as_slice(), &d, &d, &strict).unwrap(),
+                v
+            );
+        }
+        let v = json!({"short": 1, "middle": [{"long": 2}]});
+        let mut encoded = vec![];
+        encode(&v, &mut encoded, &d, &d).unwrap();
+        assert_eq!(dec_with(&encoded, &d).unwrap(), v);
+    }
+
+    #[test]
+    fn it_encodes_value_ids_of_every_width() {
+        let mut d = MapDictionary::new();
+        d.insert_as("short", 10);
+        d.insert_as("middle", 300);
+        let hash = format!("0x{}", "ab".repeat(32));
+        d.insert_as(&hash, 70_000);
+        let nod = NoDictionary {};
+        let strict = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        for (value, width, len) in [
+            ("short", IdWidth::U8, 1),
+            ("middle", IdWidth::U16, 2),
+            (hash.as_str(), IdWidth::U32, 4),
+        ] {
+            let mut encoded = vec![];
+            encode(&json!(value), &mut encoded, &nod, &d).unwrap();
+            assert_eq!(encoded.len(), 1 + len, "{}", value);
+            assert_eq!(IdWidth::from_prefix(encoded[0]), Some(width));
+            let out = decode_with(&mut encoded.as_slice(), &nod, &d, &strict).unwrap();
+            assert_eq!(out, json!(value));
+        }
+
+        // references written before widths were signaled have u32 ids
+        let legacy = [20 | 0x20, 44, 1, 0, 0];
+        assert_eq!(dec_with(&legacy, &d).unwrap(), json!("middle"));
+        let err = decode_with(&mut legacy.as_slice(), &nod, &d, &strict).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "non-minimal encoding at '': expected u16 value id, found u32 value id without width"
+        );
 
-        let s2 =
-            "{\"alpha\":\"test\",\"beta\":1,\"epsilon\":{\"gamma\":\"hello\"},\"no\":\"0x01ff\"}";
-        let v2 = Value::from_str(s2).unwrap();
-        let d2 = enc_d(&v2).unwrap();
-        println!("{}", hex::encode(&d2));
-        assert_eq!(dec_d(&d2).unwrap().to_string(), s2);
+        let missing = [20 | 0x20 | IdWidth::U8.bits(), 11];
+        let err = decode(&mut missing.as_slice(), &nod, &d).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "value 11 not found in dictionary"
+        );
+    }
+
+    #[test]
+    fn it_locates_errors_by_offset_and_path() {
+        let d = NoDictionary {};
+        let mut block: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/block.json")).unwrap();
+        block["transactions"] = (0..20)
+            .map(|i| json!({"hash": format!("0x{:064x}", i), "input": format!("0xa9059cbb{:064x}", i)}))
+            .collect();
+        let v = json!({"id": 1, "result": block});
+        let mut buf = enc(&v).unwrap();
+        // the prefix of the 17th input, two bytes before its selector
+        let mut selectors =
+            (0..buf.len() - 4).filter(|&i| buf[i..i + 4] == [0xa9, 0x05, 0x9c, 0xbb]);
+        let at = selectors.nth(17).unwrap() - 2;
+        assert_eq!(buf[at..at + 2], [19, 36]);
+        buf[at] = 0x1e;
+
+        let expected = format!(
+            "at offset {}, path '/result/transactions/17/input': invalid field type",
+            at
+        );
+        let err = decode(&mut buf.as_slice(), &d, &d).unwrap_err();
+        assert_eq!(format!("{:#}", err), expected);
+        let err = decode_to_writer(&mut buf.as_slice(), &mut std::io::sink(), &d, &d).unwrap_err();
+        assert_eq!(format!("{:#}", err), expected);
+        let err = decode_path(&buf, "result.transactions.17.input", &d, &d).unwrap_err();
+        assert_eq!(format!("{:#}", err), expected);
+        // truncated in the middle of a key
+        let err = decode_slice(&buf[..at - 3], &d, &d).unwrap_err();
+        assert!(
+            format!("{:#}", err).starts_with(&format!(
+                "at offset {}, path '/result/transactions/17'",
+                at - 7
+            )),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn it_keeps_hex_looking_keys_as_strings() {
+        let address = "0xdeadbeefdeadbeefdeadbeefdeadbeefdeadbeef";
+        let v = json!({"0": 1, "0x1234": 2, "0xABC": 3, address: 4});
+        let encoded = enc(&v).unwrap();
+        assert_eq!(dec(&encoded).unwrap(), v);
+        assert_eq!(dec_strict(&encoded).unwrap(), v);
+        // the address is a value in the sample dictionary, keys don't use it
+        let v = json!({"0x95087266018b9637aff3d76d4e0cad7e52c19636": [address]});
+        assert_eq!(dec_d(&enc_d(&v).unwrap()).unwrap(), v);
     }
 
     #[test]
@@ -354,5 +2126,817 @@ mod tests {
         let v2 = Value::from_str("{\"type\": \"BigNumber\", \"hex\": \"0xeeddcc1ff\"}").unwrap();
         let bn2 = enc(&v2).unwrap();
         assert_eq!(dec(&bn2).unwrap().as_str().unwrap(), "0x0eeddcc1ff");
+
+        // payloads over 255 bytes take the dwb form instead of a truncated size
+        let hex = format!("0x{}", "ab".repeat(300));
+        let v3 = json!({"type": "BigNumber", "hex": hex});
+        let bn3 = enc(&v3).unwrap();
+        assert_eq!(bn3[0] & 0x1F, 23);
+        assert_eq!(bn3.len(), 3 + 300);
+        assert_eq!(dec(&bn3).unwrap().as_str().unwrap(), hex);
+        let opts = DecodeOptions {
+            restore_bignumber: true,
+            ..Default::default()
+        };
+        let nod = NoDictionary {};
+        let decoder = Decoder::new(&nod, &nod).with_options(opts);
+        assert_eq!(decoder.decode(&mut bn3.as_slice()).unwrap(), v3);
+    }
+
+    #[test]
+    fn it_formats_output_as_options_ask() {
+        let d = MapDictionary::from_static(D);
+        let v = json!({"alpha": "0x01ab", "beta": {"type": "BigNumber", "hex": "0x1ff"}});
+        let buf = enc_d(&v).unwrap();
+        let decoded = |hex_uppercase, restore_bignumber| {
+            let opts = DecodeOptions {
+                hex_uppercase,
+                restore_bignumber,
+                ..Default::default()
+            };
+            let decoder = Decoder::new(&d, &d).with_options(opts);
+            let value = decoder.decode(&mut buf.as_slice()).unwrap();
+            let mut text = vec![];
+            decoder
+                .decode_to_writer(&mut buf.as_slice(), &mut text)
+                .unwrap();
+            assert_eq!(serde_json::from_slice::<Value>(&text).unwrap(), value);
+            value
+        };
+        let plain = json!({"alpha": "0x01ab", "beta": "0x01ff"});
+        let bn = json!({"type": "BigNumber", "hex": "0x01ff"});
+        assert_eq!(decoded(false, false), plain);
+        assert_eq!(
+            decoded(true, false),
+            json!({"alpha": "0x01AB", "beta": "0x01FF"})
+        );
+        assert_eq!(decoded(false, true), json!({"alpha": "0x01ab", "beta": bn}));
+        // hex of BigNumber stays as ethers writes it
+        assert_eq!(decoded(true, true), json!({"alpha": "0x01AB", "beta": bn}));
+
+        // wrappers decode with the defaults
+        let mut input = buf.as_slice();
+        assert_eq!(decode(&mut input, &d, &d).unwrap(), plain);
+        // after the prefix and the size of the object
+        let mut input = &buf[2..];
+        let m = decode_object(&mut input, 2, &d, &d).unwrap();
+        assert_eq!(Value::Object(m), plain);
+    }
+
+    #[test]
+    fn it_checksums_addresses() {
+        let nod = NoDictionary {};
+        let checksum = DecodeOptions {
+            checksum_addresses: true,
+            hex_uppercase: true,
+            ..Default::default()
+        };
+        // vectors of EIP-55
+        for addr in [
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed",
+            "0xfB6916095ca1df60bB79Ce92cE3Ea74c37c5d359",
+            "0xdbF03B407c01E7cD3CBea99509d93f8DDDC8C6FB",
+            "0xD1220A0cf47c7B9Be7A2E6BA89F429762e7b9aDb",
+        ] {
+            let lower = addr.to_lowercase();
+            let buf = enc(&json!(lower)).unwrap();
+            assert_eq!(buf[0], 15);
+            assert_eq!(enc(&json!(addr)).unwrap(), buf);
+            let decoded = decode_slice_with(&buf, &nod, &nod, &checksum).unwrap();
+            assert_eq!(decoded, json!(addr));
+            assert_eq!(dec(&buf).unwrap(), json!(lower));
+        }
+        // other widths follow hex_uppercase
+        let hash = "0xd8052f44b36869fa1f193ec2c97e6a36892840635dc347554efb8778a7a3935a";
+        for hex in ["0x01ab", "0x0102030405060708", hash] {
+            let buf = enc(&json!(hex)).unwrap();
+            let decoded = decode_slice_with(&buf, &nod, &nod, &checksum).unwrap();
+            assert_eq!(decoded, json!(hex.to_uppercase().replace("0X", "0x")));
+        }
+    }
+
+    #[test]
+    fn it_writes_hex_quantities_as_numbers() {
+        let nod = NoDictionary {};
+        let numbers = DecodeOptions {
+            hex_quantities_as_numbers: true,
+            ..Default::default()
+        };
+        let buf = enc(&json!("0x100")).unwrap();
+        assert_eq!(dec(&buf).unwrap(), json!("0x0100"));
+        let decoded = decode_slice_with(&buf, &nod, &nod, &numbers).unwrap();
+        assert_eq!(decoded, json!(256));
+
+        let hash = "0xd8052f44b36869fa1f193ec2c97e6a36892840635dc347554efb8778a7a3935a";
+        let receipt = json!({
+            "blockNumber": "0x11ab4f",
+            "gasUsed": "0x5208",
+            "status": "0x1",
+            "nonce": "0xffffffffffffffff",
+            "wide": "0x01000000000000000000000000000000",
+            "address": "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            "blockHash": hash,
+            "input": "0x",
+            "logs": ["0x01ab", "transfer"],
+        });
+        let buf = enc(&receipt).unwrap();
+        let decoded = decode_slice_with(&buf, &nod, &nod, &numbers).unwrap();
+        assert_eq!(
+            decoded,
+            json!({
+                "blockNumber": 1157967,
+                "gasUsed": 21000,
+                "status": 1,
+                "nonce": u64::MAX,
+                // B128 and wider, addresses and hashes stay hex
+                "wide": "0x01000000000000000000000000000000",
+                "address": "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+                "blockHash": hash,
+                "input": "0x",
+                "logs": [427, "transfer"],
+            })
+        );
+    }
+
+    #[test]
+    fn it_restores_bignumbers() {
+        let nod = NoDictionary {};
+        let restore = DecodeOptions {
+            restore_bignumber: true,
+            ..Default::default()
+        };
+        let word = format!("0x{}", "ab".repeat(32));
+        for (hex, expected) in [
+            ("0x1ff", "0x01ff"),
+            ("0x01ff", "0x01ff"),
+            ("0x0", "0x00"),
+            ("0x00", "0x00"),
+            ("0x000001", "0x01"),
+            (word.as_str(), word.as_str()),
+        ] {
+            let bn = |hex| json!({"type": "BigNumber", "hex": hex});
+            let buf = enc(&bn(hex)).unwrap();
+            let restored = decode_slice_with(&buf, &nod, &nod, &restore).unwrap();
+            assert_eq!(restored, bn(expected));
+            // ethers output encodes to the same bytes
+            assert_eq!(enc(&restored).unwrap(), enc(&bn(expected)).unwrap());
+        }
+        // only values that were BigNumber objects are restored
+        let v = json!(["0x01ff", {"type": "BigNumber", "hex": "0x01ff"}]);
+        let buf = enc(&v).unwrap();
+        assert_eq!(decode_slice_with(&buf, &nod, &nod, &restore).unwrap(), v);
+        assert_eq!(dec(&buf).unwrap(), json!(["0x01ff", "0x01ff"]));
+    }
+
+    #[test]
+    fn it_keeps_objects_that_are_not_bignumbers() {
+        let nod = NoDictionary {};
+        let restore = DecodeOptions {
+            restore_bignumber: true,
+            ..Default::default()
+        };
+        for v in [
+            json!({"foo": 1, "hex": "0x12"}),
+            json!({"type": 5, "hex": "0x12"}),
+            json!({"type": "Other", "hex": "0x12"}),
+            json!({"type": "BigNumber", "hex": "0x12", "extra": true}),
+        ] {
+            let buf = enc(&v).unwrap();
+            assert_eq!(dec(&buf).unwrap(), v);
+            assert_eq!(decode_slice_with(&buf, &nod, &nod, &restore).unwrap(), v);
+        }
+    }
+
+    fn dec_strict(input: &[u8]) -> anyhow::Result<Value> {
+        let d = MapDictionary::from_static(D);
+        let opts = DecodeOptions {
+            strict_minimal: true,
+            ..Default::default()
+        };
+        decode_with(&mut BufReader::new(input), &d, &d, &opts)
+    }
+
+    fn non_minimal(input: &[u8]) -> DecodeError {
+        dec_strict(input)
+            .unwrap_err()
+            .downcast::<DecodeError>()
+            .unwrap()
+    }
+
+    fn expect(path: &str, expected: &str, found: &str) -> DecodeError {
+        DecodeError::NonMinimalEncoding {
+            path: path.to_string(),
+            expected: expected.to_string(),
+            found: found.to_string(),
+        }
+    }
+
+    #[test]
+    fn it_rejects_non_minimal_encodings() {
+        // 5 as u32
+        assert_eq!(non_minimal(&[7, 5, 0, 0, 0]), expect("", "i8", "u32"));
+        // empty array in the wide form
+        assert_eq!(non_minimal(&[25, 0, 0]), expect("", "da", "dwa"));
+        // {"x": [1, 7 as u16]}
+        let nested = [22, 1, 20, 1, b'x', 21, 2, 2, 1, 5, 7, 0];
+        assert_eq!(non_minimal(&nested), expect("/x/1", "i8", "u16"));
+        assert_eq!(dec_d(&nested).unwrap(), json!({"x": [1, 7]}));
+        // field id 1 written as u16
+        let key = [22, 1, 0x85, 1, 0, 18];
+        assert_eq!(
+            non_minimal(&key),
+            expect("/alpha", "u8 field id", "u16 field id")
+        );
+        // known value written inline
+        let inline = [21, 1, 20, 4, b'b', b'e', b't', b'a'];
+        assert_eq!(
+            non_minimal(&inline),
+            expect("/0", "value dictionary reference", "ds")
+        );
+    }
+
+    #[test]
+    fn it_accepts_minimal_encodings_in_strict_mode() {
+        let v = json!({"alpha": [0, 5, 300, -1, -300, 1.5, "beta", "other", "0x01ff"]});
+        assert_eq!(
+            dec_strict(&enc_d(&v).unwrap()).unwrap(),
+            dec_d(&enc_d(&v).unwrap()).unwrap()
+        );
+    }
+
+    mod props {
+        use super::*;
+        use proptest::prelude::*;
+
+        fn arb_value() -> impl Strategy<Value = Value> {
+            let leaf = prop_oneof![
+                Just(Value::Null),
+                any::<bool>().prop_map(Value::from),
+                any::<u64>().prop_map(Value::from),
+                any::<i64>().prop_map(Value::from),
+                any::<f64>()
+                    .prop_filter("finite", |f| f.is_finite())
+                    .prop_map(Value::from),
+                "[a-z ]{0,40}".prop_map(Value::from),
+                "0x[0-9a-f]{1,100}".prop_map(Value::from),
+                prop::sample::select(D).prop_map(Value::from),
+            ];
+            leaf.prop_recursive(4, 64, 8, |inner| {
+                prop_oneof![
+                    prop::collection::vec(inner.clone(), 0..8).prop_map(Value::from),
+                    prop::collection::btree_map(
+                        prop_oneof!["[a-z]{1,8}", prop::sample::select(D).prop_map(String::from)],
+                        inner,
+                        0..8
+                    )
+                    .prop_map(|m| Value::Object(m.into_iter().collect())),
+                ]
+            })
+        }
+
+        proptest! {
+            #[test]
+            fn canonical_encoding_passes_strict_decode(v in arb_value()) {
+                let buf = enc_d(&v).unwrap();
+                prop_assert!(dec_strict(&buf).is_ok());
+                let d = MapDictionary::from_static(D);
+                let varint = enc_with(&v, &d, &framings()[1]);
+                prop_assert_eq!(dec_strict(&varint).unwrap(), dec_d(&buf).unwrap());
+            }
+
+            #[test]
+            fn encoded_size_is_length_of_encoding(v in arb_value()) {
+                let d = MapDictionary::from_static(D);
+                prop_assert_eq!(encoded_size(&v, &d, &d).unwrap(), enc_d(&v).unwrap().len());
+                prop_assert_eq!(encoded_size(&v, &NoDictionary {}, &NoDictionary {}).unwrap(), enc(&v).unwrap().len());
+                let varint = Encoder::new(&d, &d).with_options(framings()[1].clone());
+                prop_assert_eq!(varint.encoded_size(&v).unwrap(), enc_with(&v, &d, &framings()[1]).len());
+            }
+        }
+    }
+
+    #[test]
+    fn it_reads_values_longer_than_one_read() {
+        let nod = NoDictionary {};
+        for len in [0, 255, 256, 65_535, 65_536, 65_537, 200_000] {
+            let hex = format!("0x{}", "ab".repeat(len));
+            let v = json!(["x".repeat(len), hex]);
+            let buf = enc(&v).unwrap();
+            assert_eq!(decode(&mut buf.as_slice(), &nod, &nod).unwrap(), v);
+            // the stream ends in the middle of the last value
+            let cut = &buf[..buf.len() - 1];
+            let err = decode(&mut &cut[..], &nod, &nod).unwrap_err();
+            let eof = err.downcast_ref::<std::io::Error>().map(|e| e.kind());
+            assert_eq!(eof, Some(std::io::ErrorKind::UnexpectedEof), "{}", len);
+        }
+    }
+
+    #[test]
+    fn it_learns_keys_while_encoding() {
+        let opts = EncodeOptions::default();
+        let log = |i: u64| {
+            json!({
+                "address": "0x95087266018b9637aff3d76d4e0cad7e52c19636",
+                "blockNumber": format!("0x{:08x}", 17_000_000 + i),
+                "logIndex": i,
+                "removed": false,
+                "topics": ["0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef"],
+                "transactionHash": format!("0x{:064x}", i),
+                "12": "numeric keys are not learned",
+            })
+        };
+        let mut fd = MapDictionary::from_static(&["address"]);
+        let mut vd = MapDictionary::new();
+        let before = fd.clone();
+
+        let mut first = vec![];
+        let learned = encode_learning(&log(1), &mut first, &mut fd, &mut vd, &opts, false).unwrap();
+        let keys = [
+            "blockNumber",
+            "logIndex",
+            "removed",
+            "topics",
+            "transactionHash",
+        ];
+        let expected: Vec<(u32, String)> = (2..).zip(keys.map(String::from)).collect();
+        assert_eq!(learned.fields, expected);
+        assert!(learned.values.is_empty());
+
+        let mut second = vec![];
+        let learned =
+            encode_learning(&log(2), &mut second, &mut fd, &mut vd, &opts, false).unwrap();
+        assert_eq!(learned, Learned::default());
+        // every key but the numeric one is an id now
+        let saved: usize = keys.iter().map(|k| k.len()).sum();
+        assert_eq!(first.len() - second.len(), saved);
+
+        // the first blob decodes with the dictionary as it was, or as it is
+        let nod = NoDictionary {};
+        assert_eq!(decode_slice(&first, &before, &nod).unwrap(), log(1));
+        assert_eq!(decode_slice(&first, &fd, &vd).unwrap(), log(1));
+        assert_eq!(decode_slice(&second, &fd, &vd).unwrap(), log(2));
+    }
+
+    #[test]
+    fn it_learns_repeated_values_on_request() {
+        let opts = EncodeOptions::default();
+        let transfer = "0xDDF252AD1BE2C89B69C2B068FC378DAA952BA7F163C4A11628F55A4DF523B3EF";
+        let v = json!([
+            {"status": "pending", "topic": transfer, "id": "abc"},
+            {"status": "pending", "topic": transfer, "id": "abc"},
+            {"status": "done", "topic": transfer, "id": "abc"},
+        ]);
+        let (mut fd, mut vd) = (MapDictionary::new(), MapDictionary::new());
+        let mut first = vec![];
+        let learned = encode_learning(&v, &mut first, &mut fd, &mut vd, &opts, true).unwrap();
+        // short strings and strings seen once are not learned, hex is learned lowercase
+        let expected = vec![(1, "pending".to_string()), (2, transfer.to_lowercase())];
+        assert_eq!(learned.values, expected);
+
+        let mut second = vec![];
+        encode_learning(&v, &mut second, &mut fd, &mut vd, &opts, true).unwrap();
+        assert!(second.len() < first.len());
+        let decoded = decode_slice(&second, &fd, &vd).unwrap();
+        assert_eq!(decoded[1]["status"], json!("pending"));
+        assert_eq!(decoded[0]["topic"], json!(transfer.to_lowercase()));
+    }
+
+    #[test]
+    fn it_returns_bytes_written() {
+        let d = MapDictionary::from_static(D);
+        let nod = NoDictionary {};
+        let long = "x".repeat(70000);
+        for v in [
+            json!(null),
+            json!(-70000),
+            json!("0x01ff"),
+            json!([1, "two", [3.5]]),
+            json!({"alpha": "beta", "gamma": {"delta": [true]}}),
+            json!(long),
+        ] {
+            // after another document, the count is of this one only
+            let mut buf = vec![0u8; 5];
+            let n = encode(&v, &mut buf, &d, &d).unwrap();
+            assert_eq!(n, buf.len() - 5, "{}", v);
+            let mut buf = vec![];
+            let n = encode(&v, &mut buf, &nod, &nod).unwrap();
+            assert_eq!(n, buf.len(), "{}", v);
+        }
+        let mut buf = vec![];
+        let n = encode_with_fingerprint(&json!({"alpha": 1}), &mut buf, &d, &d).unwrap();
+        assert_eq!(n, buf.len());
+    }
+
+    #[test]
+    fn it_sizes_fixtures_as_encoded() {
+        let d = MapDictionary::from_static(D);
+        let nod = NoDictionary {};
+        let preserve = EncodeOptions {
+            preserve_hex_width: true,
+            ..Default::default()
+        };
+        for fixture in [
+            include_str!("../tests/fixtures/block.json"),
+            include_str!("../tests/fixtures/logs.json"),
+            include_str!("../tests/fixtures/receipts.json"),
+        ] {
+            let v: Value = serde_json::from_str(fixture).unwrap();
+            assert_eq!(encoded_size(&v, &d, &d).unwrap(), enc_d(&v).unwrap().len());
+            assert_eq!(
+                encoded_size(&v, &nod, &nod).unwrap(),
+                enc(&v).unwrap().len()
+            );
+            let encoder = Encoder::new(&nod, &nod).with_options(preserve.clone());
+            let mut buf = vec![];
+            encoder.encode(&v, &mut buf).unwrap();
+            assert_eq!(encoder.encoded_size(&v).unwrap(), buf.len());
+        }
+        let bn = json!({"type": "BigNumber", "hex": "0x1ff"});
+        assert_eq!(encoded_size(&bn, &nod, &nod).unwrap(), 4);
+        let wide = json!(vec![1; 300]);
+        assert_eq!(encoded_size(&wide, &nod, &nod).unwrap(), 3 + 1 + 300);
+    }
+
+    #[test]
+    fn it_verifies_dictionary_fingerprints() {
+        let d = MapDictionary::from_static(D);
+        let v = json!({"alpha": ["beta", 1]});
+        let mut buf = Vec::new();
+        encode_with_fingerprint(&v, &mut buf, &d, &d).unwrap();
+        assert_eq!(fingerprints(&buf), Some((d.fingerprint(), d.fingerprint())));
+        assert_eq!(dec_d(&buf).unwrap(), v);
+
+        // next generation of the value dictionary
+        let mut other = MapDictionary::from_static(D);
+        other.insert("zeta");
+        let err = decode(&mut BufReader::new(buf.as_slice()), &d, &other).unwrap_err();
+        assert_eq!(
+            err.downcast::<DecodeError>().unwrap(),
+            DecodeError::DictionaryFingerprintMismatch {
+                dictionary: "value",
+                expected: d.fingerprint(),
+                got: other.fingerprint(),
+            }
+        );
+
+        // receiver picks the generation by the fingerprint
+        let mut store = DictionaryStore::new();
+        store.add(other);
+        store.add(MapDictionary::from_static(D));
+        let (_, vf) = fingerprints(&buf).unwrap();
+        let picked = store.by_fingerprint(&vf).unwrap();
+        let out = decode(&mut BufReader::new(buf.as_slice()), &d, picked).unwrap();
+        assert_eq!(out, v);
     }
+
+    #[test]
+    fn it_decodes_legacy_blobs_without_fingerprints() {
+        let v = json!({"alpha": ["beta", 1]});
+        let buf = enc_d(&v).unwrap();
+        assert_eq!(fingerprints(&buf), None);
+        assert_eq!(dec_d(&buf).unwrap(), v);
+    }
+
+    #[test]
+    fn it_finds_values_in_bytes_dictionary() {
+        let addr = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
+        let checksummed = "0x95087266018B9637aFf3D76d4E0Cad7e52c19636";
+        let bare = "95087266018b9637aff3d76d4e0cad7e52c19636";
+        let vd = BytesDictionary::from_hex_strings(&[addr]).unwrap();
+        let nod = NoDictionary {};
+        for v in [addr, checksummed] {
+            let mut buf = Vec::new();
+            encode(&json!(v), &mut buf, &nod, &vd).unwrap();
+            assert_eq!(buf.len(), 2);
+            let out = decode(&mut BufReader::new(buf.as_slice()), &nod, &vd).unwrap();
+            assert_eq!(out, json!(addr));
+        }
+        // bytes are found by 0x-prefixed values only, other strings stay as they are
+        let mut buf = Vec::new();
+        encode(&json!(bare), &mut buf, &nod, &vd).unwrap();
+        let out = decode(&mut BufReader::new(buf.as_slice()), &nod, &vd).unwrap();
+        assert_eq!(out, json!(bare));
+    }
+
+    #[test]
+    fn it_finds_raw_bytes_in_map_dictionary() {
+        let topic = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+        let mut vd = MapDictionary::from_strings(vec!["transfer"]);
+        vd.insert_bytes(&hex::decode(&topic[2..]).unwrap());
+        assert_eq!(vd.find_hex(topic), None);
+        assert_eq!(vd.find_bytes(&hex::decode(&topic[2..]).unwrap()), Some(2));
+        let nod = NoDictionary {};
+        let v = json!({"topics": [topic, topic.to_uppercase().replace("0X", "0x")], "event": "transfer"});
+        let mut buf = Vec::new();
+        encode(&v, &mut buf, &nod, &vd).unwrap();
+        let mut inline = Vec::new();
+        encode(&v, &mut inline, &nod, &nod).unwrap();
+        assert_eq!(inline.len() - buf.len(), 2 * (33 - 2) + (10 - 2));
+        // references to the bytes decode as 0x-prefixed hex
+        let out = decode(&mut buf.as_slice(), &nod, &vd).unwrap();
+        assert_eq!(out, json!({"topics": [topic, topic], "event": "transfer"}));
+        assert_eq!(decode_slice(&buf, &nod, &vd).unwrap(), out);
+    }
+
+    #[test]
+    fn it_finds_addresses_and_hashes_in_value_dictionary() {
+        let address = "0x95087266018b9637aff3d76d4e0cad7e52c19636";
+        let topic = "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+        let other = "0x5087266018b9637aff3d76d4e0cad7e52c196369";
+        let mut d = MapDictionary::new();
+        d.insert_as(address, 3);
+        d.insert_as(topic, 300);
+        d.insert_as("0x01", 4);
+        d.insert_as("0x0102", 70_000);
+        let size = |v: &str| {
+            let mut buf = vec![];
+            encode(&json!(v), &mut buf, &NoDictionary {}, &d).unwrap();
+            assert_eq!(dec_with(&buf, &d).unwrap(), json!(v));
+            buf.len()
+        };
+        // references instead of b160 and b256
+        assert_eq!(size(address), 2);
+        assert_eq!(size(topic), 3);
+        assert_eq!(size(other), 21);
+        // packed values that are not larger than the reference stay packed
+        assert_eq!(size("0x01"), 2);
+        assert_eq!(size("0x0102"), 3);
+
+        let log = json!({"address": address, "topics": [topic, other]});
+        let mut buf = vec![];
+        encode(&log, &mut buf, &NoDictionary {}, &d).unwrap();
+        assert_eq!(
+            decode(&mut buf.as_slice(), &NoDictionary {}, &d).unwrap(),
+            log
+        );
+    }
+
+    #[test]
+    fn it_keeps_addresses_compact_in_bytes_dictionary() {
+        let addresses: Vec<String> = (0..100_000u64)
+            .map(|i| {
+                let x = i.wrapping_mul(0x9e3779b97f4a7c15);
+                format!("0x{:016x}{:016x}{:08x}", x, !x, i as u32)
+            })
+            .collect();
+        let mut md = MapDictionary::new();
+        let mut bd = BytesDictionary::new();
+        for a in &addresses {
+            md.insert(a);
+            bd.insert_hex(a).unwrap();
+        }
+        let stored = |d: &dyn Fn(u32) -> usize| (1..=100_000).map(d).sum::<usize>();
+        let md_size = stored(&|id| md.get(id).unwrap().len());
+        let bd_size = stored(&|id| bd.get(id).unwrap().len());
+        assert_eq!(md_size, 42 * 100_000);
+        assert_eq!(bd_size, 20 * 100_000);
+
+        let nod = NoDictionary {};
+        let (mut md_hits, mut bd_hits) = (0, 0);
+        for (a, id) in addresses.iter().zip(1..) {
+            let v = json!(a);
+            let mut m = Vec::new();
+            encode(&v, &mut m, &nod, &md).unwrap();
+            let mut b = Vec::new();
+            encode(&v, &mut b, &nod, &bd).unwrap();
+            let reference = match IdWidth::of(id) {
+                IdWidth::U8 => 2,
+                IdWidth::U16 => 3,
+                IdWidth::U32 => 5,
+            };
+            md_hits += (m.len() == reference) as usize;
+            bd_hits += (b.len() == reference) as usize;
+            let out_m = decode(&mut BufReader::new(m.as_slice()), &nod, &md).unwrap();
+            let out_b = decode(&mut BufReader::new(b.as_slice()), &nod, &bd).unwrap();
+            assert_eq!(out_m, v);
+            assert_eq!(out_b, v);
+        }
+        assert_eq!(md_hits, 100_000);
+        assert_eq!(bd_hits, 100_000);
+    }
+
+    #[test]
+    fn it_reports_decode_stats() {
+        let d = MapDictionary::from_static(D);
+        let v = json!({"alpha": ["beta", 1, {"gamma": null}], "other": "0x01ff"});
+        let encoded = enc_d(&v).unwrap();
+        let (value, stats) =
+            decode_with_stats(&mut encoded.as_slice(), &d, &d, &DecodeOptions::default());
+        assert_eq!(value.unwrap(), v);
+        assert_eq!(
+            stats,
+            DecodeStats {
+                bytes: encoded.len() as u64,
+                values: 7,
+                // alpha, beta, gamma
+                dictionary_hits: 3,
+                dictionary_misses: 0,
+            }
+        );
+
+        let empty = MapDictionary::from_static(&[]);
+        let (value, stats) = decode_with_stats(
+            &mut encoded.as_slice(),
+            &empty,
+            &d,
+            &DecodeOptions::default(),
+        );
+        assert!(value.is_err());
+        assert_eq!((stats.dictionary_hits, stats.dictionary_misses), (0, 1));
+    }
+
+    // runs in a child process, so that the output of decoding is not captured
+    #[test]
+    fn it_decodes_without_printing() {
+        if std::env::var_os("JSONDP_DECODE_CHILD").is_some() {
+            let d = MapDictionary::from_static(D);
+            let v = json!({"alpha": [{"beta": [1, "gamma", 1.5]}, "0x95087266018b9637aff3d76d4e0cad7e52c19636"]});
+            let encoded = enc_d(&v).unwrap();
+            for _ in 0..10 {
+                assert_eq!(decode(&mut encoded.as_slice(), &d, &d).unwrap(), v);
+                let object = &encoded[2..];
+                decode_object(&mut &object[..], 1, &d, &d).unwrap();
+            }
+            return;
+        }
+        let output = std::process::Command::new(std::env::current_exe().unwrap())
+            .args(["tests::it_decodes_without_printing", "--exact"])
+            .args(["--nocapture", "--test-threads=1"])
+            .env("JSONDP_DECODE_CHILD", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        // only lines of the test harness
+        for line in stdout.lines() {
+            assert!(
+                line.is_empty() || line.starts_with("running ") || line.starts_with("test "),
+                "decoding printed {:?}",
+                line
+            );
+        }
+    }
+
+    // `depth` one-element arrays around `false`
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut blob = [21, 1].repeat(depth);
+        blob.push(0);
+        blob
+    }
+
+    #[test]
+    fn it_limits_nesting_depth() {
+        let d = NoDictionary {};
+        let err = decode(&mut nested(10_000).as_slice(), &d, &d).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::MaxDepthExceeded { offset: 256 })
+        );
+        assert_eq!(
+            err.root_cause().to_string(),
+            "max nesting depth exceeded at offset 256"
+        );
+        assert!(decode(&mut nested(128).as_slice(), &d, &d).is_ok());
+        assert!(decode(&mut nested(129).as_slice(), &d, &d).is_err());
+
+        let deep = DecodeOptions {
+            max_depth: 300,
+            ..Default::default()
+        };
+        let value = decode_with(&mut nested(200).as_slice(), &d, &d, &deep).unwrap();
+        assert_eq!(value.pointer(&"/0".repeat(200)), Some(&json!(false)));
+
+        // an object of one field with nested arrays
+        let mut object = vec![20, 1, b'x'];
+        object.extend(nested(10_000));
+        assert!(decode_object(&mut object.as_slice(), 1, &d, &d).is_err());
+        assert!(visit(&mut nested(10_000).as_slice(), &d, &d, &mut Noop).is_err());
+        let salvaged = decode_salvage(&nested(10_000), &d, &d).unwrap();
+        assert_eq!(salvaged.errors.len(), 1);
+        assert_eq!(salvaged.errors[0].offset, 256);
+
+        // containers on the path count as well
+        let path = vec!["0"; 100].join(".");
+        assert!(decode_path(&nested(128), &path, &d, &d).is_ok());
+        let err = decode_path(&nested(200), &path, &d, &d).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<DecodeError>(),
+            Some(DecodeError::MaxDepthExceeded { .. })
+        ));
+        // and so do the siblings skipped on the way
+        let mut pair = vec![21, 2];
+        pair.extend(nested(10_000));
+        pair.push(1);
+        let err = decode_path(&pair, "1", &d, &d).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::MaxDepthExceeded { offset: 256 })
+        );
+    }
+
+    #[test]
+    fn it_rejects_sizes_over_the_budget() {
+        let d = NoDictionary {};
+        // dwa of 65535 elements in three bytes
+        let err = decode_slice(&[25, 0xFF, 0xFF], &d, &d).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::BudgetExceeded {
+                declared: 65535,
+                remaining: 0,
+                unit: "bytes"
+            })
+        );
+        // dws of 65535 bytes, then 2 bytes of it
+        let err = decode_slice(&[24, 0xFF, 0xFF, b'a', b'b'], &d, &d).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "declared size exceeds remaining budget: 65535 bytes declared, 2 left"
+        );
+        let salvaged = decode_salvage(&[25, 0xFF, 0xFF], &d, &d).unwrap();
+        assert_eq!(salvaged.errors[0].kind, "truncated");
+
+        let mut blob = BufWriter::new(Vec::new());
+        let doc = json!([[1, 2], [3, 4, 5], "x"]);
+        encode(&doc, &mut blob, &d, &d).unwrap();
+        let blob = blob.into_inner().unwrap();
+        assert_eq!(decode_slice(&blob, &d, &d).unwrap(), doc);
+
+        let items = |max_items| DecodeOptions {
+            max_items: Some(max_items),
+            ..Default::default()
+        };
+        assert_eq!(decode_slice_with(&blob, &d, &d, &items(9)).unwrap(), doc);
+        let err = decode_slice_with(&blob, &d, &d, &items(8)).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DecodeError>(),
+            Some(&DecodeError::BudgetExceeded {
+                declared: 3,
+                remaining: 2,
+                unit: "items"
+            })
+        );
+
+        // a reader past the byte budget looks truncated
+        let bytes = DecodeOptions {
+            max_bytes: Some(blob.len() as u64 - 1),
+            ..Default::default()
+        };
+        assert!(decode_with(&mut blob.as_slice(), &d, &d, &bytes).is_err());
+    }
+
+    #[test]
+    fn it_streams_large_documents_as_text() {
+        let receipts: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/receipts.json")).unwrap();
+        let blocks: Vec<Value> = (0..2000)
+            .map(|n| json!({"number": n, "receipts": receipts, "extra": "a\"b\\c\n"}))
+            .collect();
+        let doc = json!({"blocks": blocks, "block": include_str!("../tests/fixtures/block.json")});
+        let blob = enc(&doc).unwrap();
+        let d = NoDictionary {};
+        let expected = serde_json::to_string(&decode(&mut blob.as_slice(), &d, &d).unwrap());
+        let expected = expected.unwrap();
+        assert!(expected.len() > 4 << 20);
+        let mut text = Vec::with_capacity(expected.len());
+        decode_to_writer(&mut blob.as_slice(), &mut text, &d, &d).unwrap();
+        assert!(text == expected.as_bytes());
+        assert!(decode_to_writer(&mut &blob[..blob.len() - 1], &mut vec![], &d, &d).is_err());
+    }
+
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct Transfer {
+        from: String,
+        amount: u64,
+        memo: Option<String>,
+    }
+
+    #[test]
+    fn it_decodes_into_types() {
+        let d = MapDictionary::from_static(D);
+        let blob = enc_d(&json!({"from": "alpha", "amount": 5, "memo": null})).unwrap();
+        let transfer: Transfer = decode_as(&mut blob.as_slice(), &d, &d).unwrap();
+        assert_eq!(
+            transfer,
+            Transfer {
+                from: "alpha".to_string(),
+                amount: 5,
+                memo: None
+            }
+        );
+
+        let blob = enc_d(&json!({"from": "alpha"})).unwrap();
+        let err = decode_as::<Transfer, _, _, _>(&mut blob.as_slice(), &d, &d).unwrap_err();
+        match err.downcast_ref::<DecodeError>() {
+            Some(DecodeError::Shape { target, message }) => {
+                assert!(target.ends_with("Transfer"));
+                assert_eq!(message, "missing field `amount`");
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    struct Noop;
+
+    impl Visitor for Noop {}
 }
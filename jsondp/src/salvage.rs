@@ -0,0 +1,354 @@
+use crate::decode::*;
+use crate::dictionary::DictionaryRead;
+use crate::{push_path, verify_fingerprints, DecodeError, BACK_REFS_TAG, FINGERPRINT_TAG};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::io::{Cursor, ErrorKind};
+
+/// key of the marker object that replaces an unreadable subtree
+pub const ERROR_MARKER: &str = "__jsondp_error__";
+
+/// Part of the blob that could not be decoded
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SalvageError {
+    /// position of the unreadable item in the blob
+    pub offset: u64,
+    /// JSON pointer of the unreadable value
+    pub path: String,
+    pub kind: String,
+}
+
+/// Document decoded as far as possible, with markers in place of unreadable values
+#[derive(Debug, Clone, PartialEq)]
+pub struct Salvaged {
+    pub value: Value,
+    pub errors: Vec<SalvageError>,
+}
+
+impl Salvaged {
+    pub fn is_complete(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+fn marker(offset: u64, kind: &str) -> Value {
+    json!({ ERROR_MARKER: { "offset": offset, "kind": kind } })
+}
+
+// sizes over the rest of the blob mean it was cut
+fn kind_of(e: &anyhow::Error) -> String {
+    match (e.downcast_ref::<std::io::Error>(), e.downcast_ref()) {
+        (Some(io), _) if io.kind() == ErrorKind::UnexpectedEof => "truncated".to_string(),
+        (_, Some(DecodeError::BudgetExceeded { .. })) => "truncated".to_string(),
+        _ => e.to_string(),
+    }
+}
+
+struct Salvage<'a, 'd, D1: DictionaryRead, D2: DictionaryRead> {
+    input: Cursor<&'a [u8]>,
+    fd: &'d D1,
+    vd: &'d D2,
+    errors: Vec<SalvageError>,
+    // set when the position of the next item is unknown, containers stop reading then
+    lost: bool,
+    // containers around the current value
+    depth: usize,
+    // strings read so far, when the value has back-references to them
+    written: Option<Vec<Value>>,
+}
+
+impl<D1: DictionaryRead, D2: DictionaryRead> Salvage<'_, '_, D1, D2> {
+    // bytes after the type prefix at the position, declared sizes over them are corrupt
+    fn left(&self) -> Option<u64> {
+        let len = self.input.get_ref().len() as u64;
+        Some(len.saturating_sub(self.input.position() + 1))
+    }
+
+    fn fail(&mut self, offset: u64, path: &str, kind: String) -> Value {
+        let value = marker(offset, &kind);
+        self.errors.push(SalvageError {
+            offset,
+            path: path.to_string(),
+            kind,
+        });
+        value
+    }
+
+    // the blob has no lengths of containers, so after an unreadable item
+    // the next sibling can't be found and enclosing containers are truncated
+    fn value(&mut self, path: &mut String) -> Value {
+        let offset = self.input.position();
+        let limit = self.left();
+        let item = next_u8(&mut self.input).and_then(|nb| item_of(nb, &mut self.input, limit));
+        let item = match item {
+            Ok(item) => item,
+            Err(e) => {
+                self.lost = true;
+                return self.fail(offset, path, kind_of(&e));
+            }
+        };
+        let container = matches!(item, Item::Array(_) | Item::Object(_));
+        let string = matches!(
+            item,
+            Item::Str(_) | Item::Bytes(_) | Item::ValueRef(_) | Item::BytesRef(_)
+        );
+        if container {
+            if self.depth >= DEFAULT_MAX_DEPTH {
+                // the rest of the container is not read, so the position is lost
+                self.lost = true;
+                let kind = DecodeError::MaxDepthExceeded { offset }.to_string();
+                return self.fail(offset, path, kind);
+            }
+            self.depth += 1;
+        }
+        let value = match item {
+            Item::Null | Item::NonFinite(_) => Value::Null,
+            Item::Bool(b) => Value::Bool(b),
+            Item::Number(n) => Value::Number(n),
+            Item::Packed(nums) => Value::Array(nums.into_iter().map(Value::Number).collect()),
+            Item::Bytes(b) | Item::BigNumber(b) => Value::String(format!("0x{}", hex::encode(b))),
+            Item::Str(s) => Value::String(s),
+            Item::BackRef(index) => match self.written.as_ref().and_then(|w| w.get(index as usize))
+            {
+                Some(value) => value.clone(),
+                None => self.fail(
+                    offset,
+                    path,
+                    format!("back-reference {} to no earlier string", index),
+                ),
+            },
+            Item::ValueRef(id) => match self.vd.get(id).map(std::str::from_utf8) {
+                Some(Ok(s)) => Value::String(s.to_string()),
+                Some(Err(_)) => self.fail(offset, path, "invalid utf-8".to_string()),
+                None => self.fail(
+                    offset,
+                    path,
+                    format!("value {} not found in dictionary", id),
+                ),
+            },
+            Item::BytesRef(id) => match self.vd.get(id) {
+                Some(buf) => Value::String(format!("0x{}", hex::encode(buf))),
+                None => self.fail(
+                    offset,
+                    path,
+                    format!("value {} not found in dictionary", id),
+                ),
+            },
+            Item::Array(size) => {
+                let mut vals = Vec::new();
+                for i in 0..size {
+                    let len = path.len();
+                    push_path(path, &i.to_string());
+                    vals.push(self.value(path));
+                    path.truncate(len);
+                    if self.lost {
+                        break;
+                    }
+                }
+                Value::Array(vals)
+            }
+            Item::Object(size) => Value::Object(self.object(size, path)),
+        };
+        if container {
+            self.depth -= 1;
+        }
+        if let (true, Some(written)) = (string, self.written.as_mut()) {
+            written.push(value.clone());
+        }
+        value
+    }
+
+    fn object(&mut self, size: usize, path: &mut String) -> Map<String, Value> {
+        let mut m = Map::new();
+        for _ in 0..size {
+            let offset = self.input.position();
+            let limit = self.left();
+            let key = next_u8(&mut self.input).and_then(|nb| key_of(nb, &mut self.input, limit));
+            let field = match key {
+                Ok(Key::Field(id)) => match self.fd.get(id).map(std::str::from_utf8) {
+                    Some(Ok(s)) => Some(s.to_string()),
+                    _ => {
+                        self.fail(
+                            offset,
+                            path,
+                            format!("field {} not found in dictionary", id),
+                        );
+                        None
+                    }
+                },
+                Ok(Key::Str(s)) => Some(s),
+                Ok(Key::Number(n)) => Some(n.to_string()),
+                Err(e) => {
+                    // the marker takes the place of the unreadable key
+                    self.lost = true;
+                    let len = path.len();
+                    push_path(path, ERROR_MARKER);
+                    let value = self.fail(offset, path, kind_of(&e));
+                    path.truncate(len);
+                    m.insert(ERROR_MARKER.to_string(), value);
+                    break;
+                }
+            };
+            let len = path.len();
+            push_path(path, field.as_deref().unwrap_or(ERROR_MARKER));
+            let value = self.value(path);
+            path.truncate(len);
+            // value of an unknown field is read to keep the position, but dropped
+            if let Some(field) = field {
+                m.insert(field, value);
+            }
+            if self.lost {
+                break;
+            }
+        }
+        m
+    }
+}
+
+/// decodes what can be decoded from a damaged blob. Unreadable values are replaced
+/// with `{"__jsondp_error__": {"offset", "kind"}}` markers and listed in the errors,
+/// containers are truncated after them. Fails only when the dictionaries don't match
+pub fn decode_salvage<D1: DictionaryRead, D2: DictionaryRead>(
+    input: &[u8],
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Salvaged> {
+    let mut s = Salvage {
+        input: Cursor::new(input),
+        fd,
+        vd,
+        errors: vec![],
+        lost: false,
+        depth: 0,
+        written: None,
+    };
+    if input.first() == Some(&FINGERPRINT_TAG) {
+        s.input.set_position(1);
+        verify_fingerprints(&mut s.input, fd, vd)?;
+    }
+    if input.get(s.input.position() as usize) == Some(&BACK_REFS_TAG) {
+        s.input.set_position(s.input.position() + 1);
+        s.written = Some(vec![]);
+    }
+    let value = s.value(&mut String::new());
+    Ok(Salvaged {
+        value,
+        errors: s.errors,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{MapDictionary, NoDictionary};
+
+    fn fixture() -> (Value, Vec<u8>) {
+        let doc = json!({
+            "blocks": [
+                {"hash": "0x01", "number": 1},
+                {"hash": "0x02", "number": 2},
+                {"hash": "0x03", "number": 3}
+            ],
+            "tail": "end"
+        });
+        let mut blob = vec![];
+        crate::encode(&doc, &mut blob, &NoDictionary {}, &NoDictionary {}).unwrap();
+        (doc, blob)
+    }
+
+    fn position(blob: &[u8], needle: &[u8]) -> usize {
+        blob.windows(needle.len())
+            .position(|w| w == needle)
+            .unwrap()
+    }
+
+    #[test]
+    fn it_salvages_intact_blobs_completely() {
+        let (doc, blob) = fixture();
+        let s = decode_salvage(&blob, &NoDictionary {}, &NoDictionary {}).unwrap();
+        assert!(s.is_complete());
+        assert_eq!(s.value, doc);
+    }
+
+    #[test]
+    fn it_marks_corrupt_values_and_truncates_containers() {
+        let (_, blob) = fixture();
+        let nod = NoDictionary {};
+
+        // type prefix of the second block number
+        let mut bad = blob.clone();
+        let at = position(&blob, &[0x03, 0x02]);
+        bad[at] = 0x1e;
+        assert!(crate::decode(&mut bad.as_slice(), &nod, &nod).is_err());
+        let s = decode_salvage(&bad, &nod, &nod).unwrap();
+        assert_eq!(
+            s.value,
+            json!({"blocks": [
+                {"hash": "0x01", "number": 1},
+                {"hash": "0x02", "number": marker(at as u64, "invalid field type")}
+            ]})
+        );
+        assert_eq!(s.errors.len(), 1);
+        assert_eq!(s.errors[0].path, "/blocks/1/number");
+
+        // length of the last string runs past the end
+        let mut bad = blob.clone();
+        let at = position(&blob, b"end") - 2;
+        bad[at + 1] = 0x40;
+        let s = decode_salvage(&bad, &nod, &nod).unwrap();
+        assert_eq!(s.value["blocks"].as_array().unwrap().len(), 3);
+        assert_eq!(s.value["tail"], marker(at as u64, "truncated"));
+
+        // blob cut in the middle of the first block
+        let cut = position(&blob, &[0x03, 0x01]) + 1;
+        let s = decode_salvage(&blob[..cut], &nod, &nod).unwrap();
+        assert_eq!(
+            s.value,
+            json!({"blocks": [{"hash": "0x01", "number": marker(cut as u64 - 1, "truncated")}]})
+        );
+    }
+
+    #[test]
+    fn it_keeps_siblings_of_unknown_dictionary_values() {
+        let d = MapDictionary::from_static(&["alpha", "beta"]);
+        let doc = json!(["alpha", "beta", "gamma"]);
+        let mut blob = vec![];
+        crate::encode(&doc, &mut blob, &d, &d).unwrap();
+        let short = MapDictionary::from_static(&["alpha"]);
+        let s = decode_salvage(&blob, &short, &short).unwrap();
+        let at = s.errors[0].offset;
+        assert_eq!(
+            s.value,
+            json!([
+                "alpha",
+                marker(at, "value 2 not found in dictionary"),
+                "gamma"
+            ])
+        );
+    }
+
+    #[test]
+    fn it_points_markers_into_corrupt_fixture() {
+        let doc: Value =
+            serde_json::from_str(include_str!("../tests/fixtures/block.json")).unwrap();
+        let nod = NoDictionary {};
+        let mut blob = vec![];
+        crate::encode(&doc, &mut blob, &nod, &nod).unwrap();
+        for percent in [10, 50, 90] {
+            let mut bad = blob.clone();
+            bad[blob.len() * percent / 100] = 0x1d;
+            let s = decode_salvage(&bad, &nod, &nod).unwrap();
+            for e in &s.errors {
+                let found = s.value.pointer(&e.path).unwrap();
+                assert_eq!(found, &marker(e.offset, &e.kind));
+            }
+            // the unreadable item starts at or before the corrupt byte
+            if let Some(e) = s.errors.first() {
+                assert!(e.offset as usize <= blob.len() * percent / 100);
+            }
+        }
+        let cut = decode_salvage(&blob[..blob.len() * 9 / 10], &nod, &nod).unwrap();
+        assert_eq!(cut.errors.len(), 1);
+        assert_eq!(cut.errors[0].kind, "truncated");
+    }
+}
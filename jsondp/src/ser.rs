@@ -0,0 +1,683 @@
+use crate::decode::{next_item, Item};
+use crate::dictionary::DictionaryRead;
+use crate::encode::{
+    encode_array_header, encode_f64, encode_key, encode_number, encode_object_header,
+    encode_packed, encode_string, encode_value_with, BackRefs, EncodeOptions,
+};
+use crate::error::SerdeError;
+use serde::ser::{self, Serialize};
+use serde_json::{Map, Number, Value};
+use std::io::Write;
+
+/// Serializer into the encoded form. Writes the same bytes as `encode`
+/// of the value that serde_json would make, without building that value.
+/// Only NaN and infinities differ, they are written as floats and not as null.
+/// Back-references of the options are not written
+pub struct Serializer<'a, W, D1, D2> {
+    w: W,
+    fd: &'a D1,
+    vd: &'a D2,
+    opts: &'a EncodeOptions,
+}
+
+type Result<T> = std::result::Result<T, SerdeError>;
+
+impl<'a, W: Write, D1: DictionaryRead, D2: DictionaryRead> Serializer<'a, W, D1, D2> {
+    pub fn new(w: W, fd: &'a D1, vd: &'a D2, opts: &'a EncodeOptions) -> Self {
+        Self { w, fd, vd, opts }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.w
+    }
+
+    // serializer of a nested value into its own buffer
+    fn child(&self) -> Serializer<'a, Vec<u8>, D1, D2> {
+        Serializer::new(Vec::new(), self.fd, self.vd, self.opts)
+    }
+
+    fn value(&mut self, v: &Value) -> Result<()> {
+        // back-references are not written by the serializer
+        let mut refs = BackRefs::default();
+        encode_value_with(v, &mut self.w, self.fd, self.vd, self.opts, &mut refs)?;
+        Ok(())
+    }
+
+    fn number(&mut self, n: Number) -> Result<()> {
+        encode_number(&n, &mut self.w)?;
+        Ok(())
+    }
+
+    // numbers over 64 bits, serde_json keeps them only with arbitrary precision
+    fn wide(&mut self, text: String) -> Result<()> {
+        #[cfg(feature = "arbitrary_precision")]
+        if let Ok(n) = text.parse() {
+            return self.number(n);
+        }
+        Err(ser::Error::custom(format!("number {} out of range", text)))
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.w.write_all(buf).map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    // enum variants with data are objects of one field, named after the variant
+    fn variant(&mut self, variant: &str) -> Result<()> {
+        encode_object_header(1, &mut self.w, self.opts)?;
+        encode_key(variant, &mut self.w, self.fd)?;
+        Ok(())
+    }
+}
+
+/// writes the value in the encoded form, same as `encode` of `serde_json::to_value`
+pub fn to_writer<T: Serialize + ?Sized, W: Write, D1: DictionaryRead, D2: DictionaryRead>(
+    value: &T,
+    w: &mut W,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<()> {
+    let opts = EncodeOptions::default();
+    let mut ser = Serializer::new(&mut *w, fd, vd, &opts);
+    value.serialize(&mut ser).map_err(|e| e.0)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// same as `to_writer`, into a new buffer
+pub fn to_vec<T: Serialize + ?Sized, D1: DictionaryRead, D2: DictionaryRead>(
+    value: &T,
+    fd: &D1,
+    vd: &D2,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    to_writer(value, &mut buf, fd, vd)?;
+    Ok(buf)
+}
+
+impl<'s, 'a, W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::Serializer
+    for &'s mut Serializer<'a, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+    type SerializeSeq = Seq<'s, 'a, W, D1, D2>;
+    type SerializeTuple = Seq<'s, 'a, W, D1, D2>;
+    type SerializeTupleStruct = Seq<'s, 'a, W, D1, D2>;
+    type SerializeTupleVariant = Seq<'s, 'a, W, D1, D2>;
+    type SerializeMap = Fields<'s, 'a, W, D1, D2>;
+    type SerializeStruct = Fields<'s, 'a, W, D1, D2>;
+    type SerializeStructVariant = Fields<'s, 'a, W, D1, D2>;
+
+    fn serialize_bool(self, v: bool) -> Result<()> {
+        self.value(&Value::Bool(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<()> {
+        match (i64::try_from(v), u64::try_from(v)) {
+            (Ok(v), _) => self.number(v.into()),
+            (_, Ok(v)) => self.number(v.into()),
+            _ => self.wide(v.to_string()),
+        }
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<()> {
+        self.number(v.into())
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<()> {
+        match u64::try_from(v) {
+            Ok(v) => self.number(v.into()),
+            Err(_) => self.wide(v.to_string()),
+        }
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<()> {
+        self.serialize_f64(v.into())
+    }
+
+    // NaN and infinities are kept, serde_json would make them null
+    fn serialize_f64(self, v: f64) -> Result<()> {
+        match Number::from_f64(v) {
+            Some(n) => self.number(n),
+            None => {
+                encode_f64(v, &mut self.w)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn serialize_char(self, v: char) -> Result<()> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<()> {
+        encode_string(v, &mut self.w, self.vd, self.opts)?;
+        Ok(())
+    }
+
+    // serde_json writes bytes as an array of numbers
+    fn serialize_bytes(self, v: &[u8]) -> Result<()> {
+        let nums: Vec<Number> = v.iter().map(|b| Number::from(*b)).collect();
+        if encode_packed(&nums.iter().collect::<Vec<_>>(), &mut self.w, self.opts)? {
+            return Ok(());
+        }
+        encode_array_header(v.len(), &mut self.w, self.opts)?;
+        for b in v {
+            self.number((*b).into())?;
+        }
+        Ok(())
+    }
+
+    fn serialize_none(self) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<()> {
+        self.value(&Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<()> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<()> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.variant(variant)?;
+        value.serialize(self)
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq> {
+        Ok(Seq::new(self, None))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self::SerializeTuple> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Self::SerializeTupleStruct> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant> {
+        Ok(Seq::new(self, Some(variant)))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap> {
+        Ok(Fields::new(self, None))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Self::SerializeStruct> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant> {
+        Ok(Fields::new(self, Some(variant)))
+    }
+}
+
+/// Items of a sequence, buffered as the size goes before them
+pub struct Seq<'s, 'a, W, D1, D2> {
+    parent: &'s mut Serializer<'a, W, D1, D2>,
+    items: Serializer<'a, Vec<u8>, D1, D2>,
+    len: usize,
+    variant: Option<&'static str>,
+}
+
+impl<'s, 'a, W: Write, D1: DictionaryRead, D2: DictionaryRead> Seq<'s, 'a, W, D1, D2> {
+    fn new(parent: &'s mut Serializer<'a, W, D1, D2>, variant: Option<&'static str>) -> Self {
+        let items = parent.child();
+        Self {
+            parent,
+            items,
+            len: 0,
+            variant,
+        }
+    }
+
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        value.serialize(&mut self.items)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Some(variant) = self.variant {
+            self.parent.variant(variant)?;
+        }
+        // arrays of numbers are packed as `encode` packs them
+        if let Some(nums) = numbers(&self.items.w, self.len) {
+            let nums: Vec<&Number> = nums.iter().collect();
+            if encode_packed(&nums, &mut self.parent.w, self.parent.opts)? {
+                return Ok(());
+            }
+        }
+        encode_array_header(self.len, &mut self.parent.w, self.parent.opts)?;
+        self.parent.write(&self.items.w)
+    }
+}
+
+// serialized elements, when all of them are numbers
+fn numbers(mut buf: &[u8], len: usize) -> Option<Vec<Number>> {
+    (0..len)
+        .map(|_| match next_item(&mut buf).ok()? {
+            Item::Number(n) => Some(n),
+            _ => None,
+        })
+        .collect()
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeSeq
+    for Seq<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeTuple
+    for Seq<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeTupleStruct
+    for Seq<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeTupleVariant
+    for Seq<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+// encoded value of the field, with the value itself for fields
+// that may make a BigNumber object
+struct Field {
+    bytes: Vec<u8>,
+    value: Option<Value>,
+}
+
+#[cfg(not(feature = "preserve_order"))]
+type FieldMap = std::collections::BTreeMap<String, Field>;
+#[cfg(feature = "preserve_order")]
+type FieldMap = indexmap::IndexMap<String, Field>;
+
+/// Fields of a map or a struct. They are buffered and written in the order
+/// serde_json keeps them, sorted by key unless the preserve_order feature is on
+pub struct Fields<'s, 'a, W, D1, D2> {
+    parent: &'s mut Serializer<'a, W, D1, D2>,
+    fields: FieldMap,
+    key: Option<String>,
+    variant: Option<&'static str>,
+}
+
+impl<'s, 'a, W: Write, D1: DictionaryRead, D2: DictionaryRead> Fields<'s, 'a, W, D1, D2> {
+    fn new(parent: &'s mut Serializer<'a, W, D1, D2>, variant: Option<&'static str>) -> Self {
+        Self {
+            parent,
+            fields: FieldMap::new(),
+            key: None,
+            variant,
+        }
+    }
+
+    fn field<T: Serialize + ?Sized>(&mut self, key: String, value: &T) -> Result<()> {
+        let mut child = self.parent.child();
+        let value = match key.as_str() {
+            "type" | "hex" => {
+                let value = serde_json::to_value(value).map_err(anyhow::Error::from)?;
+                child.value(&value)?;
+                Some(value)
+            }
+            _ => {
+                value.serialize(&mut child)?;
+                None
+            }
+        };
+        let bytes = child.into_inner();
+        self.fields.insert(key, Field { bytes, value });
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if let Some(variant) = self.variant {
+            self.parent.variant(variant)?;
+        }
+        // the encoder writes BigNumber objects as bytes
+        let kept = |k: &str| self.fields.get(k).is_some_and(|f| f.value.is_some());
+        if self.fields.len() == 2 && kept("hex") && kept("type") {
+            let m: Map<String, Value> = self
+                .fields
+                .iter()
+                .map(|(k, f)| (k.clone(), f.value.clone().unwrap_or_default()))
+                .collect();
+            return self.parent.value(&Value::Object(m));
+        }
+        encode_object_header(self.fields.len(), &mut self.parent.w, self.parent.opts)?;
+        for (k, f) in &self.fields {
+            encode_key(k, &mut self.parent.w, self.parent.fd)?;
+            self.parent.write(&f.bytes)?;
+        }
+        Ok(())
+    }
+}
+
+// keys are strings in JSON, numbers and booleans are written as text
+fn key_of<T: Serialize + ?Sized>(key: &T) -> Result<String> {
+    match serde_json::to_value(key).map_err(anyhow::Error::from)? {
+        Value::String(s) => Ok(s),
+        Value::Number(n) => Ok(n.to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        _ => Err(ser::Error::custom("key must be a string")),
+    }
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeMap
+    for Fields<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key_of(key)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<()> {
+        match self.key.take() {
+            Some(key) => self.field(key, value),
+            None => Err(ser::Error::custom("value without a key")),
+        }
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeStruct
+    for Fields<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+impl<W: Write, D1: DictionaryRead, D2: DictionaryRead> ser::SerializeStructVariant
+    for Fields<'_, '_, W, D1, D2>
+{
+    type Ok = ();
+    type Error = SerdeError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.field(key.to_string(), value)
+    }
+
+    fn end(self) -> Result<()> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::{MapDictionary, NoDictionary};
+    use serde::Serialize;
+    use std::collections::HashMap;
+
+    #[derive(Serialize)]
+    struct Transfer {
+        from: String,
+        to: String,
+        value: u128,
+        block: Option<u64>,
+        memo: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        topics: Vec<String>,
+    }
+
+    #[derive(Serialize)]
+    struct Wrapped(Transfer);
+
+    #[derive(Serialize)]
+    struct BigNumber {
+        #[serde(rename = "type")]
+        kind: &'static str,
+        hex: String,
+    }
+
+    #[derive(Serialize)]
+    enum Event {
+        Empty,
+        Amount(i64),
+        Pair(u8, String),
+        Swap { sold: f64, bought: f64 },
+    }
+
+    #[derive(Serialize)]
+    struct Record {
+        // fields out of order of the keys
+        zeta: Vec<Event>,
+        alpha: HashMap<u32, bool>,
+        total: BigNumber,
+        raw: serde_bytes_like::Bytes,
+        pair: (i8, char),
+        unit: (),
+    }
+
+    // serializes bytes with serialize_bytes, like serde_bytes does
+    mod serde_bytes_like {
+        pub struct Bytes(pub Vec<u8>);
+
+        impl serde::Serialize for Bytes {
+            fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.serialize_bytes(&self.0)
+            }
+        }
+    }
+
+    fn transfer() -> Transfer {
+        Transfer {
+            from: "0x95087266018b9637aff3d76d4e0cad7e52c19636".to_string(),
+            to: "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48".to_string(),
+            value: 1_000_000_000_000_000_000,
+            block: Some(17_000_000),
+            memo: None,
+            topics: vec![],
+        }
+    }
+
+    // bytes must be the same as of encoding the value made by serde_json
+    fn same_as_encode<T: Serialize, D: DictionaryRead>(v: &T, d: &D) {
+        let mut expected = vec![];
+        crate::encode(&serde_json::to_value(v).unwrap(), &mut expected, d, d).unwrap();
+        assert_eq!(to_vec(v, d, d).unwrap(), expected);
+    }
+
+    #[test]
+    fn it_serializes_as_encode_does() {
+        let nod = NoDictionary {};
+        let d = MapDictionary::from_static(&[
+            "from",
+            "to",
+            "value",
+            "alpha",
+            "0x95087266018b9637aff3d76d4e0cad7e52c19636",
+        ]);
+        let record = Record {
+            zeta: vec![
+                Event::Empty,
+                Event::Amount(-5),
+                Event::Pair(7, "seven".to_string()),
+                Event::Swap {
+                    sold: 1.5,
+                    bought: -0.5,
+                },
+            ],
+            alpha: HashMap::from([(1, true), (1000, false)]),
+            total: BigNumber {
+                kind: "BigNumber",
+                hex: "0x1ff".to_string(),
+            },
+            raw: serde_bytes_like::Bytes(vec![0, 1, 255]),
+            pair: (-1, 'x'),
+            unit: (),
+        };
+        let mut t = transfer();
+        t.topics = vec!["0x01".to_string(); 300];
+        for d in [&d, &MapDictionary::from_static(&[])] {
+            same_as_encode(&transfer(), d);
+            same_as_encode(&Wrapped(transfer()), d);
+            same_as_encode(&record, d);
+            same_as_encode(&t, d);
+            same_as_encode(&vec![Some(1u8), None], d);
+            same_as_encode(&(0..300u32).map(|i| i * 7).collect::<Vec<_>>(), d);
+            same_as_encode(&vec![0.1f64; 10], d);
+            same_as_encode(&"0x01ff", d);
+            #[cfg(feature = "eth")]
+            same_as_encode(&ethers::types::Log::default(), d);
+        }
+        let mut buf = vec![];
+        to_writer(&transfer(), &mut buf, &nod, &nod).unwrap();
+        assert_eq!(buf, to_vec(&transfer(), &nod, &nod).unwrap());
+    }
+
+    #[test]
+    fn it_fails_as_serde_json_does() {
+        let nod = NoDictionary {};
+        #[cfg(not(feature = "arbitrary_precision"))]
+        {
+            assert!(to_vec(&u128::MAX, &nod, &nod).is_err());
+            assert!(serde_json::to_value(u128::MAX).is_err());
+        }
+        #[cfg(feature = "arbitrary_precision")]
+        {
+            same_as_encode(&u128::MAX, &nod);
+            same_as_encode(&i128::MIN, &nod);
+        }
+        let m = HashMap::from([((1, 2), 3)]);
+        assert!(to_vec(&m, &nod, &nod).is_err());
+        assert!(serde_json::to_value(&m).is_err());
+    }
+}
@@ -0,0 +1,225 @@
+use crate::dictionary::{fingerprint_of, DictionaryRead, DictionaryWrite, MapDictionary};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{PoisonError, RwLock};
+
+/// Dictionary that is read and added to from many threads at once, e.g. by
+/// `encode_learning` on a thread pool, with `&SharedDictionary` as the
+/// dictionary of every thread. Entries are kept in an append-only log,
+/// so references to them stay valid while other entries are added; the ids
+/// are behind a lock that is held only to look one up or to add one.
+/// Bytes inserted by threads at once get a single id
+#[derive(Default)]
+pub struct SharedDictionary {
+    entries: boxcar::Vec<Vec<u8>>,
+    index: RwLock<Index>,
+}
+
+#[derive(Default)]
+struct Index {
+    // position in the log by id
+    by_id: HashMap<u32, usize>,
+    by_bytes: HashMap<Vec<u8>, u32>,
+    last: u32,
+}
+
+impl SharedDictionary {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// shared copy of the dictionary, with the same ids
+    pub fn from_dictionary(dict: &MapDictionary) -> Self {
+        let out = Self::new();
+        {
+            let mut index = out.index.write().unwrap_or_else(PoisonError::into_inner);
+            for id in dict.ids() {
+                let item = dict.get(id).unwrap_or_default();
+                index.by_id.insert(id, out.entries.push(item.to_vec()));
+                index.by_bytes.insert(item.to_vec(), id);
+                index.last = id;
+            }
+        }
+        out
+    }
+
+    /// copy of the entries at the moment, e.g. to save them
+    pub fn to_dictionary(&self) -> MapDictionary {
+        let mut out = MapDictionary::new();
+        for (id, item) in self.snapshot() {
+            out.insert_bytes_as(item, id);
+        }
+        out
+    }
+
+    /// id of the entry, a new one after the last if it is not there
+    pub fn insert(&self, item: &str) -> u32 {
+        self.insert_bytes(item.as_bytes())
+    }
+
+    /// id of the raw entry, a new one after the last if it is not there
+    pub fn insert_bytes(&self, item: &[u8]) -> u32 {
+        if let Some(id) = self.find_bytes(item) {
+            return id;
+        }
+        let mut index = self.index.write().unwrap_or_else(PoisonError::into_inner);
+        // another thread may have added it since the lookup
+        if let Some(id) = index.by_bytes.get(item) {
+            return *id;
+        }
+        index.last += 1;
+        let id = index.last;
+        index.by_id.insert(id, self.entries.push(item.to_vec()));
+        index.by_bytes.insert(item.to_vec(), id);
+        id
+    }
+
+    /// adds keys of the json value that are not there
+    pub fn learn(&self, input: &Value) {
+        match input {
+            Value::Array(value) => {
+                for v in value {
+                    self.learn(v);
+                }
+            }
+            Value::Object(value) => {
+                for (k, v) in value {
+                    self.insert(k);
+                    self.learn(v);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// identifies the generation of the dictionary, which changes with every entry added
+    pub fn fingerprint(&self) -> [u8; 16] {
+        let snapshot = self.snapshot();
+        fingerprint_of(snapshot.iter().map(|(id, item)| (*id, *item)))
+    }
+
+    // entries by ids at the moment
+    fn snapshot(&self) -> BTreeMap<u32, &[u8]> {
+        let index = self.index.read().unwrap_or_else(PoisonError::into_inner);
+        let entries = index.by_id.iter();
+        entries
+            .filter_map(|(id, pos)| Some((*id, self.entries.get(*pos)?.as_slice())))
+            .collect()
+    }
+}
+
+impl DictionaryRead for SharedDictionary {
+    fn get(&self, index: u32) -> Option<&[u8]> {
+        let pos = *self
+            .index
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .by_id
+            .get(&index)?;
+        self.entries.get(pos).map(Vec::as_slice)
+    }
+    fn find_str(&self, value: &str) -> Option<u32> {
+        self.find_bytes(value.as_bytes())
+    }
+    fn find_bytes(&self, value: &[u8]) -> Option<u32> {
+        let index = self.index.read().unwrap_or_else(PoisonError::into_inner);
+        index.by_bytes.get(value).copied()
+    }
+    fn fingerprint(&self) -> Option<[u8; 16]> {
+        Some(SharedDictionary::fingerprint(self))
+    }
+}
+
+/// every thread adds entries through its own `&SharedDictionary`
+impl DictionaryWrite for &SharedDictionary {
+    fn insert(&mut self, bytes: &[u8]) -> Option<u32> {
+        Some(self.insert_bytes(bytes))
+    }
+}
+
+impl DictionaryWrite for SharedDictionary {
+    fn insert(&mut self, bytes: &[u8]) -> Option<u32> {
+        Some(self.insert_bytes(bytes))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_slice, encode_learning, EncodeOptions};
+    use serde_json::json;
+    use std::collections::HashSet;
+
+    #[test]
+    fn it_learns_from_many_threads() {
+        let fd = SharedDictionary::from_dictionary(&MapDictionary::from_strings(vec!["common"]));
+        let vd = SharedDictionary::new();
+        let docs: Vec<Value> = (0..60)
+            .map(|n| {
+                json!({
+                    "common": n,
+                    format!("key{}", n % 12): [format!("value{}", n % 7), format!("value{}", n % 7)],
+                    "nested": {format!("inner{}", n % 5): "once"},
+                })
+            })
+            .collect();
+        let blobs: Vec<Vec<(usize, Vec<u8>)>> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|t| {
+                    let (docs, fd, vd) = (&docs, &fd, &vd);
+                    s.spawn(move || {
+                        let (mut fd, mut vd) = (fd, vd);
+                        let opts = EncodeOptions::default();
+                        // threads encode overlapping ranges of the documents
+                        (t * 5..t * 5 + 25)
+                            .map(|n| {
+                                let mut blob = vec![];
+                                encode_learning(&docs[n], &mut blob, &mut fd, &mut vd, &opts, true)
+                                    .unwrap();
+                                (n, blob)
+                            })
+                            .collect()
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+
+        for dict in [&fd, &vd] {
+            let snapshot = dict.to_dictionary();
+            let ids: Vec<u32> = snapshot.ids().collect();
+            assert_eq!(ids, (1..=dict.len() as u32).collect::<Vec<_>>());
+            let entries: HashSet<&[u8]> = ids.iter().map(|id| snapshot.get(*id).unwrap()).collect();
+            assert_eq!(entries.len(), ids.len());
+        }
+        // 12 keys, 5 inner keys, "nested" and "common", and the 7 repeated values
+        assert_eq!(fd.len(), 12 + 5 + 2);
+        assert_eq!(fd.find_str("common"), Some(1));
+        assert_eq!(vd.len(), 7);
+        for (n, blob) in blobs.iter().flatten() {
+            assert_eq!(decode_slice(blob, &fd, &vd).unwrap(), docs[*n]);
+        }
+    }
+
+    #[test]
+    fn it_gives_one_id_to_concurrent_inserts() {
+        let d = SharedDictionary::new();
+        let ids: Vec<Vec<u32>> = std::thread::scope(|s| {
+            let threads: Vec<_> = (0..8)
+                .map(|_| s.spawn(|| (0..200).map(|n| d.insert(&format!("entry{}", n))).collect()))
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(ids.iter().all(|thread| *thread == ids[0]));
+        assert_eq!(d.len(), 200);
+        assert_eq!(d.to_dictionary().fingerprint(), d.fingerprint());
+    }
+}
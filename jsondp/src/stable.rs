@@ -0,0 +1,209 @@
+use crate::dictionary::{DictionaryRead, MapDictionary};
+use anyhow::{bail, Context};
+use serde_json::Value;
+use std::io::{Read, Write};
+
+// comment line of the text form with the highest id ever given
+const MARK: &str = "# high-water-mark: ";
+
+/// Dictionary that is only appended to, so blobs stored with it keep decoding
+/// after it learns more. New entries get ids after the highest id ever given,
+/// ids of removed entries included, which is kept in the text form as a comment.
+/// Entries added since the last `save` or `write_delta` are its delta
+#[derive(Debug, Clone)]
+pub struct StableDictionaryBuilder {
+    dict: MapDictionary,
+    // highest id ever given
+    high: u32,
+    // highest id when it was saved, later ids are the delta
+    saved: u32,
+}
+
+impl StableDictionaryBuilder {
+    /// builder on top of the dictionary, with the ids given to entries of it
+    /// that were removed since, if there were any
+    pub fn new(dict: MapDictionary, high_water_mark: u32) -> Self {
+        let high = dict.ids().next_back().unwrap_or(0).max(high_water_mark);
+        Self {
+            dict,
+            high,
+            saved: high,
+        }
+    }
+
+    /// reads the text form of `save`, with deltas of `write_delta` appended to it
+    pub fn load<R: Read>(r: &mut R) -> anyhow::Result<Self> {
+        let mut text = String::new();
+        r.read_to_string(&mut text).context("dict read")?;
+        let mut mark = 0;
+        for line in text.lines() {
+            if let Some(n) = line.trim().strip_prefix(MARK) {
+                mark = mark.max(n.parse::<u32>().context("invalid high-water mark")?);
+            }
+        }
+        let dict = MapDictionary::from(&mut text.as_bytes())?;
+        Ok(Self::new(dict, mark))
+    }
+
+    /// writes all entries in the text form, which `MapDictionary::from` reads as well
+    pub fn save<W: Write>(&mut self, w: &mut W) -> anyhow::Result<()> {
+        writeln!(w, "{}{}", MARK, self.high).context("dict write")?;
+        self.dict.write(w)?;
+        self.saved = self.high;
+        Ok(())
+    }
+
+    /// writes entries added since the dictionary was saved, to be appended to it
+    pub fn write_delta<W: Write>(&mut self, w: &mut W) -> anyhow::Result<()> {
+        writeln!(w, "{}{}", MARK, self.high).context("dict write")?;
+        self.delta().write(w)?;
+        self.saved = self.high;
+        Ok(())
+    }
+
+    /// entries added since the dictionary was saved
+    pub fn delta(&self) -> MapDictionary {
+        let mut out = MapDictionary::new();
+        for id in self.dict.ids().filter(|id| *id > self.saved) {
+            out.insert_bytes_as(self.dict.get(id).unwrap_or_default(), id);
+        }
+        out
+    }
+
+    /// highest id ever given
+    pub fn high_water_mark(&self) -> u32 {
+        self.high
+    }
+
+    pub fn dictionary(&self) -> &MapDictionary {
+        &self.dict
+    }
+
+    pub fn into_dictionary(self) -> MapDictionary {
+        self.dict
+    }
+
+    /// id of the entry, a new one after the high-water mark if it is not there
+    pub fn insert(&mut self, item: &str) -> u32 {
+        self.insert_bytes(item.as_bytes())
+    }
+
+    /// id of the raw entry, a new one after the high-water mark if it is not there
+    pub fn insert_bytes(&mut self, item: &[u8]) -> u32 {
+        if let Some(index) = self.dict.find_bytes(item) {
+            return index;
+        }
+        self.high += 1;
+        self.dict.insert_bytes_as(item, self.high);
+        self.high
+    }
+
+    /// adds the entry with the given id, e.g. from a dictionary of another process.
+    /// Fails when the entry is there under another id, or the id was ever given
+    /// to another entry
+    pub fn insert_as(&mut self, item: &str, index: u32) -> anyhow::Result<()> {
+        match self.dict.find_str(item) {
+            Some(id) if id == index => return Ok(()),
+            Some(id) => bail!("'{}' is there already with id {}, not {}", item, id, index),
+            None if index <= self.high => {
+                bail!(
+                    "id {} was given before, the high-water mark is {}",
+                    index,
+                    self.high
+                )
+            }
+            None => {}
+        }
+        self.dict.insert_as(item, index);
+        self.high = index;
+        Ok(())
+    }
+
+    /// adds keys of the json value that are not there
+    pub fn learn(&mut self, input: &Value) {
+        match input {
+            Value::Array(value) => {
+                for v in value {
+                    self.learn(v);
+                }
+            }
+            Value::Object(value) => {
+                for (k, v) in value {
+                    self.insert(k);
+                    self.learn(v);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_slice, encode};
+    use serde_json::json;
+
+    #[test]
+    fn it_only_appends_entries() {
+        let mut builder = StableDictionaryBuilder::new(MapDictionary::new(), 0);
+        builder.learn(&json!({"blockNumber": "0x1", "logs": [{"address": "0x2"}]}));
+        let mut file = vec![];
+        builder.save(&mut file).unwrap();
+        let original = builder.into_dictionary();
+        let nod = MapDictionary::new();
+        let v = json!({"blockNumber": "0x10", "logs": [{"address": "0x20"}]});
+        let mut blob = vec![];
+        encode(&v, &mut blob, &original, &nod).unwrap();
+
+        // another run learns more
+        let mut builder = StableDictionaryBuilder::load(&mut file.as_slice()).unwrap();
+        assert_eq!(builder.high_water_mark(), 3);
+        assert_eq!(builder.delta().ids().count(), 0);
+        builder.learn(&json!({"blockNumber": "0x2", "data": "0x", "topics": []}));
+        assert_eq!(builder.insert("blockNumber"), 1);
+        let delta = builder.delta();
+        assert_eq!(delta.ids().collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(delta.find_str("data"), Some(4));
+        assert_eq!(delta.find_str("topics"), Some(5));
+        builder.write_delta(&mut file).unwrap();
+        assert_eq!(builder.delta().ids().count(), 0);
+
+        let reloaded = StableDictionaryBuilder::load(&mut file.as_slice()).unwrap();
+        assert_eq!(reloaded.high_water_mark(), 5);
+        for id in original.ids() {
+            assert_eq!(reloaded.dictionary().get(id), original.get(id));
+        }
+        assert_eq!(decode_slice(&blob, reloaded.dictionary(), &nod).unwrap(), v);
+
+        // ids of removed entries are not given again
+        let mut dict = reloaded.into_dictionary();
+        dict.remove(5);
+        let mut file = vec![];
+        StableDictionaryBuilder::new(dict, 5)
+            .save(&mut file)
+            .unwrap();
+        let mut builder = StableDictionaryBuilder::load(&mut file.as_slice()).unwrap();
+        assert_eq!(builder.insert("transactions"), 6);
+    }
+
+    #[test]
+    fn it_refuses_to_move_entries() {
+        let dict = MapDictionary::from_strings(vec!["alpha", "beta"]);
+        let mut builder = StableDictionaryBuilder::new(dict, 10);
+        let err = builder.insert_as("alpha", 11).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "'alpha' is there already with id 1, not 11"
+        );
+        let err = builder.insert_as("gamma", 7).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "id 7 was given before, the high-water mark is 10"
+        );
+        builder.insert_as("beta", 2).unwrap();
+        builder.insert_as("gamma", 12).unwrap();
+        assert_eq!(builder.insert("delta"), 13);
+        assert_eq!(builder.delta().ids().collect::<Vec<_>>(), vec![12, 13]);
+    }
+}
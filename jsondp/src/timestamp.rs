@@ -0,0 +1,176 @@
+use crate::decode::{DIGITS, TIMESTAMP};
+use anyhow::Context;
+use std::io::Write;
+
+/// Time in a string value, with the form it was written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Timestamp {
+    /// unix seconds as decimal digits, e.g. "1688212800"
+    Digits(u32),
+    /// RFC3339 in UTC with whole seconds, e.g. "2023-07-01T12:00:00Z"
+    Seconds(u32),
+    /// RFC3339 in UTC with milliseconds, e.g. "2023-07-01T12:00:00.250Z"
+    Millis(u64),
+}
+
+impl Timestamp {
+    /// recognizes the string only when it renders back exactly,
+    /// so other precisions, offsets and invalid dates stay strings
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let t = match s.len() {
+            20 | 24 if s.is_ascii() && s.ends_with('Z') => {
+                let secs = rfc3339_seconds(&s[..19])?;
+                match s.len() {
+                    20 => Self::Seconds(u32::try_from(secs).ok()?),
+                    _ => {
+                        let ms: u64 = s[19..23].strip_prefix('.')?.parse().ok()?;
+                        Self::Millis(secs.checked_mul(1000)?.checked_add(ms)?)
+                    }
+                }
+            }
+            _ if s.bytes().all(|b| b.is_ascii_digit()) => Self::Digits(s.parse().ok()?),
+            _ => return None,
+        };
+        Some(t).filter(|t| t.render() == s)
+    }
+
+    /// the string the timestamp was parsed from
+    pub(crate) fn render(&self) -> String {
+        match *self {
+            Self::Digits(secs) => secs.to_string(),
+            Self::Seconds(secs) => format!("{}Z", rfc3339(secs as u64)),
+            Self::Millis(ms) => format!("{}.{:03}Z", rfc3339(ms / 1000), ms % 1000),
+        }
+    }
+
+    /// encoded size with the prefix
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Self::Digits(_) | Self::Seconds(_) => 5,
+            Self::Millis(_) => 9,
+        }
+    }
+
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> anyhow::Result<()> {
+        match *self {
+            Self::Digits(secs) => {
+                w.write_all(&[7 | TIMESTAMP | DIGITS])
+                    .context("write timestamp prefix")?;
+                w.write_all(&secs.to_le_bytes())
+                    .context("write timestamp")?;
+            }
+            Self::Seconds(secs) => {
+                w.write_all(&[7 | TIMESTAMP])
+                    .context("write timestamp prefix")?;
+                w.write_all(&secs.to_le_bytes())
+                    .context("write timestamp")?;
+            }
+            Self::Millis(ms) => {
+                w.write_all(&[9 | TIMESTAMP])
+                    .context("write timestamp prefix")?;
+                w.write_all(&ms.to_le_bytes()).context("write timestamp")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// seconds since 1970 of "YYYY-MM-DDTHH:MM:SS"
+fn rfc3339_seconds(s: &str) -> Option<u64> {
+    let b = s.as_bytes();
+    if b.len() != 19 || b[4] != b'-' || b[7] != b'-' || b[10] != b'T' {
+        return None;
+    }
+    if b[13] != b':' || b[16] != b':' {
+        return None;
+    }
+    let num = |from: usize, to: usize| -> Option<u64> {
+        let part = &s[from..to];
+        match part.bytes().all(|b| b.is_ascii_digit()) {
+            true => part.parse().ok(),
+            false => None,
+        }
+    };
+    let (y, m, d) = (num(0, 4)?, num(5, 7)?, num(8, 10)?);
+    let (hh, mm, ss) = (num(11, 13)?, num(14, 16)?, num(17, 19)?);
+    if y < 1970 || !(1..=12).contains(&m) || !(1..=31).contains(&d) {
+        return None;
+    }
+    if hh > 23 || mm > 59 || ss > 59 {
+        return None;
+    }
+    Some(days_from_civil(y, m, d) * 86400 + hh * 3600 + mm * 60 + ss)
+}
+
+// "YYYY-MM-DDTHH:MM:SS" of seconds since 1970
+fn rfc3339(secs: u64) -> String {
+    let (y, m, d) = civil_from_days(secs / 86400);
+    let t = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        y,
+        m,
+        d,
+        t / 3600,
+        t % 3600 / 60,
+        t % 60
+    )
+}
+
+// days since 1970-01-01 of the date in the proleptic Gregorian calendar,
+// after http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(y: u64, m: u64, d: u64) -> u64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * ((m + 9) % 12) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + u64::from(m <= 2);
+    (y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_what_renders_back() {
+        let cases = [
+            ("1970-01-01T00:00:00Z", Timestamp::Seconds(0)),
+            ("2000-02-29T23:59:59Z", Timestamp::Seconds(951868799)),
+            ("2023-07-01T12:00:00Z", Timestamp::Seconds(1688212800)),
+            ("2023-07-01T12:00:00.250Z", Timestamp::Millis(1688212800250)),
+            ("2200-01-01T00:00:00.000Z", Timestamp::Millis(7258118400000)),
+            ("1688212800", Timestamp::Digits(1688212800)),
+        ];
+        for (s, t) in cases {
+            assert_eq!(Timestamp::parse(s), Some(t), "{}", s);
+            assert_eq!(t.render(), s);
+        }
+        for s in [
+            "2023-02-29T00:00:00Z",
+            "2023-07-01T12:00:00+00:00",
+            "2023-07-01T12:00:00.25Z",
+            "2023-07-01 12:00:00Z",
+            "1969-12-31T23:59:59Z",
+            "2200-01-01T00:00:00Z",
+            "0123",
+            "99999999999",
+            "",
+        ] {
+            assert_eq!(Timestamp::parse(s), None, "{}", s);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::decode::{item_of, next_u8, read_children, Item};
+use crate::frame::{read_entries, read_frame, FLAG_CHECKSUM, FLAG_DICTIONARY, HEADER_SIZE, MAGIC};
+use crate::{Counting, DecodeError, BACK_REFS_TAG, FINGERPRINT_TAG};
+use anyhow::Context;
+use std::io::{ErrorKind, Read};
+
+/// What `verify` found in the blob
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// JSON type of the value: "null", "boolean", "number", "string", "array" or "object"
+    pub root: &'static str,
+    /// values in the blob, containers and elements of packed arrays included
+    pub values: u64,
+    /// bytes after the value, in the payload and after the frame, which decode never reads
+    pub trailing: u64,
+    /// the blob has a frame header
+    pub framed: bool,
+    /// the frame has a checksum, and it matched the payload
+    pub checksum: bool,
+}
+
+/// checks the structure of a framed or unframed blob without dictionaries
+/// and without building values, references are not resolved.
+/// Errors carry the offset of the value that failed, or of the checksum
+pub fn verify(bytes: &[u8]) -> anyhow::Result<VerifyReport> {
+    if !bytes.starts_with(&MAGIC) {
+        return walk(bytes, 0);
+    }
+    let mut after = bytes;
+    let (flags, payload) = read_frame(&mut after)?;
+    let mut rest = payload.as_slice();
+    if flags & FLAG_DICTIONARY != 0 {
+        read_entries(&mut rest).context("field dictionary section")?;
+        read_entries(&mut rest).context("value dictionary section")?;
+    }
+    let base = HEADER_SIZE + payload.len() - rest.len();
+    let mut report = walk(rest, base as u64)?;
+    report.trailing += after.len() as u64;
+    report.framed = true;
+    report.checksum = flags & FLAG_CHECKSUM != 0;
+    Ok(report)
+}
+
+// walks the value at the start of the bytes, which are at the offset of the blob
+fn walk(mut bytes: &[u8], base: u64) -> anyhow::Result<VerifyReport> {
+    let len = bytes.len() as u64;
+    let mut input = Counting::new(&mut bytes, Some(len));
+    let mut nb = next_u8(&mut input).map_err(|e| at(e, base))?;
+    if nb == FINGERPRINT_TAG {
+        let mut fingerprints = [0u8; 32];
+        input
+            .read_exact(&mut fingerprints)
+            .map_err(|e| at(e.into(), base))?;
+        nb = next_u8(&mut input).map_err(|e| at(e, base))?;
+    }
+    if nb == BACK_REFS_TAG {
+        nb = next_u8(&mut input).map_err(|e| at(e, base))?;
+    }
+    let offset = base + input.bytes - 1;
+    let left = input.remaining();
+    let item = item_of(nb, &mut input, left).map_err(|e| at(e, offset))?;
+    let root = match &item {
+        Item::Null => "null",
+        Item::Bool(_) => "boolean",
+        Item::Number(_) | Item::NonFinite(_) => "number",
+        Item::Bytes(_) | Item::Str(_) | Item::BackRef(_) => "string",
+        Item::ValueRef(_) | Item::BytesRef(_) => "string",
+        Item::Packed(_) | Item::Array(_) => "array",
+        Item::BigNumber(_) | Item::Object(_) => "object",
+    };
+    let values = read_children(item, &mut input).map_err(|(offset, e)| at(e, base + offset))?;
+    Ok(VerifyReport {
+        root,
+        values,
+        trailing: len - input.bytes,
+        framed: false,
+        checksum: false,
+    })
+}
+
+// the error of the value at the offset
+fn at(e: anyhow::Error, offset: u64) -> anyhow::Error {
+    match e.downcast_ref::<std::io::Error>() {
+        Some(io) if io.kind() == ErrorKind::UnexpectedEof => {
+            return DecodeError::Truncated { offset }.into()
+        }
+        _ => {}
+    }
+    // sizes are checked against the end of the blob before anything is read
+    match e.downcast_ref::<DecodeError>() {
+        Some(DecodeError::BudgetExceeded { unit: "bytes", .. }) => {
+            DecodeError::Truncated { offset }.into()
+        }
+        _ => e.context(format!("invalid value at offset {}", offset)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::NoDictionary;
+    use crate::{decode_with_stats, encode, read_framed, write_framed_with, EncodeOptions};
+    use serde_json::json;
+
+    #[test]
+    fn it_reports_what_decode_reads() {
+        let d = NoDictionary {};
+        let v = json!({"a": [1, "x", null], "b": {"c": true}, "d": [1, 2, 3, 4]});
+        let mut buf = vec![];
+        encode(&v, &mut buf, &d, &d).unwrap();
+        let (_, stats) = decode_with_stats(&mut buf.as_slice(), &d, &d, &Default::default());
+        let report = verify(&buf).unwrap();
+        assert_eq!(report.root, "object");
+        assert_eq!(report.values, stats.values);
+        assert_eq!(
+            (report.trailing, report.framed, report.checksum),
+            (0, false, false)
+        );
+
+        buf.extend_from_slice(&[0xff, 0xff]);
+        assert_eq!(verify(&buf).unwrap().trailing, 2);
+        // the last value is cut in the middle
+        let err = verify(&buf[..buf.len() - 4]).unwrap_err();
+        let found = err.downcast_ref::<DecodeError>();
+        assert_eq!(found, Some(&DecodeError::Truncated { offset: 25 }));
+        // 0x1e is no key prefix
+        buf[2] = 0x1e;
+        let err = verify(&buf).unwrap_err();
+        assert_eq!(err.to_string(), "invalid value at offset 2");
+    }
+
+    #[test]
+    fn it_fails_on_flipped_byte_with_checksum() {
+        let d = NoDictionary {};
+        let v = json!({"name": "example", "list": ["one", "two", "three"]});
+        let opts = EncodeOptions {
+            frame_checksum: true,
+            ..Default::default()
+        };
+        let mut buf = vec![];
+        write_framed_with(&v, &mut buf, &d, &d, &opts).unwrap();
+        let report = verify(&buf).unwrap();
+        assert_eq!(report.root, "object");
+        assert!(report.framed && report.checksum);
+
+        // the same structure, with "example" spelled another way
+        let mid = buf.len() / 2;
+        buf[mid] ^= 0x01;
+        let end = buf.len() - 4;
+        let expected = DecodeError::ChecksumMismatch {
+            offset: end as u64,
+            stored: u32::from_le_bytes(buf[end..].try_into().unwrap()),
+            computed: crc32c::crc32c(&buf[HEADER_SIZE..end]),
+        };
+        let err = verify(&buf).unwrap_err();
+        assert_eq!(err.downcast_ref::<DecodeError>(), Some(&expected));
+        assert!(read_framed(&mut buf.as_slice(), &d, &d).is_err());
+    }
+}
@@ -0,0 +1,326 @@
+use crate::decode::{next_item, next_key, Item, Key, DEFAULT_MAX_DEPTH};
+use crate::dictionary::DictionaryRead;
+use crate::{Counting, DecodeError};
+use anyhow::bail;
+use serde_json::Number;
+use std::io::Read;
+
+/// Scalar value passed to the visitor
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalarRef<'a> {
+    Null,
+    Bool(bool),
+    Number(&'a Number),
+    /// NaN or an infinity
+    NonFinite(f64),
+    /// bytes value, that decodes into 0x-prefixed hex string
+    Bytes(&'a [u8]),
+    Str(&'a str),
+}
+
+/// What the walker should do after the callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Continue,
+    /// skip the children of the container that was just started,
+    /// or the value of the field that was just visited
+    SkipChildren,
+    /// stop walking the document
+    Stop,
+}
+
+/// Callbacks for walking the encoded document without building Values.
+/// `on_end` is called for every container that was not skipped
+pub trait Visitor {
+    fn on_object_start(&mut self, _len: usize) -> Control {
+        Control::Continue
+    }
+    fn on_field(&mut self, _name: &str) -> Control {
+        Control::Continue
+    }
+    fn on_value(&mut self, _value: ScalarRef) -> Control {
+        Control::Continue
+    }
+    fn on_array_start(&mut self, _len: usize) -> Control {
+        Control::Continue
+    }
+    fn on_end(&mut self) -> Control {
+        Control::Continue
+    }
+}
+
+/// walks one encoded value, calling the visitor for every item.
+/// Returns `Control::Stop` if the visitor stopped the walk
+pub fn visit<R: Read, V: Visitor, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut R,
+    fd: &D1,
+    vd: &D2,
+    visitor: &mut V,
+) -> anyhow::Result<Control> {
+    let mut input = Counting::new(input, None);
+    walk(&mut input, fd, vd, visitor, 0)
+}
+
+// nesting is limited as in decoding, so crafted input can't overflow the stack
+fn enter<R: Read>(input: &Counting<'_, R>, depth: usize) -> anyhow::Result<usize> {
+    if depth >= DEFAULT_MAX_DEPTH {
+        // the container tag was read already
+        let offset = input.bytes.saturating_sub(1);
+        return Err(DecodeError::MaxDepthExceeded { offset }.into());
+    }
+    Ok(depth + 1)
+}
+
+fn walk<R: Read, V: Visitor, D1: DictionaryRead, D2: DictionaryRead>(
+    input: &mut Counting<'_, R>,
+    fd: &D1,
+    vd: &D2,
+    visitor: &mut V,
+    depth: usize,
+) -> anyhow::Result<Control> {
+    let flow = match next_item(input)? {
+        Item::Null => visitor.on_value(ScalarRef::Null),
+        Item::Bool(b) => visitor.on_value(ScalarRef::Bool(b)),
+        Item::Number(n) => visitor.on_value(ScalarRef::Number(&n)),
+        Item::NonFinite(f) => visitor.on_value(ScalarRef::NonFinite(f)),
+        Item::Bytes(b) | Item::BigNumber(b) => visitor.on_value(ScalarRef::Bytes(&b)),
+        Item::Str(s) => visitor.on_value(ScalarRef::Str(&s)),
+        Item::BackRef(_) => bail!("back-references are only read by decode"),
+        Item::ValueRef(dict_id) => match vd.get(dict_id) {
+            Some(buf) => visitor.on_value(ScalarRef::Str(std::str::from_utf8(buf)?)),
+            None => bail!(format!("value {} not found in dictionary", dict_id)),
+        },
+        Item::BytesRef(dict_id) => match vd.get(dict_id) {
+            Some(buf) => visitor.on_value(ScalarRef::Bytes(buf)),
+            None => bail!(format!("value {} not found in dictionary", dict_id)),
+        },
+        Item::Packed(nums) => match visitor.on_array_start(nums.len()) {
+            Control::Stop => Control::Stop,
+            Control::SkipChildren => Control::Continue,
+            Control::Continue => {
+                for n in &nums {
+                    if visitor.on_value(ScalarRef::Number(n)) == Control::Stop {
+                        return Ok(Control::Stop);
+                    }
+                }
+                visitor.on_end()
+            }
+        },
+        Item::Array(size) => match visitor.on_array_start(size) {
+            Control::Stop => Control::Stop,
+            Control::SkipChildren => {
+                let depth = enter(input, depth)?;
+                for _ in 0..size {
+                    discard(input, depth)?;
+                }
+                Control::Continue
+            }
+            Control::Continue => {
+                let depth = enter(input, depth)?;
+                for _ in 0..size {
+                    if walk(input, fd, vd, visitor, depth)? == Control::Stop {
+                        return Ok(Control::Stop);
+                    }
+                }
+                visitor.on_end()
+            }
+        },
+        Item::Object(size) => match visitor.on_object_start(size) {
+            Control::Stop => Control::Stop,
+            Control::SkipChildren => {
+                let depth = enter(input, depth)?;
+                for _ in 0..size {
+                    next_key(input)?;
+                    discard(input, depth)?;
+                }
+                Control::Continue
+            }
+            Control::Continue => {
+                let depth = enter(input, depth)?;
+                for _ in 0..size {
+                    let flow = match next_key(input)? {
+                        Key::Field(dict_id) => match fd.get(dict_id) {
+                            Some(found) => visitor.on_field(std::str::from_utf8(found)?),
+                            None => {
+                                bail!(format!("field value {} not found in dictionary", dict_id))
+                            }
+                        },
+                        Key::Str(s) => visitor.on_field(&s),
+                        Key::Number(n) => visitor.on_field(&n.to_string()),
+                    };
+                    match flow {
+                        Control::Stop => return Ok(Control::Stop),
+                        Control::SkipChildren => discard(input, depth)?,
+                        Control::Continue => {
+                            if walk(input, fd, vd, visitor, depth)? == Control::Stop {
+                                return Ok(Control::Stop);
+                            }
+                        }
+                    }
+                }
+                visitor.on_end()
+            }
+        },
+    };
+    Ok(match flow {
+        Control::Stop => Control::Stop,
+        _ => Control::Continue,
+    })
+}
+
+// parses and drops one value
+fn discard<R: Read>(input: &mut Counting<'_, R>, depth: usize) -> anyhow::Result<()> {
+    match next_item(input)? {
+        Item::Array(size) => {
+            let depth = enter(input, depth)?;
+            for _ in 0..size {
+                discard(input, depth)?;
+            }
+        }
+        Item::Object(size) => {
+            let depth = enter(input, depth)?;
+            for _ in 0..size {
+                next_key(input)?;
+                discard(input, depth)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dictionary::MapDictionary;
+    use crate::encode;
+    use serde_json::Value;
+    use std::io::BufReader;
+    use std::str::FromStr;
+
+    // records every callback as a line, skipping children of the given field
+    struct Recorder {
+        events: Vec<String>,
+        skip: Option<&'static str>,
+        stop_at: Option<&'static str>,
+    }
+
+    impl Recorder {
+        fn new() -> Self {
+            Self {
+                events: vec![],
+                skip: None,
+                stop_at: None,
+            }
+        }
+    }
+
+    impl Visitor for Recorder {
+        fn on_object_start(&mut self, len: usize) -> Control {
+            self.events.push(format!("object {}", len));
+            Control::Continue
+        }
+        fn on_field(&mut self, name: &str) -> Control {
+            self.events.push(format!("field {}", name));
+            if self.stop_at == Some(name) {
+                return Control::Stop;
+            }
+            if self.skip == Some(name) {
+                return Control::SkipChildren;
+            }
+            Control::Continue
+        }
+        fn on_value(&mut self, value: ScalarRef) -> Control {
+            self.events.push(format!("value {:?}", value));
+            Control::Continue
+        }
+        fn on_array_start(&mut self, len: usize) -> Control {
+            self.events.push(format!("array {}", len));
+            Control::Continue
+        }
+        fn on_end(&mut self) -> Control {
+            self.events.push("end".to_string());
+            Control::Continue
+        }
+    }
+
+    fn fixture() -> (Vec<u8>, MapDictionary) {
+        let d = MapDictionary::from_strings(vec!["alpha", "beta", "gamma", "delta", "epsilon"]);
+        let s = "{\"alpha\":\"test\",\"beta\":[1,2],\"epsilon\":{\"gamma\":\"hello\"},\"no\":\"0x01ff\"}";
+        let mut buf = Vec::new();
+        encode(&Value::from_str(s).unwrap(), &mut buf, &d, &d).unwrap();
+        (buf, d)
+    }
+
+    #[test]
+    fn it_visits_object() {
+        let (buf, d) = fixture();
+        let mut r = Recorder::new();
+        let flow = visit(&mut BufReader::new(buf.as_slice()), &d, &d, &mut r).unwrap();
+        assert_eq!(flow, Control::Continue);
+        assert_eq!(
+            r.events,
+            vec![
+                "object 4",
+                "field alpha",
+                "value Str(\"test\")",
+                "field beta",
+                "array 2",
+                "value Number(Number(1))",
+                "value Number(Number(2))",
+                "end",
+                "field epsilon",
+                "object 1",
+                "field gamma",
+                "value Str(\"hello\")",
+                "end",
+                "field no",
+                "value Bytes([1, 255])",
+                "end",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_skips_children() {
+        let (buf, d) = fixture();
+        let mut r = Recorder::new();
+        r.skip = Some("epsilon");
+        visit(&mut BufReader::new(buf.as_slice()), &d, &d, &mut r).unwrap();
+        assert_eq!(
+            r.events,
+            vec![
+                "object 4",
+                "field alpha",
+                "value Str(\"test\")",
+                "field beta",
+                "array 2",
+                "value Number(Number(1))",
+                "value Number(Number(2))",
+                "end",
+                "field epsilon",
+                "field no",
+                "value Bytes([1, 255])",
+                "end",
+            ]
+        );
+    }
+
+    #[test]
+    fn it_stops() {
+        let (buf, d) = fixture();
+        let mut r = Recorder::new();
+        r.stop_at = Some("beta");
+        let flow = visit(&mut BufReader::new(buf.as_slice()), &d, &d, &mut r).unwrap();
+        assert_eq!(flow, Control::Stop);
+        assert_eq!(
+            r.events,
+            vec![
+                "object 4",
+                "field alpha",
+                "value Str(\"test\")",
+                "field beta"
+            ]
+        );
+    }
+}
@@ -0,0 +1,36 @@
+use std::process::Command;
+
+// dependency tree of the crate built with the given cargo feature flags
+fn tree(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO"))
+        .args([
+            "tree",
+            "-p",
+            "jsondp",
+            "-e",
+            "normal",
+            "--offline",
+            "--prefix",
+            "none",
+        ])
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cargo tree");
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn it_builds_without_the_ethereum_stack() {
+    let minimal = tree(&["--no-default-features"]);
+    for dep in ["ethers ", "lazy_static ", "tracing "] {
+        assert!(!minimal.contains(dep), "{} in minimal build", dep);
+    }
+    let eth = tree(&["--no-default-features", "--features", "eth"]);
+    assert!(eth.contains("ethers "));
+}
@@ -0,0 +1,165 @@
+use jsondp::decode::skip_value;
+use jsondp::dictionary::{DictionaryRead, MapDictionary, NoDictionary};
+use jsondp::{DecodeOptions, Decoder, Visitor};
+use serde_json::{json, Value};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+// visitor that takes everything it is given
+struct Nothing;
+
+impl Visitor for Nothing {}
+
+// every way to read a blob, which may fail but must not panic
+fn read_all<D: DictionaryRead>(input: &[u8], d: &D) {
+    let strict = || DecodeOptions {
+        strict_minimal: true,
+        ..Default::default()
+    };
+    let _ = jsondp::decode(&mut &input[..], d, d);
+    let _ = Decoder::new(d, d)
+        .with_options(strict())
+        .decode_slice(input);
+    let _ = jsondp::decode_borrowed(input, d, d);
+    let _ = Decoder::new(d, d)
+        .with_options(strict())
+        .decode_borrowed(input);
+    let _ = jsondp::decode_to_writer(&mut &input[..], &mut std::io::sink(), d, d);
+    let _ = jsondp::decode_salvage(input, d, d);
+    let _ = jsondp::visit(&mut &input[..], d, d, &mut Nothing);
+    let _ = jsondp::from_slice::<Value, _, _>(input, d, d);
+    let _ = jsondp::decode_path(input, "a.0.b", d, d);
+    let _ = jsondp::iter_decode(input, d, d).count();
+    let _ = jsondp::read_framed(&mut &input[..], d, d);
+    let _ = jsondp::decode_self_describing(&mut &input[..]);
+    let _ = jsondp::verify(input);
+    let _ = skip_value(&mut &input[..]);
+}
+
+fn check(input: &[u8]) {
+    let d = MapDictionary::from_static(&["a", "b", "0x95087266018b9637aff3d76d4e0cad7e52c19636"]);
+    let read = catch_unwind(AssertUnwindSafe(|| {
+        read_all(input, &NoDictionary {});
+        read_all(input, &d);
+    }));
+    assert!(read.is_ok(), "panic on {}", hex::encode(input));
+}
+
+// xorshift, so failures are reproduced by the same seed
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        (0..len).map(|_| self.next() as u8).collect()
+    }
+}
+
+#[test]
+fn it_never_panics_on_crafted_input() {
+    let mut cases: Vec<Vec<u8>> = vec![
+        vec![],
+        // invalid UTF-8 in every string form
+        vec![20, 2, 0xc3, 0x28],
+        vec![24, 2, 0, 0xff, 0xfe],
+        vec![28, 1, 0, 0, 0, 0x80],
+        vec![20 | 0x80, 1, 0xff],
+        vec![22, 1, 20, 1, 0xff, 31],
+        vec![27, 0, 20, 1, 0xff],
+        // truncated containers and values
+        vec![21, 3, 2, 1],
+        vec![22, 2, 20, 1, b'a'],
+        vec![26, 0xff, 0xff],
+        vec![9, 1, 2, 3],
+        vec![25, 0xff, 0xff, 0x1f],
+        // absurd sizes
+        vec![28, 0xff, 0xff, 0xff, 0xff, b'a'],
+        vec![29, 0xff, 0xff, 0xff, 0xff],
+        [&[21 | 0x80][..], &[0xff; 8], &[0x7f, 31]].concat(),
+        [&[21 | 0x80 | 0x40][..], &[0xff; 8], &[0x7f, 9]].concat(),
+        vec![25 | 0x40, 0xff, 0xff, 9, 1],
+        // varints of 64 bits, and over
+        [&[19 | 0x80][..], &[0xff; 9], &[0x01]].concat(),
+        [&[19 | 0x80][..], &[0xff; 9], &[0x02]].concat(),
+        // unknown tags and flags
+        vec![30],
+        vec![0x1e | 0x40, 0],
+        vec![9 | 0x40 | 0x80, 0, 0, 0, 0, 0, 0, 0, 0],
+        vec![7 | 0x40, 0xff, 0xff, 0xff, 0xff],
+        vec![9 | 0x40, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        vec![27, 0xff, 19, 1, 0],
+        vec![27 | 0x40, 0xff],
+        vec![0xf0],
+        vec![0xf1, 27 | 0x40, 5],
+        // dictionary references to nothing
+        vec![22, 1, 0x40 | 0x20, 0x7f, 31],
+        vec![20 | 0x20 | 0x40, 0x7f],
+        vec![19 | 0x20 | 0xc0, 0xff, 0xff, 0xff, 0xff],
+        // frames
+        b"JDP1".to_vec(),
+        b"JDP1\x01\x02\x04\x00\x00\x00\x01\x01\x01".to_vec(),
+        b"JDP1\x01\x04\x01\x00\x00\x00\x1f\x00\x00\x00\x00".to_vec(),
+        b"JDP1\x01\x01\x04\x00\x00\x00\x28\xb5\x2f\xfd".to_vec(),
+        b"JDP1\x02\x00\xff\xff\xff\xff".to_vec(),
+    ];
+    // nesting deeper than any limit
+    cases.push([22, 1, 20, 1, b'a'].repeat(100_000));
+    cases.push([21, 1].repeat(100_000));
+    for case in &cases {
+        check(case);
+    }
+}
+
+#[test]
+fn it_never_panics_on_random_input() {
+    let mut random = Random(0x9e3779b97f4a7c15);
+    for len in 0..400 {
+        let input = random.bytes(len % 64);
+        check(&input);
+    }
+}
+
+#[test]
+fn it_never_panics_on_damaged_blobs() {
+    let d = NoDictionary {};
+    let v = json!({
+        "a": [1, 2, 3, "0x95087266018b9637aff3d76d4e0cad7e52c19636", {"b": null}],
+        "text": "hello, world",
+        "at": "2023-07-01T12:00:00Z",
+        "big": {"type": "BigNumber", "hex": "0x0de0b6b3a7640000"},
+        "float": 1.5,
+        "12": -300,
+    });
+    let opts = jsondp::EncodeOptions {
+        back_references: true,
+        detect_timestamps: true,
+        ..Default::default()
+    };
+    let mut blobs = vec![];
+    for opts in [jsondp::EncodeOptions::default(), opts] {
+        let mut buf = vec![];
+        jsondp::encode_with(&v, &mut buf, &d, &d, &opts).unwrap();
+        blobs.push(buf);
+    }
+    let mut framed = vec![];
+    jsondp::write_framed(&v, &mut framed, &d, &d).unwrap();
+    blobs.push(framed);
+
+    let mut random = Random(42);
+    for blob in &blobs {
+        for end in 0..blob.len() {
+            check(&blob[..end]);
+        }
+        for _ in 0..200 {
+            let mut damaged = blob.clone();
+            let at = random.next() as usize % damaged.len();
+            damaged[at] = random.next() as u8;
+            check(&damaged);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use crate::{KvTxn, KV};
+use async_trait::async_trait;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::*;
+
+/// Failure injection settings, all rates are probabilities in [0, 1]
+#[derive(Debug, Clone, Default)]
+pub struct ChaosProfile {
+    /// seed of the random generator, same seed gives the same failures
+    pub seed: u64,
+    /// probability of `get` returning an error
+    pub read_error_rate: f64,
+    /// probability of `set` returning an error without storing the value,
+    /// for the `set` of transactions too
+    pub write_error_rate: f64,
+    /// probability of the transaction commit failing, the transaction is rolled back then
+    pub commit_error_rate: f64,
+    /// probability of the call being delayed by `slow_delay`
+    pub slow_rate: f64,
+    /// delay of slow calls
+    pub slow_delay: Duration,
+}
+
+/// Random generator for failure decisions (splitmix64).
+/// Not cryptographic, but stable across platforms and releases, so failures can be replayed
+#[derive(Debug)]
+pub struct Chaos {
+    state: Mutex<u64>,
+}
+
+impl Chaos {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Mutex::new(seed),
+        }
+    }
+
+    /// next pseudo-random number of the sequence
+    pub fn next_u64(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        *state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = *state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// returns true with the given probability
+    pub fn roll(&self, rate: f64) -> bool {
+        if rate <= 0.0 {
+            return false;
+        }
+        // 53 bits of randomness as a float in [0, 1)
+        let x = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        x < rate
+    }
+}
+
+/// KV wrapper that injects failures and slow responses into the inner storage
+#[derive(Debug)]
+pub struct ChaosKV<K: KV> {
+    inner: K,
+    profile: ChaosProfile,
+    chaos: Chaos,
+    failed_commits: AtomicU64,
+}
+
+impl<K: KV> ChaosKV<K> {
+    pub fn new(inner: K, profile: ChaosProfile) -> Self {
+        // seed must be visible in the logs so the failing run can be replayed
+        info!(seed = profile.seed, "chaos storage enabled");
+        let chaos = Chaos::new(profile.seed);
+        Self {
+            inner,
+            profile,
+            chaos,
+            failed_commits: AtomicU64::new(0),
+        }
+    }
+
+    /// transactions rolled back by an injected commit failure
+    pub fn failed_commits(&self) -> u64 {
+        self.failed_commits.load(Ordering::Relaxed)
+    }
+
+    async fn maybe_slow(&self) {
+        if self.chaos.roll(self.profile.slow_rate) {
+            tokio::time::sleep(self.profile.slow_delay).await;
+        }
+    }
+}
+
+/// Transaction of the inner storage which `set` and `commit` may fail
+struct ChaosTxn<'a, K: KV> {
+    inner: Box<dyn KvTxn + 'a>,
+    kv: &'a ChaosKV<K>,
+}
+
+#[async_trait]
+impl<K: KV + Sync + Send> KvTxn for ChaosTxn<'_, K> {
+    async fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+        self.kv.maybe_slow().await;
+        if self.kv.chaos.roll(self.kv.profile.write_error_rate) {
+            anyhow::bail!("chaos: injected write failure for key {} of {}", n, bucket);
+        }
+        self.inner.set(bucket, n, v).await
+    }
+
+    async fn delete(&mut self, bucket: &str, n: u32) -> anyhow::Result<()> {
+        self.inner.delete(bucket, n).await
+    }
+
+    async fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        let kv = self.kv;
+        kv.maybe_slow().await;
+        if kv.chaos.roll(kv.profile.commit_error_rate) {
+            kv.failed_commits.fetch_add(1, Ordering::Relaxed);
+            self.inner.rollback().await?;
+            anyhow::bail!("chaos: injected commit failure");
+        }
+        self.inner.commit().await
+    }
+
+    async fn rollback(self: Box<Self>) -> anyhow::Result<()> {
+        self.inner.rollback().await
+    }
+}
+
+#[async_trait]
+impl<K: KV + Sync + Send> KV for ChaosKV<K> {
+    async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>> {
+        self.maybe_slow().await;
+        if self.chaos.roll(self.profile.read_error_rate) {
+            anyhow::bail!("chaos: injected read failure for key {}", n);
+        }
+        self.inner.get(n).await
+    }
+
+    async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+        self.maybe_slow().await;
+        if self.chaos.roll(self.profile.write_error_rate) {
+            anyhow::bail!("chaos: injected write failure for key {}", n);
+        }
+        self.inner.set(n, v).await
+    }
+
+    async fn keys(&self, from: u32, to: u32) -> anyhow::Result<Option<Vec<u32>>> {
+        self.maybe_slow().await;
+        if self.chaos.roll(self.profile.read_error_rate) {
+            anyhow::bail!("chaos: injected read failure for keys {}..{}", from, to);
+        }
+        self.inner.keys(from, to).await
+    }
+
+    async fn transaction(&self) -> anyhow::Result<Option<Box<dyn KvTxn + '_>>> {
+        self.maybe_slow().await;
+        Ok(match self.inner.transaction().await? {
+            Some(inner) => Some(Box::new(ChaosTxn { inner, kv: self })),
+            None => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_replays_with_same_seed() {
+        let a = Chaos::new(42);
+        let b = Chaos::new(42);
+        let ra: Vec<bool> = (0..100).map(|_| a.roll(0.3)).collect();
+        let rb: Vec<bool> = (0..100).map(|_| b.roll(0.3)).collect();
+        assert_eq!(ra, rb);
+        let hits = ra.iter().filter(|x| **x).count();
+        assert!(hits > 10 && hits < 60);
+    }
+
+    #[tokio::test]
+    async fn it_rolls_back_failed_commits() {
+        let kv = ChaosKV::new(
+            crate::MemoryKV::new().bucket("blocks"),
+            ChaosProfile {
+                seed: 1,
+                commit_error_rate: 1.0,
+                ..Default::default()
+            },
+        );
+        let mut txn = kv.transaction().await.unwrap().unwrap();
+        txn.set("blocks", 1, vec![1]).await.unwrap();
+        txn.set("blocks", 2, vec![2]).await.unwrap();
+        assert!(txn.commit().await.is_err());
+        assert_eq!(kv.failed_commits(), 1);
+        assert_eq!(kv.keys(0, 10).await.unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn it_never_fails_at_zero_rate() {
+        let c = Chaos::new(7);
+        assert!((0..1000).all(|_| !c.roll(0.0)));
+        assert!((0..1000).all(|_| c.roll(1.0)));
+    }
+}
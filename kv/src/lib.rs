@@ -1,79 +1,82 @@
 use async_trait::async_trait;
-use sqlx::postgres::PgPool;
-use sqlx::Row;
-use tracing::*;
+use std::fmt;
 
-#[async_trait]
-pub trait KV {
-    // get returns the block data from persistent storage
-    async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>>;
-    // set updates or inserts the block into persistent storage
-    async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()>;
-}
+#[cfg(feature = "chaos")]
+pub mod chaos;
+pub mod memory;
+#[cfg(feature = "postgres")]
+mod partition;
+#[cfg(feature = "postgres")]
+mod postgres;
+
+pub use memory::{MemoryKV, MemoryTxn};
+#[cfg(feature = "postgres")]
+pub use postgres::{PostgresKV, PostgresTxn, Record};
 
-#[derive(Debug)]
-pub struct PostgresKV {
-    pub db: PgPool,
-    pub table_name: String,
+/// Value over the configured `max_value_bytes` of the storage
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueTooLarge {
+    pub key: u32,
+    pub size: usize,
+    pub limit: usize,
 }
 
-impl PostgresKV {
-    pub async fn new(database_url: &str, table_name: &str) -> Self {
-        let db = sqlx::postgres::PgPoolOptions::new()
-            .max_connections(1)
-            .connect(&database_url)
-            .await
-            .expect("could not connect to database_url");
+impl fmt::Display for ValueTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "value of key {} is {} bytes, over the limit of {} bytes",
+            self.key, self.size, self.limit
+        )
+    }
+}
 
-        info!("checking postgres tables");
-        sqlx::query(&format!(
-            "CREATE TABLE IF NOT EXISTS {} (\"k\" INTEGER, \"v\" BYTEA, PRIMARY KEY (\"k\"))",
-            table_name,
-        ))
-        .execute(&db)
-        .await
-        .expect("init database");
+impl std::error::Error for ValueTooLarge {}
 
-        Self {
-            db,
-            table_name: table_name.to_string(),
+/// fails with `ValueTooLarge` when the value is over the limit
+pub fn check_value_size(key: u32, v: &[u8], limit: Option<usize>) -> anyhow::Result<()> {
+    match limit {
+        Some(limit) if v.len() > limit => Err(ValueTooLarge {
+            key,
+            size: v.len(),
+            limit,
         }
+        .into()),
+        _ => Ok(()),
     }
 }
 
-#[derive(sqlx::FromRow)]
-pub struct Record {
-    pub k: u32,
-    pub v: Vec<u8>,
-}
-
 #[async_trait]
-impl KV for PostgresKV {
-    #[instrument(level = "TRACE")]
-    async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>> {
-        let sql = format!("SELECT v FROM {} WHERE k=$1 LIMIT 1", self.table_name);
-        let rows = sqlx::query(&sql)
-            .bind(n as i32)
-            .fetch_optional(&self.db)
-            .await?;
-        Ok(match rows {
-            Some(row) => Some(row.get::<Vec<u8>, _>("v")),
-            None => None,
-        })
+pub trait KV {
+    // get returns the block data from persistent storage
+    async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>>;
+    // set updates or inserts the block into persistent storage
+    async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()>;
+    /// starts a transaction over the buckets of the same storage,
+    /// None when the backend can't write several keys atomically
+    async fn transaction(&self) -> anyhow::Result<Option<Box<dyn KvTxn + '_>>> {
+        Ok(None)
+    }
+    /// keys of the bucket from `from` to `to` inclusive, in ascending order,
+    /// None when the backend can't list its keys
+    async fn keys(&self, _from: u32, _to: u32) -> anyhow::Result<Option<Vec<u32>>> {
+        Ok(None)
     }
+}
 
-    #[instrument(level = "TRACE")]
-    async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
-        let sql = format!(
-            "INSERT INTO {} (k, v) VALUES ($1, $2) ON CONFLICT(k) DO UPDATE SET v=$2",
-            self.table_name
-        );
-        let _ = sqlx::query(&sql)
-            .bind(n as i32)
-            .bind(v)
-            .execute(&self.db)
-            .await?
-            .rows_affected();
+/// Writes to several buckets of one storage that are applied all together or not at all.
+/// Buckets are addressed by name: the table name for Postgres, the bucket name for memory
+#[async_trait]
+pub trait KvTxn: Send {
+    async fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> anyhow::Result<()>;
+    async fn set_many(&mut self, bucket: &str, items: Vec<(u32, Vec<u8>)>) -> anyhow::Result<()> {
+        for (n, v) in items {
+            self.set(bucket, n, v).await?;
+        }
         Ok(())
     }
+    async fn delete(&mut self, bucket: &str, n: u32) -> anyhow::Result<()>;
+    async fn commit(self: Box<Self>) -> anyhow::Result<()>;
+    /// drops all writes of the transaction, same as dropping it without commit
+    async fn rollback(self: Box<Self>) -> anyhow::Result<()>;
 }
@@ -0,0 +1,176 @@
+use crate::{check_value_size, KvTxn, KV};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+type Buckets = BTreeMap<String, BTreeMap<u32, Vec<u8>>>;
+
+/// In-process storage, for tests and benchmarks that should not depend on a database
+#[derive(Debug, Default)]
+pub struct MemoryKV {
+    name: String,
+    store: Arc<Mutex<Buckets>>,
+    max_value_bytes: Option<usize>,
+}
+
+impl MemoryKV {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// another bucket of the same store, transactions of either can write to both
+    pub fn bucket(&self, name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            store: self.store.clone(),
+            max_value_bytes: self.max_value_bytes,
+        }
+    }
+
+    /// rejects values over the limit with `ValueTooLarge`
+    pub fn with_max_value_bytes(mut self, limit: usize) -> Self {
+        self.max_value_bytes = Some(limit);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn len(&self) -> usize {
+        let store = self.store.lock().unwrap();
+        store.get(&self.name).map_or(0, |b| b.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl KV for MemoryKV {
+    async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>> {
+        let store = self.store.lock().unwrap();
+        Ok(store.get(&self.name).and_then(|b| b.get(&n)).cloned())
+    }
+
+    async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+        check_value_size(n, &v, self.max_value_bytes)?;
+        let mut store = self.store.lock().unwrap();
+        store.entry(self.name.clone()).or_default().insert(n, v);
+        Ok(())
+    }
+
+    async fn transaction(&self) -> anyhow::Result<Option<Box<dyn KvTxn + '_>>> {
+        Ok(Some(Box::new(MemoryTxn {
+            store: &self.store,
+            max_value_bytes: self.max_value_bytes,
+            writes: vec![],
+        })))
+    }
+
+    async fn keys(&self, from: u32, to: u32) -> anyhow::Result<Option<Vec<u32>>> {
+        let store = self.store.lock().unwrap();
+        let keys = match (store.get(&self.name), from <= to) {
+            (Some(b), true) => b.range(from..=to).map(|(k, _)| *k).collect(),
+            _ => vec![],
+        };
+        Ok(Some(keys))
+    }
+}
+
+/// Writes buffered until commit and applied under one lock
+pub struct MemoryTxn<'a> {
+    store: &'a Mutex<Buckets>,
+    max_value_bytes: Option<usize>,
+    // None deletes the key
+    writes: Vec<(String, u32, Option<Vec<u8>>)>,
+}
+
+#[async_trait]
+impl KvTxn for MemoryTxn<'_> {
+    async fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+        check_value_size(n, &v, self.max_value_bytes)?;
+        self.writes.push((bucket.to_string(), n, Some(v)));
+        Ok(())
+    }
+
+    async fn delete(&mut self, bucket: &str, n: u32) -> anyhow::Result<()> {
+        self.writes.push((bucket.to_string(), n, None));
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        let mut store = self.store.lock().unwrap();
+        for (bucket, n, v) in self.writes {
+            let bucket = store.entry(bucket).or_default();
+            match v {
+                Some(v) => bucket.insert(n, v),
+                None => bucket.remove(&n),
+            };
+        }
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ValueTooLarge;
+
+    #[tokio::test]
+    async fn it_applies_transactions_across_buckets() {
+        let blocks = MemoryKV::new().bucket("blocks");
+        let checkpoints = blocks.bucket("checkpoints");
+        blocks.set(1, vec![1]).await.unwrap();
+
+        let mut txn = blocks.transaction().await.unwrap().unwrap();
+        txn.set_many("blocks", vec![(2, vec![2]), (3, vec![3])])
+            .await
+            .unwrap();
+        txn.delete("blocks", 1).await.unwrap();
+        txn.set("checkpoints", 0, vec![3]).await.unwrap();
+        // nothing is visible before commit
+        assert_eq!(checkpoints.get(0).await.unwrap(), None);
+        assert_eq!(blocks.len(), 1);
+        txn.commit().await.unwrap();
+        assert_eq!(checkpoints.get(0).await.unwrap(), Some(vec![3]));
+        assert_eq!(blocks.get(1).await.unwrap(), None);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks.keys(0, 10).await.unwrap(), Some(vec![2, 3]));
+        assert_eq!(blocks.keys(3, u32::MAX).await.unwrap(), Some(vec![3]));
+        assert_eq!(blocks.keys(4, 2).await.unwrap(), Some(vec![]));
+
+        let mut txn = checkpoints.transaction().await.unwrap().unwrap();
+        txn.set("checkpoints", 0, vec![4]).await.unwrap();
+        txn.rollback().await.unwrap();
+        assert_eq!(checkpoints.get(0).await.unwrap(), Some(vec![3]));
+    }
+
+    #[tokio::test]
+    async fn it_rejects_values_over_the_limit() {
+        let kv = MemoryKV::new().with_max_value_bytes(4);
+        kv.set(1, vec![0; 4]).await.unwrap();
+        let err = kv.set(2, vec![0; 5]).await.unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ValueTooLarge>(),
+            Some(&ValueTooLarge {
+                key: 2,
+                size: 5,
+                limit: 4
+            })
+        );
+        let other = kv.bucket("other");
+        let mut txn = other.transaction().await.unwrap().unwrap();
+        let err = txn
+            .set_many("other", vec![(3, vec![0; 2]), (4, vec![0; 8])])
+            .await
+            .unwrap_err();
+        assert!(err.is::<ValueTooLarge>());
+        assert_eq!(kv.get(2).await.unwrap(), None);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::postgres::connect;
+use crate::PostgresKV;
+use anyhow::{bail, Context};
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use tracing::*;
+
+async fn table_exists(db: &PgPool, table_name: &str) -> anyhow::Result<bool> {
+    let row = sqlx::query("SELECT to_regclass($1) IS NOT NULL AS found")
+        .bind(table_name)
+        .fetch_one(db)
+        .await?;
+    Ok(row.get::<bool, _>("found"))
+}
+
+/// whether the table is declared with PARTITION BY
+pub(crate) async fn is_partitioned(db: &PgPool, table_name: &str) -> anyhow::Result<bool> {
+    let row = sqlx::query(
+        "SELECT count(*) AS n FROM pg_partitioned_table pt \
+        JOIN pg_class c ON c.oid = pt.partrelid WHERE c.relname = $1",
+    )
+    .bind(table_name)
+    .fetch_one(db)
+    .await?;
+    Ok(row.get::<i64, _>("n") > 0)
+}
+
+// indexes of existing child partitions, parsed from their names
+async fn list_partitions(db: &PgPool, table_name: &str) -> anyhow::Result<BTreeSet<u32>> {
+    let rows = sqlx::query(
+        "SELECT c.relname AS name FROM pg_inherits i \
+        JOIN pg_class c ON c.oid = i.inhrelid \
+        JOIN pg_class p ON p.oid = i.inhparent WHERE p.relname = $1",
+    )
+    .bind(table_name)
+    .fetch_all(db)
+    .await?;
+    let prefix = format!("{}_p", table_name);
+    Ok(rows
+        .iter()
+        .filter_map(|row| {
+            let name: String = row.get("name");
+            name.strip_prefix(&prefix)?.parse().ok()
+        })
+        .collect())
+}
+
+async fn create_partitioned(db: &PgPool, table_name: &str) -> anyhow::Result<()> {
+    sqlx::query(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (\"k\" INTEGER, \"v\" BYTEA, PRIMARY KEY (\"k\")) PARTITION BY RANGE (\"k\")",
+        table_name,
+    ))
+    .execute(db)
+    .await
+    .context("init partitioned table")?;
+    Ok(())
+}
+
+impl PostgresKV {
+    /// opens the table in partitioned mode, every child partition holds `width` keys.
+    /// Existing plain tables must be migrated first
+    pub async fn try_new_partitioned(
+        database_url: &str,
+        table_name: &str,
+        width: u32,
+    ) -> anyhow::Result<Self> {
+        if width == 0 {
+            bail!("partition width must be positive");
+        }
+        let db = connect(database_url).await?;
+        if table_exists(&db, table_name).await? && !is_partitioned(&db, table_name).await? {
+            bail!(
+                "table {} is not partitioned, migrate it with `btxs kv partition --migrate`",
+                table_name
+            );
+        }
+        info!("checking postgres partitioned tables");
+        create_partitioned(&db, table_name).await?;
+        let partitions = list_partitions(&db, table_name).await?;
+        Ok(Self {
+            db,
+            table_name: table_name.to_string(),
+            partition_width: Some(width),
+            partitions: Mutex::new(partitions),
+            max_value_bytes: None,
+        })
+    }
+
+    /// indexes of the child partitions, partition `i` holds keys from `i * width`
+    pub async fn partitions(&self) -> anyhow::Result<Vec<u32>> {
+        Ok(list_partitions(&self.db, &self.table_name)
+            .await?
+            .into_iter()
+            .collect())
+    }
+
+    fn partition_name(&self, index: u32) -> String {
+        format!("{}_p{}", self.table_name, index)
+    }
+
+    /// index and DDL of the child partition for the key, None when it is known to exist
+    pub(crate) fn partition_sql(&self, k: u32) -> Option<(u32, String)> {
+        let width = self.partition_width?;
+        let index = k / width;
+        if self.partitions.lock().unwrap().contains(&index) {
+            return None;
+        }
+        let from = index as u64 * width as u64;
+        let to = from + width as u64;
+        let to = if to > i32::MAX as u64 {
+            "MAXVALUE".to_string()
+        } else {
+            to.to_string()
+        };
+        let sql = format!(
+            "CREATE TABLE IF NOT EXISTS {} PARTITION OF {} FOR VALUES FROM ({}) TO ({})",
+            self.partition_name(index),
+            self.table_name,
+            from,
+            to,
+        );
+        Some((index, sql))
+    }
+
+    /// creates child partition for the key if it doesn't exist yet
+    pub(crate) async fn ensure_partition(&self, k: u32) -> anyhow::Result<()> {
+        let (index, sql) = match self.partition_sql(k) {
+            Some(p) => p,
+            None => return Ok(()),
+        };
+        debug!(index, "creating partition");
+        sqlx::query(&sql)
+            .execute(&self.db)
+            .await
+            .with_context(|| format!("create partition {}", index))?;
+        self.partitions.lock().unwrap().insert(index);
+        Ok(())
+    }
+
+    /// removes all keys below `n`. In partitioned mode whole partitions are detached
+    /// and dropped, only the partition containing `n` is cleaned by deleting rows
+    pub async fn prune_below(&self, n: u32) -> anyhow::Result<()> {
+        if let Some(width) = self.partition_width {
+            for index in list_partitions(&self.db, &self.table_name).await? {
+                if (index as u64 + 1) * width as u64 > n as u64 {
+                    continue;
+                }
+                let name = self.partition_name(index);
+                info!(partition = name.as_str(), "dropping partition");
+                sqlx::query(&format!(
+                    "ALTER TABLE {} DETACH PARTITION {}",
+                    self.table_name, name
+                ))
+                .execute(&self.db)
+                .await
+                .with_context(|| format!("detach {}", name))?;
+                sqlx::query(&format!("DROP TABLE {}", name))
+                    .execute(&self.db)
+                    .await
+                    .with_context(|| format!("drop {}", name))?;
+                self.partitions.lock().unwrap().remove(&index);
+            }
+        }
+        let sql = format!("DELETE FROM {} WHERE k < $1", self.table_name);
+        sqlx::query(&sql)
+            .bind(n as i32)
+            .execute(&self.db)
+            .await
+            .context("prune rows")?;
+        Ok(())
+    }
+
+    /// copies a plain table into the partitioned layout in batches of keys.
+    /// The plain table is kept as `<table>_legacy`, interrupted migration can be restarted
+    pub async fn migrate_to_partitioned(
+        database_url: &str,
+        table_name: &str,
+        width: u32,
+        batch: u32,
+    ) -> anyhow::Result<Self> {
+        let legacy = format!("{}_legacy", table_name);
+        {
+            let db = connect(database_url).await?;
+            if !table_exists(&db, &legacy).await? {
+                if !table_exists(&db, table_name).await? || is_partitioned(&db, table_name).await? {
+                    info!("nothing to migrate");
+                    return Self::try_new_partitioned(database_url, table_name, width).await;
+                }
+                info!(legacy = legacy.as_str(), "renaming plain table");
+                sqlx::query(&format!("ALTER TABLE {} RENAME TO {}", table_name, legacy))
+                    .execute(&db)
+                    .await?;
+                // primary key index keeps its name and would clash with the new table
+                sqlx::query(&format!(
+                    "ALTER INDEX IF EXISTS {}_pkey RENAME TO {}_pkey",
+                    table_name, legacy
+                ))
+                .execute(&db)
+                .await?;
+            }
+        }
+
+        let storage = Self::try_new_partitioned(database_url, table_name, width).await?;
+        let row = sqlx::query(&format!(
+            "SELECT min(k) AS lo, max(k) AS hi FROM {}",
+            legacy
+        ))
+        .fetch_one(&storage.db)
+        .await?;
+        let (lo, hi) = match (
+            row.get::<Option<i32>, _>("lo"),
+            row.get::<Option<i32>, _>("hi"),
+        ) {
+            (Some(lo), Some(hi)) => (lo as u32, hi as u32),
+            _ => return Ok(storage),
+        };
+        let batch = batch.max(1);
+        let mut from = lo;
+        while from <= hi {
+            let to = from.saturating_add(batch);
+            for index in from / width..=(to - 1) / width {
+                storage.ensure_partition(index * width).await?;
+            }
+            let copied = sqlx::query(&format!(
+                "INSERT INTO {} (k, v) SELECT k, v FROM {} WHERE k >= $1 AND k < $2 ON CONFLICT (k) DO NOTHING",
+                table_name, legacy
+            ))
+            .bind(from as i32)
+            .bind(to as i32)
+            .execute(&storage.db)
+            .await
+            .with_context(|| format!("copy keys {}..{}", from, to))?
+            .rows_affected();
+            info!(from, to, copied, "migrated");
+            if to == u32::MAX {
+                break;
+            }
+            from = to;
+        }
+        Ok(storage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PostgresKV, KV};
+    use std::env;
+
+    // tests need a scratch database, e.g. DATABASE_URL=postgres://localhost/btxs_test
+    fn database_url() -> Option<String> {
+        env::var("DATABASE_URL").ok()
+    }
+
+    async fn drop_tables(storage: &PostgresKV, table_name: &str) {
+        for t in [table_name.to_string(), format!("{}_legacy", table_name)] {
+            sqlx::query(&format!("DROP TABLE IF EXISTS {} CASCADE", t))
+                .execute(&storage.db)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn it_creates_partitions_and_prunes() {
+        let url = match database_url() {
+            Some(url) => url,
+            None => return,
+        };
+        let table = "kv_test_partitioned";
+        let plain = PostgresKV::try_new(&url, "kv_test_scratch").await.unwrap();
+        drop_tables(&plain, table).await;
+
+        let storage = PostgresKV::try_new_partitioned(&url, table, 10)
+            .await
+            .unwrap();
+        for k in [1, 15, 25, 27] {
+            storage.set(k, vec![k as u8]).await.unwrap();
+        }
+        assert_eq!(storage.partitions().await.unwrap(), vec![0, 1, 2]);
+        // reads cross partitions through the parent table
+        assert_eq!(storage.get(15).await.unwrap(), Some(vec![15]));
+        assert_eq!(storage.get(27).await.unwrap(), Some(vec![27]));
+
+        storage.prune_below(26).await.unwrap();
+        assert_eq!(storage.partitions().await.unwrap(), vec![2]);
+        assert_eq!(storage.get(15).await.unwrap(), None);
+        assert_eq!(storage.get(25).await.unwrap(), None);
+        assert_eq!(storage.get(27).await.unwrap(), Some(vec![27]));
+        drop_tables(&storage, table).await;
+    }
+
+    #[tokio::test]
+    async fn it_migrates_plain_table() {
+        let url = match database_url() {
+            Some(url) => url,
+            None => return,
+        };
+        let table = "kv_test_migrated";
+        let plain = PostgresKV::try_new(&url, "kv_test_scratch").await.unwrap();
+        drop_tables(&plain, table).await;
+
+        let plain = PostgresKV::try_new(&url, table).await.unwrap();
+        for k in 0..35 {
+            plain.set(k, vec![k as u8]).await.unwrap();
+        }
+        assert!(PostgresKV::try_new_partitioned(&url, table, 10)
+            .await
+            .is_err());
+
+        let storage = PostgresKV::migrate_to_partitioned(&url, table, 10, 7)
+            .await
+            .unwrap();
+        assert_eq!(storage.partitions().await.unwrap(), vec![0, 1, 2, 3]);
+        for k in 0..35 {
+            assert_eq!(storage.get(k).await.unwrap(), Some(vec![k as u8]));
+        }
+        drop_tables(&storage, table).await;
+    }
+}
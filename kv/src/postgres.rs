@@ -0,0 +1,249 @@
+use crate::{check_value_size, KvTxn, KV};
+use anyhow::Context;
+use async_trait::async_trait;
+use sqlx::postgres::{PgPool, Postgres};
+use sqlx::Row;
+use sqlx::Transaction;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+use tracing::*;
+
+#[derive(Debug)]
+pub struct PostgresKV {
+    pub db: PgPool,
+    pub table_name: String,
+    /// number of keys in every child partition, None for a plain table
+    pub partition_width: Option<u32>,
+    // indexes of child partitions that are known to exist
+    pub(crate) partitions: Mutex<BTreeSet<u32>>,
+    /// values over the limit are rejected with `ValueTooLarge`
+    pub max_value_bytes: Option<usize>,
+}
+
+impl PostgresKV {
+    pub async fn new(database_url: &str, table_name: &str) -> Self {
+        Self::try_new(database_url, table_name)
+            .await
+            .expect("could not init database")
+    }
+
+    /// same as `new`, but returns connection errors instead of panicking
+    pub async fn try_new(database_url: &str, table_name: &str) -> anyhow::Result<Self> {
+        let db = connect(database_url).await?;
+
+        info!("checking postgres tables");
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (\"k\" INTEGER, \"v\" BYTEA, PRIMARY KEY (\"k\"))",
+            table_name,
+        ))
+        .execute(&db)
+        .await
+        .context("init database")?;
+
+        Ok(Self {
+            db,
+            table_name: table_name.to_string(),
+            partition_width: None,
+            partitions: Mutex::new(BTreeSet::new()),
+            max_value_bytes: None,
+        })
+    }
+
+    pub fn with_max_value_bytes(mut self, limit: usize) -> Self {
+        self.max_value_bytes = Some(limit);
+        self
+    }
+
+    /// refreshes planner statistics of the table
+    pub async fn analyze(&self) -> anyhow::Result<()> {
+        sqlx::query(&format!("ANALYZE {}", self.table_name))
+            .execute(&self.db)
+            .await
+            .context("analyze")?;
+        Ok(())
+    }
+}
+
+pub(crate) async fn connect(database_url: &str) -> anyhow::Result<PgPool> {
+    sqlx::postgres::PgPoolOptions::new()
+        .max_connections(1)
+        .connect(database_url)
+        .await
+        .context("could not connect to database_url")
+}
+
+#[derive(sqlx::FromRow)]
+pub struct Record {
+    pub k: u32,
+    pub v: Vec<u8>,
+}
+
+#[async_trait]
+impl KV for PostgresKV {
+    #[instrument(level = "TRACE")]
+    async fn get(&self, n: u32) -> anyhow::Result<Option<Vec<u8>>> {
+        let sql = format!("SELECT v FROM {} WHERE k=$1 LIMIT 1", self.table_name);
+        let rows = sqlx::query(&sql)
+            .bind(n as i32)
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(rows.map(|row| row.get::<Vec<u8>, _>("v")))
+    }
+
+    #[instrument(level = "TRACE")]
+    async fn set(&self, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+        check_value_size(n, &v, self.max_value_bytes)?;
+        self.ensure_partition(n).await?;
+        let sql = format!(
+            "INSERT INTO {} (k, v) VALUES ($1, $2) ON CONFLICT(k) DO UPDATE SET v=$2",
+            self.table_name
+        );
+        let _ = sqlx::query(&sql)
+            .bind(n as i32)
+            .bind(v)
+            .execute(&self.db)
+            .await?
+            .rows_affected();
+        Ok(())
+    }
+
+    async fn transaction(&self) -> anyhow::Result<Option<Box<dyn KvTxn + '_>>> {
+        let tx = self.db.begin().await.context("begin")?;
+        Ok(Some(Box::new(PostgresTxn {
+            kv: self,
+            tx,
+            created: vec![],
+        })))
+    }
+
+    /// keys are stored as signed integers, the ones over i32::MAX are not listed
+    async fn keys(&self, from: u32, to: u32) -> anyhow::Result<Option<Vec<u32>>> {
+        let (Ok(from), to) = (i32::try_from(from), to.min(i32::MAX as u32) as i32) else {
+            return Ok(Some(vec![]));
+        };
+        let sql = format!(
+            "SELECT k FROM {} WHERE k >= $1 AND k <= $2 ORDER BY k",
+            self.table_name
+        );
+        let rows = sqlx::query(&sql)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.db)
+            .await
+            .context("keys")?;
+        Ok(Some(
+            rows.iter().map(|r| r.get::<i32, _>("k") as u32).collect(),
+        ))
+    }
+}
+
+/// Transaction over tables of the same database. Child partitions are created
+/// inside the transaction for the own table, other tables are written as plain ones
+pub struct PostgresTxn<'a> {
+    kv: &'a PostgresKV,
+    tx: Transaction<'static, Postgres>,
+    // partitions created by the transaction, known to exist after commit
+    created: Vec<u32>,
+}
+
+#[async_trait]
+impl KvTxn for PostgresTxn<'_> {
+    async fn set(&mut self, bucket: &str, n: u32, v: Vec<u8>) -> anyhow::Result<()> {
+        check_value_size(n, &v, self.kv.max_value_bytes)?;
+        if bucket == self.kv.table_name {
+            if let Some((index, sql)) = self.kv.partition_sql(n) {
+                if !self.created.contains(&index) {
+                    sqlx::query(&sql)
+                        .execute(&mut self.tx)
+                        .await
+                        .with_context(|| format!("create partition {}", index))?;
+                    self.created.push(index);
+                }
+            }
+        }
+        let sql = format!(
+            "INSERT INTO {} (k, v) VALUES ($1, $2) ON CONFLICT(k) DO UPDATE SET v=$2",
+            bucket
+        );
+        sqlx::query(&sql)
+            .bind(n as i32)
+            .bind(v)
+            .execute(&mut self.tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&mut self, bucket: &str, n: u32) -> anyhow::Result<()> {
+        let sql = format!("DELETE FROM {} WHERE k=$1", bucket);
+        sqlx::query(&sql)
+            .bind(n as i32)
+            .execute(&mut self.tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> anyhow::Result<()> {
+        self.tx.commit().await.context("commit")?;
+        self.kv.partitions.lock().unwrap().extend(self.created);
+        Ok(())
+    }
+
+    async fn rollback(self: Box<Self>) -> anyhow::Result<()> {
+        self.tx.rollback().await.context("rollback")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[tokio::test]
+    async fn it_commits_across_tables_or_nothing() {
+        let url = match env::var("DATABASE_URL") {
+            Ok(url) => url,
+            Err(_) => return,
+        };
+        let blocks = PostgresKV::try_new_partitioned(&url, "kv_test_txn_blocks", 10)
+            .await
+            .unwrap();
+        let checkpoints = PostgresKV::try_new(&url, "kv_test_txn_checkpoints")
+            .await
+            .unwrap();
+        for t in ["kv_test_txn_blocks", "kv_test_txn_checkpoints"] {
+            sqlx::query(&format!("DELETE FROM {}", t))
+                .execute(&blocks.db)
+                .await
+                .unwrap();
+        }
+
+        let mut txn = blocks.transaction().await.unwrap().unwrap();
+        txn.set_many("kv_test_txn_blocks", vec![(41, vec![41]), (42, vec![42])])
+            .await
+            .unwrap();
+        txn.set("kv_test_txn_checkpoints", 0, vec![42])
+            .await
+            .unwrap();
+        txn.rollback().await.unwrap();
+        assert_eq!(blocks.get(41).await.unwrap(), None);
+        assert_eq!(checkpoints.get(0).await.unwrap(), None);
+
+        let mut txn = blocks.transaction().await.unwrap().unwrap();
+        txn.set_many("kv_test_txn_blocks", vec![(41, vec![41]), (42, vec![42])])
+            .await
+            .unwrap();
+        txn.delete("kv_test_txn_blocks", 41).await.unwrap();
+        txn.set("kv_test_txn_checkpoints", 0, vec![42])
+            .await
+            .unwrap();
+        txn.commit().await.unwrap();
+        assert_eq!(blocks.get(41).await.unwrap(), None);
+        assert_eq!(blocks.get(42).await.unwrap(), Some(vec![42]));
+        assert_eq!(checkpoints.get(0).await.unwrap(), Some(vec![42]));
+        assert_eq!(blocks.keys(0, 100).await.unwrap(), Some(vec![42]));
+        assert_eq!(blocks.keys(43, u32::MAX).await.unwrap(), Some(vec![]));
+        // partition created by the transaction is known afterwards
+        assert!(blocks.partition_sql(43).is_none());
+    }
+}
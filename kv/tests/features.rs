@@ -0,0 +1,34 @@
+use std::process::Command;
+
+// dependency tree of the crate built with the given cargo feature flags
+fn tree(args: &[&str]) -> String {
+    let out = Command::new(env!("CARGO"))
+        .args([
+            "tree",
+            "-p",
+            "kv",
+            "-e",
+            "normal",
+            "--offline",
+            "--prefix",
+            "none",
+        ])
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cargo tree");
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    String::from_utf8(out.stdout).unwrap()
+}
+
+#[test]
+fn it_builds_without_sqlx() {
+    let minimal = tree(&["--no-default-features"]);
+    assert!(!minimal.contains("sqlx"), "sqlx in minimal build");
+    let postgres = tree(&["--no-default-features", "--features", "postgres"]);
+    assert!(postgres.contains("sqlx "));
+}